@@ -0,0 +1,17 @@
+//! Embeds the build timestamp and git commit for `GET /version`.
+
+use vergen_gitcl::{BuildBuilder, Emitter, GitclBuilder};
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let build = BuildBuilder::default().build_timestamp(true).build()?;
+    let git = GitclBuilder::default().sha(true).dirty(true).build()?;
+
+    // Outside a git checkout the git values fall back to placeholders
+    // instead of failing the build.
+    Emitter::default()
+        .add_instructions(&build)?
+        .add_instructions(&git)?
+        .emit()?;
+
+    Ok(())
+}
@@ -0,0 +1,55 @@
+//! The conversion formulas, shared by the server and by the dashboard's
+//! client-side previews so both always agree.
+//!
+//! `no_std` and, by default, dependency-free so it compiles to
+//! `wasm32-unknown-unknown`:
+//!
+//! ```text
+//! cargo rustc -p conversion-core --release --target wasm32-unknown-unknown \
+//!     --features wasm --crate-type cdylib
+//! ```
+#![no_std]
+
+#[cfg(any(feature = "protobuf", feature = "serde"))]
+extern crate alloc;
+
+#[cfg(feature = "serde")]
+pub mod case;
+
+#[cfg(feature = "protobuf")]
+pub mod proto;
+
+pub fn fahrenheit_to_celsius(fahrenheit: f32) -> f32 {
+    (fahrenheit - 32.0) / 1.8
+}
+
+pub fn celsius_to_fahrenheit(celsius: f32) -> f32 {
+    32.0 + (celsius * 1.8)
+}
+
+pub fn celsius_to_kelvin(celsius: f32) -> f32 {
+    celsius + 273.15
+}
+
+pub fn kelvin_to_celsius(kelvin: f32) -> f32 {
+    kelvin - 273.15
+}
+
+#[cfg(feature = "wasm")]
+mod wasm {
+    #[no_mangle]
+    pub extern "C" fn to_celsius(fahrenheit: f32) -> f32 {
+        super::fahrenheit_to_celsius(fahrenheit)
+    }
+
+    #[no_mangle]
+    pub extern "C" fn to_fahrenheit(celsius: f32) -> f32 {
+        super::celsius_to_fahrenheit(celsius)
+    }
+
+    #[cfg(target_arch = "wasm32")]
+    #[panic_handler]
+    fn panic(_: &core::panic::PanicInfo) -> ! {
+        core::arch::wasm32::unreachable()
+    }
+}
@@ -0,0 +1,31 @@
+//! The messages of `proto/conversions.proto`, derived by hand so building
+//! needs no `protoc`.
+
+use alloc::string::String;
+use alloc::vec::Vec;
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct Temperature {
+    #[prost(float, tag = "1")]
+    pub fahrenheit: f32,
+    #[prost(float, tag = "2")]
+    pub celsius: f32,
+    #[prost(string, optional, tag = "3")]
+    pub client_request_id: Option<String>,
+    #[prost(float, tag = "4")]
+    pub kelvin: f32,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct Temperatures {
+    #[prost(message, repeated, tag = "1")]
+    pub temperatures: Vec<Temperature>,
+}
+
+#[derive(Clone, PartialEq, prost::Message)]
+pub struct UsageStatistics {
+    #[prost(uint32, tag = "1")]
+    pub to_fahrenheit: u32,
+    #[prost(uint32, tag = "2")]
+    pub to_celsius: u32,
+}
@@ -0,0 +1,107 @@
+//! Per-key endpoint toggles: admins switch individual API-key routes off for
+//! a key, [`enforce_endpoint_access`] refuses them, and `/api/whoami` shows a
+//! key what it may call.
+
+use std::collections::BTreeMap;
+
+use actix_web::body::MessageBody;
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::middleware::Next;
+use actix_web::{get, put, web, Error, HttpRequest, HttpResponse, Responder};
+use actix_web_httpauth::extractors::basic::BasicAuth;
+use serde::{Deserialize, Serialize};
+
+use crate::auth;
+use crate::casing::{self, FieldCase};
+use crate::db::{self, ApiEndpoint};
+use crate::error::ApiError;
+use crate::rbac::{Authorized, Operator};
+use crate::scale::Scale;
+use crate::scopes::KeyScope;
+
+/// Rejects a request with 403 when its route is disabled for its API key.
+/// Routes that aren't an [`ApiEndpoint`] pass through.
+///
+/// Must run inside the authentication middleware.
+pub async fn enforce_endpoint_access(
+    mut req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    let endpoint = req
+        .match_pattern()
+        .and_then(|pattern| ApiEndpoint::for_route(req.method().as_str(), &pattern));
+
+    if let Some(endpoint) = endpoint {
+        let credentials = req.extract::<BasicAuth>().await?;
+        let access = auth::key_access(req.request(), credentials.user_id())
+            .map_err(|_| ApiError::Internal)?;
+
+        if access.is_some_and(|access| access.disabled.contains(endpoint)) {
+            return Err(ApiError::EndpointDisabled.into());
+        }
+    }
+
+    next.call(req).await
+}
+
+#[derive(Serialize)]
+struct WhoAmI {
+    id: i64,
+    tier: db::Tier,
+    /// Every API-key endpoint, and whether this key may call it.
+    endpoints: BTreeMap<ApiEndpoint, bool>,
+    /// What this key was issued for; see [`crate::scopes`].
+    scopes: Vec<KeyScope>,
+    /// The scales conversions answer this key in.
+    output_scales: Vec<Scale>,
+    /// The field case of this key's response bodies.
+    field_case: FieldCase,
+}
+
+#[get("/whoami")]
+pub async fn whoami(auth: BasicAuth, req: HttpRequest) -> actix_web::Result<impl Responder> {
+    let access = auth::key_access(&req, auth.user_id())
+        .map_err(|_| ApiError::Internal)?
+        .ok_or(ApiError::Unauthorized)?;
+
+    let endpoints = ApiEndpoint::ALL
+        .into_iter()
+        .map(|endpoint| (endpoint, !access.disabled.contains(endpoint)))
+        .collect();
+
+    Ok(casing::Json(WhoAmI {
+        id: access.id,
+        tier: access.tier,
+        endpoints,
+        scopes: access.scopes.iter().collect(),
+        output_scales: access.output_scales.iter().collect(),
+        field_case: casing::field_case(&req),
+    }))
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct DisabledEndpoints {
+    disabled: Vec<ApiEndpoint>,
+}
+
+/// Replaces the endpoints disabled for key `id`; those not listed are
+/// enabled again.
+#[put("/keys/{id}/endpoints")]
+pub async fn set_endpoints(
+    _: Authorized<Operator>,
+    id: web::Path<i64>,
+    body: web::Json<DisabledEndpoints>,
+    database: web::Data<db::Pool>,
+    key_cache: web::Data<auth::KeyCache>,
+) -> actix_web::Result<impl Responder> {
+    let disabled = body.disabled.iter().copied().collect();
+
+    let found = auth::set_disabled_endpoints(database, &key_cache, id.into_inner(), disabled)
+        .await
+        .map_err(|_| ApiError::Internal)?;
+    if !found {
+        return Err(ApiError::KeyNotFound.into());
+    }
+
+    Ok(HttpResponse::Ok().json(body.into_inner()))
+}
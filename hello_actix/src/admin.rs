@@ -0,0 +1,242 @@
+use actix_web::{get, post, web, HttpResponse, Responder};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use tracing::warn;
+
+use crate::config::Config;
+use crate::error::ApiError;
+use crate::jsonapi::Listed;
+use crate::rbac::{Admin, Authorized, Viewer};
+use crate::scopes::{KeyScope, ScopeSet};
+use crate::{auth, db, report, signup, UsageStats, WorkerCounters};
+
+#[get("/reports/monthly/{year}/{month}")]
+pub async fn monthly_report(
+    _: Authorized<Viewer>,
+    period: web::Path<(i32, u32)>,
+    database: web::Data<db::Pool>,
+) -> actix_web::Result<impl Responder> {
+    let (year, month) = period.into_inner();
+    let (from, to) = report::month_bounds(year, month).ok_or(ApiError::InvalidReportPeriod)?;
+
+    let (usage, countries) = db::read_consistent(database, move |conn| {
+        Ok((
+            db::count_usage(conn, from, to)?,
+            db::count_by_country(conn, from, to)?,
+        ))
+    })
+    .await?;
+    let html = report::render_monthly_html(year, month, &usage, &countries);
+
+    Ok(HttpResponse::Ok()
+        .content_type("text/html; charset=utf-8")
+        .body(html))
+}
+
+#[derive(Deserialize)]
+pub struct ComparedPeriods {
+    period_a: String,
+    period_b: String,
+}
+
+#[derive(Serialize)]
+struct UsageComparison {
+    period_a: String,
+    period_b: String,
+    endpoints: Vec<report::EndpointDelta>,
+}
+
+/// Calls per endpoint in month `period_b` against month `period_a`, e.g. to
+/// gauge the impact of a release.
+#[get("/usage/compare")]
+pub async fn compare_usage(
+    _: Authorized<Viewer>,
+    periods: web::Query<ComparedPeriods>,
+    database: web::Data<db::Pool>,
+) -> actix_web::Result<impl Responder> {
+    let ComparedPeriods { period_a, period_b } = periods.into_inner();
+    let (from_a, to_a) = report::parse_month(&period_a).ok_or(ApiError::InvalidReportPeriod)?;
+    let (from_b, to_b) = report::parse_month(&period_b).ok_or(ApiError::InvalidReportPeriod)?;
+
+    let (usage_a, usage_b) = db::read_consistent(database, move |conn| {
+        Ok((
+            db::count_usage(conn, from_a, to_a)?,
+            db::count_usage(conn, from_b, to_b)?,
+        ))
+    })
+    .await?;
+
+    Ok(web::Json(UsageComparison {
+        period_a,
+        period_b,
+        endpoints: report::compare(&usage_a, &usage_b),
+    }))
+}
+
+#[derive(Serialize)]
+struct WorkerStats {
+    total: WorkerCounters,
+    workers: Vec<WorkerEntry>,
+    restored: WorkerCounters,
+}
+
+#[derive(Serialize)]
+struct WorkerEntry {
+    worker: usize,
+    #[serde(flatten)]
+    counters: WorkerCounters,
+}
+
+/// The `/usage-statistics` counters, in total and per worker, to check they
+/// add up and to spot workers taking more than their share. Read without
+/// resetting them.
+#[get("/stats/workers")]
+pub async fn worker_stats(_: Authorized<Viewer>, stats: web::Data<UsageStats>) -> impl Responder {
+    let workers = stats.counters.by_worker();
+    let restored = stats.counters.restored();
+
+    // From the same reads, so the total adds up even while counting goes on.
+    web::Json(WorkerStats {
+        total: workers
+            .iter()
+            .fold(restored, |total, (_, counts)| total + *counts),
+        workers: workers
+            .into_iter()
+            .map(|(worker, counters)| WorkerEntry { worker, counters })
+            .collect(),
+        restored,
+    })
+}
+
+/// The `?from=&to=` window of [`bucketed_usage`], and the buckets to count
+/// it in; hourly unless `bucket` says otherwise.
+#[derive(Deserialize)]
+pub struct UsageWindow {
+    from: DateTime<Utc>,
+    to: DateTime<Utc>,
+    #[serde(default)]
+    bucket: db::Bucket,
+}
+
+#[derive(Serialize)]
+struct BucketedUsage {
+    from: DateTime<Utc>,
+    to: DateTime<Utc>,
+    bucket: db::Bucket,
+    counts: Vec<db::BucketCount>,
+}
+
+/// Calls per endpoint per hour or day between `from` and `to`, for traffic
+/// graphs. Anonymous counts are included.
+#[get("/usage")]
+pub async fn bucketed_usage(
+    _: Authorized<Viewer>,
+    window: web::Query<UsageWindow>,
+    database: web::Data<db::Pool>,
+) -> actix_web::Result<impl Responder> {
+    let UsageWindow { from, to, bucket } = window.into_inner();
+    if from >= to {
+        return Err(ApiError::InvalidUsageRange.into());
+    }
+
+    let counts = db::bucketed_usage_counts(database, from, to, bucket).await?;
+
+    Ok(web::Json(BucketedUsage {
+        from,
+        to,
+        bucket,
+        counts,
+    }))
+}
+
+/// Re-reads the master key, as [`auth::reload_master_key`] does, once its
+/// file has been restored or rotated in place.
+#[post("/master-key/reload")]
+pub async fn reload_master_key(_: Authorized<Admin>) -> actix_web::Result<impl Responder> {
+    auth::reload_master_key().map_err(|err| {
+        warn!(%err, "failed to reload the master key");
+        ApiError::Internal
+    })?;
+
+    Ok(HttpResponse::NoContent().finish())
+}
+
+/// The resolved configuration, to diagnose a misbehaving deployment.
+#[get("/config")]
+pub async fn effective_config(_: Authorized<Admin>, config: web::Data<Config>) -> impl Responder {
+    HttpResponse::Ok().json(config.effective())
+}
+
+#[get("/keys")]
+pub async fn list_keys(
+    _: Authorized<Admin>,
+    database: web::Data<db::Pool>,
+) -> actix_web::Result<impl Responder> {
+    Ok(Listed(db::list_api_keys(database).await?))
+}
+
+#[derive(Deserialize)]
+pub struct NewApiKey {
+    email: String,
+    #[serde(default)]
+    tier: db::Tier,
+    scopes: Vec<KeyScope>,
+}
+
+#[derive(Serialize)]
+struct IssuedApiKey {
+    api_key: String,
+    scopes: Vec<KeyScope>,
+}
+
+/// Issues a key with any scopes, `admin` included, which signups can't ask
+/// for. As at signup, any key issued to the same address before is
+/// revoked.
+#[post("/keys")]
+pub async fn issue_key(
+    _: Authorized<Admin>,
+    body: web::Json<NewApiKey>,
+    database: web::Data<db::Pool>,
+    keys: web::Data<dyn auth::KeyGenerator>,
+    key_cache: web::Data<auth::KeyCache>,
+) -> actix_web::Result<impl Responder> {
+    let body = body.into_inner();
+    let email = signup::normalize_email(&body.email)?;
+    if body.scopes.is_empty() {
+        return Err(ApiError::InvalidKeyScopes.into());
+    }
+    let scopes: ScopeSet = body.scopes.into_iter().collect();
+
+    let api_key = auth::store_api_key(
+        database, &key_cache, &**keys, email, body.tier, None, scopes,
+    )
+    .await?;
+
+    Ok(HttpResponse::Created().json(IssuedApiKey {
+        api_key,
+        scopes: scopes.iter().collect(),
+    }))
+}
+
+/// Usage recorded for key `id`, oldest first. Empty once the key's data has
+/// been erased.
+#[get("/keys/{id}/usage")]
+pub async fn key_usage(
+    _: Authorized<Admin>,
+    id: web::Path<i64>,
+    database: web::Data<db::Pool>,
+) -> actix_web::Result<impl Responder> {
+    let id = id.into_inner();
+
+    let api_key = db::run(database.clone(), move |conn| {
+        auth::api_key_by_id(conn, id).map_err(|err| err.to_string())
+    })
+    .await?;
+
+    let usage = match api_key {
+        Some(api_key) => db::usage_of_key(database, api_key).await?,
+        None => Vec::new(),
+    };
+
+    Ok(Listed(usage))
+}
@@ -0,0 +1,96 @@
+//! Long-polling usage alerts: `GET /api/alerts/wait` completes once the
+//! caller's calls reach a threshold, or when its timeout elapses.
+//!
+//! Calls are counted in memory per key since the server started, by the
+//! conversion handlers through [`crate::UsageStats`].
+
+use std::time::Duration;
+
+use actix_web::{get, web, Responder};
+use actix_web_httpauth::extractors::basic::BasicAuth;
+use dashmap::DashMap;
+use serde::{Deserialize, Serialize};
+use tokio::sync::watch;
+
+use crate::{auth, UsageStats};
+
+const DEFAULT_WAIT_SECONDS: u64 = 30;
+const MAX_WAIT_SECONDS: u64 = 60;
+
+/// Running call counts per API key, keyed by the key's fingerprint, each
+/// watchable by any number of waiting requests.
+#[derive(Debug, Default)]
+pub struct UsageWatch {
+    calls: DashMap<String, watch::Sender<u64>>,
+}
+
+impl UsageWatch {
+    pub fn new() -> Self {
+        UsageWatch::default()
+    }
+
+    /// Keys with a running count.
+    pub fn entries(&self) -> usize {
+        self.calls.len()
+    }
+
+    pub fn record(&self, api_key: &str) {
+        self.sender(api_key).send_modify(|calls| *calls += 1);
+    }
+
+    pub fn subscribe(&self, api_key: &str) -> watch::Receiver<u64> {
+        self.sender(api_key).subscribe()
+    }
+
+    fn sender(&self, api_key: &str) -> watch::Sender<u64> {
+        self.calls
+            .entry(auth::fingerprint(api_key))
+            .or_insert_with(|| watch::channel(0).0)
+            .clone()
+    }
+}
+
+#[derive(Deserialize, Debug)]
+pub struct WaitParams {
+    threshold: u64,
+    /// Capped at [`MAX_WAIT_SECONDS`].
+    timeout_seconds: Option<u64>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct UsageAlert {
+    /// `false` when the timeout elapsed first.
+    pub crossed: bool,
+    pub calls: u64,
+    pub threshold: u64,
+}
+
+/// Registered outside the `/api` scope's concurrency limit, so a waiting
+/// request doesn't hold one of the key's slots.
+#[get("/wait")]
+pub async fn wait_for_usage(
+    auth: BasicAuth,
+    params: web::Query<WaitParams>,
+    stats: web::Data<UsageStats>,
+) -> impl Responder {
+    let mut calls = stats.alerts.subscribe(auth.user_id());
+    let threshold = params.threshold;
+    let timeout = params
+        .timeout_seconds
+        .unwrap_or(DEFAULT_WAIT_SECONDS)
+        .min(MAX_WAIT_SECONDS);
+
+    let crossed = actix_web::rt::time::timeout(
+        Duration::from_secs(timeout),
+        calls.wait_for(|&calls| calls >= threshold),
+    )
+    .await
+    .is_ok_and(|changed| changed.is_ok());
+
+    let calls = *calls.borrow();
+    web::Json(UsageAlert {
+        crossed,
+        calls,
+        threshold,
+    })
+}
@@ -1,15 +1,24 @@
-use actix_web::{error, web};
+use actix_web::{web, HttpRequest};
 use base64::engine::general_purpose::STANDARD_NO_PAD as BASE64;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
 use base64::Engine as _;
+use chrono::{DateTime, Utc};
 use ring::rand::SecureRandom;
-use ring::{aead, rand};
-use std::collections::HashMap;
+use ring::{aead, digest, rand};
+use std::collections::{HashMap, HashSet};
 use std::error::Error;
 use std::fs::read_to_string;
-use std::iter::repeat_with;
-use std::sync::{Arc, LazyLock, RwLock};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, OnceLock, PoisonError, RwLock};
+use std::time::Instant;
+use tracing::warn;
 
-use crate::db;
+use crate::casing::FieldCase;
+use crate::clock;
+use crate::db::{self, FromRow as _};
+use crate::metrics;
+use crate::scale::ScaleSet;
+use crate::scopes::ScopeSet;
 
 const MASTER_KEY_FILE: &str = "master.key";
 const SALT_LENGTH: usize = 16;
@@ -17,11 +26,62 @@ const MASTER_KEY_LENGTH: usize = 32;
 
 type Result<T> = std::result::Result<T, Box<dyn Error>>;
 
-#[allow(clippy::type_complexity)]
-static API_KEYS: LazyLock<Arc<RwLock<HashMap<String, Vec<u8>>>>> =
-    LazyLock::new(|| Arc::new(RwLock::new(HashMap::new())));
+/// Read from [`MASTER_KEY_FILE`] on first use; see [`reload_master_key`].
+static MASTER_KEY: OnceLock<RwLock<Arc<aead::LessSafeKey>>> = OnceLock::new();
+
+/// What is held in memory about an active key, to check requests against.
+#[derive(Debug, Clone, Copy)]
+pub struct KeyAccess {
+    pub id: i64,
+    pub tier: db::Tier,
+    pub disabled: db::EndpointSet,
+    pub output_scales: ScaleSet,
+    /// Set for this key alone, over its tier's; 0 is unlimited.
+    pub monthly_quota: Option<u64>,
+    /// `None` for a key that doesn't expire.
+    pub expires_at: Option<DateTime<Utc>>,
+    /// `None` leaves it to the instance; see [`crate::casing`].
+    pub field_case: Option<FieldCase>,
+    pub scopes: ScopeSet,
+}
+
+impl KeyAccess {
+    pub fn is_expired(&self, now: DateTime<Utc>) -> bool {
+        self.expires_at.is_some_and(|expires_at| expires_at <= now)
+    }
+}
+
+/// The cached master key, loading it on first use.
+fn master_key() -> Result<Arc<aead::LessSafeKey>> {
+    if let Some(key) = MASTER_KEY.get() {
+        return Ok(key.read()?.clone());
+    }
+
+    let key = Arc::new(get_or_create_master_key()?);
+    // Another thread may have got there first; theirs is the same key.
+    Ok(MASTER_KEY.get_or_init(|| RwLock::new(key)).read()?.clone())
+}
+
+/// Re-reads the master key file, e.g. after it is restored from a backup.
+/// Keys already in memory stay as they were decrypted. Fails rather than
+/// create a new key when the file is missing, which would leave every
+/// stored key undecryptable.
+pub fn reload_master_key() -> Result<()> {
+    if !std::path::Path::new(MASTER_KEY_FILE).exists() {
+        return Err(format!("no {MASTER_KEY_FILE} to reload").into());
+    }
+
+    let key = Arc::new(get_or_create_master_key()?);
+    let cached = MASTER_KEY.get_or_init(|| RwLock::new(key.clone()));
+    *cached.write()? = key;
+    Ok(())
+}
 
 fn get_or_create_master_key() -> Result<aead::LessSafeKey> {
+    metrics::CRYPTO
+        .master_key_loads
+        .fetch_add(1, Ordering::Relaxed);
+
     let key = if let Ok(existing_key) = read_to_string(MASTER_KEY_FILE) {
         BASE64.decode(existing_key.trim())?
     } else {
@@ -50,8 +110,16 @@ fn generate_salt() -> Result<[u8; SALT_LENGTH]> {
     Ok(salt)
 }
 
+/// Runs `op`, timing it into [`metrics::CRYPTO`].
+fn timed<T>(op: impl FnOnce() -> Result<T>) -> Result<T> {
+    let start = Instant::now();
+    let result = op();
+    metrics::CRYPTO.observe(start.elapsed().as_micros() as u64);
+    result
+}
+
 fn encrypt(plaintext: &str, salt: &[u8]) -> Result<String> {
-    let key = get_or_create_master_key()?;
+    let key = master_key()?;
     let nonce = aead::Nonce::assume_unique_for_key([0; 12]);
     let mut in_out = plaintext.as_bytes().to_vec();
     key.seal_in_place_append_tag(nonce, aead::Aad::from(salt), &mut in_out)
@@ -60,7 +128,7 @@ fn encrypt(plaintext: &str, salt: &[u8]) -> Result<String> {
 }
 
 fn decrypt(ciphertext: &str, salt: &[u8]) -> Result<String> {
-    let key = get_or_create_master_key()?;
+    let key = master_key()?;
     let nonce = aead::Nonce::assume_unique_for_key([0; 12]);
     let mut in_out = BASE64.decode(ciphertext)?;
     let plaintext = key
@@ -69,60 +137,650 @@ fn decrypt(ciphertext: &str, salt: &[u8]) -> Result<String> {
     Ok(String::from_utf8(plaintext.to_vec())?)
 }
 
-pub fn create_api_key() -> String {
-    repeat_with(fastrand::alphanumeric).take(40).collect()
+/// A stable, non-reversible identifier for an API key, safe to use as a map
+/// key or in logs without exposing the key itself.
+pub fn fingerprint(api_key: &str) -> String {
+    let hash = digest::digest(&digest::SHA256, api_key.as_bytes());
+    BASE64.encode(&hash.as_ref()[..16])
+}
+
+/// A random, URL-safe bearer secret for one-time links and session cookies.
+pub fn generate_token() -> Result<String> {
+    let rng = rand::SystemRandom::new();
+    let mut token = [0u8; 32];
+    rng.fill(&mut token)
+        .map_err(|_| "Failed to generate token")?;
+    Ok(URL_SAFE_NO_PAD.encode(token))
+}
+
+/// Tokens are only ever stored hashed, so a leaked database can't be used to
+/// hijack logins.
+pub fn hash_token(token: &str) -> String {
+    let hash = digest::digest(&digest::SHA256, token.as_bytes());
+    BASE64.encode(hash.as_ref())
+}
+
+/// Starts every key [`create_api_key`] issues, so leaked keys are easy to
+/// scan for.
+pub const KEY_PREFIX: &str = "tk_live_";
+/// 256 random bits in base 62.
+const KEY_PAYLOAD_LENGTH: usize = 43;
+/// 32 bits of the payload's SHA-256, in base 62.
+const KEY_CHECKSUM_LENGTH: usize = 6;
+
+const BASE62: &[u8; 62] = b"0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz";
+
+/// `bytes` as a big-endian number in base 62, left-padded with `0` to
+/// `width` digits. `width` must fit the number.
+fn base62(bytes: &[u8], width: usize) -> String {
+    let mut number = bytes.to_vec();
+    let mut digits = Vec::with_capacity(width);
+    while digits.len() < width {
+        let mut remainder = 0;
+        for byte in &mut number {
+            let value = remainder * 256 + u32::from(*byte);
+            *byte = (value / 62) as u8;
+            remainder = value % 62;
+        }
+        digits.push(BASE62[remainder as usize]);
+    }
+    digits.reverse();
+    String::from_utf8(digits).unwrap()
+}
+
+fn key_checksum(payload: &str) -> String {
+    let hash = digest::digest(&digest::SHA256, payload.as_bytes());
+    base62(&hash.as_ref()[..4], KEY_CHECKSUM_LENGTH)
+}
+
+/// A new key: [`KEY_PREFIX`], 256 bits from the system's secure random
+/// source, and a checksum of those; see [`is_plausible_key`].
+pub fn create_api_key() -> Result<String> {
+    let rng = rand::SystemRandom::new();
+    let mut payload = [0u8; 32];
+    rng.fill(&mut payload)
+        .map_err(|_| "Failed to generate key")?;
+
+    let payload = base62(&payload, KEY_PAYLOAD_LENGTH);
+    let checksum = key_checksum(&payload);
+    Ok(format!("{KEY_PREFIX}{payload}{checksum}"))
+}
+
+/// `false` for a key in [`create_api_key`]'s format with a checksum that
+/// doesn't match, which can't have been issued; checking needs no lookup.
+/// Keys without [`KEY_PREFIX`], as issued before it, are let through.
+pub fn is_plausible_key(api_key: &str) -> bool {
+    let Some(rest) = api_key.strip_prefix(KEY_PREFIX) else {
+        return true;
+    };
+    if rest.len() != KEY_PAYLOAD_LENGTH + KEY_CHECKSUM_LENGTH || !rest.is_ascii() {
+        return false;
+    }
+
+    let (payload, checksum) = rest.split_at(KEY_PAYLOAD_LENGTH);
+    key_checksum(payload) == checksum
+}
+
+/// Where signup gets new keys from. [`RandomKeys`] outside tests; a closure
+/// returning fixed keys makes issuance predictable.
+pub trait KeyGenerator: Send + Sync {
+    fn generate(&self) -> Result<String>;
 }
 
-pub fn load_api_keys(database: web::Data<db::Pool>) -> Result<()> {
-    let conn = database
-        .get()
-        .map_err(actix_web::error::ErrorInternalServerError)?;
+/// [`create_api_key`].
+#[derive(Debug, Default, Clone, Copy)]
+pub struct RandomKeys;
+
+impl KeyGenerator for RandomKeys {
+    fn generate(&self) -> Result<String> {
+        create_api_key()
+    }
+}
+
+impl<F: Fn() -> String + Send + Sync> KeyGenerator for F {
+    fn generate(&self) -> Result<String> {
+        Ok(self())
+    }
+}
+
+/// Keys held in memory, so requests are checked without a query. One per
+/// app, in [`crate::AppState`]; keys issued elsewhere are looked up on a
+/// miss, see [`KeyCache::check`].
+#[derive(Debug, Default)]
+pub struct KeyCache {
+    /// Keys not revoked, expired ones included: whether a key has expired
+    /// is up to the clock of whoever asks.
+    active: RwLock<HashMap<String, KeyAccess>>,
+    /// Fingerprints of revoked keys, so they can be told apart from unknown
+    /// ones without keeping them in memory.
+    revoked: RwLock<HashSet<String>>,
+    /// Whether `active` has been loaded since startup.
+    loaded: AtomicBool,
+}
+
+fn poisoned<T>(_: PoisonError<T>) -> Box<dyn Error> {
+    "the key cache is poisoned".into()
+}
+
+impl KeyCache {
+    pub fn new() -> Self {
+        KeyCache::default()
+    }
+
+    /// Caches every stored key, blocking on a connection from `database`.
+    pub fn load(&self, database: web::Data<db::Pool>) -> Result<()> {
+        let conn = database
+            .get()
+            .map_err(actix_web::error::ErrorInternalServerError)?;
+
+        self.fill(stored_keys(&conn)?)
+    }
+
+    /// [`KeyCache::load`] on a database thread. Waiting for a connection on
+    /// an async worker can starve the task holding the last one.
+    async fn reload(&self, database: web::Data<db::Pool>) -> Result<()> {
+        let keys = db::run(database, |conn| {
+            stored_keys(conn).map_err(|err| err.to_string())
+        })
+        .await?;
+        self.fill(keys)
+    }
+
+    fn fill(&self, keys: Vec<CachedKey>) -> Result<()> {
+        self.insert(keys)?;
+        self.loaded.store(true, Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// Merges `keys` into the cache. They come decrypted, so the locks are
+    /// held for the inserts alone.
+    fn insert(&self, keys: Vec<CachedKey>) -> Result<()> {
+        let mut active = self.active.write().map_err(poisoned)?;
+        let mut revoked = self.revoked.write().map_err(poisoned)?;
+
+        for key in keys {
+            match key.access {
+                Some(access) => {
+                    active.insert(key.api_key, access);
+                }
+                None => {
+                    revoked.insert(fingerprint(&key.api_key));
+                    active.remove(&key.api_key);
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Whether the cache has been loaded since startup. Until then every
+    /// key is looked up in the database.
+    pub fn is_loaded(&self) -> bool {
+        self.loaded.load(Ordering::Relaxed)
+    }
+
+    /// Keys held: unrevoked ones, and fingerprints of revoked ones.
+    pub fn counts(&self) -> Result<(usize, usize)> {
+        Ok((
+            self.active.read().map_err(poisoned)?.len(),
+            self.revoked.read().map_err(poisoned)?.len(),
+        ))
+    }
+
+    /// `None` if the key is unknown, revoked or expired by `now`.
+    pub fn access(&self, api_key: &str, now: DateTime<Utc>) -> Result<Option<KeyAccess>> {
+        let active = self.active.read().map_err(poisoned)?;
+
+        Ok(active
+            .get(api_key)
+            .filter(|access| !access.is_expired(now))
+            .copied())
+    }
+
+    /// Whether `api_key` is cached as active and hasn't expired by `now`.
+    pub fn is_allowed(&self, api_key: &str, now: DateTime<Utc>) -> Result<bool> {
+        Ok(self.access(api_key, now)?.is_some())
+    }
+
+    fn status(&self, api_key: &str, now: DateTime<Utc>) -> Result<KeyStatus> {
+        if let Some(access) = self.active.read().map_err(poisoned)?.get(api_key) {
+            return Ok(if access.is_expired(now) {
+                KeyStatus::Expired
+            } else {
+                KeyStatus::Active
+            });
+        }
+
+        let fingerprint = fingerprint(api_key);
+        if self
+            .revoked
+            .read()
+            .map_err(poisoned)?
+            .contains(&fingerprint)
+        {
+            Ok(KeyStatus::Revoked)
+        } else {
+            Ok(KeyStatus::Unknown)
+        }
+    }
+
+    /// Whether `api_key` is active, revoked, expired by `now` or unknown,
+    /// falling back to the database when it isn't in memory: for every key
+    /// while the cache is cold, as on a freshly started replica, and then
+    /// for this key alone, as one created on another replica. Keys failing
+    /// [`is_plausible_key`] are unknown without looking.
+    pub async fn check(
+        &self,
+        database: web::Data<db::Pool>,
+        api_key: &str,
+        now: DateTime<Utc>,
+    ) -> Result<KeyStatus> {
+        if !is_plausible_key(api_key) {
+            return Ok(KeyStatus::Unknown);
+        }
+
+        let status = self.status(api_key, now)?;
+        if status != KeyStatus::Unknown {
+            return Ok(status);
+        }
+
+        if self.is_loaded() {
+            let api_key = api_key.to_owned();
+            let key = db::run(database, move |conn| {
+                stored_key(conn, &api_key).map_err(|err| err.to_string())
+            })
+            .await?;
+            self.insert(key.into_iter().collect())?;
+        } else {
+            self.reload(database).await?;
+        }
+        self.status(api_key, now)
+    }
 
-    let mut stmt = conn.prepare(
+    /// Stops accepting keys right away, e.g. once their rows are erased.
+    pub fn forget(&self, keys: &[String]) -> Result<()> {
+        let mut active = self.active.write().map_err(poisoned)?;
+
+        for key in keys {
+            active.remove(key);
+        }
+
+        Ok(())
+    }
+}
+
+/// What `req`'s app has cached about `api_key`, by the app's clock; see
+/// [`KeyCache::access`].
+pub fn key_access(req: &HttpRequest, api_key: &str) -> Result<Option<KeyAccess>> {
+    let cache = req
+        .app_data::<web::Data<KeyCache>>()
+        .ok_or("no key cache registered")?;
+
+    cache.access(api_key, clock::now(req))
+}
+
+/// The tier of an active key, or `None` if the key is unknown, revoked or
+/// expired; see [`key_access`].
+pub fn key_tier(req: &HttpRequest, api_key: &str) -> Result<Option<db::Tier>> {
+    Ok(key_access(req, api_key)?.map(|access| access.tier))
+}
+
+/// A row as the cache holds it: decrypted, with what the key may do, or
+/// `None` for `access` once revoked.
+struct CachedKey {
+    id: i64,
+    api_key: String,
+    access: Option<KeyAccess>,
+    fingerprinted: bool,
+}
+
+/// Stored keys matching `filter`, with the columns [`cached_key`] reads.
+fn cached_keys_sql(filter: &str) -> String {
+    format!(
         "
-        SELECT  api_key, salt
+        SELECT  {}, {}, disabled_endpoints, output_scales, field_case, scopes, fingerprint,
+                (SELECT monthly_limit FROM quotas WHERE key_id = api_keys.id) AS monthly_quota,
+                revoked_at IS NOT NULL AS revoked
         FROM    api_keys
-        WHERE   revoked_at IS NULL
+        WHERE   api_key IS NOT NULL {filter}
     ;",
+        db::ApiKeyRecord::COLUMNS,
+        db::EncryptedApiKey::COLUMNS,
+    )
+}
+
+/// Decrypts a row of [`cached_keys_sql`]; `None` if its data has been
+/// erased.
+fn cached_key(row: &rusqlite::Row) -> Result<Option<CachedKey>> {
+    let record = db::ApiKeyRecord::from_row(row)?;
+    let stored = db::EncryptedApiKey::from_row(row)?;
+    let Some(api_key) = decrypt_stored(&stored)? else {
+        return Ok(None);
+    };
+
+    let revoked: bool = row.get("revoked")?;
+    let access = KeyAccess {
+        id: record.id,
+        tier: record.tier,
+        disabled: row.get("disabled_endpoints")?,
+        output_scales: row.get("output_scales")?,
+        monthly_quota: row.get("monthly_quota")?,
+        expires_at: record.expires_at,
+        field_case: row.get("field_case")?,
+        scopes: row.get("scopes")?,
+    };
+    let fingerprint: Option<String> = row.get("fingerprint")?;
+
+    Ok(Some(CachedKey {
+        id: record.id,
+        api_key,
+        access: (!revoked).then_some(access),
+        fingerprinted: fingerprint.is_some(),
+    }))
+}
+
+/// Every stored key, decrypted. Backfills the fingerprints of keys stored
+/// before fingerprints were, so issuance sees them too; of duplicates among
+/// them, only the first gets one.
+fn stored_keys(conn: &rusqlite::Connection) -> Result<Vec<CachedKey>> {
+    let mut stmt = conn.prepare(&cached_keys_sql(""))?;
+    let mut rows = stmt.query(())?;
+
+    let mut keys = Vec::new();
+    while let Some(row) = rows.next()? {
+        keys.extend(cached_key(row)?);
+    }
+
+    let mut stmt = conn.prepare_cached(
+        "UPDATE OR IGNORE api_keys SET fingerprint = ?2 WHERE id = ?1 AND fingerprint IS NULL;",
     )?;
+    for key in keys.iter().filter(|key| !key.fingerprinted) {
+        stmt.execute((key.id, fingerprint(&key.api_key)))?;
+    }
+
+    Ok(keys)
+}
 
-    let mut rows = stmt.query(()).map_err(error::ErrorInternalServerError)?;
+/// `api_key` alone, found by its fingerprint, if it is stored. Decrypts one
+/// row at most, where [`stored_keys`] does them all.
+fn stored_key(conn: &rusqlite::Connection, api_key: &str) -> Result<Option<CachedKey>> {
+    let mut stmt = conn.prepare_cached(&cached_keys_sql("AND fingerprint = ?1"))?;
+    let mut rows = stmt.query((fingerprint(api_key),))?;
 
-    let mut api_keys = API_KEYS.write().unwrap();
+    let Some(row) = rows.next()? else {
+        return Ok(None);
+    };
+    // A fingerprint is a truncated hash, so the key itself decides.
+    Ok(cached_key(row)?.filter(|key| key.api_key == api_key))
+}
 
-    while let Some(row) = rows.next().map_err(error::ErrorInternalServerError)? {
-        let api_key: String = row.get(0).map_err(error::ErrorInternalServerError)?;
-        let salt: String = row.get(1).map_err(error::ErrorInternalServerError)?;
+/// Decrypts a stored key; `None` if its data has been erased.
+fn decrypt_stored(stored: &db::EncryptedApiKey) -> Result<Option<String>> {
+    let (Some(ciphertext), Some(salt)) = (&stored.ciphertext, &stored.salt) else {
+        return Ok(None);
+    };
 
-        let salt = BASE64.decode(salt)?;
+    let salt = BASE64.decode(salt)?;
+    Ok(Some(timed(|| decrypt(ciphertext, &salt))?))
+}
 
-        let api_key = decrypt(&api_key, &salt)?;
-        api_keys.insert(api_key, salt);
+/// The stored record of a key active at `now`. Keys are stored encrypted,
+/// so this decrypts each active key in turn.
+pub fn find_api_key(
+    conn: &rusqlite::Connection,
+    api_key: &str,
+    now: DateTime<Utc>,
+) -> Result<Option<db::ApiKeyRecord>> {
+    let mut stmt = conn.prepare(&format!(
+        "
+        SELECT  {}, {}
+        FROM    api_keys
+        WHERE   revoked_at IS NULL AND api_key IS NOT NULL
+          AND   (expires_at IS NULL OR expires_at > ?1)
+    ;",
+        db::ApiKeyRecord::COLUMNS,
+        db::EncryptedApiKey::COLUMNS,
+    ))?;
+    let mut rows = stmt.query((db::Timestamp(now),))?;
+
+    while let Some(row) = rows.next()? {
+        let stored = db::EncryptedApiKey::from_row(row)?;
+
+        if decrypt_stored(&stored)?.as_deref() == Some(api_key) {
+            return Ok(Some(db::ApiKeyRecord::from_row(row)?));
+        }
     }
 
-    Ok(())
+    Ok(None)
+}
+
+/// Key `id`, decrypted; `None` if there is no such key or its data has been
+/// erased.
+pub fn api_key_by_id(conn: &rusqlite::Connection, id: i64) -> Result<Option<String>> {
+    let mut stmt = conn.prepare(&format!(
+        "SELECT {} FROM api_keys WHERE id = ?1;",
+        db::EncryptedApiKey::COLUMNS,
+    ))?;
+    let mut rows = stmt.query((id,))?;
+
+    match rows.next()? {
+        Some(row) => decrypt_stored(&db::EncryptedApiKey::from_row(row)?),
+        None => Ok(None),
+    }
+}
+
+/// The keys issued to one owner, decrypted.
+#[derive(Debug)]
+pub struct OwnerKeys {
+    pub email: Option<String>,
+    pub ids: Vec<i64>,
+    pub api_keys: Vec<String>,
+}
+
+/// Every key issued to the owner of key `id`, or just that key if it has no
+/// owner on record. `None` if there is no such key. Keys already erased are
+/// listed in `ids` but not `api_keys`.
+pub fn owner_keys(conn: &rusqlite::Connection, id: i64) -> Result<Option<OwnerKeys>> {
+    let sql = format!(
+        "
+    SELECT  {}, {}
+    FROM    api_keys
+    WHERE   id = ?1 OR email = (SELECT email FROM api_keys WHERE id = ?1)
+    ORDER BY id;
+    ",
+        db::ApiKeyRecord::COLUMNS,
+        db::EncryptedApiKey::COLUMNS,
+    );
+
+    let mut stmt = conn.prepare(&sql)?;
+    let mut rows = stmt.query((id,))?;
+
+    let mut owner = OwnerKeys {
+        email: None,
+        ids: Vec::new(),
+        api_keys: Vec::new(),
+    };
+
+    while let Some(row) = rows.next()? {
+        let record = db::ApiKeyRecord::from_row(row)?;
+        owner.ids.push(record.id);
+        owner.email = owner.email.or(record.email);
+
+        if let Some(api_key) = decrypt_stored(&db::EncryptedApiKey::from_row(row)?)? {
+            owner.api_keys.push(api_key);
+        }
+    }
+
+    Ok((!owner.ids.is_empty()).then_some(owner))
+}
+
+/// Keys [`store_api_key`] draws before giving up on finding one not
+/// already stored. A random key colliding once is already unheard of.
+const KEY_ATTEMPTS: usize = 3;
+
+/// Stores a key from `keys` issued to the owner of `email` with `scopes`,
+/// expiring at `expires_at` if given, and returns it. Draws again while the drawn key
+/// is already stored, under any owner.
+pub async fn store_api_key(
+    database: web::Data<db::Pool>,
+    cache: &KeyCache,
+    keys: &dyn KeyGenerator,
+    email: String,
+    tier: db::Tier,
+    expires_at: Option<DateTime<Utc>>,
+    scopes: ScopeSet,
+) -> Result<String> {
+    for _ in 0..KEY_ATTEMPTS {
+        let api_key = keys.generate()?;
+        let salt = generate_salt()?;
+        let query = db::Query::StoreApiKey {
+            api_key: timed(|| encrypt(&api_key, &salt))?,
+            salt: BASE64.encode(salt),
+            fingerprint: fingerprint(&api_key),
+            email: email.clone(),
+            tier,
+            expires_at,
+            scopes,
+        };
+
+        if query.execute(database.clone()).await? == Some(true) {
+            cache.reload(database).await?;
+            return Ok(api_key);
+        }
+        warn!(key = %fingerprint(&api_key), "generated a key already stored, drawing again");
+    }
+
+    Err(format!("no unused key in {KEY_ATTEMPTS} attempts").into())
 }
 
-pub async fn store_api_key(database: web::Data<db::Pool>, api_key: impl AsRef<str>) -> Result<()> {
-    let salt = generate_salt()?;
-    let api_key = encrypt(api_key.as_ref(), &salt)?;
-    let salt = BASE64.encode(salt);
-    let query = db::Query::StoreApiKey { salt, api_key };
+/// Revokes `token`, found by its fingerprint as keys are stored encrypted.
+/// `false` if there is no such key left to revoke.
+pub async fn revoke_api_key(
+    database: web::Data<db::Pool>,
+    cache: &KeyCache,
+    token: String,
+) -> Result<bool> {
+    let query = db::Query::RevokeApiKey(fingerprint(&token));
+    if query.execute(database.clone()).await? != Some(true) {
+        return Ok(false);
+    }
 
+    cache.reload(database).await?;
+    Ok(true)
+}
+
+/// Revokes key `id`. `false` if there is no such key left to revoke.
+pub async fn revoke_api_key_by_id(
+    database: web::Data<db::Pool>,
+    cache: &KeyCache,
+    id: i64,
+) -> Result<bool> {
+    let query = db::Query::RevokeApiKeyById(id);
+    if query.execute(database.clone()).await? != Some(true) {
+        return Ok(false);
+    }
+
+    cache.reload(database).await?;
+    Ok(true)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyStatus {
+    Active,
+    Revoked,
+    Expired,
+    Unknown,
+}
+
+/// Turns off `disabled` for key `id`, and everything else on. `false` if
+/// there is no such key.
+pub async fn set_disabled_endpoints(
+    database: web::Data<db::Pool>,
+    cache: &KeyCache,
+    id: i64,
+    disabled: db::EndpointSet,
+) -> Result<bool> {
+    let query = db::Query::SetDisabledEndpoints { id, disabled };
+    let found = query.execute(database.clone()).await? == Some(true);
+
+    cache.reload(database).await?;
+    Ok(found)
+}
+
+/// Sets the monthly quota of key `id`; see [`db::Query::SetMonthlyQuota`].
+/// `false` if there is no such key.
+pub async fn set_monthly_quota(
+    database: web::Data<db::Pool>,
+    cache: &KeyCache,
+    id: i64,
+    monthly_limit: Option<u64>,
+) -> Result<bool> {
+    let query = db::Query::SetMonthlyQuota { id, monthly_limit };
+    let found = query.execute(database.clone()).await? == Some(true);
+
+    cache.reload(database).await?;
+    Ok(found)
+}
+
+/// Sets the field case of key `id`'s response bodies.
+pub async fn set_field_case(
+    database: web::Data<db::Pool>,
+    cache: &KeyCache,
+    id: i64,
+    field_case: Option<FieldCase>,
+) -> Result<()> {
+    let query = db::Query::SetFieldCase { id, field_case };
     query.execute(database.clone()).await?;
 
-    load_api_keys(database.clone())
+    cache.reload(database).await
 }
 
-pub async fn revoke_api_key(database: web::Data<db::Pool>, token: String) -> Result<()> {
-    let query = db::Query::RevokeApiKey(token);
+/// Sets the scales conversions answer key `id` in.
+pub async fn set_output_scales(
+    database: web::Data<db::Pool>,
+    cache: &KeyCache,
+    id: i64,
+    scales: ScaleSet,
+) -> Result<()> {
+    let query = db::Query::SetOutputScales { id, scales };
     query.execute(database.clone()).await?;
 
-    load_api_keys(database.clone())
+    cache.reload(database).await
 }
 
-pub fn is_key_allowed_access(api_key: &str) -> Result<bool> {
-    let api_keys = API_KEYS.read()?;
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-    Ok(api_keys.contains_key(api_key))
+    #[test]
+    fn base62_digits() {
+        assert_eq!(base62(&[0], 3), "000");
+        assert_eq!(base62(&[61], 1), "z");
+        assert_eq!(base62(&[1, 0], 3), "048");
+        assert_eq!(
+            base62(&[0xff; 32], KEY_PAYLOAD_LENGTH).len(),
+            KEY_PAYLOAD_LENGTH
+        );
+    }
+
+    #[test]
+    fn created_keys_are_plausible() {
+        let api_key = create_api_key().unwrap();
+        assert!(api_key.starts_with(KEY_PREFIX));
+        assert!(is_plausible_key(&api_key));
+        assert_ne!(create_api_key().unwrap(), api_key);
+    }
+
+    #[test]
+    fn implausible_keys() {
+        let api_key = create_api_key().unwrap();
+        let (payload, checksum) = api_key.split_at(api_key.len() - KEY_CHECKSUM_LENGTH);
+        let last = if checksum.ends_with('0') { '1' } else { '0' };
+        let tampered = format!("{payload}{}{last}", &checksum[..KEY_CHECKSUM_LENGTH - 1]);
+        assert!(!is_plausible_key(&tampered));
+        assert!(!is_plausible_key(payload));
+        assert!(!is_plausible_key(&format!("{api_key}0")));
+        // Keys issued before the prefix.
+        assert!(is_plausible_key("legacy-key"));
+    }
 }
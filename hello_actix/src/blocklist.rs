@@ -0,0 +1,267 @@
+//! Addresses and networks refused outright, managed under
+//! `/admin/blocklist`. An address that keeps failing authentication is
+//! blocked for a while automatically.
+
+use std::collections::{BTreeMap, HashMap};
+use std::net::IpAddr;
+use std::sync::RwLock;
+
+use actix_web::body::MessageBody;
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::header::AUTHORIZATION;
+use actix_web::http::StatusCode;
+use actix_web::middleware::Next;
+use actix_web::{delete, get, post, web, Error, HttpRequest, HttpResponse, Responder};
+use chrono::{DateTime, Duration, Utc};
+use dashmap::DashMap;
+use ipnet::IpNet;
+use serde::Deserialize;
+use tracing::{instrument, warn};
+
+use crate::config::Config;
+use crate::error::ApiError;
+use crate::jsonapi::Listed;
+use crate::rbac::{Authorized, Operator, Viewer};
+use crate::{clock, db};
+
+/// The client's address: the socket peer, or the address reported by a
+/// proxy when `trust_proxy_headers` is set.
+pub fn client_ip(req: &HttpRequest) -> Option<IpAddr> {
+    let trust_proxy = req
+        .app_data::<web::Data<Config>>()
+        .is_some_and(|config| config.trust_proxy_headers);
+
+    if trust_proxy {
+        req.connection_info().realip_remote_addr()?.parse().ok()
+    } else {
+        req.peer_addr().map(|addr| addr.ip())
+    }
+}
+
+/// Parses a single address or a CIDR network, normalized so that host bits
+/// are cleared.
+pub fn parse_network(network: &str) -> Option<IpNet> {
+    let network = network.trim();
+
+    network
+        .parse::<IpNet>()
+        .or_else(|_| network.parse::<IpAddr>().map(IpNet::from))
+        .ok()
+        .map(|network| network.trunc())
+}
+
+/// Networks by prefix length, each with the time its block lapses.
+type Networks = BTreeMap<u8, HashMap<IpNet, Option<DateTime<Utc>>>>;
+
+#[derive(Debug, Default)]
+struct Failures {
+    count: u32,
+    since: Option<DateTime<Utc>>,
+}
+
+/// In-memory copy of the `blocklist` table, shared by every worker.
+///
+/// Networks are grouped by prefix length, so a lookup costs one hash probe
+/// per distinct length on the list rather than one comparison per entry.
+#[derive(Debug, Default)]
+pub struct Blocklist {
+    networks: RwLock<Networks>,
+    failures: DashMap<IpAddr, Failures>,
+}
+
+impl Blocklist {
+    pub fn new() -> Self {
+        Blocklist::default()
+    }
+
+    /// Addresses with authentication failures being counted.
+    pub fn tracked_addresses(&self) -> usize {
+        self.failures.len()
+    }
+
+    /// Replaces the in-memory list with the table's current contents.
+    pub async fn reload(&self, database: web::Data<db::Pool>) -> Result<(), Error> {
+        let blocks = db::list_blocks(database).await?;
+
+        let mut networks = Networks::new();
+        for block in blocks {
+            let Some(network) = parse_network(&block.network) else {
+                warn!(network = %block.network, "ignoring unparseable blocklist entry");
+                continue;
+            };
+
+            let expires_at = networks
+                .entry(network.prefix_len())
+                .or_default()
+                .entry(network)
+                .or_insert(block.expires_at);
+            // Overlapping entries: the longest-lived one wins.
+            if expires_at.is_some()
+                && (block.expires_at.is_none() || block.expires_at > *expires_at)
+            {
+                *expires_at = block.expires_at;
+            }
+        }
+
+        *self.networks.write().unwrap() = networks;
+        Ok(())
+    }
+
+    pub fn is_blocked(&self, ip: IpAddr, now: DateTime<Utc>) -> bool {
+        let networks = self.networks.read().unwrap();
+
+        networks.iter().any(|(&prefix_len, entries)| {
+            let Ok(network) = IpNet::new(ip, prefix_len) else {
+                return false;
+            };
+
+            entries
+                .get(&network.trunc())
+                .is_some_and(|expires_at| expires_at.is_none_or(|expires_at| expires_at > now))
+        })
+    }
+
+    /// Counts a failed authentication at `now`, returning `true` once the
+    /// address has reached the limit within the current window.
+    fn record_failure(&self, ip: IpAddr, limit: u32, window: Duration, now: DateTime<Utc>) -> bool {
+        let mut failures = self.failures.entry(ip).or_default();
+
+        if failures.since.is_none_or(|since| now - since > window) {
+            *failures = Failures {
+                count: 0,
+                since: Some(now),
+            };
+        }
+        failures.count += 1;
+
+        if failures.count >= limit {
+            drop(failures);
+            self.failures.remove(&ip);
+            true
+        } else {
+            false
+        }
+    }
+}
+
+/// Refuses requests from blocked addresses with 403, and blocks addresses
+/// that rack up `auth_failure_limit` failed authentications within
+/// `auth_failure_block_minutes`. A failure is a 401 to a request that
+/// carried credentials, so merely being logged out doesn't count.
+pub async fn enforce_blocklist(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    let Some(ip) = client_ip(req.request()) else {
+        return next.call(req).await;
+    };
+    let Some(blocklist) = req.app_data::<web::Data<Blocklist>>().cloned() else {
+        return next.call(req).await;
+    };
+
+    let now = clock::now(req.request());
+    if blocklist.is_blocked(ip, now) {
+        return Err(ApiError::Blocked.into());
+    }
+
+    let has_credentials = req.headers().contains_key(AUTHORIZATION);
+    let config = req.app_data::<web::Data<Config>>().cloned();
+    let database = req.app_data::<web::Data<db::Pool>>().cloned();
+
+    let response = next.call(req).await;
+
+    let status = match &response {
+        Ok(res) => res.status(),
+        Err(err) => err.as_response_error().status_code(),
+    };
+    if let (StatusCode::UNAUTHORIZED, Some(config), Some(database)) = (status, config, database) {
+        if has_credentials && config.auth_failure_limit > 0 {
+            let window = Duration::minutes(config.auth_failure_block_minutes);
+            if blocklist.record_failure(ip, config.auth_failure_limit, window, now) {
+                block_temporarily(&blocklist, database, ip, now + window).await;
+            }
+        }
+    }
+
+    response
+}
+
+#[instrument(skip(blocklist, database))]
+async fn block_temporarily(
+    blocklist: &Blocklist,
+    database: web::Data<db::Pool>,
+    ip: IpAddr,
+    until: DateTime<Utc>,
+) {
+    let query = db::Query::AddBlock {
+        network: IpNet::from(ip).to_string(),
+        reason: Some("repeated authentication failures".into()),
+        expires_at: Some(until),
+    };
+
+    let result = match query.execute(database.clone()).await {
+        Ok(_) => blocklist.reload(database).await,
+        Err(err) => Err(err),
+    };
+    match result {
+        Ok(()) => warn!("blocked after repeated authentication failures"),
+        Err(err) => warn!(%err, "failed to block address"),
+    }
+}
+
+#[get("/blocklist")]
+pub async fn list_blocks(
+    _: Authorized<Viewer>,
+    database: web::Data<db::Pool>,
+) -> actix_web::Result<impl Responder> {
+    Ok(Listed(db::list_blocks(database).await?))
+}
+
+#[derive(Deserialize, Debug)]
+pub struct NewBlock {
+    /// An address, or a network in CIDR notation.
+    network: String,
+    reason: Option<String>,
+    /// Omit to block until the entry is deleted.
+    expires_at: Option<DateTime<Utc>>,
+}
+
+#[post("/blocklist")]
+pub async fn add_block(
+    _: Authorized<Operator>,
+    body: web::Json<NewBlock>,
+    blocklist: web::Data<Blocklist>,
+    database: web::Data<db::Pool>,
+) -> actix_web::Result<impl Responder> {
+    let body = body.into_inner();
+    let network = parse_network(&body.network).ok_or(ApiError::InvalidNetwork)?;
+
+    let query = db::Query::AddBlock {
+        network: network.to_string(),
+        reason: body.reason,
+        expires_at: body.expires_at,
+    };
+    query.execute(database.clone()).await?;
+    blocklist.reload(database).await?;
+
+    Ok(HttpResponse::NoContent().finish())
+}
+
+#[delete("/blocklist/{id}")]
+pub async fn delete_block(
+    _: Authorized<Operator>,
+    id: web::Path<i64>,
+    blocklist: web::Data<Blocklist>,
+    database: web::Data<db::Pool>,
+) -> actix_web::Result<impl Responder> {
+    let query = db::Query::DeleteBlock {
+        id: id.into_inner(),
+    };
+
+    if query.execute(database.clone()).await? != Some(true) {
+        return Err(ApiError::BlockNotFound.into());
+    }
+    blocklist.reload(database).await?;
+
+    Ok(HttpResponse::NoContent().finish())
+}
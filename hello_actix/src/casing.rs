@@ -0,0 +1,147 @@
+//! camelCase or snake_case field names in the bodies of the API-key routes,
+//! for clients whose schema linters insist on one. The instance picks a
+//! default with [`Config::field_case`], and each key may pick its own at
+//! `PUT /api/field-case`. Renaming is done as bodies are serialized, by
+//! [`conversion_core::case`]. Error bodies keep their documented shape.
+
+use std::fmt;
+use std::str::FromStr;
+
+use actix_web::body::BoxBody;
+use actix_web::http::header::Header as _;
+use actix_web::{put, web, HttpRequest, HttpResponse, Responder};
+use actix_web_httpauth::extractors::basic::BasicAuth;
+use actix_web_httpauth::headers::authorization::{Authorization, Basic};
+use conversion_core::case::CamelCase;
+use rusqlite::types::{FromSql, FromSqlError, FromSqlResult, ToSql, ToSqlOutput, ValueRef};
+use serde::{Deserialize, Serialize};
+
+use crate::auth;
+use crate::config::Config;
+use crate::db;
+use crate::error::ApiError;
+use crate::fields::Partial;
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FieldCase {
+    /// `field_name`, as documented.
+    #[default]
+    Snake,
+    /// `fieldName`.
+    Camel,
+}
+
+impl fmt::Display for FieldCase {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            FieldCase::Snake => "snake",
+            FieldCase::Camel => "camel",
+        })
+    }
+}
+
+#[derive(Debug)]
+pub struct UnknownFieldCase;
+
+impl fmt::Display for UnknownFieldCase {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("expected snake or camel")
+    }
+}
+
+impl std::error::Error for UnknownFieldCase {}
+
+impl FromStr for FieldCase {
+    type Err = UnknownFieldCase;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "snake" => Ok(FieldCase::Snake),
+            "camel" => Ok(FieldCase::Camel),
+            _ => Err(UnknownFieldCase),
+        }
+    }
+}
+
+impl ToSql for FieldCase {
+    fn to_sql(&self) -> rusqlite::Result<ToSqlOutput<'_>> {
+        Ok(ToSqlOutput::from(self.to_string()))
+    }
+}
+
+impl FromSql for FieldCase {
+    fn column_result(value: ValueRef<'_>) -> FromSqlResult<Self> {
+        value
+            .as_str()?
+            .parse()
+            .map_err(|err| FromSqlError::Other(Box::new(err)))
+    }
+}
+
+/// The field case `req` is answered in: its API key's choice, else the
+/// instance's default.
+pub fn field_case(req: &HttpRequest) -> FieldCase {
+    let chosen = Authorization::<Basic>::parse(req)
+        .ok()
+        .and_then(|auth| {
+            auth::key_access(req, auth.as_ref().user_id())
+                .ok()
+                .flatten()
+        })
+        .and_then(|access| access.field_case);
+
+    chosen
+        .or_else(|| {
+            req.app_data::<web::Data<Config>>()
+                .map(|config| config.field_case)
+        })
+        .unwrap_or_default()
+}
+
+/// A JSON response with `body`, in the request's [`field_case`].
+pub fn json_response(req: &HttpRequest, body: impl Serialize) -> HttpResponse {
+    match field_case(req) {
+        FieldCase::Snake => HttpResponse::Ok().json(body),
+        FieldCase::Camel => HttpResponse::Ok().json(CamelCase(body)),
+    }
+}
+
+/// `web::Json`, in the request's [`field_case`], with the fields asked for
+/// in `?fields=`; see [`crate::fields`].
+pub struct Json<T>(pub T);
+
+impl<T: Serialize> Responder for Json<T> {
+    type Body = BoxBody;
+
+    fn respond_to(self, req: &HttpRequest) -> HttpResponse {
+        json_response(req, Partial::of(req, self.0))
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct FieldCaseChoice {
+    /// `null` goes back to the instance's default.
+    field_case: Option<FieldCase>,
+}
+
+/// Chooses the field case of the calling key's response bodies.
+#[put("/field-case")]
+pub async fn set_field_case(
+    auth: BasicAuth,
+    body: web::Json<FieldCaseChoice>,
+    database: web::Data<db::Pool>,
+    key_cache: web::Data<auth::KeyCache>,
+    req: HttpRequest,
+) -> actix_web::Result<impl Responder> {
+    let access = auth::key_access(&req, auth.user_id())
+        .map_err(|_| ApiError::Internal)?
+        .ok_or(ApiError::Unauthorized)?;
+
+    auth::set_field_case(database, &key_cache, access.id, body.field_case)
+        .await
+        .map_err(|_| ApiError::Internal)?;
+
+    // Already in the new case, the key being reloaded.
+    Ok(Json(body.into_inner()))
+}
@@ -0,0 +1,109 @@
+//! Proof-of-work gate on signup, to make farming free-tier keys expensive.
+//!
+//! A client fetches a challenge, then searches for a `nonce` such that
+//! `SHA-256("{challenge}:{nonce}")` starts with at least `difficulty` zero
+//! bits, and submits both with its signup. Each challenge is accepted once.
+
+use actix_web::{get, web, HttpRequest, Responder};
+use chrono::{DateTime, Duration, Utc};
+use ring::digest;
+use serde::{Deserialize, Serialize};
+
+use crate::config::Config;
+use crate::error::ApiError;
+use crate::{auth, clock, db};
+
+const CHALLENGE_TTL_MINUTES: i64 = 10;
+
+#[derive(Debug, Serialize)]
+pub struct Challenge {
+    pub challenge: String,
+    /// Leading zero bits required of the solution's hash.
+    pub difficulty: u32,
+    pub expires_in_seconds: i64,
+}
+
+/// A challenge and the nonce solving it, as submitted with a signup.
+#[derive(Debug, Deserialize)]
+pub struct Solution {
+    pub challenge: String,
+    pub nonce: String,
+}
+
+fn leading_zero_bits(hash: &[u8]) -> u32 {
+    let mut bits = 0;
+    for byte in hash {
+        bits += byte.leading_zeros();
+        if *byte != 0 {
+            break;
+        }
+    }
+    bits
+}
+
+pub fn is_solved(challenge: &str, nonce: &str, difficulty: u32) -> bool {
+    let hash = digest::digest(&digest::SHA256, format!("{challenge}:{nonce}").as_bytes());
+    leading_zero_bits(hash.as_ref()) >= difficulty
+}
+
+/// Reference solver, for clients and tests. Expect around `2^difficulty`
+/// hashes.
+pub fn solve(challenge: &str, difficulty: u32) -> String {
+    (0u64..)
+        .map(|nonce| nonce.to_string())
+        .find(|nonce| is_solved(challenge, nonce, difficulty))
+        .expect("the nonce space is exhausted")
+}
+
+/// Checks a solution and spends its challenge. Passes trivially when the
+/// deployment disabled the challenge.
+pub async fn verify(
+    database: web::Data<db::Pool>,
+    config: &Config,
+    solution: Option<&Solution>,
+    now: DateTime<Utc>,
+) -> Result<(), actix_web::Error> {
+    if config.signup_pow_difficulty == 0 {
+        return Ok(());
+    }
+
+    let Some(solution) = solution else {
+        return Err(ApiError::InvalidChallenge.into());
+    };
+    if !is_solved(
+        &solution.challenge,
+        &solution.nonce,
+        config.signup_pow_difficulty,
+    ) {
+        return Err(ApiError::InvalidChallenge.into());
+    }
+
+    let token_hash = auth::hash_token(&solution.challenge);
+    if db::redeem_challenge(database, token_hash, now).await? {
+        Ok(())
+    } else {
+        Err(ApiError::InvalidChallenge.into())
+    }
+}
+
+#[get("/signup/challenge")]
+pub async fn issue_challenge(
+    config: web::Data<Config>,
+    database: web::Data<db::Pool>,
+    req: HttpRequest,
+) -> actix_web::Result<impl Responder> {
+    let challenge = auth::generate_token().map_err(|_| ApiError::Internal)?;
+    let ttl = Duration::minutes(CHALLENGE_TTL_MINUTES);
+
+    let query = db::Query::StoreChallenge {
+        token_hash: auth::hash_token(&challenge),
+        expires_at: clock::now(&req) + ttl,
+    };
+    query.execute(database).await?;
+
+    Ok(web::Json(Challenge {
+        challenge,
+        difficulty: config.signup_pow_difficulty,
+        expires_in_seconds: ttl.num_seconds(),
+    }))
+}
@@ -0,0 +1,73 @@
+//! Dev-only fault injection, so clients' retry logic can be exercised
+//! against a local instance.
+//!
+//! Enabled with `CHAOS=true`; each fault fires independently with its
+//! configured probability. Release builds ignore the flag entirely.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+use actix_web::body::MessageBody;
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::middleware::Next;
+use actix_web::{rt, web, Error};
+use tracing::warn;
+
+use crate::config::Config;
+use crate::error::ApiError;
+
+/// Read by the db layer, which has no access to app data.
+static DB_FAILURE_PROBABILITY: AtomicU64 = AtomicU64::new(0);
+
+/// Arms database fault injection and reports whether the request
+/// middleware should be mounted. Call once at startup.
+pub fn install(config: &Config) -> bool {
+    if config.chaos && !cfg!(debug_assertions) {
+        warn!("CHAOS is set but ignored in release builds");
+        return false;
+    }
+
+    if config.chaos {
+        warn!(
+            latency_ms = config.chaos_latency_ms,
+            latency_probability = config.chaos_latency_probability,
+            error_probability = config.chaos_error_probability,
+            db_failure_probability = config.chaos_db_failure_probability,
+            "chaos mode enabled",
+        );
+        DB_FAILURE_PROBABILITY.store(
+            config.chaos_db_failure_probability.to_bits(),
+            Ordering::Relaxed,
+        );
+    }
+
+    config.chaos
+}
+
+fn roll(probability: f64) -> bool {
+    probability > 0.0 && fastrand::f64() < probability
+}
+
+pub fn should_fail_db() -> bool {
+    roll(f64::from_bits(
+        DB_FAILURE_PROBABILITY.load(Ordering::Relaxed),
+    ))
+}
+
+/// Delays or fails requests at random according to the chaos settings.
+pub async fn inject_faults(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    if let Some(config) = req.app_data::<web::Data<Config>>() {
+        if roll(config.chaos_latency_probability) {
+            rt::time::sleep(Duration::from_millis(config.chaos_latency_ms)).await;
+        }
+
+        if roll(config.chaos_error_probability) {
+            return Err(ApiError::InjectedFault.into());
+        }
+    }
+
+    next.call(req).await
+}
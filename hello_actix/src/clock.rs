@@ -0,0 +1,58 @@
+//! Where handlers get the time, so usage and quota logic can be tested at
+//! any moment without sleeping. The app's [`Clock`] is registered as
+//! `app_data`; see [`crate::AppState`].
+
+use actix_web::{web, HttpRequest};
+use chrono::{DateTime, Utc};
+
+pub trait Clock: Send + Sync {
+    fn now(&self) -> DateTime<Utc>;
+}
+
+/// The system clock.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> DateTime<Utc> {
+        Utc::now()
+    }
+}
+
+/// The time by the app's clock, or the system's where none is registered.
+pub fn now(req: &HttpRequest) -> DateTime<Utc> {
+    req.app_data::<web::Data<dyn Clock>>()
+        .map_or_else(Utc::now, |clock| clock.now())
+}
+
+/// A clock that only moves when told to, in builds with the `test-util`
+/// feature.
+#[cfg(feature = "test-util")]
+#[derive(Debug)]
+pub struct MockClock {
+    now: std::sync::Mutex<DateTime<Utc>>,
+}
+
+#[cfg(feature = "test-util")]
+impl MockClock {
+    pub fn new(now: DateTime<Utc>) -> Self {
+        MockClock {
+            now: std::sync::Mutex::new(now),
+        }
+    }
+
+    pub fn set(&self, now: DateTime<Utc>) {
+        *self.now.lock().unwrap() = now;
+    }
+
+    pub fn advance(&self, by: chrono::Duration) {
+        *self.now.lock().unwrap() += by;
+    }
+}
+
+#[cfg(feature = "test-util")]
+impl Clock for MockClock {
+    fn now(&self) -> DateTime<Utc> {
+        *self.now.lock().unwrap()
+    }
+}
@@ -0,0 +1,206 @@
+//! Conversions over CoAP, for battery-powered sensors where HTTP is too
+//! heavy. Needs the `coap` feature and `coap_bind` set.
+//!
+//! A device sends `GET coap://host/to-celsius/77?k=<api key>` (or
+//! `to-fahrenheit`) and gets back just the converted value as text, e.g.
+//! `25`. Errors carry just the matching CoAP response code.
+//!
+//! The listener speaks plain CoAP over UDP, so keys cross the network in
+//! the clear: bind it to a trusted network, or front it with a DTLS
+//! terminator.
+
+#[cfg(feature = "coap")]
+pub use listener::spawn;
+
+/// Warns when a bind address is configured without the feature to use it.
+#[cfg(not(feature = "coap"))]
+pub fn spawn(
+    config: actix_web::web::Data<crate::config::Config>,
+    _database: actix_web::web::Data<crate::db::Pool>,
+    _key_cache: actix_web::web::Data<crate::auth::KeyCache>,
+) -> std::io::Result<()> {
+    if config.coap_bind.is_some() {
+        tracing::warn!("a CoAP address is configured but the `coap` feature is disabled");
+    }
+    Ok(())
+}
+
+#[cfg(feature = "coap")]
+mod listener {
+    use std::net::SocketAddr;
+    use std::rc::Rc;
+
+    use actix_web::rt::net::UdpSocket;
+    use actix_web::{rt, web};
+    use coap_lite::{
+        CoapOption, CoapRequest, ContentFormat, MessageClass, Packet, RequestType, ResponseType,
+    };
+    use tracing::{info, warn};
+
+    use crate::auth::KeyCache;
+    use crate::config::Config;
+    use crate::error::ApiError;
+    use crate::{db, gateway, tasks};
+
+    /// Large enough for any request a sensor sends.
+    const MAX_DATAGRAM: usize = 1152;
+
+    /// Binds `coap_bind` and serves requests in the background. Call once,
+    /// from `main`.
+    pub fn spawn(
+        config: web::Data<Config>,
+        database: web::Data<db::Pool>,
+        key_cache: web::Data<KeyCache>,
+    ) -> std::io::Result<()> {
+        let Some(bind) = config.coap_bind.clone() else {
+            return Ok(());
+        };
+        let socket = std::net::UdpSocket::bind(&bind)?;
+        socket.set_nonblocking(true)?;
+
+        // Each start registers its own handle on the socket, as a restart
+        // follows a panic.
+        tasks::supervise("coap_listener", move |_| {
+            serve(
+                socket.try_clone(),
+                bind.clone(),
+                config.clone(),
+                database.clone(),
+                key_cache.clone(),
+            )
+        });
+
+        Ok(())
+    }
+
+    async fn serve(
+        socket: std::io::Result<std::net::UdpSocket>,
+        bind: String,
+        config: web::Data<Config>,
+        database: web::Data<db::Pool>,
+        key_cache: web::Data<KeyCache>,
+    ) {
+        let socket = match socket.and_then(UdpSocket::from_std) {
+            Ok(socket) => Rc::new(socket),
+            Err(err) => return warn!(%err, "failed to register CoAP socket"),
+        };
+        info!(%bind, "listening for CoAP");
+
+        let mut buf = [0; MAX_DATAGRAM];
+        loop {
+            let (len, peer) = match socket.recv_from(&mut buf).await {
+                Ok(received) => received,
+                Err(err) => {
+                    warn!(%err, "failed to receive CoAP datagram");
+                    continue;
+                }
+            };
+            let Ok(packet) = Packet::from_bytes(&buf[..len]) else {
+                continue;
+            };
+
+            rt::spawn(respond(
+                socket.clone(),
+                config.clone(),
+                database.clone(),
+                key_cache.clone(),
+                packet,
+                peer,
+            ));
+        }
+    }
+
+    async fn respond(
+        socket: Rc<UdpSocket>,
+        config: web::Data<Config>,
+        database: web::Data<db::Pool>,
+        key_cache: web::Data<KeyCache>,
+        packet: Packet,
+        peer: SocketAddr,
+    ) {
+        // Only requests; stray acknowledgements and resets need no reply.
+        if !matches!(packet.header.code, MessageClass::Request(_)) {
+            return;
+        }
+
+        let api_key = packet
+            .get_option(CoapOption::UriQuery)
+            .into_iter()
+            .flatten()
+            .find_map(|query| query.strip_prefix(b"k="))
+            .and_then(|key| std::str::from_utf8(key).ok())
+            .map(str::to_owned);
+
+        let mut request = CoapRequest::from_packet(packet, peer);
+        let result = match (request.get_method(), request.get_path_as_vec()) {
+            (RequestType::Get, Ok(path)) => {
+                convert(&config, database, &key_cache, api_key, &path).await
+            }
+            _ => Err(ResponseType::NotFound),
+        };
+
+        let Some(response) = request.response.as_mut() else {
+            return;
+        };
+        let payload = match result {
+            Ok(value) => {
+                response.set_status(ResponseType::Content);
+                value.to_string()
+            }
+            Err(status) => {
+                response.set_status(status);
+                String::new()
+            }
+        };
+        response
+            .message
+            .set_content_format(ContentFormat::TextPlain);
+        response.message.payload = payload.into_bytes();
+
+        match response.message.to_bytes() {
+            Ok(bytes) => {
+                if let Err(err) = socket.send_to(&bytes, peer).await {
+                    warn!(%err, %peer, "failed to send CoAP response");
+                }
+            }
+            Err(err) => warn!(?err, "failed to encode CoAP response"),
+        }
+    }
+
+    async fn convert(
+        config: &Config,
+        database: web::Data<db::Pool>,
+        key_cache: &KeyCache,
+        api_key: Option<String>,
+        path: &[String],
+    ) -> Result<f32, ResponseType> {
+        let [endpoint, value] = path else {
+            return Err(ResponseType::NotFound);
+        };
+        let endpoint: db::ApiEndpoint = endpoint.parse().map_err(|_| ResponseType::NotFound)?;
+        let value: f32 = value.parse().map_err(|_| ResponseType::BadRequest)?;
+        let api_key = api_key.ok_or(ResponseType::Unauthorized)?;
+
+        let temperature = gateway::convert(database, key_cache, config, &api_key, endpoint, value)
+            .await
+            .map_err(|err| status(&err))?;
+
+        Ok(match endpoint {
+            db::ApiEndpoint::ToCelsius => temperature.celsius,
+            _ => temperature.fahrenheit,
+        })
+    }
+
+    fn status(err: &ApiError) -> ResponseType {
+        use actix_web::ResponseError as _;
+
+        match err.status_code().as_u16() {
+            400 => ResponseType::BadRequest,
+            401 => ResponseType::Unauthorized,
+            403 => ResponseType::Forbidden,
+            404 => ResponseType::NotFound,
+            429 => ResponseType::TooManyRequests,
+            _ => ResponseType::InternalServerError,
+        }
+    }
+}
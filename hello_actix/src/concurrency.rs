@@ -0,0 +1,110 @@
+use std::sync::Arc;
+
+use actix_web::body::MessageBody;
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::middleware::Next;
+use actix_web::{web, Error};
+use actix_web_httpauth::extractors::basic::BasicAuth;
+use dashmap::DashMap;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+use crate::auth;
+use crate::config::Config;
+use crate::error::ApiError;
+use crate::throttling::{self, Rule};
+
+/// Tracks in-flight requests per API key, keyed by the key's fingerprint so
+/// that raw keys are not held in yet another map.
+#[derive(Debug, Default)]
+pub struct ConcurrencyLimiter {
+    permits: DashMap<String, Arc<Semaphore>>,
+}
+
+impl ConcurrencyLimiter {
+    pub fn new() -> Self {
+        ConcurrencyLimiter::default()
+    }
+
+    /// Keys with a semaphore, in use or not.
+    pub fn entries(&self) -> usize {
+        self.permits.len()
+    }
+
+    fn semaphore(&self, fingerprint: &str, max: usize) -> Arc<Semaphore> {
+        self.permits
+            .entry(fingerprint.to_owned())
+            .or_insert_with(|| Arc::new(Semaphore::new(max)))
+            .clone()
+    }
+
+    /// Drops the entry for a key once nothing is in flight, so idle keys do
+    /// not accumulate.
+    fn release(&self, fingerprint: &str, max: usize) {
+        self.permits.remove_if(fingerprint, |_, semaphore| {
+            Arc::strong_count(semaphore) == 1 && semaphore.available_permits() == max
+        });
+    }
+
+    /// Drops the entries of keys with nothing in flight that [`InFlight`]
+    /// didn't get to, e.g. after a tier change. Run on a timer; see
+    /// [`crate::scheduler::spawn_limiter_eviction`].
+    pub fn evict_idle(&self) {
+        // Every request in flight holds a clone through its permit.
+        self.permits
+            .retain(|_, semaphore| Arc::strong_count(semaphore) > 1);
+    }
+}
+
+/// A request in flight. Dropped with the request, whether it finished or
+/// was given up on, it hands back its permit and tidies the key's entry.
+struct InFlight {
+    permit: Option<OwnedSemaphorePermit>,
+    limiter: web::Data<ConcurrencyLimiter>,
+    fingerprint: String,
+    max: usize,
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        drop(self.permit.take());
+        self.limiter.release(&self.fingerprint, self.max);
+    }
+}
+
+/// Rejects a request with 429 when its API key already has as many requests
+/// in flight as its tier allows.
+///
+/// Must run inside the authentication middleware.
+pub async fn limit_concurrency(
+    mut req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    let credentials = req.extract::<BasicAuth>().await?;
+    let fingerprint = auth::fingerprint(credentials.user_id());
+
+    let tier = auth::key_tier(req.request(), credentials.user_id())
+        .map_err(|_| ApiError::Internal)?
+        .unwrap_or_default();
+    let max = match req.app_data::<web::Data<Config>>() {
+        Some(config) => config.max_concurrent_requests(tier),
+        None => Config::default().max_concurrent_requests(tier),
+    };
+    let limiter = req
+        .app_data::<web::Data<ConcurrencyLimiter>>()
+        .cloned()
+        .ok_or(ApiError::Internal)?;
+
+    let semaphore = limiter.semaphore(&fingerprint, max);
+    let Ok(permit) = semaphore.try_acquire_owned() else {
+        throttling::throttled(&req, Rule::Concurrency, credentials.user_id());
+        return Err(ApiError::TooManyConcurrentRequests.into());
+    };
+    let _in_flight = InFlight {
+        permit: Some(permit),
+        limiter,
+        fingerprint,
+        max,
+    };
+
+    next.call(req).await
+}
@@ -0,0 +1,452 @@
+use std::num::NonZeroUsize;
+use std::str::FromStr;
+
+use serde::{Serialize, Serializer};
+
+use crate::casing::FieldCase;
+use crate::db;
+use crate::error::KeyHelp;
+use crate::plugin::PluginRegistry;
+use crate::usage::Overflow;
+
+/// Settings that can be tuned per deployment through environment variables.
+///
+/// Read once at startup and shared with handlers and middleware via
+/// `web::Data<Config>`.
+#[derive(Debug, Clone, Serialize)]
+pub struct Config {
+    /// How many requests a single API key may have in flight at once.
+    pub max_concurrent_requests_per_key: usize,
+    /// The same, for keys issued through self-service signup.
+    pub free_tier_max_concurrent_requests: usize,
+    /// Requests a single API key may make per minute under `/api`, in
+    /// bursts of up to as many; 0 is unlimited. See [`crate::ratelimit`].
+    pub requests_per_minute_per_key: u32,
+    /// The same, for keys issued through self-service signup.
+    pub free_tier_requests_per_minute: u32,
+    /// Calls a key may make per calendar month (UTC); 0 is unlimited. See
+    /// [`crate::quota`] and [`crate::forecast`].
+    pub monthly_call_quota_per_key: u64,
+    /// The same, for keys issued through self-service signup.
+    pub free_tier_monthly_call_quota: u64,
+    /// Told, besides the key's owner, when a key first passes 80% or 95% of
+    /// its quota in a month; see [`crate::quota`].
+    #[serde(serialize_with = "redact")]
+    pub quota_webhook_url: Option<String>,
+    /// Bearer token guarding the `/admin` scope. Admin routes reject every
+    /// request while this is unset.
+    #[serde(serialize_with = "redact")]
+    pub admin_token: Option<String>,
+    /// Reject requests carrying credentials unless they arrived over HTTPS,
+    /// either directly or as reported by a proxy via `Forwarded` or
+    /// `X-Forwarded-Proto`.
+    pub require_https: bool,
+    /// Take the client address from `Forwarded` / `X-Forwarded-For`. Only
+    /// enable behind a proxy that sets these itself, as clients can forge
+    /// them.
+    pub trust_proxy_headers: bool,
+    /// Failed authentications after which an address is blocked; 0 never
+    /// blocks. See [`crate::blocklist`].
+    pub auth_failure_limit: u32,
+    /// Window for counting those failures, and how long the block lasts.
+    pub auth_failure_block_minutes: i64,
+    /// Address for a second, internal listener, e.g. `10.0.0.5:9090`. When
+    /// set, the admin, metrics and health routes are served there instead
+    /// of on the public listener.
+    pub internal_bind: Option<String>,
+    /// Worker threads serving requests on each listener; one per CPU by
+    /// default.
+    pub workers: usize,
+    /// Threads each worker may start for blocking calls, such as running
+    /// scripts. By default the workers share four per CPU.
+    pub blocking_threads: usize,
+    /// Threads running every query, on connections from the pool. With one,
+    /// the default, writes run in order and never find the database
+    /// busy; more let slow reads run alongside. See [`crate::db::run`].
+    pub db_threads: usize,
+    /// Database jobs that may wait for a database thread at once before
+    /// requests fail fast with 503 instead; 0 never refuses.
+    pub max_blocking_queue: usize,
+    /// JSON document listing the latest release and end-of-life versions,
+    /// checked every few hours; see [`crate::releases`].
+    pub releases_url: Option<String>,
+    /// Externally reachable base URL, used to build links sent to users.
+    pub public_url: String,
+    /// Where 401s point clients to get an API key; signup on `public_url`
+    /// when unset. See [`Config::key_help`].
+    pub key_request_url: Option<String>,
+    /// Where 401s point clients to read up on the API; the OpenAPI document
+    /// on `public_url` when unset.
+    pub docs_url: Option<String>,
+    /// Serve Swagger UI at `/docs`, for trying the API out in a browser.
+    /// Switch off where the API shouldn't be explorable that way.
+    pub swagger_ui: bool,
+    /// How long an emailed login link stays valid.
+    pub magic_link_ttl_minutes: i64,
+    /// How long an emailed signup verification link stays valid.
+    pub signup_link_ttl_hours: i64,
+    /// Leading zero bits required of the proof of work submitted with a
+    /// signup; see [`crate::challenge`]. 0 disables the challenge.
+    pub signup_pow_difficulty: u32,
+    /// How long a dashboard session lasts after login.
+    pub session_ttl_hours: i64,
+    /// Fault injection for resilience testing. Only honoured in debug
+    /// builds; see [`crate::chaos`].
+    pub chaos: bool,
+    pub chaos_latency_ms: u64,
+    pub chaos_latency_probability: f64,
+    pub chaos_error_probability: f64,
+    pub chaos_db_failure_probability: f64,
+    /// Never store API keys, request ids, tags or locations with usage; only
+    /// count calls per endpoint and hour. See [`crate::db::Query::anonymized_if`].
+    pub anonymous_usage: bool,
+    /// Field names in the bodies of the API-key routes, for keys that
+    /// haven't chosen; see [`crate::casing`].
+    pub field_case: FieldCase,
+    /// Calls that may wait to be written to the database; 0 writes each
+    /// before its response is sent. See [`crate::usage::QueuedSink`].
+    pub usage_queue_capacity: usize,
+    /// What happens to calls that find that queue full.
+    pub usage_queue_overflow: Overflow,
+    /// Most queued calls written in one transaction.
+    pub usage_batch_size: usize,
+    /// How long the queue's writer lets calls pile up after writing a
+    /// batch short of [`Config::usage_batch_size`].
+    pub usage_flush_interval_ms: u64,
+    /// statsd server to count conversion calls on, e.g. `127.0.0.1:8125`;
+    /// see [`crate::usage::StatsdSink`].
+    pub statsd_addr: Option<String>,
+    /// When set, anonymized request traces are appended to this file.
+    pub record_file: Option<String>,
+    /// `sendmail`-compatible binary that email goes out through, e.g.
+    /// `/usr/sbin/sendmail`; see [`crate::notify::Sendmail`]. Without one,
+    /// no email is sent, signup and login links included.
+    pub sendmail: Option<String>,
+    /// Sender of that email.
+    pub mail_from: String,
+    /// Wall-clock budget for one run of a scripted conversion.
+    pub script_timeout_ms: u64,
+    /// MaxMind country (or city) database for usage enrichment; see
+    /// [`crate::geoip`].
+    pub geoip_country_db: Option<String>,
+    /// MaxMind ASN database for usage enrichment.
+    pub geoip_asn_db: Option<String>,
+    /// UDP address to serve CoAP on, e.g. `0.0.0.0:5683`; see
+    /// [`crate::coap`].
+    pub coap_bind: Option<String>,
+    /// TCP address to serve the line protocol on, e.g. `0.0.0.0:7070`; see
+    /// [`crate::line`].
+    pub line_bind: Option<String>,
+    /// `host[:port]` of an MQTT broker to bridge conversions to; see
+    /// [`crate::mqtt`].
+    pub mqtt_broker: Option<String>,
+    pub mqtt_client_id: String,
+    pub mqtt_username: Option<String>,
+    #[serde(serialize_with = "redact")]
+    pub mqtt_password: Option<String>,
+    /// Comma-separated topic filters the bridge subscribes to.
+    pub mqtt_topics: String,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            max_concurrent_requests_per_key: 8,
+            free_tier_max_concurrent_requests: 2,
+            requests_per_minute_per_key: 600,
+            free_tier_requests_per_minute: 60,
+            monthly_call_quota_per_key: 0,
+            free_tier_monthly_call_quota: 10_000,
+            quota_webhook_url: None,
+            admin_token: None,
+            require_https: false,
+            trust_proxy_headers: false,
+            auth_failure_limit: 20,
+            auth_failure_block_minutes: 15,
+            internal_bind: None,
+            workers: cpus(),
+            blocking_threads: blocking_threads(cpus()),
+            db_threads: 1,
+            max_blocking_queue: 16 * cpus(),
+            releases_url: None,
+            public_url: "http://127.0.0.1:8080".into(),
+            key_request_url: None,
+            docs_url: None,
+            swagger_ui: true,
+            magic_link_ttl_minutes: 15,
+            signup_link_ttl_hours: 24,
+            signup_pow_difficulty: 20,
+            session_ttl_hours: 12,
+            chaos: false,
+            chaos_latency_ms: 500,
+            chaos_latency_probability: 0.0,
+            chaos_error_probability: 0.0,
+            chaos_db_failure_probability: 0.0,
+            anonymous_usage: false,
+            field_case: FieldCase::Snake,
+            usage_queue_capacity: 10_000,
+            usage_queue_overflow: Overflow::DropOldest,
+            usage_batch_size: 500,
+            usage_flush_interval_ms: 100,
+            statsd_addr: None,
+            record_file: None,
+            sendmail: None,
+            mail_from: "hello_actix@localhost".into(),
+            script_timeout_ms: 50,
+            geoip_country_db: None,
+            geoip_asn_db: None,
+            coap_bind: None,
+            line_bind: None,
+            mqtt_broker: None,
+            mqtt_client_id: "hello_actix".into(),
+            mqtt_username: None,
+            mqtt_password: None,
+            mqtt_topics: "sensors/#".into(),
+        }
+    }
+}
+
+impl Config {
+    pub fn from_env() -> Self {
+        let defaults = Config::default();
+        let workers = env_or("WORKERS", defaults.workers).max(1);
+
+        Config {
+            max_concurrent_requests_per_key: env_or(
+                "MAX_CONCURRENT_REQUESTS_PER_KEY",
+                defaults.max_concurrent_requests_per_key,
+            ),
+            free_tier_max_concurrent_requests: env_or(
+                "FREE_TIER_MAX_CONCURRENT_REQUESTS",
+                defaults.free_tier_max_concurrent_requests,
+            ),
+            requests_per_minute_per_key: env_or(
+                "REQUESTS_PER_MINUTE_PER_KEY",
+                defaults.requests_per_minute_per_key,
+            ),
+            free_tier_requests_per_minute: env_or(
+                "FREE_TIER_REQUESTS_PER_MINUTE",
+                defaults.free_tier_requests_per_minute,
+            ),
+            monthly_call_quota_per_key: env_or(
+                "MONTHLY_CALL_QUOTA_PER_KEY",
+                defaults.monthly_call_quota_per_key,
+            ),
+            free_tier_monthly_call_quota: env_or(
+                "FREE_TIER_MONTHLY_CALL_QUOTA",
+                defaults.free_tier_monthly_call_quota,
+            ),
+            quota_webhook_url: std::env::var("QUOTA_WEBHOOK_URL")
+                .ok()
+                .filter(|url| !url.is_empty()),
+            admin_token: std::env::var("ADMIN_TOKEN")
+                .ok()
+                .filter(|token| !token.is_empty()),
+            require_https: env_or("REQUIRE_HTTPS", defaults.require_https),
+            trust_proxy_headers: env_or("TRUST_PROXY_HEADERS", defaults.trust_proxy_headers),
+            auth_failure_limit: env_or("AUTH_FAILURE_LIMIT", defaults.auth_failure_limit),
+            auth_failure_block_minutes: env_or(
+                "AUTH_FAILURE_BLOCK_MINUTES",
+                defaults.auth_failure_block_minutes,
+            ),
+            internal_bind: std::env::var("INTERNAL_BIND")
+                .ok()
+                .filter(|bind| !bind.is_empty()),
+            workers,
+            blocking_threads: env_or("BLOCKING_THREADS", blocking_threads(workers)).max(1),
+            db_threads: env_or("DB_THREADS", defaults.db_threads).max(1),
+            max_blocking_queue: env_or("MAX_BLOCKING_QUEUE", defaults.max_blocking_queue),
+            releases_url: std::env::var("RELEASES_URL")
+                .ok()
+                .filter(|url| !url.is_empty()),
+            public_url: env_or("PUBLIC_URL", defaults.public_url),
+            key_request_url: std::env::var("KEY_REQUEST_URL")
+                .ok()
+                .filter(|url| !url.is_empty()),
+            docs_url: std::env::var("DOCS_URL").ok().filter(|url| !url.is_empty()),
+            swagger_ui: env_or("SWAGGER_UI", defaults.swagger_ui),
+            magic_link_ttl_minutes: env_or(
+                "MAGIC_LINK_TTL_MINUTES",
+                defaults.magic_link_ttl_minutes,
+            ),
+            signup_link_ttl_hours: env_or("SIGNUP_LINK_TTL_HOURS", defaults.signup_link_ttl_hours),
+            signup_pow_difficulty: env_or("SIGNUP_POW_DIFFICULTY", defaults.signup_pow_difficulty),
+            session_ttl_hours: env_or("SESSION_TTL_HOURS", defaults.session_ttl_hours),
+            chaos: env_or("CHAOS", defaults.chaos),
+            chaos_latency_ms: env_or("CHAOS_LATENCY_MS", defaults.chaos_latency_ms),
+            chaos_latency_probability: env_or(
+                "CHAOS_LATENCY_PROBABILITY",
+                defaults.chaos_latency_probability,
+            ),
+            chaos_error_probability: env_or(
+                "CHAOS_ERROR_PROBABILITY",
+                defaults.chaos_error_probability,
+            ),
+            chaos_db_failure_probability: env_or(
+                "CHAOS_DB_FAILURE_PROBABILITY",
+                defaults.chaos_db_failure_probability,
+            ),
+            anonymous_usage: env_or("ANONYMOUS_USAGE", defaults.anonymous_usage),
+            field_case: env_or("FIELD_CASE", defaults.field_case),
+            usage_queue_capacity: env_or("USAGE_QUEUE_CAPACITY", defaults.usage_queue_capacity),
+            usage_queue_overflow: env_or("USAGE_QUEUE_OVERFLOW", defaults.usage_queue_overflow),
+            usage_batch_size: env_or("USAGE_BATCH_SIZE", defaults.usage_batch_size).max(1),
+            usage_flush_interval_ms: env_or(
+                "USAGE_FLUSH_INTERVAL_MS",
+                defaults.usage_flush_interval_ms,
+            ),
+            statsd_addr: std::env::var("STATSD_ADDR")
+                .ok()
+                .filter(|addr| !addr.is_empty()),
+            record_file: std::env::var("RECORD_FILE")
+                .ok()
+                .filter(|path| !path.is_empty()),
+            sendmail: std::env::var("SENDMAIL")
+                .ok()
+                .filter(|path| !path.is_empty()),
+            mail_from: env_or("MAIL_FROM", defaults.mail_from),
+            script_timeout_ms: env_or("SCRIPT_TIMEOUT_MS", defaults.script_timeout_ms),
+            geoip_country_db: std::env::var("GEOIP_COUNTRY_DB")
+                .ok()
+                .filter(|path| !path.is_empty()),
+            geoip_asn_db: std::env::var("GEOIP_ASN_DB")
+                .ok()
+                .filter(|path| !path.is_empty()),
+            coap_bind: std::env::var("COAP_BIND")
+                .ok()
+                .filter(|bind| !bind.is_empty()),
+            line_bind: std::env::var("LINE_BIND")
+                .ok()
+                .filter(|bind| !bind.is_empty()),
+            mqtt_broker: std::env::var("MQTT_BROKER")
+                .ok()
+                .filter(|broker| !broker.is_empty()),
+            mqtt_client_id: env_or("MQTT_CLIENT_ID", defaults.mqtt_client_id),
+            mqtt_username: std::env::var("MQTT_USERNAME")
+                .ok()
+                .filter(|username| !username.is_empty()),
+            mqtt_password: std::env::var("MQTT_PASSWORD")
+                .ok()
+                .filter(|password| !password.is_empty()),
+            mqtt_topics: env_or("MQTT_TOPICS", defaults.mqtt_topics),
+        }
+    }
+
+    pub fn mqtt_topic_filters(&self) -> Vec<String> {
+        self.mqtt_topics
+            .split(',')
+            .map(str::trim)
+            .filter(|topic| !topic.is_empty())
+            .map(str::to_owned)
+            .collect()
+    }
+
+    /// The links unauthenticated clients are given to get going.
+    pub fn key_help(&self) -> KeyHelp {
+        let public_url = self.public_url.trim_end_matches('/');
+        KeyHelp {
+            key_request_url: self
+                .key_request_url
+                .clone()
+                .unwrap_or_else(|| format!("{public_url}/signup")),
+            docs_url: self
+                .docs_url
+                .clone()
+                .unwrap_or_else(|| format!("{public_url}/openapi.json")),
+        }
+    }
+
+    /// In-flight request limit for one key of the given tier.
+    pub fn max_concurrent_requests(&self, tier: db::Tier) -> usize {
+        match tier {
+            db::Tier::Free => self.free_tier_max_concurrent_requests,
+            db::Tier::Standard => self.max_concurrent_requests_per_key,
+        }
+    }
+
+    /// Request rate limit for one key of the given tier, `None` if
+    /// unlimited.
+    pub fn requests_per_minute(&self, tier: db::Tier) -> Option<u32> {
+        let limit = match tier {
+            db::Tier::Free => self.free_tier_requests_per_minute,
+            db::Tier::Standard => self.requests_per_minute_per_key,
+        };
+        (limit > 0).then_some(limit)
+    }
+
+    /// Monthly call quota for one key of the given tier, `None` if unlimited.
+    pub fn monthly_quota(&self, tier: db::Tier) -> Option<u64> {
+        let quota = match tier {
+            db::Tier::Free => self.free_tier_monthly_call_quota,
+            db::Tier::Standard => self.monthly_call_quota_per_key,
+        };
+        (quota > 0).then_some(quota)
+    }
+}
+
+/// Shared by the workers unless `BLOCKING_THREADS` says otherwise.
+const BLOCKING_THREADS_PER_CPU: usize = 4;
+
+/// Each of `workers`' share of [`BLOCKING_THREADS_PER_CPU`].
+fn blocking_threads(workers: usize) -> usize {
+    (BLOCKING_THREADS_PER_CPU * cpus()).div_ceil(workers.max(1))
+}
+
+fn cpus() -> usize {
+    std::thread::available_parallelism().map_or(1, NonZeroUsize::get)
+}
+
+/// Falls back to `default` when the variable is unset or fails to parse.
+fn env_or<T: FromStr>(name: &str, default: T) -> T {
+    std::env::var(name)
+        .ok()
+        .and_then(|value| value.trim().parse().ok())
+        .unwrap_or(default)
+}
+
+fn redact<S: Serializer>(secret: &Option<String>, serializer: S) -> Result<S::Ok, S::Error> {
+    secret.as_ref().map(|_| "[redacted]").serialize(serializer)
+}
+
+/// Cargo features this binary was built with.
+pub fn enabled_features() -> Vec<&'static str> {
+    [
+        ("coap", cfg!(feature = "coap")),
+        ("dashboard", cfg!(feature = "dashboard")),
+        ("geoip", cfg!(feature = "geoip")),
+        ("jemalloc", cfg!(feature = "jemalloc")),
+        ("mimalloc", cfg!(feature = "mimalloc")),
+        ("mqtt", cfg!(feature = "mqtt")),
+        ("pprof", cfg!(feature = "pprof")),
+        ("protobuf", cfg!(feature = "protobuf")),
+        ("scripting", cfg!(feature = "scripting")),
+        ("tools", cfg!(feature = "tools")),
+        ("webhooks", cfg!(feature = "webhooks")),
+    ]
+    .into_iter()
+    .filter_map(|(name, enabled)| enabled.then_some(name))
+    .collect()
+}
+
+/// Everything that shapes a running instance, with secrets redacted. Logged
+/// at startup and served at `GET /admin/config`.
+#[derive(Debug, Serialize)]
+pub struct EffectiveConfig<'a> {
+    pub version: &'static str,
+    pub features: Vec<&'static str>,
+    pub plugins: Vec<&'static str>,
+    pub database: &'static str,
+    pub settings: &'a Config,
+}
+
+impl Config {
+    pub fn effective(&self) -> EffectiveConfig<'_> {
+        EffectiveConfig {
+            version: env!("CARGO_PKG_VERSION"),
+            features: enabled_features(),
+            plugins: PluginRegistry::compiled_in().names(),
+            database: db::DB_FILE,
+            settings: self,
+        }
+    }
+}
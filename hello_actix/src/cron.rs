@@ -0,0 +1,237 @@
+//! Five-field cron expressions (`minute hour day-of-month month
+//! day-of-week`), evaluated in UTC.
+//!
+//! Each field takes `*`, a number, a range `a-b`, a step `*/n` or `a-b/n`,
+//! or a comma-separated list of those. Day of week runs 0-7, both 0 and 7
+//! being Sunday. As in Vixie cron, when both day fields are restricted a day
+//! matching either of them is due.
+
+use std::fmt;
+use std::str::FromStr;
+
+use chrono::{DateTime, Datelike, Days, NaiveDate, TimeDelta, Timelike, Utc};
+
+/// How far ahead [`Schedule::next_after`] looks, enough for `0 0 29 2 *`.
+const HORIZON_DAYS: u64 = 366 * 8;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Schedule {
+    expression: String,
+    minutes: u64,
+    hours: u64,
+    days_of_month: u64,
+    months: u64,
+    days_of_week: u64,
+    /// Whether the day fields start with `*`, for the either-day rule.
+    any_day_of_month: bool,
+    any_day_of_week: bool,
+}
+
+#[derive(Debug)]
+pub struct InvalidSchedule;
+
+impl fmt::Display for InvalidSchedule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("invalid cron expression")
+    }
+}
+
+impl std::error::Error for InvalidSchedule {}
+
+impl FromStr for Schedule {
+    type Err = InvalidSchedule;
+
+    fn from_str(expression: &str) -> Result<Self, Self::Err> {
+        let fields: Vec<&str> = expression.split_whitespace().collect();
+        let [minute, hour, day_of_month, month, day_of_week] = fields[..] else {
+            return Err(InvalidSchedule);
+        };
+
+        let mut days_of_week = parse_field(day_of_week, 0, 7)?;
+        if days_of_week & (1 << 7) != 0 {
+            days_of_week = (days_of_week | 1) & !(1 << 7);
+        }
+
+        Ok(Schedule {
+            expression: fields.join(" "),
+            minutes: parse_field(minute, 0, 59)?,
+            hours: parse_field(hour, 0, 23)?,
+            days_of_month: parse_field(day_of_month, 1, 31)?,
+            months: parse_field(month, 1, 12)?,
+            days_of_week,
+            any_day_of_month: day_of_month.starts_with('*'),
+            any_day_of_week: day_of_week.starts_with('*'),
+        })
+    }
+}
+
+impl fmt::Display for Schedule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.expression)
+    }
+}
+
+/// The values `field` allows, as bits of a mask.
+fn parse_field(field: &str, min: u32, max: u32) -> Result<u64, InvalidSchedule> {
+    let mut mask = 0;
+
+    for part in field.split(',') {
+        let (range, step) = match part.split_once('/') {
+            Some((range, step)) => (range, step.parse().map_err(|_| InvalidSchedule)?),
+            None => (part, 1),
+        };
+        let (start, end) = match range {
+            "*" => (min, max),
+            range => match range.split_once('-') {
+                Some((start, end)) => (parse_value(start)?, parse_value(end)?),
+                None => {
+                    let value = parse_value(range)?;
+                    // `5/15` runs from 5 to the end, as in Vixie cron.
+                    (value, if part.contains('/') { max } else { value })
+                }
+            },
+        };
+
+        if step == 0 || start < min || end > max || start > end {
+            return Err(InvalidSchedule);
+        }
+        for value in (start..=end).step_by(step) {
+            mask |= 1 << value;
+        }
+    }
+
+    Ok(mask)
+}
+
+fn parse_value(value: &str) -> Result<u32, InvalidSchedule> {
+    value.parse().map_err(|_| InvalidSchedule)
+}
+
+impl Schedule {
+    /// The first minute strictly after `after` the schedule is due, `None`
+    /// if it never is (e.g. `0 0 31 2 *`).
+    pub fn next_after(&self, after: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        let start = after
+            .with_second(0)?
+            .with_nanosecond(0)?
+            .checked_add_signed(TimeDelta::minutes(1))?;
+
+        let first_day = start.date_naive();
+        for offset in 0..HORIZON_DAYS {
+            let day = first_day.checked_add_days(Days::new(offset))?;
+            if !self.is_due_on(day) {
+                continue;
+            }
+
+            let (from_hour, from_minute) = if day == first_day {
+                (start.hour(), start.minute())
+            } else {
+                (0, 0)
+            };
+            for hour in (from_hour..24).filter(|hour| self.hours & (1 << hour) != 0) {
+                let from_minute = if hour == from_hour { from_minute } else { 0 };
+                if let Some(minute) =
+                    (from_minute..60).find(|minute| self.minutes & (1 << minute) != 0)
+                {
+                    return Some(day.and_hms_opt(hour, minute, 0)?.and_utc());
+                }
+            }
+        }
+
+        None
+    }
+
+    fn is_due_on(&self, day: NaiveDate) -> bool {
+        if self.months & (1 << day.month()) == 0 {
+            return false;
+        }
+
+        let day_of_month = self.days_of_month & (1 << day.day()) != 0;
+        let day_of_week = self.days_of_week & (1 << day.weekday().num_days_from_sunday()) != 0;
+        match (self.any_day_of_month, self.any_day_of_week) {
+            (false, false) => day_of_month || day_of_week,
+            _ => day_of_month && day_of_week,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(s: &str) -> DateTime<Utc> {
+        s.parse().unwrap()
+    }
+
+    fn next(expression: &str, after: &str) -> Option<DateTime<Utc>> {
+        let schedule: Schedule = expression.parse().unwrap();
+        schedule.next_after(at(after))
+    }
+
+    #[test]
+    fn fields() {
+        assert_eq!(parse_field("*", 0, 3).unwrap(), 0b1111);
+        assert_eq!(parse_field("2", 0, 59).unwrap(), 1 << 2);
+        assert_eq!(parse_field("1-3", 0, 59).unwrap(), 0b1110);
+        assert_eq!(parse_field("*/2", 0, 5).unwrap(), 0b10101);
+        assert_eq!(parse_field("1-5/2", 0, 59).unwrap(), 0b101010);
+        assert_eq!(
+            parse_field("5/15", 0, 59).unwrap(),
+            1 << 5 | 1 << 20 | 1 << 35 | 1 << 50
+        );
+        assert_eq!(parse_field("1,3", 0, 59).unwrap(), 0b1010);
+    }
+
+    #[test]
+    fn invalid_expressions() {
+        for expression in [
+            "",
+            "* * * *",
+            "* * * * * *",
+            "60 * * * *",
+            "* 24 * * *",
+            "* * 0 * *",
+            "* * * 13 *",
+            "* * * * 8",
+            "3-1 * * * *",
+            "*/0 * * * *",
+            "a * * * *",
+        ] {
+            assert!(expression.parse::<Schedule>().is_err(), "{expression}");
+        }
+    }
+
+    #[test]
+    fn sunday_is_0_or_7() {
+        let zero: Schedule = "0 0 * * 0".parse().unwrap();
+        let seven: Schedule = "0 0 * * 7".parse().unwrap();
+        assert_eq!(zero.days_of_week, seven.days_of_week);
+    }
+
+    #[test]
+    fn next_runs() {
+        // 2024-01-01 is a Monday.
+        assert_eq!(
+            next("*/15 * * * *", "2024-01-01T10:07:30Z"),
+            Some(at("2024-01-01T10:15:00Z"))
+        );
+        assert_eq!(
+            next("0 9 * * *", "2024-01-01T09:00:00Z"),
+            Some(at("2024-01-02T09:00:00Z"))
+        );
+        assert_eq!(
+            next("0 0 * * 0", "2024-01-01T00:00:00Z"),
+            Some(at("2024-01-07T00:00:00Z"))
+        );
+        // Either day field, when both are restricted.
+        assert_eq!(
+            next("0 0 15 * 3", "2024-01-01T00:00:00Z"),
+            Some(at("2024-01-03T00:00:00Z"))
+        );
+        assert_eq!(
+            next("0 0 29 2 *", "2024-03-01T00:00:00Z"),
+            Some(at("2028-02-29T00:00:00Z"))
+        );
+        assert_eq!(next("0 0 31 2 *", "2024-01-01T00:00:00Z"), None);
+    }
+}
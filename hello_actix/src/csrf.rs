@@ -0,0 +1,55 @@
+use actix_web::body::MessageBody;
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::header::AUTHORIZATION;
+use actix_web::middleware::Next;
+use actix_web::Error;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine as _;
+use ring::digest;
+
+use crate::error::ApiError;
+use crate::session::SESSION_COOKIE;
+
+pub const CSRF_HEADER: &str = "X-CSRF-Token";
+
+/// Tokens are bound to the session they were issued for, so they need no
+/// storage of their own and die with the session.
+pub(crate) fn token_for(session_id: &str) -> String {
+    let mut context = digest::Context::new(&digest::SHA256);
+    context.update(b"csrf:");
+    context.update(session_id.as_bytes());
+    URL_SAFE_NO_PAD.encode(context.finish())
+}
+
+/// Rejects state-changing requests that ride on the session cookie without a
+/// matching `X-CSRF-Token`.
+///
+/// Requests carrying an `Authorization` header (API keys, the admin token)
+/// are exempt: browsers never attach those cross-site on their own.
+pub async fn require_csrf(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    let is_state_changing = !req.method().is_safe();
+    let is_cookie_authenticated = !req.headers().contains_key(AUTHORIZATION);
+
+    if is_state_changing && is_cookie_authenticated {
+        if let Some(session) = req.cookie(SESSION_COOKIE) {
+            let expected = token_for(session.value());
+            let supplied = req
+                .headers()
+                .get(CSRF_HEADER)
+                .and_then(|value| value.to_str().ok())
+                .unwrap_or_default();
+
+            let expected = digest::digest(&digest::SHA256, expected.as_bytes());
+            let supplied = digest::digest(&digest::SHA256, supplied.as_bytes());
+
+            if expected.as_ref() != supplied.as_ref() {
+                return Err(ApiError::InvalidCsrfToken.into());
+            }
+        }
+    }
+
+    next.call(req).await
+}
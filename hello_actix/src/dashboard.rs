@@ -0,0 +1,77 @@
+//! The dashboard's own routes: login, logout, CSRF tokens, TOTP and user
+//! management. Compiled in with the `dashboard` feature (on by default).
+
+use actix_web::cookie::Cookie;
+use actix_web::{get, post, web, HttpRequest, HttpResponse, Responder};
+use serde::Serialize;
+
+use crate::error::ApiError;
+use crate::plugin::Plugin;
+use crate::session::{DashboardUser, SESSION_COOKIE};
+use crate::{auth, csrf, db, login, totp, users};
+
+pub struct Dashboard;
+
+impl Plugin for Dashboard {
+    fn name(&self) -> &'static str {
+        "dashboard"
+    }
+
+    fn configure(&self, cfg: &mut web::ServiceConfig) {
+        cfg.service(login::request_magic_link)
+            .service(login::redeem_magic_link)
+            .service(logout)
+            .service(csrf_token)
+            .service(me)
+            .service(totp::enroll)
+            .service(totp::confirm);
+    }
+
+    fn configure_admin(&self, cfg: &mut web::ServiceConfig) {
+        cfg.service(users::list_users)
+            .service(users::create_user)
+            .service(users::set_user_role)
+            .service(users::revoke_user_sessions);
+    }
+}
+
+/// Who the current dashboard session belongs to.
+#[get("/dashboard/me")]
+pub async fn me(user: DashboardUser) -> impl Responder {
+    web::Json(user.0)
+}
+
+#[derive(Serialize)]
+struct CsrfToken {
+    token: String,
+}
+
+/// The token dashboard clients must echo in `X-CSRF-Token` on state-changing
+/// requests.
+#[get("/auth/csrf-token")]
+pub async fn csrf_token(req: HttpRequest, _: DashboardUser) -> actix_web::Result<impl Responder> {
+    let session_id = req.cookie(SESSION_COOKIE).ok_or(ApiError::NotLoggedIn)?;
+
+    Ok(web::Json(CsrfToken {
+        token: csrf::token_for(session_id.value()),
+    }))
+}
+
+/// Ends the current session, server-side as well as in the browser.
+#[post("/auth/logout")]
+pub async fn logout(
+    req: HttpRequest,
+    database: web::Data<db::Pool>,
+) -> actix_web::Result<impl Responder> {
+    if let Some(cookie) = req.cookie(SESSION_COOKIE) {
+        let query = db::Query::DeleteSession {
+            id_hash: auth::hash_token(cookie.value()),
+        };
+        query.execute(database).await?;
+    }
+
+    let mut removal = Cookie::build(SESSION_COOKIE, "").path("/").finish();
+    removal.make_removal();
+
+    Ok(HttpResponse::NoContent().cookie(removal).finish())
+}
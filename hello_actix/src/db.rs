@@ -1,73 +1,439 @@
 // Pattern extracted from the official SQLite example
 // https://github.com/actix/examples/blob/master/databases/sqlite/src/db.rs
-use chrono::{DateTime, Utc};
+//
+// Statements are plain rusqlite SQL, checked when they run rather than at
+// build time. sqlx's `query!` macros would catch column and type mismatches
+// earlier, but only after moving every query and the r2d2 pool off
+// rusqlite, which isn't planned; the snapshot and contract tests run each
+// statement instead.
+use chrono::{DateTime, DurationRound, NaiveDate, SecondsFormat, Utc};
 
 use actix_web::{error, web, Error};
+use serde::{Deserialize, Serialize};
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc, Mutex, OnceLock};
+use std::thread;
+use std::time::Duration;
+
+use tokio::sync::oneshot;
+use tracing::warn;
+
+use crate::casing::FieldCase;
+use crate::config::Config;
+use crate::error::ApiError;
+use crate::scale::ScaleSet;
+use crate::scopes::{KeyScope, ScopeSet};
+use crate::{chaos, geoip, metrics, migrate, notify};
 
 pub type Pool = r2d2::Pool<r2d2_sqlite::SqliteConnectionManager>;
-//
-// pub type Connection = r2d2::PooledConnection<r2d2_sqlite::SqliteConnectionManager>;
+
+pub type Connection = r2d2::PooledConnection<r2d2_sqlite::SqliteConnectionManager>;
 
 pub const DB_FILE: &str = "api-db.sqlite";
 
+/// How often [`Query::execute`] retries a busy database before giving up.
+const MAX_BUSY_RETRIES: u32 = 5;
+
 use rusqlite::{
-    types::{FromSql, FromSqlError, ToSqlOutput},
-    ToSql,
+    types::{FromSql, FromSqlError, FromSqlResult, ToSqlOutput, ValueRef},
+    OptionalExtension, ToSql,
 };
 
-/// Deliberately not marked async, because it is not intended to be used while
-/// the web API itself is live.
+/// Brings the schema up to date; see [`migrate`]. Deliberately not marked
+/// async, because it is not intended to be used while the web API itself is
+/// live.
 pub fn setup(pool: Pool) {
     let conn = pool.get().expect("unable to connect to the database");
 
-    conn.execute(
-        "
-    CREATE TABLE IF NOT EXISTS usage (
-        id INTEGER PRIMARY KEY,
-        api_key TEXT,
-        endpoint TEXT,
-        called_at TEXT
-    );",
-        (),
-    )
-    .expect("unable to create `usage` table");
+    migrate::apply(&conn, None, |_, _| {}).expect("unable to migrate the database");
+}
 
-    conn.execute(
-        "
-    CREATE TABLE IF NOT EXISTS api_keys (
-        id INTEGER PRIMARY KEY,
-        salt TEXT,
-        api_key TEXT,
-        created_at TEXT NOT NULL,
-        revoked_at TEXT
-    );",
-        (),
-    )
-    .expect("unable to create `api_keys` table");
+/// Database threads started by [`run`] when [`start_threads`] wasn't
+/// called first, as in tests.
+const DEFAULT_THREADS: usize = 1;
 
-    conn.execute(
-        "
-    CREATE INDEX IF NOT EXISTS api_keys_api_key_idx 
-    ON api_keys (api_key);
-  ",
-        (),
+/// Work for a database thread; see [`run`].
+type Job = Box<dyn FnOnce() + Send>;
+
+static THREADS: OnceLock<mpsc::Sender<Job>> = OnceLock::new();
+
+/// Jobs [`run`] lets run or wait at once; 0 is unlimited. Set by
+/// [`start_threads`], as the db layer has no access to app data.
+static MAX_JOBS: AtomicUsize = AtomicUsize::new(0);
+
+static JOBS: AtomicUsize = AtomicUsize::new(0);
+
+/// Starts the `db_threads` threads that all database work runs on, and
+/// makes [`run`] refuse jobs once `max_blocking_queue` are waiting for one.
+/// Call once at startup, before anything touches the database.
+pub fn start_threads(config: &Config) {
+    let limit = match config.max_blocking_queue {
+        0 => 0,
+        queue => config.db_threads + queue,
+    };
+    MAX_JOBS.store(limit, Ordering::Relaxed);
+
+    if THREADS.set(spawn_threads(config.db_threads)).is_err() {
+        warn!("database threads already started");
+    }
+}
+
+fn spawn_threads(count: usize) -> mpsc::Sender<Job> {
+    let (sender, receiver) = mpsc::channel::<Job>();
+    let receiver = Arc::new(Mutex::new(receiver));
+
+    for n in 0..count.max(1) {
+        let receiver = receiver.clone();
+        thread::Builder::new()
+            .name(format!("db-{n}"))
+            .spawn(move || loop {
+                let Ok(job) = receiver.lock().unwrap().recv() else {
+                    return;
+                };
+                // The job's caller hears of a panic as its reply going
+                // missing; the thread carries on.
+                let _ = panic::catch_unwind(AssertUnwindSafe(job));
+            })
+            .expect("unable to start a database thread");
+    }
+
+    sender
+}
+
+/// Counts a job in [`JOBS`] until dropped.
+struct Queued;
+
+impl Queued {
+    fn start() -> Option<Queued> {
+        let limit = MAX_JOBS.load(Ordering::Relaxed);
+        // Counted either way, and uncounted as a refused one drops.
+        let queued = Queued;
+        let before = JOBS.fetch_add(1, Ordering::Relaxed);
+        (limit == 0 || before < limit).then_some(queued)
+    }
+}
+
+impl Drop for Queued {
+    fn drop(&mut self) {
+        JOBS.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Runs `job` on a database thread with a connection from `database`, and
+/// returns what it did without blocking the async runtime. Every query the
+/// API serves goes through here, so the threads bound how much SQLite work
+/// runs at once. Fails with 503 rather than queue past
+/// `max_blocking_queue`.
+pub async fn run<T, E, F>(database: web::Data<Pool>, job: F) -> Result<T, Error>
+where
+    F: FnOnce(&mut Connection) -> Result<T, E> + Send + 'static,
+    T: Send + 'static,
+    E: fmt::Debug + fmt::Display + Send + 'static,
+{
+    submit(database, job)
+        .await?
+        .map_err(error::ErrorInternalServerError)
+}
+
+/// [`run`] in one transaction, so every query in `job` reads the database
+/// as of the first: a usage row rolled up into `usage_buckets` meanwhile is
+/// counted once, before or after, never twice or not at all. For responses
+/// built from several queries.
+pub async fn read_consistent<T, F>(database: web::Data<Pool>, job: F) -> Result<T, Error>
+where
+    F: FnOnce(&rusqlite::Connection) -> rusqlite::Result<T> + Send + 'static,
+    T: Send + 'static,
+{
+    run(database, move |conn| {
+        let tx = conn.transaction()?;
+        let read = job(&tx)?;
+        tx.commit()?;
+
+        Ok::<_, rusqlite::Error>(read)
+    })
+    .await
+}
+
+/// [`run`], leaving the job's own error to the caller.
+async fn submit<T, E, F>(database: web::Data<Pool>, job: F) -> Result<Result<T, E>, Error>
+where
+    F: FnOnce(&mut Connection) -> Result<T, E> + Send + 'static,
+    T: Send + 'static,
+    E: Send + 'static,
+{
+    if chaos::should_fail_db() {
+        return Err(error::ErrorInternalServerError("injected database failure"));
+    }
+
+    let Some(queued) = Queued::start() else {
+        return Err(ApiError::ServerBusy.into());
+    };
+    let (reply, replied) = oneshot::channel();
+    let job: Job = Box::new(move || {
+        let _queued = queued;
+        let _ = reply.send(database.get().map(|mut conn| job(&mut conn)));
+    });
+
+    THREADS
+        .get_or_init(|| spawn_threads(DEFAULT_THREADS))
+        .send(job)
+        .map_err(|_| error::ErrorInternalServerError("database threads stopped"))?;
+    replied
+        .await
+        .map_err(|_| error::ErrorInternalServerError("database job panicked"))?
+        .map_err(error::ErrorInternalServerError)
+}
+
+fn is_busy(err: &rusqlite::Error) -> bool {
+    matches!(
+        err.sqlite_error_code(),
+        Some(rusqlite::ErrorCode::DatabaseBusy | rusqlite::ErrorCode::DatabaseLocked)
     )
-    .expect("unable to create `api_keys_api_key_idx` index");
 }
 
-#[derive(Debug)]
+/// Exponential from 10ms, with full jitter so writers that collided don't
+/// collide again.
+fn busy_backoff(retry: u32) -> Duration {
+    let ceiling = 10 << retry.min(6);
+    Duration::from_millis(fastrand::u64(1..=ceiling))
+}
+
+/// How every timestamp is stored: RFC 3339 in UTC, to the millisecond
+/// (`2024-05-01T12:00:00.123Z`), so stored times sort and compare as text.
+/// Reading refuses values without an offset rather than guess their zone.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Timestamp(pub DateTime<Utc>);
+
+impl Timestamp {
+    pub fn now() -> Self {
+        Timestamp(Utc::now())
+    }
+}
+
+impl From<Timestamp> for DateTime<Utc> {
+    fn from(timestamp: Timestamp) -> Self {
+        timestamp.0
+    }
+}
+
+impl ToSql for Timestamp {
+    fn to_sql(&self) -> rusqlite::Result<ToSqlOutput<'_>> {
+        Ok(ToSqlOutput::from(
+            self.0.to_rfc3339_opts(SecondsFormat::Millis, true),
+        ))
+    }
+}
+
+impl FromSql for Timestamp {
+    fn column_result(value: ValueRef<'_>) -> FromSqlResult<Self> {
+        DateTime::parse_from_rfc3339(value.as_str()?)
+            .map(|at| Timestamp(at.with_timezone(&Utc)))
+            .map_err(|err| FromSqlError::Other(Box::new(err)))
+    }
+}
+
+/// Every route API keys call, as labelled in usage rows.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
 pub enum ApiEndpoint {
     ToCelsius,
     ToFahrenheit,
+    /// A scripted conversion; see `crate::scripting`.
+    Convert,
+    WaitForUsage,
+    ExportData,
+    DeleteApiKey,
+    #[serde(rename = "whoami")]
+    WhoAmI,
+    UsageForecast,
+    Eval,
+    Scales,
+    OutputScales,
+    ToKelvin,
+    FromKelvin,
+    MyUsage,
+    FieldCase,
+    /// Revoking a key other than the caller's.
+    RevokeApiKey,
 }
 
 impl ApiEndpoint {
-    fn as_str(&self) -> &str {
+    pub const ALL: [ApiEndpoint; 16] = [
+        ApiEndpoint::ToCelsius,
+        ApiEndpoint::ToFahrenheit,
+        ApiEndpoint::Convert,
+        ApiEndpoint::WaitForUsage,
+        ApiEndpoint::ExportData,
+        ApiEndpoint::DeleteApiKey,
+        ApiEndpoint::WhoAmI,
+        ApiEndpoint::UsageForecast,
+        ApiEndpoint::Eval,
+        ApiEndpoint::Scales,
+        ApiEndpoint::OutputScales,
+        ApiEndpoint::ToKelvin,
+        ApiEndpoint::FromKelvin,
+        ApiEndpoint::MyUsage,
+        ApiEndpoint::FieldCase,
+        ApiEndpoint::RevokeApiKey,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
         match self {
             ApiEndpoint::ToCelsius => "to-celsius",
             ApiEndpoint::ToFahrenheit => "to-fahrenheit",
+            ApiEndpoint::Convert => "convert",
+            ApiEndpoint::WaitForUsage => "wait-for-usage",
+            ApiEndpoint::ExportData => "export-data",
+            ApiEndpoint::DeleteApiKey => "delete-api-key",
+            ApiEndpoint::WhoAmI => "whoami",
+            ApiEndpoint::UsageForecast => "usage-forecast",
+            ApiEndpoint::Eval => "eval",
+            ApiEndpoint::Scales => "scales",
+            ApiEndpoint::OutputScales => "output-scales",
+            ApiEndpoint::ToKelvin => "to-kelvin",
+            ApiEndpoint::FromKelvin => "from-kelvin",
+            ApiEndpoint::MyUsage => "my-usage",
+            ApiEndpoint::FieldCase => "field-case",
+            ApiEndpoint::RevokeApiKey => "revoke-api-key",
+        }
+    }
+
+    /// Position in an [`EndpointSet`]. Stored, so never renumber.
+    fn bit(&self) -> u32 {
+        let position = match self {
+            ApiEndpoint::ToCelsius => 0,
+            ApiEndpoint::ToFahrenheit => 1,
+            ApiEndpoint::Convert => 2,
+            ApiEndpoint::WaitForUsage => 3,
+            ApiEndpoint::ExportData => 4,
+            ApiEndpoint::DeleteApiKey => 5,
+            ApiEndpoint::WhoAmI => 6,
+            ApiEndpoint::UsageForecast => 7,
+            ApiEndpoint::Eval => 8,
+            ApiEndpoint::Scales => 9,
+            ApiEndpoint::OutputScales => 10,
+            ApiEndpoint::ToKelvin => 11,
+            ApiEndpoint::FromKelvin => 12,
+            ApiEndpoint::MyUsage => 13,
+            ApiEndpoint::FieldCase => 14,
+            ApiEndpoint::RevokeApiKey => 15,
+        };
+        1 << position
+    }
+
+    /// Whether calls are recorded as usage, and so count against quotas.
+    pub fn is_metered(&self) -> bool {
+        matches!(
+            self,
+            ApiEndpoint::ToCelsius
+                | ApiEndpoint::ToFahrenheit
+                | ApiEndpoint::Convert
+                | ApiEndpoint::Eval
+                | ApiEndpoint::ToKelvin
+                | ApiEndpoint::FromKelvin
+        )
+    }
+
+    /// The scope a key needs to call the endpoint; `None` for any key.
+    pub fn scope(&self) -> Option<KeyScope> {
+        match self {
+            ApiEndpoint::ToCelsius
+            | ApiEndpoint::ToFahrenheit
+            | ApiEndpoint::Convert
+            | ApiEndpoint::Eval
+            | ApiEndpoint::Scales
+            | ApiEndpoint::ToKelvin
+            | ApiEndpoint::FromKelvin
+            | ApiEndpoint::OutputScales
+            | ApiEndpoint::FieldCase => Some(KeyScope::ConvertRead),
+            ApiEndpoint::WaitForUsage
+            | ApiEndpoint::ExportData
+            | ApiEndpoint::UsageForecast
+            | ApiEndpoint::MyUsage => Some(KeyScope::UsageRead),
+            ApiEndpoint::RevokeApiKey => Some(KeyScope::Admin),
+            ApiEndpoint::DeleteApiKey | ApiEndpoint::WhoAmI => None,
+        }
+    }
+
+    /// Method and path template of the route, as in `/openapi.json`.
+    pub fn route(&self) -> (&'static str, &'static str) {
+        match self {
+            ApiEndpoint::ToCelsius => ("GET", "/api/to-celsius/{fahrenheit}"),
+            ApiEndpoint::ToFahrenheit => ("GET", "/api/to-fahrenheit/{celsius}"),
+            ApiEndpoint::Convert => ("GET", "/api/convert/{name}/{value}"),
+            ApiEndpoint::WaitForUsage => ("GET", "/api/alerts/wait"),
+            ApiEndpoint::ExportData => ("GET", "/my/data-export"),
+            ApiEndpoint::DeleteApiKey => ("DELETE", "/api-key"),
+            ApiEndpoint::WhoAmI => ("GET", "/api/whoami"),
+            ApiEndpoint::UsageForecast => ("GET", "/api/my-usage/forecast"),
+            ApiEndpoint::Eval => ("GET", "/api/eval"),
+            ApiEndpoint::Scales => ("GET", "/api/scales/{scale}"),
+            ApiEndpoint::OutputScales => ("PUT", "/api/output-scales"),
+            ApiEndpoint::ToKelvin => ("GET", "/api/to-kelvin/{celsius}"),
+            ApiEndpoint::FromKelvin => ("GET", "/api/from-kelvin/{kelvin}"),
+            ApiEndpoint::MyUsage => ("GET", "/api/usage/me"),
+            ApiEndpoint::FieldCase => ("PUT", "/api/field-case"),
+            ApiEndpoint::RevokeApiKey => ("DELETE", "/api-key/{id}"),
         }
     }
+
+    /// The endpoint served at route `pattern`, e.g. from
+    /// [`actix_web::HttpRequest::match_pattern`]. Patterns may carry a prefix
+    /// the API was mounted under.
+    pub fn for_route(method: &str, pattern: &str) -> Option<ApiEndpoint> {
+        ApiEndpoint::ALL.into_iter().find(|endpoint| {
+            let (route_method, path) = endpoint.route();
+            route_method == method && pattern.ends_with(path)
+        })
+    }
+}
+
+/// Endpoints packed into a bitmask, as stored in `api_keys.disabled_endpoints`.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct EndpointSet(u32);
+
+impl EndpointSet {
+    pub fn contains(&self, endpoint: ApiEndpoint) -> bool {
+        self.0 & endpoint.bit() != 0
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = ApiEndpoint> + '_ {
+        ApiEndpoint::ALL
+            .into_iter()
+            .filter(|endpoint| self.contains(*endpoint))
+    }
+}
+
+impl FromIterator<ApiEndpoint> for EndpointSet {
+    fn from_iter<I: IntoIterator<Item = ApiEndpoint>>(endpoints: I) -> Self {
+        EndpointSet(
+            endpoints
+                .into_iter()
+                .fold(0, |bits, endpoint| bits | endpoint.bit()),
+        )
+    }
+}
+
+impl ToSql for EndpointSet {
+    fn to_sql(&self) -> rusqlite::Result<rusqlite::types::ToSqlOutput<'_>> {
+        Ok(ToSqlOutput::from(self.0))
+    }
+}
+
+impl FromSql for EndpointSet {
+    fn column_result(value: rusqlite::types::ValueRef<'_>) -> rusqlite::types::FromSqlResult<Self> {
+        u32::column_result(value).map(EndpointSet)
+    }
+}
+
+impl std::fmt::Display for ApiEndpoint {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
 }
 
 #[derive(Debug)]
@@ -88,6 +454,20 @@ impl std::str::FromStr for ApiEndpoint {
         match s {
             "to-celsius" => Ok(ApiEndpoint::ToCelsius),
             "to-fahrenheit" => Ok(ApiEndpoint::ToFahrenheit),
+            "convert" => Ok(ApiEndpoint::Convert),
+            "wait-for-usage" => Ok(ApiEndpoint::WaitForUsage),
+            "export-data" => Ok(ApiEndpoint::ExportData),
+            "delete-api-key" => Ok(ApiEndpoint::DeleteApiKey),
+            "whoami" => Ok(ApiEndpoint::WhoAmI),
+            "usage-forecast" => Ok(ApiEndpoint::UsageForecast),
+            "eval" => Ok(ApiEndpoint::Eval),
+            "scales" => Ok(ApiEndpoint::Scales),
+            "output-scales" => Ok(ApiEndpoint::OutputScales),
+            "to-kelvin" => Ok(ApiEndpoint::ToKelvin),
+            "from-kelvin" => Ok(ApiEndpoint::FromKelvin),
+            "my-usage" => Ok(ApiEndpoint::MyUsage),
+            "field-case" => Ok(ApiEndpoint::FieldCase),
+            "revoke-api-key" => Ok(ApiEndpoint::RevokeApiKey),
             _ => Err(UnknownApiEndpoint(s.to_string())),
         }
     }
@@ -110,26 +490,382 @@ impl FromSql for ApiEndpoint {
     }
 }
 
+/// Limits attached to an API key. Keys issued before tiers existed have none
+/// recorded and count as [`Tier::Standard`].
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Tier {
+    /// Issued through self-service signup.
+    #[default]
+    Free,
+    Standard,
+}
+
+impl Tier {
+    fn as_str(&self) -> &str {
+        match self {
+            Tier::Free => "free",
+            Tier::Standard => "standard",
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct UnknownTier(String);
+
+impl std::fmt::Display for UnknownTier {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "unknown tier ({})", self.0)
+    }
+}
+
+impl std::error::Error for UnknownTier {}
+
+impl std::str::FromStr for Tier {
+    type Err = UnknownTier;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "free" => Ok(Tier::Free),
+            "standard" => Ok(Tier::Standard),
+            _ => Err(UnknownTier(s.to_string())),
+        }
+    }
+}
+
+impl ToSql for Tier {
+    fn to_sql(&self) -> rusqlite::Result<rusqlite::types::ToSqlOutput<'_>> {
+        Ok(ToSqlOutput::from(self.as_str()))
+    }
+}
+
+impl FromSql for Tier {
+    fn column_result(value: rusqlite::types::ValueRef<'_>) -> rusqlite::types::FromSqlResult<Self> {
+        value
+            .as_str()?
+            .parse()
+            .map_err(|err| FromSqlError::Other(Box::new(err)))
+    }
+}
+
+/// What a dashboard user may do. Ordered so that each role includes the
+/// permissions of the ones before it.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    #[default]
+    Viewer,
+    Operator,
+    Admin,
+}
+
+impl Role {
+    fn as_str(&self) -> &str {
+        match self {
+            Role::Viewer => "viewer",
+            Role::Operator => "operator",
+            Role::Admin => "admin",
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct UnknownRole(String);
+
+impl std::fmt::Display for UnknownRole {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "unknown role ({})", self.0)
+    }
+}
+
+impl std::error::Error for UnknownRole {}
+
+impl std::str::FromStr for Role {
+    type Err = UnknownRole;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "viewer" => Ok(Role::Viewer),
+            "operator" => Ok(Role::Operator),
+            "admin" => Ok(Role::Admin),
+            _ => Err(UnknownRole(s.to_string())),
+        }
+    }
+}
+
+impl ToSql for Role {
+    fn to_sql(&self) -> rusqlite::Result<rusqlite::types::ToSqlOutput<'_>> {
+        Ok(ToSqlOutput::from(self.as_str()))
+    }
+}
+
+impl FromSql for Role {
+    fn column_result(value: rusqlite::types::ValueRef<'_>) -> rusqlite::types::FromSqlResult<Self> {
+        value
+            .as_str()?
+            .parse()
+            .map_err(|err| FromSqlError::Other(Box::new(err)))
+    }
+}
+
+/// One call to record, for [`Query::RecordApiUsageBatch`].
+#[derive(Debug, Clone)]
+pub struct ApiUsage {
+    pub api_key: String,
+    pub endpoint: ApiEndpoint,
+    pub called_at: DateTime<Utc>,
+    pub client_request_id: Option<String>,
+    pub tag: Option<String>,
+    pub location: geoip::Location,
+    /// Time spent handling the call, when measured.
+    pub latency_ms: Option<u32>,
+}
+
+const INSERT_USAGE: &str = "
+INSERT INTO usage (api_key, endpoint, called_at, client_request_id, tag, country, asn, latency_ms)
+VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8);
+";
+
+/// Rows per multi-row `INSERT` in [`Query::RecordApiUsageBatch`]; at eight
+/// parameters a row, well within SQLite's limit per statement.
+const USAGE_ROWS_PER_INSERT: usize = 100;
+
+fn insert_usage_rows(conn: &rusqlite::Connection, rows: &[ApiUsage]) -> rusqlite::Result<()> {
+    let sql = format!(
+        "
+        INSERT INTO usage (api_key, endpoint, called_at, client_request_id, tag, country, asn, latency_ms)
+        VALUES {};
+        ",
+        vec!["(?, ?, ?, ?, ?, ?, ?, ?)"; rows.len()].join(", ")
+    );
+
+    let called_at: Vec<_> = rows
+        .iter()
+        .map(|usage| Timestamp(usage.called_at))
+        .collect();
+    let mut params: Vec<&dyn ToSql> = Vec::with_capacity(8 * rows.len());
+    for (usage, called_at) in rows.iter().zip(&called_at) {
+        params.extend([
+            &usage.api_key as &dyn ToSql,
+            &usage.endpoint,
+            called_at,
+            &usage.client_request_id,
+            &usage.tag,
+            &usage.location.country,
+            &usage.location.asn,
+            &usage.latency_ms,
+        ]);
+    }
+
+    conn.prepare_cached(&sql)?.execute(params.as_slice())?;
+    Ok(())
+}
+
+#[derive(Clone)]
 pub enum Query {
     // CheckApiKey(String),
     RecordApiUsage {
         api_key: String,
         endpoint: ApiEndpoint,
         called_at: DateTime<Utc>,
+        client_request_id: Option<String>,
+        tag: Option<String>,
+        location: geoip::Location,
+        latency_ms: Option<u32>,
     },
+    /// Inserts many usage rows in one transaction.
+    RecordApiUsageBatch(Vec<ApiUsage>),
+    /// Counts each call in its endpoint's bucket for the hour, recording
+    /// nothing about the caller.
+    CountApiUsage {
+        calls: Vec<(ApiEndpoint, DateTime<Utc>)>,
+    },
+    /// Revokes the key with this fingerprint, as [`Query::StoreApiKey`]
+    /// stores it. Returns `Some(false)` when there is no such key left to
+    /// revoke.
     RevokeApiKey(String),
+    /// [`Query::RevokeApiKey`], for key `id`.
+    RevokeApiKeyById(i64),
+    /// Also revokes any key previously issued to `email`, so each address
+    /// holds at most one active key. Returns `Some(false)`, changing
+    /// nothing, when a key with the same `fingerprint` is already stored.
     StoreApiKey {
         salt: String,
         api_key: String,
+        fingerprint: String,
+        email: String,
+        tier: Tier,
+        /// `None` for a key that doesn't expire.
+        expires_at: Option<DateTime<Utc>>,
+        scopes: ScopeSet,
+    },
+    CreateUser {
+        email: String,
+        role: Role,
+    },
+    /// Returns `Some(false)` when the user doesn't exist.
+    SetRole {
+        user_id: i64,
+        role: Role,
+    },
+    StoreMagicLink {
+        token_hash: String,
+        user_id: i64,
+        expires_at: DateTime<Utc>,
+    },
+    StoreSignup {
+        token_hash: String,
+        email: String,
+        expires_at: DateTime<Utc>,
+    },
+    StoreChallenge {
+        token_hash: String,
+        expires_at: DateTime<Utc>,
+    },
+    CreateSession {
+        id_hash: String,
+        user_id: i64,
+        expires_at: DateTime<Utc>,
+    },
+    /// Pushes back the expiry of a live session. Writes at most once per
+    /// minute per session; returns `Some(true)` when the expiry moved.
+    RefreshSession {
+        id_hash: String,
+        expires_at: DateTime<Utc>,
+    },
+    DeleteSession {
+        id_hash: String,
+    },
+    /// Logs a user out everywhere.
+    DeleteUserSessions {
+        user_id: i64,
+    },
+    /// Blocks an address or CIDR network, until `expires_at` if given.
+    AddBlock {
+        network: String,
+        reason: Option<String>,
+        expires_at: Option<DateTime<Utc>>,
+    },
+    /// Returns `Some(false)` when there was no such entry.
+    DeleteBlock {
+        id: i64,
+    },
+    /// Returns `Some(false)` if there is no key `id`.
+    SetDisabledEndpoints {
+        id: i64,
+        disabled: EndpointSet,
+    },
+    SetOutputScales {
+        id: i64,
+        scales: ScaleSet,
+    },
+    /// Sets the field case of key `id`'s response bodies; `None` leaves it
+    /// to the instance.
+    SetFieldCase {
+        id: i64,
+        field_case: Option<FieldCase>,
+    },
+    /// Sets the monthly quota of key `id`, 0 for none; `None` leaves it to
+    /// the key's tier. Returns `Some(false)` when there is no such key.
+    SetMonthlyQuota {
+        id: i64,
+        monthly_limit: Option<u64>,
+    },
+    /// Subscribes `target` to usage reports on `schedule`, a cron
+    /// expression first due at `next_run_at`.
+    AddReportSubscription {
+        endpoint: Option<ApiEndpoint>,
+        schedule: String,
+        target: notify::Target,
+        next_run_at: DateTime<Utc>,
+    },
+    /// Returns `Some(false)` when there was no such subscription.
+    DeleteReportSubscription {
+        id: i64,
+    },
+    /// Records a report as sent at `ran_at`. A `next_run_at` of `None`
+    /// retires the subscription.
+    MarkReportSent {
+        id: i64,
+        ran_at: DateTime<Utc>,
+        next_run_at: Option<DateTime<Utc>>,
+    },
+    /// Notes that key `key_id` was warned about passing `threshold` percent
+    /// of its quota in `period` (`YYYY-MM`). Returns `Some(false)` if it
+    /// already had been.
+    RecordQuotaWarning {
+        key_id: i64,
+        period: String,
+        threshold: u64,
+    },
+    DeleteExpiredBlocks,
+    /// Removes magic links, signup links and challenges, and sessions whose
+    /// expiry has passed.
+    DeleteExpiredLogins,
+    /// Replaces a user's pending TOTP secret. Fails (returns `Some(false)`)
+    /// once TOTP has been enabled, so enrollment can't be silently redone.
+    SetTotpSecret {
+        user_id: i64,
+        secret: String,
+    },
+    /// Accepts a TOTP time step for a user, enabling TOTP if it was pending.
+    /// Returns `Some(false)` for a step at or before the last one accepted,
+    /// so each code can only be used once.
+    AcceptTotpStep {
+        user_id: i64,
+        step: i64,
     },
 }
 
 impl Query {
+    /// Swaps a [`Query::RecordApiUsage`] or [`Query::RecordApiUsageBatch`]
+    /// for a [`Query::CountApiUsage`] when `anonymous` is set, for
+    /// deployments that mustn't keep per-key usage.
+    pub fn anonymized_if(self, anonymous: bool) -> Self {
+        match self {
+            Query::RecordApiUsage {
+                endpoint,
+                called_at,
+                ..
+            } if anonymous => Query::CountApiUsage {
+                calls: vec![(endpoint, called_at)],
+            },
+            Query::RecordApiUsageBatch(usage) if anonymous => Query::CountApiUsage {
+                calls: usage
+                    .into_iter()
+                    .map(|usage| (usage.endpoint, usage.called_at))
+                    .collect(),
+            },
+            query => query,
+        }
+    }
+
+    /// Runs the query, retrying with jittered backoff while SQLite reports
+    /// the database busy or locked, so short write bursts don't fail
+    /// requests outright.
     pub async fn execute(self, database: web::Data<Pool>) -> Result<Option<bool>, Error> {
-        let conn = web::block(move || database.get())
-            .await?
-            .map_err(error::ErrorInternalServerError)?;
+        let mut retries = 0;
+        loop {
+            let query = self.clone();
+            let outcome = submit(database.clone(), move |conn| query.run(conn)).await?;
+            match outcome {
+                Err(err) if is_busy(&err) && retries < MAX_BUSY_RETRIES => {
+                    retries += 1;
+                    metrics::DB.busy_retries.fetch_add(1, Ordering::Relaxed);
+                    actix_web::rt::time::sleep(busy_backoff(retries)).await;
+                }
+                Err(err) => {
+                    if is_busy(&err) {
+                        metrics::DB.busy_failures.fetch_add(1, Ordering::Relaxed);
+                    }
+                    return Err(error::ErrorInternalServerError(err));
+                }
+                Ok(result) => return Ok(result),
+            }
+        }
+    }
 
+    fn run(self, conn: &rusqlite::Connection) -> rusqlite::Result<Option<bool>> {
         match self {
             // Query::CheckApiKey(key) => {
             //     let sql = "
@@ -153,59 +889,1390 @@ impl Query {
                 api_key,
                 endpoint,
                 called_at,
+                client_request_id,
+                tag,
+                location,
+                latency_ms,
             } => {
+                let mut stmt = conn.prepare_cached(INSERT_USAGE)?;
+
+                let _n_rows = stmt.execute((
+                    api_key,
+                    endpoint,
+                    Timestamp(called_at),
+                    client_request_id,
+                    tag,
+                    location.country,
+                    location.asn,
+                    latency_ms,
+                ))?;
+
+                Ok(None)
+            }
+            Query::RecordApiUsageBatch(usage) => {
+                let tx = conn.unchecked_transaction()?;
+                for rows in usage.chunks(USAGE_ROWS_PER_INSERT) {
+                    insert_usage_rows(&tx, rows)?;
+                }
+                tx.commit()?;
+
+                Ok(None)
+            }
+            Query::CountApiUsage { calls } => {
                 let sql = "
-                INSERT INTO usage (api_key, endpoint, called_at) 
-                VALUES (?1, ?2, ?3);
+                INSERT INTO usage_buckets (bucket, endpoint, calls)
+                VALUES (?1, ?2, 1)
+                ON CONFLICT (bucket, endpoint) DO UPDATE SET calls = calls + 1;
                 ";
 
-                let mut stmt = conn
-                    .prepare_cached(sql)
-                    .map_err(error::ErrorInternalServerError)?;
-
-                let _n_rows = stmt
-                    .execute((api_key, endpoint, called_at))
-                    .map_err(error::ErrorInternalServerError)?;
+                let tx = conn.unchecked_transaction()?;
+                {
+                    let mut stmt = tx.prepare_cached(sql)?;
+                    for (endpoint, called_at) in calls {
+                        let bucket = called_at
+                            .duration_trunc(chrono::TimeDelta::hours(1))
+                            .map_err(|err| {
+                                rusqlite::Error::ToSqlConversionFailure(Box::new(err))
+                            })?;
+                        stmt.execute((Timestamp(bucket), endpoint))?;
+                    }
+                }
+                tx.commit()?;
 
                 Ok(None)
             }
-            Query::StoreApiKey { api_key, salt } => {
+            Query::StoreApiKey {
+                api_key,
+                salt,
+                fingerprint,
+                email,
+                tier,
+                expires_at,
+                scopes,
+            } => {
+                let now = Timestamp::now();
+
                 let sql = "
-                INSERT INTO api_keys (api_key, salt, created_at)
-                VALUES (?1, ?2, ?3);
+                INSERT INTO api_keys (api_key, salt, fingerprint, created_at, email, tier, expires_at, scopes)
+                VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)
+                ON CONFLICT (fingerprint) DO NOTHING;
                 ";
 
-                let now = Utc::now();
+                let mut stmt = conn.prepare_cached(sql)?;
 
-                let mut stmt = conn
-                    .prepare_cached(sql)
-                    .map_err(error::ErrorInternalServerError)?;
+                let expires_at = expires_at.map(Timestamp);
+                if stmt.execute((
+                    api_key,
+                    salt,
+                    fingerprint,
+                    &now,
+                    &email,
+                    tier,
+                    expires_at,
+                    scopes,
+                ))? == 0
+                {
+                    return Ok(Some(false));
+                }
 
-                let _n_rows = stmt
-                    .execute((api_key, salt, now))
-                    .map_err(error::ErrorInternalServerError)?;
+                conn.execute(
+                    "
+                    UPDATE api_keys SET revoked_at = ?1
+                    WHERE email = ?2 AND revoked_at IS NULL AND id != last_insert_rowid();
+                    ",
+                    (now, &email),
+                )?;
 
-                Ok(None)
+                Ok(Some(true))
             }
-            Query::RevokeApiKey(key) => {
+            Query::RevokeApiKey(fingerprint) => {
                 let sql = "
                 UPDATE api_keys
-                SET revoked_at = ?1 
-                WHERE api_key = ?2;
+                SET revoked_at = ?1
+                WHERE fingerprint = ?2 AND revoked_at IS NULL;
                 ";
 
-                let now = Utc::now();
+                let now = Timestamp::now();
+
+                let mut stmt = conn.prepare_cached(sql)?;
+
+                Ok(Some(stmt.execute((now, fingerprint))? > 0))
+            }
+            Query::RevokeApiKeyById(id) => {
+                let sql = "
+                UPDATE api_keys
+                SET revoked_at = ?1
+                WHERE id = ?2 AND revoked_at IS NULL;
+                ";
+
+                let now = Timestamp::now();
+
+                let mut stmt = conn.prepare_cached(sql)?;
+
+                Ok(Some(stmt.execute((now, id))? > 0))
+            }
+            Query::CreateUser { email, role } => {
+                let sql = "
+                INSERT INTO users (email, created_at)
+                VALUES (?1, ?2)
+                ON CONFLICT (email) DO NOTHING;
+                ";
+
+                let now = Timestamp::now();
+
+                let mut stmt = conn.prepare_cached(sql)?;
+
+                let n_rows = stmt.execute((email, now))?;
+
+                if n_rows > 0 {
+                    conn.execute(
+                        "INSERT INTO roles (user_id, role) VALUES (?1, ?2);",
+                        (conn.last_insert_rowid(), role),
+                    )?;
+                }
+
+                Ok(Some(n_rows > 0))
+            }
+            Query::SetRole { user_id, role } => {
+                let sql = "
+                INSERT INTO roles (user_id, role)
+                SELECT id, ?2 FROM users WHERE id = ?1
+                ON CONFLICT (user_id) DO UPDATE SET role = excluded.role;
+                ";
 
-                let mut stmt = conn
-                    .prepare_cached(sql)
-                    .map_err(error::ErrorInternalServerError)?;
+                let mut stmt = conn.prepare_cached(sql)?;
 
-                let _n_rows = stmt
-                    .execute((now, key))
-                    .map_err(error::ErrorInternalServerError)?;
+                let n_rows = stmt.execute((user_id, role))?;
+
+                Ok(Some(n_rows > 0))
+            }
+            Query::StoreMagicLink {
+                token_hash,
+                user_id,
+                expires_at,
+            } => {
+                let sql = "
+                INSERT INTO magic_links (token_hash, user_id, expires_at)
+                VALUES (?1, ?2, ?3);
+                ";
+
+                let mut stmt = conn.prepare_cached(sql)?;
+
+                let _n_rows = stmt.execute((token_hash, user_id, Timestamp(expires_at)))?;
 
                 Ok(None)
             }
-        }
-    }
+            Query::StoreSignup {
+                token_hash,
+                email,
+                expires_at,
+            } => {
+                let sql = "
+                INSERT INTO signups (token_hash, email, expires_at)
+                VALUES (?1, ?2, ?3);
+                ";
+
+                let mut stmt = conn.prepare_cached(sql)?;
+
+                let _n_rows = stmt.execute((token_hash, email, Timestamp(expires_at)))?;
+
+                Ok(None)
+            }
+            Query::StoreChallenge {
+                token_hash,
+                expires_at,
+            } => {
+                let sql = "
+                INSERT INTO signup_challenges (token_hash, expires_at)
+                VALUES (?1, ?2);
+                ";
+
+                let mut stmt = conn.prepare_cached(sql)?;
+
+                let _n_rows = stmt.execute((token_hash, Timestamp(expires_at)))?;
+
+                Ok(None)
+            }
+            Query::CreateSession {
+                id_hash,
+                user_id,
+                expires_at,
+            } => {
+                let sql = "
+                INSERT INTO sessions (id_hash, user_id, created_at, expires_at)
+                VALUES (?1, ?2, ?3, ?4);
+                ";
+
+                let now = Timestamp::now();
+
+                let mut stmt = conn.prepare_cached(sql)?;
+
+                let _n_rows = stmt.execute((id_hash, user_id, now, Timestamp(expires_at)))?;
+
+                Ok(None)
+            }
+            Query::SetTotpSecret { user_id, secret } => {
+                let sql = "
+                UPDATE users
+                SET    totp_secret = ?2, totp_last_step = NULL
+                WHERE  id = ?1 AND totp_enabled_at IS NULL;
+                ";
+
+                let mut stmt = conn.prepare_cached(sql)?;
+
+                let n_rows = stmt.execute((user_id, secret))?;
+
+                Ok(Some(n_rows > 0))
+            }
+            Query::AcceptTotpStep { user_id, step } => {
+                let sql = "
+                UPDATE users
+                SET    totp_last_step = ?2,
+                       totp_enabled_at = COALESCE(totp_enabled_at, ?3)
+                WHERE  id = ?1 AND (totp_last_step IS NULL OR totp_last_step < ?2);
+                ";
+
+                let now = Timestamp::now();
+
+                let mut stmt = conn.prepare_cached(sql)?;
+
+                let n_rows = stmt.execute((user_id, step, now))?;
+
+                Ok(Some(n_rows > 0))
+            }
+            Query::RefreshSession {
+                id_hash,
+                expires_at,
+            } => {
+                let sql = "
+                UPDATE sessions
+                SET    expires_at = ?2, last_seen_at = ?3
+                WHERE  id_hash = ?1
+                  AND  expires_at > ?3
+                  AND  (last_seen_at IS NULL OR last_seen_at < ?4);
+                ";
+
+                let now = Utc::now();
+                let throttle = now - chrono::Duration::minutes(1);
+
+                let mut stmt = conn.prepare_cached(sql)?;
+
+                let n_rows = stmt.execute((
+                    id_hash,
+                    Timestamp(expires_at),
+                    Timestamp(now),
+                    Timestamp(throttle),
+                ))?;
+
+                Ok(Some(n_rows > 0))
+            }
+            Query::DeleteSession { id_hash } => {
+                let sql = "DELETE FROM sessions WHERE id_hash = ?1;";
+
+                let mut stmt = conn.prepare_cached(sql)?;
+
+                let n_rows = stmt.execute((id_hash,))?;
+
+                Ok(Some(n_rows > 0))
+            }
+            Query::DeleteUserSessions { user_id } => {
+                let sql = "DELETE FROM sessions WHERE user_id = ?1;";
+
+                let mut stmt = conn.prepare_cached(sql)?;
+
+                let n_rows = stmt.execute((user_id,))?;
+
+                Ok(Some(n_rows > 0))
+            }
+            Query::AddBlock {
+                network,
+                reason,
+                expires_at,
+            } => {
+                let sql = "
+                INSERT INTO blocklist (network, reason, created_at, expires_at)
+                VALUES (?1, ?2, ?3, ?4);
+                ";
+
+                let mut stmt = conn.prepare_cached(sql)?;
+
+                let _n_rows =
+                    stmt.execute((network, reason, Timestamp::now(), expires_at.map(Timestamp)))?;
+
+                Ok(None)
+            }
+            Query::DeleteBlock { id } => {
+                let n_rows = conn.execute("DELETE FROM blocklist WHERE id = ?1;", (id,))?;
+
+                Ok(Some(n_rows > 0))
+            }
+            Query::SetDisabledEndpoints { id, disabled } => {
+                let n_rows = conn.execute(
+                    "UPDATE api_keys SET disabled_endpoints = ?2 WHERE id = ?1;",
+                    (id, disabled),
+                )?;
+
+                Ok(Some(n_rows > 0))
+            }
+            Query::SetOutputScales { id, scales } => {
+                let n_rows = conn.execute(
+                    "UPDATE api_keys SET output_scales = ?2 WHERE id = ?1;",
+                    (id, scales),
+                )?;
+
+                Ok(Some(n_rows > 0))
+            }
+            Query::SetFieldCase { id, field_case } => {
+                let n_rows = conn.execute(
+                    "UPDATE api_keys SET field_case = ?2 WHERE id = ?1;",
+                    (id, field_case),
+                )?;
+
+                Ok(Some(n_rows > 0))
+            }
+            Query::SetMonthlyQuota { id, monthly_limit } => {
+                let exists: bool = conn.query_row(
+                    "SELECT EXISTS (SELECT 1 FROM api_keys WHERE id = ?1);",
+                    (id,),
+                    |row| row.get(0),
+                )?;
+                if !exists {
+                    return Ok(Some(false));
+                }
+
+                match monthly_limit {
+                    Some(monthly_limit) => conn.execute(
+                        "
+                        INSERT INTO quotas (key_id, monthly_limit, updated_at) VALUES (?1, ?2, ?3)
+                        ON CONFLICT (key_id) DO UPDATE
+                        SET monthly_limit = excluded.monthly_limit, updated_at = excluded.updated_at;
+                        ",
+                        (id, monthly_limit, Timestamp::now()),
+                    )?,
+                    None => conn.execute("DELETE FROM quotas WHERE key_id = ?1;", (id,))?,
+                };
+
+                Ok(Some(true))
+            }
+            Query::AddReportSubscription {
+                endpoint,
+                schedule,
+                target,
+                next_run_at,
+            } => {
+                let sql = "
+                INSERT INTO report_subscriptions (endpoint, schedule, target, created_at, next_run_at)
+                VALUES (?1, ?2, ?3, ?4, ?5);
+                ";
+
+                let mut stmt = conn.prepare_cached(sql)?;
+
+                let _n_rows = stmt.execute((
+                    endpoint,
+                    schedule,
+                    target,
+                    Timestamp::now(),
+                    Timestamp(next_run_at),
+                ))?;
+
+                Ok(None)
+            }
+            Query::DeleteReportSubscription { id } => {
+                let n_rows =
+                    conn.execute("DELETE FROM report_subscriptions WHERE id = ?1;", (id,))?;
+
+                Ok(Some(n_rows > 0))
+            }
+            Query::MarkReportSent {
+                id,
+                ran_at,
+                next_run_at,
+            } => {
+                let n_rows = conn.execute(
+                    "UPDATE report_subscriptions SET last_run_at = ?2, next_run_at = ?3 WHERE id = ?1;",
+                    (id, Timestamp(ran_at), next_run_at.map(Timestamp)),
+                )?;
+
+                Ok(Some(n_rows > 0))
+            }
+            Query::RecordQuotaWarning {
+                key_id,
+                period,
+                threshold,
+            } => {
+                let sql = "
+                INSERT OR IGNORE INTO quota_warnings (key_id, period, threshold, sent_at)
+                VALUES (?1, ?2, ?3, ?4);
+                ";
+
+                let mut stmt = conn.prepare_cached(sql)?;
+
+                let n_rows = stmt.execute((key_id, period, threshold, Timestamp::now()))?;
+
+                Ok(Some(n_rows > 0))
+            }
+            Query::DeleteExpiredBlocks => {
+                conn.execute(
+                    "DELETE FROM blocklist WHERE expires_at <= ?1;",
+                    (Timestamp::now(),),
+                )?;
+
+                Ok(None)
+            }
+            Query::DeleteExpiredLogins => {
+                let now = Timestamp::now();
+
+                conn.execute("DELETE FROM magic_links WHERE expires_at <= ?1;", (now,))?;
+                conn.execute("DELETE FROM signups WHERE expires_at <= ?1;", (now,))?;
+                conn.execute(
+                    "DELETE FROM signup_challenges WHERE expires_at <= ?1;",
+                    (now,),
+                )?;
+                conn.execute("DELETE FROM sessions WHERE expires_at <= ?1;", (now,))?;
+
+                Ok(None)
+            }
+        }
+    }
+}
+
+/// Number of calls a key made to one endpoint within a reporting window.
+/// Calls counted while usage was anonymous have no key.
+#[derive(Debug, Serialize)]
+pub struct UsageCount {
+    pub api_key: Option<String>,
+    pub endpoint: String,
+    pub calls: u64,
+}
+
+/// Aggregates `usage` rows with `from <= called_at < to` per key and
+/// endpoint, plus anonymous counts per endpoint from `usage_buckets`.
+pub async fn usage_counts(
+    database: web::Data<Pool>,
+    from: DateTime<Utc>,
+    to: DateTime<Utc>,
+) -> Result<Vec<UsageCount>, Error> {
+    run(database, move |conn| count_usage(conn, from, to)).await
+}
+
+/// [`usage_counts`] on `conn`, e.g. within [`read_consistent`].
+pub fn count_usage(
+    conn: &rusqlite::Connection,
+    from: DateTime<Utc>,
+    to: DateTime<Utc>,
+) -> rusqlite::Result<Vec<UsageCount>> {
+    let sql = "
+    SELECT   api_key, endpoint, COUNT(*)
+    FROM     usage
+    WHERE    called_at >= ?1 AND called_at < ?2
+    GROUP BY api_key, endpoint
+    UNION ALL
+    SELECT   NULL, endpoint, SUM(calls)
+    FROM     usage_buckets
+    WHERE    bucket >= ?1 AND bucket < ?2
+    GROUP BY endpoint
+    ORDER BY 1, 2;
+    ";
+
+    let mut stmt = conn.prepare_cached(sql)?;
+
+    let rows = stmt.query_map((Timestamp(from), Timestamp(to)), |row| {
+        Ok(UsageCount {
+            api_key: row.get(0)?,
+            endpoint: row.get(1)?,
+            calls: row.get(2)?,
+        })
+    })?;
+
+    rows.collect()
+}
+
+/// How finely [`bucketed_usage_counts`] splits a window.
+#[derive(Debug, Default, Clone, Copy, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Bucket {
+    #[default]
+    Hour,
+    Day,
+}
+
+impl Bucket {
+    /// Leading characters stored timestamps in one bucket share, and what
+    /// completes those into the bucket's start.
+    fn prefix(&self) -> (usize, &'static str) {
+        match self {
+            Bucket::Hour => (13, ":00:00.000Z"),
+            Bucket::Day => (10, "T00:00:00.000Z"),
+        }
+    }
+}
+
+/// Calls to one endpoint within one bucket.
+#[derive(Debug, Serialize)]
+pub struct BucketCount {
+    pub start: DateTime<Utc>,
+    pub endpoint: String,
+    pub calls: u64,
+}
+
+/// Aggregates `usage` rows with `from <= called_at < to` per `bucket` and
+/// endpoint, plus anonymous counts from `usage_buckets`, oldest first.
+/// Buckets without calls are left out.
+pub async fn bucketed_usage_counts(
+    database: web::Data<Pool>,
+    from: DateTime<Utc>,
+    to: DateTime<Utc>,
+    bucket: Bucket,
+) -> Result<Vec<BucketCount>, Error> {
+    let sql = "
+    SELECT   start, endpoint, SUM(calls)
+    FROM     (
+        SELECT   substr(called_at, 1, ?3) || ?4 AS start, endpoint, COUNT(*) AS calls
+        FROM     usage
+        WHERE    called_at >= ?1 AND called_at < ?2
+        GROUP BY 1, 2
+        UNION ALL
+        SELECT   substr(bucket, 1, ?3) || ?4, endpoint, SUM(calls)
+        FROM     usage_buckets
+        WHERE    bucket >= ?1 AND bucket < ?2
+        GROUP BY 1, 2
+    )
+    GROUP BY 1, 2
+    ORDER BY 1, 2;
+    ";
+    let (length, rest) = bucket.prefix();
+
+    run(database, move |conn| {
+        let mut stmt = conn.prepare_cached(sql)?;
+
+        let rows = stmt.query_map((Timestamp(from), Timestamp(to), length, rest), |row| {
+            Ok(BucketCount {
+                start: row.get::<_, Timestamp>(0)?.into(),
+                endpoint: row.get(1)?,
+                calls: row.get(2)?,
+            })
+        })?;
+
+        rows.collect::<Result<Vec<_>, _>>()
+    })
+    .await
+}
+
+/// An entry on the abuse blocklist.
+#[derive(Debug, Clone, Serialize)]
+pub struct Block {
+    pub id: i64,
+    pub network: String,
+    pub reason: Option<String>,
+    pub created_at: DateTime<Utc>,
+    pub expires_at: Option<DateTime<Utc>>,
+}
+
+/// Every blocklist entry that hasn't expired, oldest first.
+pub async fn list_blocks(database: web::Data<Pool>) -> Result<Vec<Block>, Error> {
+    let sql = "
+    SELECT   id, network, reason, created_at, expires_at
+    FROM     blocklist
+    WHERE    expires_at IS NULL OR expires_at > ?1
+    ORDER BY id;
+    ";
+
+    run(database, move |conn| {
+        let mut stmt = conn.prepare_cached(sql)?;
+
+        let rows = stmt.query_map((Timestamp::now(),), |row| {
+            Ok(Block {
+                id: row.get(0)?,
+                network: row.get(1)?,
+                reason: row.get(2)?,
+                created_at: row.get::<_, Timestamp>(3)?.into(),
+                expires_at: row.get::<_, Option<Timestamp>>(4)?.map(DateTime::from),
+            })
+        })?;
+
+        rows.collect::<Result<Vec<_>, _>>()
+    })
+    .await
+}
+
+/// A recurring usage report; see [`crate::subscriptions`].
+#[derive(Debug, Clone, Serialize)]
+pub struct ReportSubscription {
+    pub id: i64,
+    /// `None` reports every endpoint.
+    pub endpoint: Option<ApiEndpoint>,
+    /// A cron expression, in UTC.
+    pub schedule: String,
+    pub target: notify::Target,
+    pub created_at: DateTime<Utc>,
+    pub last_run_at: Option<DateTime<Utc>>,
+    /// `None` once the schedule never comes due again.
+    pub next_run_at: Option<DateTime<Utc>>,
+}
+
+impl FromRow for ReportSubscription {
+    const COLUMNS: &'static str =
+        "id, endpoint, schedule, target, created_at, last_run_at, next_run_at";
+
+    fn from_row(row: &rusqlite::Row<'_>) -> rusqlite::Result<Self> {
+        Ok(ReportSubscription {
+            id: row.get("id")?,
+            endpoint: row.get("endpoint")?,
+            schedule: row.get("schedule")?,
+            target: row.get("target")?,
+            created_at: row.get::<_, Timestamp>("created_at")?.into(),
+            last_run_at: row
+                .get::<_, Option<Timestamp>>("last_run_at")?
+                .map(DateTime::from),
+            next_run_at: row
+                .get::<_, Option<Timestamp>>("next_run_at")?
+                .map(DateTime::from),
+        })
+    }
+}
+
+/// Every report subscription, oldest first.
+pub async fn list_report_subscriptions(
+    database: web::Data<Pool>,
+) -> Result<Vec<ReportSubscription>, Error> {
+    let sql = format!(
+        "
+    SELECT   {}
+    FROM     report_subscriptions
+    ORDER BY id;
+    ",
+        ReportSubscription::COLUMNS
+    );
+
+    run(database, move |conn| {
+        let mut stmt = conn.prepare_cached(&sql)?;
+
+        let rows = stmt.query_map((), ReportSubscription::from_row)?;
+
+        rows.collect::<Result<Vec<_>, _>>()
+    })
+    .await
+}
+
+/// The report subscriptions due by `now`, oldest first.
+pub async fn due_report_subscriptions(
+    database: web::Data<Pool>,
+    now: DateTime<Utc>,
+) -> Result<Vec<ReportSubscription>, Error> {
+    let sql = format!(
+        "
+    SELECT   {}
+    FROM     report_subscriptions
+    WHERE    next_run_at <= ?1
+    ORDER BY id;
+    ",
+        ReportSubscription::COLUMNS
+    );
+
+    run(database, move |conn| {
+        let mut stmt = conn.prepare_cached(&sql)?;
+
+        let rows = stmt.query_map((Timestamp(now),), ReportSubscription::from_row)?;
+
+        rows.collect::<Result<Vec<_>, _>>()
+    })
+    .await
+}
+
+/// Number of calls from one country within a reporting window. `None`
+/// collects callers that couldn't be located.
+#[derive(Debug, Serialize)]
+pub struct CountryCount {
+    pub country: Option<String>,
+    pub calls: u64,
+}
+
+/// Aggregates `usage` rows with `from <= called_at < to` per country,
+/// busiest first.
+pub async fn usage_by_country(
+    database: web::Data<Pool>,
+    from: DateTime<Utc>,
+    to: DateTime<Utc>,
+) -> Result<Vec<CountryCount>, Error> {
+    run(database, move |conn| count_by_country(conn, from, to)).await
+}
+
+/// [`usage_by_country`] on `conn`, e.g. within [`read_consistent`].
+pub fn count_by_country(
+    conn: &rusqlite::Connection,
+    from: DateTime<Utc>,
+    to: DateTime<Utc>,
+) -> rusqlite::Result<Vec<CountryCount>> {
+    let sql = "
+    SELECT   country, COUNT(*)
+    FROM     usage
+    WHERE    called_at >= ?1 AND called_at < ?2
+    GROUP BY country
+    ORDER BY COUNT(*) DESC, country;
+    ";
+
+    let mut stmt = conn.prepare_cached(sql)?;
+
+    let rows = stmt.query_map((Timestamp(from), Timestamp(to)), |row| {
+        Ok(CountryCount {
+            country: row.get(0)?,
+            calls: row.get(1)?,
+        })
+    })?;
+
+    rows.collect()
+}
+
+/// A type read from a row by column name rather than position, so reordering
+/// a `SELECT` can't silently shuffle fields.
+pub trait FromRow: Sized {
+    /// The columns [`FromRow::from_row`] reads, for a `SELECT` list.
+    const COLUMNS: &'static str;
+
+    fn from_row(row: &rusqlite::Row<'_>) -> rusqlite::Result<Self>;
+}
+
+/// What is stored about an API key besides the key itself.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ApiKeyRecord {
+    pub id: i64,
+    pub email: Option<String>,
+    pub tier: Tier,
+    pub created_at: DateTime<Utc>,
+    /// `None` for a key that doesn't expire.
+    pub expires_at: Option<DateTime<Utc>>,
+}
+
+impl FromRow for ApiKeyRecord {
+    const COLUMNS: &'static str = "id, email, tier, created_at, expires_at";
+
+    fn from_row(row: &rusqlite::Row<'_>) -> rusqlite::Result<Self> {
+        Ok(ApiKeyRecord {
+            id: row.get("id")?,
+            email: row.get("email")?,
+            // Keys issued before tiers existed.
+            tier: row
+                .get::<_, Option<Tier>>("tier")?
+                .unwrap_or(Tier::Standard),
+            created_at: row.get::<_, Timestamp>("created_at")?.into(),
+            expires_at: row
+                .get::<_, Option<Timestamp>>("expires_at")?
+                .map(Into::into),
+        })
+    }
+}
+
+/// An API key as stored: encrypted, with the salt it was encrypted with.
+/// Both are `None` once the key's data has been erased.
+#[derive(Debug, Clone)]
+pub struct EncryptedApiKey {
+    pub ciphertext: Option<String>,
+    pub salt: Option<String>,
+}
+
+impl FromRow for EncryptedApiKey {
+    const COLUMNS: &'static str = "api_key, salt";
+
+    fn from_row(row: &rusqlite::Row<'_>) -> rusqlite::Result<Self> {
+        Ok(EncryptedApiKey {
+            ciphertext: row.get("api_key")?,
+            salt: row.get("salt")?,
+        })
+    }
+}
+
+/// One recorded call, as stored.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct UsageRecord {
+    /// Only identifies the row to admins; owners' exports leave it out.
+    #[serde(skip)]
+    pub id: i64,
+    pub endpoint: String,
+    pub called_at: DateTime<Utc>,
+    pub client_request_id: Option<String>,
+    pub tag: Option<String>,
+    pub country: Option<String>,
+    pub asn: Option<u32>,
+    pub latency_ms: Option<u32>,
+}
+
+impl FromRow for UsageRecord {
+    const COLUMNS: &'static str =
+        "id, endpoint, called_at, client_request_id, tag, country, asn, latency_ms";
+
+    fn from_row(row: &rusqlite::Row<'_>) -> rusqlite::Result<Self> {
+        Ok(UsageRecord {
+            id: row.get("id")?,
+            endpoint: row.get("endpoint")?,
+            called_at: row.get::<_, Timestamp>("called_at")?.into(),
+            client_request_id: row.get("client_request_id")?,
+            tag: row.get("tag")?,
+            country: row.get("country")?,
+            asn: row.get("asn")?,
+            latency_ms: row.get("latency_ms")?,
+        })
+    }
+}
+
+/// Every key that hasn't been revoked, oldest first.
+pub async fn list_api_keys(database: web::Data<Pool>) -> Result<Vec<ApiKeyRecord>, Error> {
+    let sql = format!(
+        "
+    SELECT   {}
+    FROM     api_keys
+    WHERE    revoked_at IS NULL
+    ORDER BY id;
+    ",
+        ApiKeyRecord::COLUMNS
+    );
+
+    run(database, move |conn| {
+        let mut stmt = conn.prepare_cached(&sql)?;
+
+        let rows = stmt.query_map((), ApiKeyRecord::from_row)?;
+
+        rows.collect::<Result<Vec<_>, _>>()
+    })
+    .await
+}
+
+/// Every usage row recorded for `api_key`, oldest first.
+pub async fn usage_of_key(
+    database: web::Data<Pool>,
+    api_key: String,
+) -> Result<Vec<UsageRecord>, Error> {
+    let sql = format!(
+        "
+    SELECT   {}
+    FROM     usage
+    WHERE    api_key = ?1
+    ORDER BY called_at, id;
+    ",
+        UsageRecord::COLUMNS
+    );
+
+    run(database, move |conn| {
+        let mut stmt = conn.prepare_cached(&sql)?;
+
+        let rows = stmt.query_map((api_key,), UsageRecord::from_row)?;
+
+        rows.collect::<Result<Vec<_>, _>>()
+    })
+    .await
+}
+
+/// Calls `api_key` made on one day (UTC).
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct DailyCount {
+    pub day: NaiveDate,
+    pub calls: u64,
+}
+
+/// Counts the usage rows of `api_key` with `from <= called_at < to` per
+/// day, oldest first. Days without calls are left out.
+pub async fn daily_usage_of_key(
+    database: web::Data<Pool>,
+    api_key: String,
+    from: DateTime<Utc>,
+    to: DateTime<Utc>,
+) -> Result<Vec<DailyCount>, Error> {
+    let sql = "
+    SELECT   substr(called_at, 1, 10), COUNT(*)
+    FROM     usage
+    WHERE    api_key = ?1 AND called_at >= ?2 AND called_at < ?3
+    GROUP BY 1
+    ORDER BY 1;
+    ";
+
+    run(database, move |conn| {
+        let mut stmt = conn.prepare_cached(sql)?;
+
+        let rows = stmt.query_map((api_key, Timestamp(from), Timestamp(to)), |row| {
+            Ok(DailyCount {
+                day: row.get(0)?,
+                calls: row.get(1)?,
+            })
+        })?;
+
+        rows.collect::<Result<Vec<_>, _>>()
+    })
+    .await
+}
+
+/// Calls `api_key` made to one endpoint, over all the usage kept.
+#[derive(Debug, Clone, Serialize)]
+pub struct EndpointUsage {
+    pub endpoint: String,
+    pub calls: u64,
+    pub first_call_at: DateTime<Utc>,
+    pub last_call_at: DateTime<Utc>,
+}
+
+/// Counts the usage rows of `api_key` per endpoint, with the first and
+/// last call to each, by endpoint.
+pub async fn usage_summary_of_key(
+    database: web::Data<Pool>,
+    api_key: String,
+) -> Result<Vec<EndpointUsage>, Error> {
+    let sql = "
+    SELECT   endpoint, COUNT(*), MIN(called_at), MAX(called_at)
+    FROM     usage
+    WHERE    api_key = ?1
+    GROUP BY endpoint
+    ORDER BY endpoint;
+    ";
+
+    run(database, move |conn| {
+        let mut stmt = conn.prepare_cached(sql)?;
+
+        let rows = stmt.query_map((api_key,), |row| {
+            Ok(EndpointUsage {
+                endpoint: row.get(0)?,
+                calls: row.get(1)?,
+                first_call_at: row.get::<_, Timestamp>(2)?.into(),
+                last_call_at: row.get::<_, Timestamp>(3)?.into(),
+            })
+        })?;
+
+        rows.collect::<Result<Vec<_>, _>>()
+    })
+    .await
+}
+
+/// Replaces the saved value of each of `counters`, in one transaction.
+pub async fn save_counters(
+    database: web::Data<Pool>,
+    counters: Vec<(&'static str, u64)>,
+) -> Result<(), Error> {
+    let sql = "
+    INSERT INTO counters (name, value, saved_at) VALUES (?1, ?2, ?3)
+    ON CONFLICT (name) DO UPDATE SET value = excluded.value, saved_at = excluded.saved_at;
+    ";
+
+    run(database, move |conn| {
+        let tx = conn.transaction()?;
+        let saved_at = Timestamp::now();
+        {
+            let mut stmt = tx.prepare_cached(sql)?;
+            for (name, value) in counters {
+                stmt.execute((name, value, &saved_at))?;
+            }
+        }
+        tx.commit()
+    })
+    .await
+}
+
+/// The counters as [`save_counters`] last left them, by name.
+pub async fn saved_counters(database: web::Data<Pool>) -> Result<Vec<(String, u64)>, Error> {
+    run(database, |conn| {
+        let mut stmt = conn.prepare_cached("SELECT name, value FROM counters ORDER BY name;")?;
+        let rows = stmt.query_map((), |row| Ok((row.get(0)?, row.get(1)?)))?;
+
+        rows.collect::<Result<Vec<_>, _>>()
+    })
+    .await
+}
+
+/// Requests one rule turned away within a minute, for one key and address.
+#[derive(Debug)]
+pub struct Throttled {
+    pub minute: DateTime<Utc>,
+    pub rule: &'static str,
+    pub key_id: Option<i64>,
+    pub ip: Option<String>,
+    pub count: u64,
+}
+
+/// Adds `rows` to the `throttled` table, dropping rows from before
+/// `expired`, in one transaction.
+pub async fn save_throttled(
+    database: web::Data<Pool>,
+    rows: Vec<Throttled>,
+    expired: DateTime<Utc>,
+) -> Result<(), Error> {
+    let sql =
+        "INSERT INTO throttled (minute, rule, key_id, ip, count) VALUES (?1, ?2, ?3, ?4, ?5);";
+
+    run(database, move |conn| {
+        let tx = conn.transaction()?;
+        {
+            let mut stmt = tx.prepare_cached(sql)?;
+            for row in rows {
+                stmt.execute((
+                    Timestamp(row.minute),
+                    row.rule,
+                    row.key_id,
+                    row.ip,
+                    row.count,
+                ))?;
+            }
+        }
+        tx.execute(
+            "DELETE FROM throttled WHERE minute < ?1;",
+            (Timestamp(expired),),
+        )?;
+        tx.commit()
+    })
+    .await
+}
+
+/// Requests turned away since some time, in total and by what.
+#[derive(Debug, Serialize)]
+pub struct ThrottledSummary {
+    pub total: u64,
+    pub by_rule: BTreeMap<String, u64>,
+    /// The keys turned away most, most first.
+    pub top_keys: Vec<ThrottledKey>,
+    /// The addresses turned away most, most first.
+    pub top_ips: Vec<ThrottledIp>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ThrottledKey {
+    pub key_id: i64,
+    pub count: u64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ThrottledIp {
+    pub ip: String,
+    pub count: u64,
+}
+
+/// Sums the `throttled` rows from `since` on, with at most `top` keys and
+/// addresses.
+pub fn summarize_throttled(
+    conn: &rusqlite::Connection,
+    since: DateTime<Utc>,
+    top: usize,
+) -> rusqlite::Result<ThrottledSummary> {
+    let since = Timestamp(since);
+
+    let mut stmt = conn.prepare_cached(
+        "
+        SELECT      rule, SUM(count)
+        FROM        throttled
+        WHERE       minute >= ?1
+        GROUP BY    rule;
+        ",
+    )?;
+    let by_rule = stmt
+        .query_map((&since,), |row| Ok((row.get(0)?, row.get(1)?)))?
+        .collect::<Result<BTreeMap<String, u64>, _>>()?;
+
+    let mut stmt = conn.prepare_cached(
+        "
+        SELECT      key_id, SUM(count) AS total
+        FROM        throttled
+        WHERE       minute >= ?1 AND key_id IS NOT NULL
+        GROUP BY    key_id
+        ORDER BY    total DESC, key_id
+        LIMIT       ?2;
+        ",
+    )?;
+    let top_keys = stmt
+        .query_map((&since, top), |row| {
+            Ok(ThrottledKey {
+                key_id: row.get(0)?,
+                count: row.get(1)?,
+            })
+        })?
+        .collect::<Result<_, _>>()?;
+
+    let mut stmt = conn.prepare_cached(
+        "
+        SELECT      ip, SUM(count) AS total
+        FROM        throttled
+        WHERE       minute >= ?1 AND ip IS NOT NULL
+        GROUP BY    ip
+        ORDER BY    total DESC, ip
+        LIMIT       ?2;
+        ",
+    )?;
+    let top_ips = stmt
+        .query_map((&since, top), |row| {
+            Ok(ThrottledIp {
+                ip: row.get(0)?,
+                count: row.get(1)?,
+            })
+        })?
+        .collect::<Result<_, _>>()?;
+
+    Ok(ThrottledSummary {
+        total: by_rule.values().sum(),
+        by_rule,
+        top_keys,
+        top_ips,
+    })
+}
+
+/// Tombstone left by [`erase_owner_data`]. Holds no personal data, only
+/// which key rows were scrubbed, when, and by whom.
+#[derive(Debug, Serialize)]
+pub struct Erasure {
+    pub key_ids: Vec<i64>,
+    pub usage_rows: usize,
+    pub erased_by: String,
+    pub erased_at: DateTime<Utc>,
+}
+
+/// In one transaction: deletes the usage of `api_keys`, pending signups for
+/// `email`, and the secret and address of key rows `key_ids`, which are
+/// revoked. The rows themselves stay, keeping their ids valid.
+pub async fn erase_owner_data(
+    database: web::Data<Pool>,
+    key_ids: Vec<i64>,
+    api_keys: Vec<String>,
+    email: Option<String>,
+    erased_by: String,
+) -> Result<Erasure, Error> {
+    let key_ids_json = serde_json::to_string(&key_ids).map_err(error::ErrorInternalServerError)?;
+
+    run(database, move |conn| -> rusqlite::Result<_> {
+        let tx = conn.transaction()?;
+
+        let erased_at = Utc::now();
+        let mut usage_rows = 0;
+
+        for api_key in &api_keys {
+            usage_rows += tx.execute("DELETE FROM usage WHERE api_key = ?1;", (api_key,))?;
+        }
+
+        if let Some(email) = &email {
+            tx.execute("DELETE FROM signups WHERE email = ?1;", (email,))?;
+        }
+
+        for id in &key_ids {
+            tx.execute("DELETE FROM throttled WHERE key_id = ?1;", (id,))?;
+            tx.execute(
+                "
+                UPDATE  api_keys
+                SET     api_key = NULL, salt = NULL, email = NULL,
+                        revoked_at = COALESCE(revoked_at, ?2)
+                WHERE   id = ?1;
+                ",
+                (id, Timestamp(erased_at)),
+            )?;
+        }
+
+        let erasure = Erasure {
+            key_ids,
+            usage_rows,
+            erased_by,
+            erased_at,
+        };
+
+        tx.execute(
+            "
+            INSERT INTO erasures (key_ids, usage_rows, erased_by, erased_at)
+            VALUES (?1, ?2, ?3, ?4);
+            ",
+            (
+                key_ids_json,
+                erasure.usage_rows,
+                &erasure.erased_by,
+                Timestamp(erasure.erased_at),
+            ),
+        )?;
+
+        tx.commit()?;
+
+        Ok(erasure)
+    })
+    .await
+}
+
+/// A dashboard account, identified by email.
+#[derive(Debug, Clone, Serialize)]
+pub struct User {
+    pub id: i64,
+    pub email: String,
+    pub role: Role,
+}
+
+fn user_from_row(row: &rusqlite::Row<'_>) -> rusqlite::Result<User> {
+    Ok(User {
+        id: row.get(0)?,
+        email: row.get(1)?,
+        role: row.get(2)?,
+    })
+}
+
+pub async fn list_users(database: web::Data<Pool>) -> Result<Vec<User>, Error> {
+    let sql = "
+    SELECT    users.id, users.email, COALESCE(roles.role, 'viewer')
+    FROM      users
+    LEFT JOIN roles ON roles.user_id = users.id
+    ORDER BY  users.id;
+    ";
+
+    run(database, move |conn| {
+        let mut stmt = conn.prepare_cached(sql)?;
+
+        let rows = stmt.query_map((), user_from_row)?;
+
+        rows.collect::<Result<Vec<_>, _>>()
+    })
+    .await
+}
+
+pub async fn find_user_by_email(
+    database: web::Data<Pool>,
+    email: String,
+) -> Result<Option<User>, Error> {
+    let sql = "
+    SELECT    users.id, users.email, COALESCE(roles.role, 'viewer')
+    FROM      users
+    LEFT JOIN roles ON roles.user_id = users.id
+    WHERE     users.email = ?1;
+    ";
+
+    run(database, move |conn| {
+        let mut stmt = conn.prepare_cached(sql)?;
+
+        stmt.query_row((email,), user_from_row).optional()
+    })
+    .await
+}
+
+/// Marks an unexpired, unused magic link as used and returns its owner.
+/// Each link can therefore be redeemed at most once.
+pub async fn redeem_magic_link(
+    database: web::Data<Pool>,
+    token_hash: String,
+    now: DateTime<Utc>,
+) -> Result<Option<User>, Error> {
+    let sql = "
+    UPDATE  magic_links
+    SET     used_at = ?2
+    WHERE   token_hash = ?1 AND used_at IS NULL AND expires_at > ?2
+    RETURNING user_id;
+    ";
+
+    let now = Timestamp(now);
+
+    run(database, move |conn| {
+        let mut stmt = conn.prepare_cached(sql)?;
+
+        let user_id: Option<i64> = stmt
+            .query_row((token_hash, now), |row| row.get(0))
+            .optional()?;
+
+        match user_id {
+            Some(user_id) => conn
+                .query_row(
+                    "
+                    SELECT    users.id, users.email, COALESCE(roles.role, 'viewer')
+                    FROM      users
+                    LEFT JOIN roles ON roles.user_id = users.id
+                    WHERE     users.id = ?1;
+                    ",
+                    (user_id,),
+                    user_from_row,
+                )
+                .optional(),
+            None => Ok(None),
+        }
+    })
+    .await
+}
+
+/// Marks an unexpired, unused signup link as used and returns the address it
+/// verified. Each link can therefore be redeemed at most once.
+pub async fn redeem_signup(
+    database: web::Data<Pool>,
+    token_hash: String,
+    now: DateTime<Utc>,
+) -> Result<Option<String>, Error> {
+    let sql = "
+    UPDATE  signups
+    SET     used_at = ?2
+    WHERE   token_hash = ?1 AND used_at IS NULL AND expires_at > ?2
+    RETURNING email;
+    ";
+
+    run(database, move |conn| {
+        let mut stmt = conn.prepare_cached(sql)?;
+
+        stmt.query_row((token_hash, Timestamp(now)), |row| row.get(0))
+            .optional()
+    })
+    .await
+}
+
+/// Marks an unexpired, unused signup challenge as used. Returns `false` when
+/// there was no such challenge, so each one is accepted at most once.
+pub async fn redeem_challenge(
+    database: web::Data<Pool>,
+    token_hash: String,
+    now: DateTime<Utc>,
+) -> Result<bool, Error> {
+    let sql = "
+    UPDATE  signup_challenges
+    SET     used_at = ?2
+    WHERE   token_hash = ?1 AND used_at IS NULL AND expires_at > ?2;
+    ";
+
+    run(database, move |conn| -> rusqlite::Result<_> {
+        let n_rows = conn.execute(sql, (token_hash, Timestamp(now)))?;
+
+        Ok(n_rows > 0)
+    })
+    .await
+}
+
+/// Looks up the user owning a session unexpired at `now`.
+pub async fn find_session_user(
+    database: web::Data<Pool>,
+    id_hash: String,
+    now: DateTime<Utc>,
+) -> Result<Option<User>, Error> {
+    let sql = "
+    SELECT    users.id, users.email, COALESCE(roles.role, 'viewer')
+    FROM      sessions
+    JOIN      users ON users.id = sessions.user_id
+    LEFT JOIN roles ON roles.user_id = users.id
+    WHERE     sessions.id_hash = ?1 AND sessions.expires_at > ?2;
+    ";
+
+    let now = Timestamp(now);
+
+    run(database, move |conn| {
+        let mut stmt = conn.prepare_cached(sql)?;
+
+        stmt.query_row((id_hash, now), user_from_row).optional()
+    })
+    .await
+}
+
+/// A user's TOTP enrollment.
+#[derive(Debug, Clone)]
+pub struct TotpState {
+    pub secret: String,
+    pub enabled: bool,
+}
+
+pub async fn find_totp(
+    database: web::Data<Pool>,
+    user_id: i64,
+) -> Result<Option<TotpState>, Error> {
+    let sql = "
+    SELECT  totp_secret, totp_enabled_at IS NOT NULL
+    FROM    users
+    WHERE   id = ?1 AND totp_secret IS NOT NULL;
+    ";
+
+    run(database, move |conn| {
+        let mut stmt = conn.prepare_cached(sql)?;
+
+        stmt.query_row((user_id,), |row| {
+            Ok(TotpState {
+                secret: row.get(0)?,
+                enabled: row.get(1)?,
+            })
+        })
+        .optional()
+    })
+    .await
 }
@@ -0,0 +1,99 @@
+use std::collections::HashMap;
+
+use actix_web::body::MessageBody;
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::header::{HeaderName, HeaderValue, LINK};
+use actix_web::middleware::Next;
+use actix_web::{web, Error};
+use chrono::{DateTime, Utc};
+
+use crate::releases::{ReleaseStatus, X_API_DEPRECATED_VERSION};
+
+const DEPRECATION: HeaderName = HeaderName::from_static("deprecation");
+const SUNSET: HeaderName = HeaderName::from_static("sunset");
+
+/// Wind-down schedule for a single route.
+#[derive(Debug, Clone)]
+pub struct Deprecation {
+    /// When the route was deprecated.
+    pub since: DateTime<Utc>,
+    /// When the route is expected to stop responding.
+    pub sunset: Option<DateTime<Utc>>,
+    /// Path of the endpoint clients should migrate to.
+    pub successor: Option<String>,
+}
+
+/// Deprecated routes, keyed by their full match pattern as registered with
+/// actix (e.g. `/api/to-celsius/{fahrenheit}`).
+#[derive(Debug, Default, Clone)]
+pub struct DeprecationRegistry {
+    routes: HashMap<String, Deprecation>,
+}
+
+impl DeprecationRegistry {
+    pub fn new() -> Self {
+        DeprecationRegistry::default()
+    }
+
+    pub fn deprecate(mut self, pattern: impl Into<String>, deprecation: Deprecation) -> Self {
+        self.routes.insert(pattern.into(), deprecation);
+        self
+    }
+
+    pub fn get(&self, pattern: &str) -> Option<&Deprecation> {
+        self.routes.get(pattern)
+    }
+}
+
+/// Adds `Deprecation` (RFC 9745), `Sunset` (RFC 8594) and a successor `Link`
+/// to responses from routes listed in the [`DeprecationRegistry`], and
+/// `X-API-Deprecated-Version` to every response while this version is
+/// end-of-life; see [`ReleaseStatus::advisory`].
+pub async fn emit_deprecation_headers(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    let registry = req.app_data::<web::Data<DeprecationRegistry>>().cloned();
+    let advisory = req
+        .app_data::<web::Data<ReleaseStatus>>()
+        .and_then(|status| status.advisory());
+    let mut res = next.call(req).await?;
+
+    if let Some(advisory) = advisory {
+        res.headers_mut().insert(X_API_DEPRECATED_VERSION, advisory);
+    }
+
+    let Some(registry) = registry else {
+        return Ok(res);
+    };
+    let Some(deprecation) = res
+        .request()
+        .match_pattern()
+        .and_then(|pattern| registry.get(&pattern).cloned())
+    else {
+        return Ok(res);
+    };
+
+    let headers = res.headers_mut();
+
+    let since = format!("@{}", deprecation.since.timestamp());
+    if let Ok(value) = HeaderValue::from_str(&since) {
+        headers.insert(DEPRECATION, value);
+    }
+
+    if let Some(sunset) = deprecation.sunset {
+        let sunset = sunset.format("%a, %d %b %Y %H:%M:%S GMT").to_string();
+        if let Ok(value) = HeaderValue::from_str(&sunset) {
+            headers.insert(SUNSET, value);
+        }
+    }
+
+    if let Some(successor) = deprecation.successor {
+        let link = format!("<{successor}>; rel=\"successor-version\"");
+        if let Ok(value) = HeaderValue::from_str(&link) {
+            headers.append(LINK, value);
+        }
+    }
+
+    Ok(res)
+}
@@ -0,0 +1,153 @@
+//! Warm shutdown for blue/green deploys. `POST /admin/drain` takes the
+//! instance out of rotation: `/readyz` turns 503, responses close their
+//! connection instead of keeping it alive, and the caller can wait for the
+//! requests in flight to finish, then have the server stop.
+
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use actix_web::body::MessageBody;
+use actix_web::dev::{ServerHandle, ServiceRequest, ServiceResponse};
+use actix_web::http::header::{HeaderValue, CONNECTION};
+use actix_web::middleware::Next;
+use actix_web::{post, rt, web, Error, HttpMessage, HttpRequest, HttpResponse, Responder};
+use serde::{Deserialize, Serialize};
+use tracing::info;
+
+use crate::rbac::{Admin, Authorized};
+
+/// Longest a drain request may wait for in-flight requests.
+const MAX_WAIT_SECONDS: u64 = 300;
+
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+/// Shared by every worker, and by the internal listener if there is one.
+#[derive(Debug, Default)]
+pub struct Drain {
+    draining: AtomicBool,
+    in_flight: AtomicUsize,
+    /// Set by `main` once the public server is running.
+    server: Mutex<Option<ServerHandle>>,
+}
+
+impl Drain {
+    pub fn new() -> Self {
+        Drain::default()
+    }
+
+    pub fn is_draining(&self) -> bool {
+        self.draining.load(Ordering::Relaxed)
+    }
+
+    pub fn in_flight(&self) -> usize {
+        self.in_flight.load(Ordering::Relaxed)
+    }
+
+    /// The server `shutdown=true` stops.
+    pub fn set_server(&self, server: ServerHandle) {
+        *self.server.lock().unwrap() = Some(server);
+    }
+}
+
+/// Marks a request counted by [`track_requests`], so a drain request served
+/// on the public listener doesn't wait for itself.
+#[derive(Debug, Clone, Copy)]
+struct Tracked;
+
+struct InFlight<'a>(&'a Drain);
+
+impl Drop for InFlight<'_> {
+    fn drop(&mut self) {
+        self.0.in_flight.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Counts requests in flight and, while draining, closes each connection
+/// after its response. Install app-wide on the public listener.
+pub async fn track_requests(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    let Some(drain) = req.app_data::<web::Data<Drain>>().cloned() else {
+        return next.call(req).await;
+    };
+
+    drain.in_flight.fetch_add(1, Ordering::Relaxed);
+    let _in_flight = InFlight(&drain);
+    req.extensions_mut().insert(Tracked);
+
+    let mut res = next.call(req).await?;
+    if drain.is_draining() {
+        res.headers_mut()
+            .insert(CONNECTION, HeaderValue::from_static("close"));
+    }
+
+    Ok(res)
+}
+
+#[derive(Debug, Deserialize)]
+pub struct DrainParams {
+    /// How long to wait for requests in flight to finish; 0 answers at once.
+    #[serde(default)]
+    wait_seconds: u64,
+    /// Stop the server once drained.
+    #[serde(default)]
+    shutdown: bool,
+}
+
+#[derive(Debug, Serialize)]
+pub struct DrainStatus {
+    pub draining: bool,
+    /// Not counting this request.
+    pub in_flight: usize,
+    pub drained: bool,
+    pub shutting_down: bool,
+}
+
+/// Starts draining, which can't be undone short of a restart. 200 once no
+/// other requests are in flight, 202 while some still are.
+#[post("/drain")]
+pub async fn start_drain(
+    _: Authorized<Admin>,
+    params: web::Query<DrainParams>,
+    drain: web::Data<Drain>,
+    req: HttpRequest,
+) -> impl Responder {
+    if !drain.draining.swap(true, Ordering::Relaxed) {
+        info!(in_flight = drain.in_flight(), "draining");
+    }
+
+    let own = usize::from(req.extensions().get::<Tracked>().is_some());
+    let others = || drain.in_flight().saturating_sub(own);
+
+    let deadline = Duration::from_secs(params.wait_seconds.min(MAX_WAIT_SECONDS));
+    let _ = rt::time::timeout(deadline, async {
+        while others() > 0 {
+            rt::time::sleep(POLL_INTERVAL).await;
+        }
+    })
+    .await;
+
+    let in_flight = others();
+    let drained = in_flight == 0;
+    let server = drain.server.lock().unwrap().clone();
+    let shutting_down = params.shutdown && drained && server.is_some();
+    if let (true, Some(server)) = (shutting_down, server) {
+        info!("drained; stopping the server");
+        // Stopping waits for this response too, so don't await it here.
+        rt::spawn(async move { server.stop(true).await });
+    }
+
+    let status = DrainStatus {
+        draining: true,
+        in_flight,
+        drained,
+        shutting_down,
+    };
+    if drained {
+        HttpResponse::Ok().json(status)
+    } else {
+        HttpResponse::Accepted().json(status)
+    }
+}
@@ -0,0 +1,846 @@
+use actix_web::http::header::WWW_AUTHENTICATE;
+use actix_web::http::StatusCode;
+use actix_web::{get, web, HttpRequest, HttpResponse, Responder, ResponseError};
+use serde::Serialize;
+
+use crate::i18n::Lang;
+
+/// Errors surfaced to API clients.
+///
+/// Every variant has a stable machine-readable `code` plus a human-readable
+/// `message` which is localized according to `Accept-Language` by
+/// [`crate::i18n::localize_errors`].
+#[derive(Debug, Clone)]
+pub enum ApiError {
+    Unauthorized,
+    TooManyConcurrentRequests,
+    RateLimited,
+    QuotaExceeded,
+    MalformedCredentials,
+    KeyRevoked,
+    KeyExpired,
+    InvalidClientRequestId { max_length: usize },
+    InvalidUsageTag { max_length: usize },
+    TooManyValues { max: usize },
+    InvalidPrecision { max: u32 },
+    InvalidReportPeriod,
+    InvalidUsageRange,
+    InvalidExpression,
+    AdminDisabled,
+    AdminUnauthorized,
+    HttpsRequired,
+    InvalidEmail,
+    InvalidMagicLink,
+    InvalidSignupLink,
+    InvalidKeyTtl,
+    InvalidKeyScopes,
+    InvalidChallenge,
+    Blocked,
+    EndpointDisabled,
+    MissingScope,
+    InvalidNetwork,
+    InvalidSchedule,
+    InvalidWebhookUrl,
+    BlockNotFound,
+    SubscriptionNotFound,
+    KeyNotFound,
+    ScaleNotFound,
+    NotLoggedIn,
+    TotpRequired,
+    InvalidTotpCode,
+    TotpAlreadyEnabled,
+    TotpNotEnrolled,
+    InsufficientRole,
+    UserNotFound,
+    InvalidCsrfToken,
+    InvalidConversionName { max_length: usize },
+    InvalidScript,
+    ConversionNotFound,
+    ScriptFailed,
+    InvalidReading,
+    InvalidCommand,
+    ProfileInProgress,
+    ServerBusy,
+    InjectedFault,
+    Internal,
+}
+
+#[derive(Serialize)]
+struct ErrorBody<'a> {
+    code: &'a str,
+    message: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    help: Option<&'a KeyHelp>,
+}
+
+/// Where a client without a working API key can get one, sent along with
+/// [`ApiError::Unauthorized`], [`ApiError::KeyRevoked`] and
+/// [`ApiError::KeyExpired`]; see [`crate::config::Config::key_help`].
+#[derive(Debug, Clone, Serialize)]
+pub struct KeyHelp {
+    pub key_request_url: String,
+    pub docs_url: String,
+}
+
+impl ApiError {
+    pub fn code(&self) -> &'static str {
+        match self {
+            ApiError::Unauthorized => "unauthorized",
+            ApiError::TooManyConcurrentRequests => "too_many_concurrent_requests",
+            ApiError::RateLimited => "rate_limited",
+            ApiError::QuotaExceeded => "quota_exceeded",
+            ApiError::MalformedCredentials => "malformed_credentials",
+            ApiError::KeyRevoked => "key_revoked",
+            ApiError::KeyExpired => "key_expired",
+            ApiError::InvalidClientRequestId { .. } => "invalid_client_request_id",
+            ApiError::InvalidUsageTag { .. } => "invalid_usage_tag",
+            ApiError::TooManyValues { .. } => "too_many_values",
+            ApiError::InvalidPrecision { .. } => "invalid_precision",
+            ApiError::InvalidReportPeriod => "invalid_report_period",
+            ApiError::InvalidUsageRange => "invalid_usage_range",
+            ApiError::InvalidExpression => "invalid_expression",
+            ApiError::AdminDisabled => "admin_disabled",
+            ApiError::AdminUnauthorized => "admin_unauthorized",
+            ApiError::HttpsRequired => "https_required",
+            ApiError::InvalidEmail => "invalid_email",
+            ApiError::InvalidMagicLink => "invalid_magic_link",
+            ApiError::InvalidSignupLink => "invalid_signup_link",
+            ApiError::InvalidKeyTtl => "invalid_key_ttl",
+            ApiError::InvalidKeyScopes => "invalid_key_scopes",
+            ApiError::InvalidChallenge => "invalid_challenge",
+            ApiError::Blocked => "blocked",
+            ApiError::EndpointDisabled => "endpoint_disabled",
+            ApiError::MissingScope => "missing_scope",
+            ApiError::InvalidNetwork => "invalid_network",
+            ApiError::InvalidSchedule => "invalid_schedule",
+            ApiError::InvalidWebhookUrl => "invalid_webhook_url",
+            ApiError::BlockNotFound => "block_not_found",
+            ApiError::SubscriptionNotFound => "subscription_not_found",
+            ApiError::KeyNotFound => "key_not_found",
+            ApiError::ScaleNotFound => "scale_not_found",
+            ApiError::NotLoggedIn => "not_logged_in",
+            ApiError::TotpRequired => "totp_required",
+            ApiError::InvalidTotpCode => "invalid_totp_code",
+            ApiError::TotpAlreadyEnabled => "totp_already_enabled",
+            ApiError::TotpNotEnrolled => "totp_not_enrolled",
+            ApiError::InsufficientRole => "insufficient_role",
+            ApiError::UserNotFound => "user_not_found",
+            ApiError::InvalidCsrfToken => "invalid_csrf_token",
+            ApiError::InvalidConversionName { .. } => "invalid_conversion_name",
+            ApiError::InvalidScript => "invalid_script",
+            ApiError::ConversionNotFound => "conversion_not_found",
+            ApiError::ScriptFailed => "script_failed",
+            ApiError::InvalidReading => "invalid_reading",
+            ApiError::InvalidCommand => "invalid_command",
+            ApiError::ProfileInProgress => "profile_in_progress",
+            ApiError::ServerBusy => "server_busy",
+            ApiError::InjectedFault => "injected_fault",
+            ApiError::Internal => "internal_error",
+        }
+    }
+
+    pub fn message(&self, lang: Lang) -> String {
+        match (self, lang) {
+            (ApiError::Unauthorized, Lang::En) => "Supplied token is not authorized.".into(),
+            (ApiError::Unauthorized, Lang::It) => "Il token fornito non è autorizzato.".into(),
+            (ApiError::Unauthorized, Lang::Es) => {
+                "El token proporcionado no está autorizado.".into()
+            }
+
+            (ApiError::TooManyConcurrentRequests, Lang::En) => {
+                "Too many concurrent requests for this API key.".into()
+            }
+            (ApiError::TooManyConcurrentRequests, Lang::It) => {
+                "Troppe richieste simultanee per questa chiave API.".into()
+            }
+            (ApiError::TooManyConcurrentRequests, Lang::Es) => {
+                "Demasiadas solicitudes simultáneas para esta clave de API.".into()
+            }
+
+            (ApiError::RateLimited, Lang::En) => {
+                "Too many requests for this API key; slow down and retry shortly.".into()
+            }
+            (ApiError::RateLimited, Lang::It) => {
+                "Troppe richieste per questa chiave API; rallenta e riprova a breve.".into()
+            }
+            (ApiError::RateLimited, Lang::Es) => {
+                "Demasiadas solicitudes para esta clave de API; reduce el ritmo y reintenta en breve.".into()
+            }
+
+            (ApiError::QuotaExceeded, Lang::En) => {
+                "This API key has used its monthly quota.".into()
+            }
+            (ApiError::QuotaExceeded, Lang::It) => {
+                "Questa chiave API ha esaurito la quota mensile.".into()
+            }
+            (ApiError::QuotaExceeded, Lang::Es) => {
+                "Esta clave de API ha agotado su cuota mensual.".into()
+            }
+
+            (ApiError::MalformedCredentials, Lang::En) => {
+                "Credentials must be an API key sent with HTTP Basic authentication.".into()
+            }
+            (ApiError::MalformedCredentials, Lang::It) => {
+                "Le credenziali devono essere una chiave API inviata con l'autenticazione HTTP Basic.".into()
+            }
+            (ApiError::MalformedCredentials, Lang::Es) => {
+                "Las credenciales deben ser una clave de API enviada con autenticación HTTP Basic.".into()
+            }
+
+            (ApiError::KeyRevoked, Lang::En) => "Supplied API key has been revoked.".into(),
+            (ApiError::KeyRevoked, Lang::It) => "La chiave API fornita è stata revocata.".into(),
+            (ApiError::KeyRevoked, Lang::Es) => {
+                "La clave de API proporcionada ha sido revocada.".into()
+            }
+
+            (ApiError::KeyExpired, Lang::En) => "Supplied API key has expired.".into(),
+            (ApiError::KeyExpired, Lang::It) => "La chiave API fornita è scaduta.".into(),
+            (ApiError::KeyExpired, Lang::Es) => "La clave de API proporcionada ha caducado.".into(),
+
+            (ApiError::InvalidClientRequestId { max_length }, Lang::En) => {
+                format!("client_request_id must be at most {max_length} bytes.")
+            }
+            (ApiError::InvalidClientRequestId { max_length }, Lang::It) => {
+                format!("client_request_id non può superare {max_length} byte.")
+            }
+            (ApiError::InvalidClientRequestId { max_length }, Lang::Es) => {
+                format!("client_request_id debe tener como máximo {max_length} bytes.")
+            }
+
+            (ApiError::InvalidUsageTag { max_length }, Lang::En) => {
+                format!("X-Usage-Tag must be 1-{max_length} characters of [A-Za-z0-9._-].")
+            }
+            (ApiError::InvalidUsageTag { max_length }, Lang::It) => {
+                format!(
+                    "X-Usage-Tag deve contenere da 1 a {max_length} caratteri tra [A-Za-z0-9._-]."
+                )
+            }
+            (ApiError::InvalidUsageTag { max_length }, Lang::Es) => {
+                format!(
+                    "X-Usage-Tag debe tener entre 1 y {max_length} caracteres de [A-Za-z0-9._-]."
+                )
+            }
+
+            (ApiError::TooManyValues { max }, Lang::En) => {
+                format!("At most {max} comma-separated values can be converted at once.")
+            }
+            (ApiError::TooManyValues { max }, Lang::It) => {
+                format!("Si possono convertire al massimo {max} valori separati da virgole alla volta.")
+            }
+            (ApiError::TooManyValues { max }, Lang::Es) => {
+                format!("Se pueden convertir como máximo {max} valores separados por comas a la vez.")
+            }
+
+            (ApiError::InvalidPrecision { max }, Lang::En) => {
+                format!("precision must be a whole number of decimal places from 0 to {max}.")
+            }
+            (ApiError::InvalidPrecision { max }, Lang::It) => {
+                format!("precision deve essere un numero intero di decimali da 0 a {max}.")
+            }
+            (ApiError::InvalidPrecision { max }, Lang::Es) => {
+                format!("precision debe ser un número entero de decimales entre 0 y {max}.")
+            }
+
+            (ApiError::InvalidReportPeriod, Lang::En) => "Invalid year or month.".into(),
+            (ApiError::InvalidReportPeriod, Lang::It) => "Anno o mese non valido.".into(),
+            (ApiError::InvalidReportPeriod, Lang::Es) => "Año o mes no válido.".into(),
+
+            (ApiError::InvalidUsageRange, Lang::En) => "The start of the range must come before its end.".into(),
+            (ApiError::InvalidUsageRange, Lang::It) => "L'inizio dell'intervallo deve precedere la fine.".into(),
+            (ApiError::InvalidUsageRange, Lang::Es) => "El inicio del rango debe ser anterior a su final.".into(),
+
+            (ApiError::InvalidExpression, Lang::En) => {
+                "Expressions must read like `25C to F`, using C, F or K.".into()
+            }
+            (ApiError::InvalidExpression, Lang::It) => {
+                "Le espressioni devono avere la forma `25C to F`, con C, F o K.".into()
+            }
+            (ApiError::InvalidExpression, Lang::Es) => {
+                "Las expresiones deben tener la forma `25C to F`, con C, F o K.".into()
+            }
+
+            (ApiError::AdminDisabled, Lang::En) => {
+                "Admin access is disabled on this deployment.".into()
+            }
+            (ApiError::AdminDisabled, Lang::It) => {
+                "L'accesso amministrativo è disabilitato su questa installazione.".into()
+            }
+            (ApiError::AdminDisabled, Lang::Es) => {
+                "El acceso de administración está deshabilitado en esta instalación.".into()
+            }
+
+            (ApiError::AdminUnauthorized, Lang::En) => {
+                "Supplied admin token is not authorized.".into()
+            }
+            (ApiError::AdminUnauthorized, Lang::It) => {
+                "Il token amministrativo fornito non è autorizzato.".into()
+            }
+            (ApiError::AdminUnauthorized, Lang::Es) => {
+                "El token de administración proporcionado no está autorizado.".into()
+            }
+
+            (ApiError::HttpsRequired, Lang::En) => {
+                "Credentials must be sent over HTTPS. Retry using an https:// URL and consider rotating this key.".into()
+            }
+            (ApiError::HttpsRequired, Lang::It) => {
+                "Le credenziali devono essere inviate tramite HTTPS. Riprova con un URL https:// e valuta di sostituire questa chiave.".into()
+            }
+            (ApiError::HttpsRequired, Lang::Es) => {
+                "Las credenciales deben enviarse por HTTPS. Reintenta con una URL https:// y considera rotar esta clave.".into()
+            }
+
+            (ApiError::InvalidEmail, Lang::En) => "Invalid email address.".into(),
+            (ApiError::InvalidEmail, Lang::It) => "Indirizzo email non valido.".into(),
+            (ApiError::InvalidEmail, Lang::Es) => "Dirección de correo no válida.".into(),
+
+            (ApiError::InvalidMagicLink, Lang::En) => {
+                "This login link is invalid, expired, or already used.".into()
+            }
+            (ApiError::InvalidMagicLink, Lang::It) => {
+                "Questo link di accesso non è valido, è scaduto o è già stato usato.".into()
+            }
+            (ApiError::InvalidMagicLink, Lang::Es) => {
+                "Este enlace de acceso no es válido, ha caducado o ya se ha usado.".into()
+            }
+
+            (ApiError::InvalidSignupLink, Lang::En) => {
+                "This verification link is invalid, expired, or already used.".into()
+            }
+            (ApiError::InvalidSignupLink, Lang::It) => {
+                "Questo link di verifica non è valido, è scaduto o è già stato usato.".into()
+            }
+            (ApiError::InvalidSignupLink, Lang::Es) => {
+                "Este enlace de verificación no es válido, ha caducado o ya se ha usado.".into()
+            }
+
+            (ApiError::InvalidKeyTtl, Lang::En) => {
+                "Key lifetimes are a whole number of days or hours, such as 30d or 12h.".into()
+            }
+            (ApiError::InvalidKeyTtl, Lang::It) => {
+                "La durata di una chiave è un numero intero di giorni o ore, come 30d o 12h.".into()
+            }
+            (ApiError::InvalidKeyTtl, Lang::Es) => {
+                "La vigencia de una clave es un número entero de días u horas, como 30d o 12h.".into()
+            }
+
+            (ApiError::InvalidKeyScopes, Lang::En) => {
+                "scopes must be a comma-separated list of convert:read and usage:read.".into()
+            }
+            (ApiError::InvalidKeyScopes, Lang::It) => {
+                "scopes deve essere un elenco separato da virgole di convert:read e usage:read.".into()
+            }
+            (ApiError::InvalidKeyScopes, Lang::Es) => {
+                "scopes debe ser una lista separada por comas de convert:read y usage:read.".into()
+            }
+
+            (ApiError::InvalidChallenge, Lang::En) => {
+                "Missing or invalid proof of work. Fetch a fresh challenge from /signup/challenge.".into()
+            }
+            (ApiError::InvalidChallenge, Lang::It) => {
+                "Prova di lavoro mancante o non valida. Richiedi una nuova sfida a /signup/challenge.".into()
+            }
+            (ApiError::InvalidChallenge, Lang::Es) => {
+                "Prueba de trabajo ausente o no válida. Solicita un nuevo desafío en /signup/challenge.".into()
+            }
+
+            (ApiError::Blocked, Lang::En) => "Requests from this address are blocked.".into(),
+            (ApiError::Blocked, Lang::It) => "Le richieste da questo indirizzo sono bloccate.".into(),
+            (ApiError::Blocked, Lang::Es) => {
+                "Las solicitudes desde esta dirección están bloqueadas.".into()
+            }
+
+            (ApiError::EndpointDisabled, Lang::En) => {
+                "This endpoint is disabled for your API key.".into()
+            }
+            (ApiError::EndpointDisabled, Lang::It) => {
+                "Questo endpoint è disabilitato per la tua chiave API.".into()
+            }
+            (ApiError::EndpointDisabled, Lang::Es) => {
+                "Este endpoint está deshabilitado para tu clave de API.".into()
+            }
+
+            (ApiError::MissingScope, Lang::En) => {
+                "Your API key lacks the scope this endpoint needs.".into()
+            }
+            (ApiError::MissingScope, Lang::It) => {
+                "La tua chiave API non ha l'ambito richiesto da questo endpoint.".into()
+            }
+            (ApiError::MissingScope, Lang::Es) => {
+                "Tu clave de API no tiene el ámbito que requiere este endpoint.".into()
+            }
+
+            (ApiError::InvalidNetwork, Lang::En) => {
+                "network must be an IP address or a CIDR network.".into()
+            }
+            (ApiError::InvalidNetwork, Lang::It) => {
+                "network deve essere un indirizzo IP o una rete CIDR.".into()
+            }
+            (ApiError::InvalidNetwork, Lang::Es) => {
+                "network debe ser una dirección IP o una red CIDR.".into()
+            }
+
+            (ApiError::InvalidSchedule, Lang::En) => {
+                "schedule must be a five-field cron expression that comes due.".into()
+            }
+            (ApiError::InvalidSchedule, Lang::It) => {
+                "schedule deve essere un'espressione cron a cinque campi che arrivi a scadenza.".into()
+            }
+            (ApiError::InvalidSchedule, Lang::Es) => {
+                "schedule debe ser una expresión cron de cinco campos que llegue a vencer.".into()
+            }
+
+            (ApiError::InvalidWebhookUrl, Lang::En) => {
+                "webhook must be an http or https URL.".into()
+            }
+            (ApiError::InvalidWebhookUrl, Lang::It) => {
+                "webhook deve essere un URL http o https.".into()
+            }
+            (ApiError::InvalidWebhookUrl, Lang::Es) => {
+                "webhook debe ser una URL http o https.".into()
+            }
+
+            (ApiError::BlockNotFound, Lang::En) => "Blocklist entry not found.".into(),
+            (ApiError::BlockNotFound, Lang::It) => "Voce della blocklist non trovata.".into(),
+            (ApiError::BlockNotFound, Lang::Es) => {
+                "Entrada de la lista de bloqueo no encontrada.".into()
+            }
+
+            (ApiError::SubscriptionNotFound, Lang::En) => "Report subscription not found.".into(),
+            (ApiError::SubscriptionNotFound, Lang::It) => {
+                "Sottoscrizione al report non trovata.".into()
+            }
+            (ApiError::SubscriptionNotFound, Lang::Es) => {
+                "Suscripción al informe no encontrada.".into()
+            }
+
+            (ApiError::KeyNotFound, Lang::En) => "API key not found.".into(),
+            (ApiError::KeyNotFound, Lang::It) => "Chiave API non trovata.".into(),
+            (ApiError::KeyNotFound, Lang::Es) => "Clave de API no encontrada.".into(),
+
+            (ApiError::ScaleNotFound, Lang::En) => {
+                "No such temperature scale; use celsius, fahrenheit or kelvin.".into()
+            }
+            (ApiError::ScaleNotFound, Lang::It) => {
+                "Scala di temperatura inesistente; usa celsius, fahrenheit o kelvin.".into()
+            }
+            (ApiError::ScaleNotFound, Lang::Es) => {
+                "No existe esa escala de temperatura; usa celsius, fahrenheit o kelvin.".into()
+            }
+
+            (ApiError::NotLoggedIn, Lang::En) => "Please log in to the dashboard.".into(),
+            (ApiError::NotLoggedIn, Lang::It) => "Accedi alla dashboard.".into(),
+            (ApiError::NotLoggedIn, Lang::Es) => "Inicia sesión en el panel.".into(),
+
+            (ApiError::TotpRequired, Lang::En) => {
+                "This operation requires a TOTP code in the X-TOTP-Code header.".into()
+            }
+            (ApiError::TotpRequired, Lang::It) => {
+                "Questa operazione richiede un codice TOTP nell'header X-TOTP-Code.".into()
+            }
+            (ApiError::TotpRequired, Lang::Es) => {
+                "Esta operación requiere un código TOTP en la cabecera X-TOTP-Code.".into()
+            }
+
+            (ApiError::InvalidTotpCode, Lang::En) => "Invalid or already used TOTP code.".into(),
+            (ApiError::InvalidTotpCode, Lang::It) => "Codice TOTP non valido o già usato.".into(),
+            (ApiError::InvalidTotpCode, Lang::Es) => "Código TOTP no válido o ya usado.".into(),
+
+            (ApiError::TotpAlreadyEnabled, Lang::En) => {
+                "Two-factor authentication is already enabled.".into()
+            }
+            (ApiError::TotpAlreadyEnabled, Lang::It) => {
+                "L'autenticazione a due fattori è già attiva.".into()
+            }
+            (ApiError::TotpAlreadyEnabled, Lang::Es) => {
+                "La autenticación de dos factores ya está activada.".into()
+            }
+
+            (ApiError::TotpNotEnrolled, Lang::En) => {
+                "Enroll in two-factor authentication first.".into()
+            }
+            (ApiError::TotpNotEnrolled, Lang::It) => {
+                "Configura prima l'autenticazione a due fattori.".into()
+            }
+            (ApiError::TotpNotEnrolled, Lang::Es) => {
+                "Configura primero la autenticación de dos factores.".into()
+            }
+
+            (ApiError::InsufficientRole, Lang::En) => {
+                "Your role does not permit this operation.".into()
+            }
+            (ApiError::InsufficientRole, Lang::It) => {
+                "Il tuo ruolo non consente questa operazione.".into()
+            }
+            (ApiError::InsufficientRole, Lang::Es) => {
+                "Tu rol no permite esta operación.".into()
+            }
+
+            (ApiError::UserNotFound, Lang::En) => "User not found.".into(),
+            (ApiError::UserNotFound, Lang::It) => "Utente non trovato.".into(),
+            (ApiError::UserNotFound, Lang::Es) => "Usuario no encontrado.".into(),
+
+            (ApiError::InvalidCsrfToken, Lang::En) => {
+                "Missing or invalid X-CSRF-Token header.".into()
+            }
+            (ApiError::InvalidCsrfToken, Lang::It) => {
+                "Header X-CSRF-Token mancante o non valido.".into()
+            }
+            (ApiError::InvalidCsrfToken, Lang::Es) => {
+                "Cabecera X-CSRF-Token ausente o no válida.".into()
+            }
+
+            (ApiError::InvalidConversionName { max_length }, Lang::En) => {
+                format!("Conversion names must be 1-{max_length} characters of [a-z0-9-].")
+            }
+            (ApiError::InvalidConversionName { max_length }, Lang::It) => {
+                format!("I nomi delle conversioni devono avere 1-{max_length} caratteri tra [a-z0-9-].")
+            }
+            (ApiError::InvalidConversionName { max_length }, Lang::Es) => {
+                format!("Los nombres de conversión deben tener 1-{max_length} caracteres de [a-z0-9-].")
+            }
+
+            (ApiError::InvalidScript, Lang::En) => "The conversion script does not compile.".into(),
+            (ApiError::InvalidScript, Lang::It) => {
+                "Lo script di conversione non compila.".into()
+            }
+            (ApiError::InvalidScript, Lang::Es) => {
+                "El script de conversión no compila.".into()
+            }
+
+            (ApiError::ConversionNotFound, Lang::En) => "Conversion not found.".into(),
+            (ApiError::ConversionNotFound, Lang::It) => "Conversione non trovata.".into(),
+            (ApiError::ConversionNotFound, Lang::Es) => "Conversión no encontrada.".into(),
+
+            (ApiError::ScriptFailed, Lang::En) => {
+                "The conversion script failed, ran out of time or did not return a number.".into()
+            }
+            (ApiError::ScriptFailed, Lang::It) => {
+                "Lo script di conversione è fallito, ha esaurito il tempo o non ha restituito un numero.".into()
+            }
+            (ApiError::ScriptFailed, Lang::Es) => {
+                "El script de conversión falló, agotó el tiempo o no devolvió un número.".into()
+            }
+
+            (ApiError::InvalidReading, Lang::En) => {
+                "A reading must be JSON with an api_key and a numeric value.".into()
+            }
+            (ApiError::InvalidReading, Lang::It) => {
+                "Una lettura deve essere JSON con api_key e un value numerico.".into()
+            }
+            (ApiError::InvalidReading, Lang::Es) => {
+                "Una lectura debe ser JSON con api_key y un value numérico.".into()
+            }
+
+            (ApiError::InvalidCommand, Lang::En) => {
+                "Commands must read `<api key> CONVERT <c2f|f2c> <value>`.".into()
+            }
+            (ApiError::InvalidCommand, Lang::It) => {
+                "I comandi devono avere la forma `<chiave API> CONVERT <c2f|f2c> <valore>`.".into()
+            }
+            (ApiError::InvalidCommand, Lang::Es) => {
+                "Los comandos deben tener la forma `<clave de API> CONVERT <c2f|f2c> <valor>`.".into()
+            }
+
+            (ApiError::ProfileInProgress, Lang::En) => {
+                "A CPU profile is already being taken; try again once it is done.".into()
+            }
+            (ApiError::ProfileInProgress, Lang::It) => {
+                "È già in corso un profilo della CPU; riprova quando sarà terminato.".into()
+            }
+            (ApiError::ProfileInProgress, Lang::Es) => {
+                "Ya se está tomando un perfil de CPU; inténtalo de nuevo cuando termine.".into()
+            }
+
+            (ApiError::ServerBusy, Lang::En) => {
+                "The server is too busy to take this request; retry shortly.".into()
+            }
+            (ApiError::ServerBusy, Lang::It) => {
+                "Il server è troppo occupato per questa richiesta; riprova tra poco.".into()
+            }
+            (ApiError::ServerBusy, Lang::Es) => {
+                "El servidor está demasiado ocupado para esta solicitud; reinténtalo en breve.".into()
+            }
+
+            (ApiError::InjectedFault, Lang::En) => "Fault injected by chaos mode.".into(),
+            (ApiError::InjectedFault, Lang::It) => {
+                "Errore simulato dalla modalità chaos.".into()
+            }
+            (ApiError::InjectedFault, Lang::Es) => "Fallo simulado por el modo caos.".into(),
+
+            (ApiError::Internal, Lang::En) => "Internal server error.".into(),
+            (ApiError::Internal, Lang::It) => "Errore interno del server.".into(),
+            (ApiError::Internal, Lang::Es) => "Error interno del servidor.".into(),
+        }
+    }
+
+    /// Builds the JSON error body in the given language.
+    /// One of every variant this build can return, in declaration order.
+    pub fn catalog() -> Vec<ApiError> {
+        let catalog = vec![
+            ApiError::Unauthorized,
+            ApiError::TooManyConcurrentRequests,
+            ApiError::RateLimited,
+            ApiError::QuotaExceeded,
+            ApiError::MalformedCredentials,
+            ApiError::KeyRevoked,
+            ApiError::KeyExpired,
+            ApiError::InvalidClientRequestId {
+                max_length: crate::MAX_CLIENT_REQUEST_ID_LENGTH,
+            },
+            ApiError::InvalidUsageTag {
+                max_length: crate::MAX_USAGE_TAG_LENGTH,
+            },
+            ApiError::TooManyValues {
+                max: crate::MAX_VALUES_PER_CONVERSION,
+            },
+            ApiError::InvalidPrecision {
+                max: crate::MAX_PRECISION,
+            },
+            ApiError::InvalidReportPeriod,
+            ApiError::InvalidUsageRange,
+            ApiError::InvalidExpression,
+            ApiError::AdminDisabled,
+            ApiError::AdminUnauthorized,
+            ApiError::HttpsRequired,
+            ApiError::InvalidEmail,
+            ApiError::InvalidMagicLink,
+            ApiError::InvalidSignupLink,
+            ApiError::InvalidKeyTtl,
+            ApiError::InvalidKeyScopes,
+            ApiError::InvalidChallenge,
+            ApiError::Blocked,
+            ApiError::EndpointDisabled,
+            ApiError::MissingScope,
+            ApiError::InvalidNetwork,
+            ApiError::InvalidSchedule,
+            ApiError::InvalidWebhookUrl,
+            ApiError::BlockNotFound,
+            ApiError::SubscriptionNotFound,
+            ApiError::KeyNotFound,
+            ApiError::ScaleNotFound,
+            ApiError::NotLoggedIn,
+            ApiError::TotpRequired,
+            ApiError::InvalidTotpCode,
+            ApiError::TotpAlreadyEnabled,
+            ApiError::TotpNotEnrolled,
+            ApiError::InsufficientRole,
+            ApiError::UserNotFound,
+            ApiError::InvalidCsrfToken,
+            #[cfg(feature = "scripting")]
+            ApiError::InvalidConversionName {
+                max_length: crate::scripting::MAX_NAME_LENGTH,
+            },
+            #[cfg(feature = "scripting")]
+            ApiError::InvalidScript,
+            #[cfg(feature = "scripting")]
+            ApiError::ConversionNotFound,
+            #[cfg(feature = "scripting")]
+            ApiError::ScriptFailed,
+            #[cfg(feature = "mqtt")]
+            ApiError::InvalidReading,
+            ApiError::InvalidCommand,
+            #[cfg(feature = "pprof")]
+            ApiError::ProfileInProgress,
+            ApiError::ServerBusy,
+            ApiError::InjectedFault,
+            ApiError::Internal,
+        ];
+
+        // Stops compiling when a variant is added, as a reminder to list it
+        // above.
+        for error in &catalog {
+            match error {
+                ApiError::Unauthorized
+                | ApiError::TooManyConcurrentRequests
+                | ApiError::RateLimited
+                | ApiError::QuotaExceeded
+                | ApiError::MalformedCredentials
+                | ApiError::KeyRevoked
+                | ApiError::KeyExpired
+                | ApiError::InvalidClientRequestId { .. }
+                | ApiError::InvalidUsageTag { .. }
+                | ApiError::TooManyValues { .. }
+                | ApiError::InvalidPrecision { .. }
+                | ApiError::InvalidReportPeriod
+                | ApiError::InvalidUsageRange
+                | ApiError::InvalidExpression
+                | ApiError::AdminDisabled
+                | ApiError::AdminUnauthorized
+                | ApiError::HttpsRequired
+                | ApiError::InvalidEmail
+                | ApiError::InvalidMagicLink
+                | ApiError::InvalidSignupLink
+                | ApiError::InvalidKeyTtl
+                | ApiError::InvalidKeyScopes
+                | ApiError::InvalidChallenge
+                | ApiError::Blocked
+                | ApiError::EndpointDisabled
+                | ApiError::MissingScope
+                | ApiError::InvalidNetwork
+                | ApiError::InvalidSchedule
+                | ApiError::InvalidWebhookUrl
+                | ApiError::BlockNotFound
+                | ApiError::SubscriptionNotFound
+                | ApiError::KeyNotFound
+                | ApiError::ScaleNotFound
+                | ApiError::NotLoggedIn
+                | ApiError::TotpRequired
+                | ApiError::InvalidTotpCode
+                | ApiError::TotpAlreadyEnabled
+                | ApiError::TotpNotEnrolled
+                | ApiError::InsufficientRole
+                | ApiError::UserNotFound
+                | ApiError::InvalidCsrfToken
+                | ApiError::InvalidConversionName { .. }
+                | ApiError::InvalidScript
+                | ApiError::ConversionNotFound
+                | ApiError::ScriptFailed
+                | ApiError::InvalidReading
+                | ApiError::InvalidCommand
+                | ApiError::ProfileInProgress
+                | ApiError::ServerBusy
+                | ApiError::InjectedFault
+                | ApiError::Internal => {}
+            }
+        }
+
+        catalog
+    }
+
+    pub fn localized_response(&self, lang: Lang) -> HttpResponse {
+        self.localized_response_with_help(lang, None)
+    }
+
+    /// [`ApiError::localized_response`], with `help` added for the errors
+    /// it is meant for.
+    pub fn localized_response_with_help(&self, lang: Lang, help: Option<&KeyHelp>) -> HttpResponse {
+        let mut response = HttpResponse::build(self.status_code());
+        if let Some(challenge) = self.challenge() {
+            response.insert_header((WWW_AUTHENTICATE, challenge));
+        }
+        let help = help.filter(|_| {
+            matches!(
+                self,
+                ApiError::Unauthorized | ApiError::KeyRevoked | ApiError::KeyExpired
+            )
+        });
+        response.json(ErrorBody {
+            code: self.code(),
+            message: self.message(lang),
+            help,
+        })
+    }
+
+    /// The `WWW-Authenticate` challenge a 401 comes with, for the scheme
+    /// that was rejected.
+    fn challenge(&self) -> Option<&'static str> {
+        match self {
+            ApiError::Unauthorized => Some(r#"Basic realm="api", charset="UTF-8""#),
+            ApiError::AdminUnauthorized => Some(r#"Bearer realm="admin""#),
+            _ => None,
+        }
+    }
+
+    /// The JSON body of an error response, for transports other than HTTP.
+    pub fn body(&self, lang: Lang) -> impl Serialize + '_ {
+        ErrorBody {
+            code: self.code(),
+            message: self.message(lang),
+            help: None,
+        }
+    }
+}
+
+impl std::fmt::Display for ApiError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.message(Lang::default()))
+    }
+}
+
+impl std::error::Error for ApiError {}
+
+impl ResponseError for ApiError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            ApiError::Unauthorized
+            | ApiError::AdminUnauthorized
+            | ApiError::NotLoggedIn
+            | ApiError::TotpRequired => StatusCode::UNAUTHORIZED,
+            ApiError::TooManyConcurrentRequests
+            | ApiError::RateLimited
+            | ApiError::QuotaExceeded => StatusCode::TOO_MANY_REQUESTS,
+            ApiError::InvalidClientRequestId { .. }
+            | ApiError::InvalidUsageTag { .. }
+            | ApiError::TooManyValues { .. }
+            | ApiError::InvalidPrecision { .. }
+            | ApiError::InvalidReportPeriod
+            | ApiError::InvalidUsageRange
+            | ApiError::InvalidExpression
+            | ApiError::MalformedCredentials
+            | ApiError::InvalidEmail
+            | ApiError::InvalidMagicLink
+            | ApiError::InvalidSignupLink
+            | ApiError::InvalidChallenge
+            | ApiError::InvalidNetwork
+            | ApiError::InvalidSchedule
+            | ApiError::InvalidWebhookUrl
+            | ApiError::InvalidConversionName { .. }
+            | ApiError::InvalidScript
+            | ApiError::InvalidReading
+            | ApiError::InvalidKeyTtl
+            | ApiError::InvalidKeyScopes
+            | ApiError::InvalidCommand => StatusCode::BAD_REQUEST,
+            ApiError::AdminDisabled
+            | ApiError::HttpsRequired
+            | ApiError::InvalidTotpCode
+            | ApiError::TotpNotEnrolled
+            | ApiError::InsufficientRole
+            | ApiError::InvalidCsrfToken
+            | ApiError::Blocked
+            | ApiError::KeyRevoked
+            | ApiError::KeyExpired
+            | ApiError::MissingScope
+            | ApiError::EndpointDisabled => StatusCode::FORBIDDEN,
+            ApiError::UserNotFound
+            | ApiError::ConversionNotFound
+            | ApiError::BlockNotFound
+            | ApiError::SubscriptionNotFound
+            | ApiError::ScaleNotFound
+            | ApiError::KeyNotFound => StatusCode::NOT_FOUND,
+            ApiError::ScriptFailed => StatusCode::UNPROCESSABLE_ENTITY,
+            ApiError::TotpAlreadyEnabled | ApiError::ProfileInProgress => StatusCode::CONFLICT,
+            ApiError::ServerBusy => StatusCode::SERVICE_UNAVAILABLE,
+            ApiError::InjectedFault | ApiError::Internal => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        self.localized_response(Lang::default())
+    }
+}
+
+#[derive(Serialize)]
+struct CatalogEntry {
+    code: &'static str,
+    status: u16,
+    description: String,
+}
+
+/// Every error code the API can return, with its status and a description
+/// in the language negotiated from `Accept-Language`. Served outside the
+/// `/api` scope, so SDK generators need no key.
+#[get("/api/errors")]
+pub async fn error_catalog(req: HttpRequest) -> impl Responder {
+    let lang = Lang::from_request(&req);
+
+    let entries: Vec<_> = ApiError::catalog()
+        .iter()
+        .map(|error| CatalogEntry {
+            code: error.code(),
+            status: error.status_code().as_u16(),
+            description: error.message(lang),
+        })
+        .collect();
+
+    web::Json(entries)
+}
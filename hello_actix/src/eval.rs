@@ -0,0 +1,159 @@
+//! `GET /api/eval?expr=25C to F`: conversions written out as text, for
+//! chatbots and command-line tools that would rather not build paths.
+
+use std::str::FromStr;
+
+use actix_web::{get, web, HttpRequest, Responder};
+use actix_web_httpauth::extractors::basic::BasicAuth;
+use serde::{Deserialize, Serialize};
+
+use crate::error::ApiError;
+use crate::scale::Scale;
+use crate::{casing, clock, db, geoip, usage, UsageTag};
+
+/// `<number><scale> to <scale>`, e.g. `25C to F`, `300 K in C` or
+/// `-40°F to celsius`. Scales are as [`Scale`] parses them.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Expression {
+    pub value: f32,
+    pub from: Scale,
+    pub to: Scale,
+}
+
+impl FromStr for Expression {
+    type Err = ApiError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let words: Vec<&str> = s.split_whitespace().collect();
+        let keyword = words
+            .iter()
+            .position(|word| matches!(word.to_ascii_lowercase().as_str(), "to" | "in"))
+            .ok_or(ApiError::InvalidExpression)?;
+
+        // Spacing is optional between a number and its scale.
+        let quantity = words[..keyword].concat();
+        let split = quantity
+            .find(|c: char| c.is_alphabetic() || c == '°')
+            .ok_or(ApiError::InvalidExpression)?;
+        let (value, from) = quantity.split_at(split);
+
+        let value: f32 = value.parse().map_err(|_| ApiError::InvalidExpression)?;
+        if !value.is_finite() {
+            return Err(ApiError::InvalidExpression);
+        }
+
+        Ok(Expression {
+            value,
+            from: from.parse().map_err(|_| ApiError::InvalidExpression)?,
+            to: words[keyword + 1..]
+                .concat()
+                .parse()
+                .map_err(|_| ApiError::InvalidExpression)?,
+        })
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct Quantity {
+    pub value: f32,
+    pub scale: Scale,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Evaluation {
+    pub input: Quantity,
+    pub output: Quantity,
+    /// The result as a sentence, e.g. `25 °C = 77 °F`.
+    pub text: String,
+}
+
+impl Expression {
+    pub fn evaluate(&self) -> Evaluation {
+        let output = self.from.convert(self.value, self.to);
+
+        Evaluation {
+            input: Quantity {
+                value: self.value,
+                scale: self.from,
+            },
+            output: Quantity {
+                value: output,
+                scale: self.to,
+            },
+            text: format!(
+                "{} {} = {} {}",
+                self.value,
+                self.from.symbol(),
+                output,
+                self.to.symbol()
+            ),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug)]
+pub struct EvalParams {
+    expr: String,
+}
+
+#[get("/eval", wrap = "actix_web::middleware::from_fn(usage::record_usage)")]
+pub async fn eval(
+    params: web::Query<EvalParams>,
+    tag: UsageTag,
+    location: geoip::Location,
+    req: HttpRequest,
+    auth: BasicAuth,
+) -> actix_web::Result<impl Responder> {
+    let expression: Expression = params.expr.parse()?;
+
+    let call = db::ApiUsage {
+        api_key: auth.user_id().to_string(),
+        endpoint: db::ApiEndpoint::Eval,
+        called_at: clock::now(&req),
+        client_request_id: None,
+        tag: tag.0,
+        location,
+        latency_ms: None,
+    };
+    usage::defer(&req, call);
+
+    Ok(casing::Json(expression.evaluate()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(s: &str) -> Option<Expression> {
+        s.parse().ok()
+    }
+
+    #[test]
+    fn expressions() {
+        let expression = Expression {
+            value: 25.0,
+            from: Scale::Celsius,
+            to: Scale::Fahrenheit,
+        };
+        assert_eq!(parse("25C to F"), Some(expression));
+        assert_eq!(parse("25 C TO F"), Some(expression));
+        assert_eq!(parse("25C in F"), Some(expression));
+        assert_eq!(parse("-40°F to celsius").map(|e| e.value), Some(-40.0));
+        assert_eq!(parse("300 K in C").map(|e| e.from), Some(Scale::Kelvin));
+    }
+
+    #[test]
+    fn invalid_expressions() {
+        for s in [
+            "",
+            "25C",
+            "C to F",
+            "25 to F",
+            "25C to",
+            "25X to F",
+            "inf C to F",
+        ] {
+            assert_eq!(parse(s), None, "{s}");
+        }
+    }
+}
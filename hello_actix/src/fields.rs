@@ -0,0 +1,92 @@
+//! Partial responses: `?fields=celsius,client_request_id` keeps only the
+//! named fields of a JSON body, for clients on constrained links. Fields
+//! are picked at the top level of the body, or of each item of a list, and
+//! may be named in either field case. Names matching nothing are ignored.
+//! With [`crate::negotiate`]'s envelope, `data` is cut down and `meta` kept.
+
+use actix_web::{web, HttpRequest};
+use conversion_core::case::{camel_case, CamelCase};
+use serde::ser::Error as _;
+use serde::{Deserialize, Serialize, Serializer};
+use serde_json::Value;
+
+use crate::casing::{self, FieldCase};
+
+#[derive(Deserialize)]
+struct FieldsParams {
+    fields: Option<String>,
+}
+
+/// The fields the query string asks for, if it does. Malformed values
+/// count as none.
+pub fn requested(req: &HttpRequest) -> Option<Vec<String>> {
+    let params = web::Query::<FieldsParams>::from_query(req.query_string()).ok()?;
+    let fields = params.into_inner().fields?;
+
+    Some(
+        fields
+            .split(',')
+            .map(str::trim)
+            .filter(|field| !field.is_empty())
+            .map(str::to_owned)
+            .collect(),
+    )
+}
+
+/// Keeps the entries of `value` named in `fields`, in objects and in the
+/// objects of arrays.
+pub fn project(value: Value, fields: &[String]) -> Value {
+    match value {
+        Value::Object(object) => Value::Object(
+            object
+                .into_iter()
+                .filter(|(key, _)| {
+                    let key = camel_case(key);
+                    fields.iter().any(|field| key == camel_case(field))
+                })
+                .collect(),
+        ),
+        Value::Array(items) => Value::Array(
+            items
+                .into_iter()
+                .map(|item| project(item, fields))
+                .collect(),
+        ),
+        value => value,
+    }
+}
+
+/// `T`, cut down to the fields its request asked for.
+pub struct Partial<T> {
+    value: T,
+    fields: Option<Vec<String>>,
+    case: FieldCase,
+}
+
+impl<T> Partial<T> {
+    pub fn of(req: &HttpRequest, value: T) -> Self {
+        Partial {
+            value,
+            fields: requested(req),
+            case: casing::field_case(req),
+        }
+    }
+}
+
+impl<T: Serialize> Serialize for Partial<T> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let Some(fields) = &self.fields else {
+            return self.value.serialize(serializer);
+        };
+
+        // Renamed before it is cut down, so the names match either way. Its
+        // keys are map keys from here on, which renaming again leaves be.
+        let value = match self.case {
+            FieldCase::Snake => serde_json::to_value(&self.value),
+            FieldCase::Camel => serde_json::to_value(CamelCase(&self.value)),
+        }
+        .map_err(S::Error::custom)?;
+
+        project(value, fields).serialize(serializer)
+    }
+}
@@ -0,0 +1,123 @@
+//! Seeded databases for integration tests and tooling, in builds with the
+//! `test-util` feature:
+//!
+//! ```ignore
+//! let fixture = FixtureDb::with_keys(3).with_usage(100).build().await;
+//! let app = app!(config(), fixture.database);
+//! ```
+
+use actix_web::web;
+use chrono::{Duration, Utc};
+use r2d2_sqlite::SqliteConnectionManager;
+
+use crate::auth;
+use crate::db;
+use crate::geoip;
+use crate::plugin::PluginRegistry;
+use crate::scopes::ScopeSet;
+
+/// The endpoints seeded usage is spread over.
+const ENDPOINTS: [db::ApiEndpoint; 4] = [
+    db::ApiEndpoint::ToCelsius,
+    db::ApiEndpoint::ToFahrenheit,
+    db::ApiEndpoint::ToKelvin,
+    db::ApiEndpoint::FromKelvin,
+];
+
+/// How far back seeded usage goes.
+const USAGE_SPAN: Duration = Duration::days(30);
+
+/// What to seed; see [`FixtureDb::build`].
+#[derive(Debug, Clone, Default)]
+pub struct FixtureDb {
+    keys: usize,
+    usage: usize,
+}
+
+/// A migrated in-memory database and the keys seeded into it, oldest first.
+pub struct Fixture {
+    pub database: web::Data<db::Pool>,
+    pub api_keys: Vec<String>,
+}
+
+impl FixtureDb {
+    /// `count` free-tier keys, owned by `user0@example.com` and so on.
+    pub fn with_keys(count: usize) -> Self {
+        FixtureDb {
+            keys: count,
+            ..FixtureDb::default()
+        }
+    }
+
+    /// `count` calls in all, spread evenly over the keys and conversion
+    /// endpoints, and over the last 30 days. Needs at least one key.
+    pub fn with_usage(self, count: usize) -> Self {
+        FixtureDb {
+            usage: count,
+            ..self
+        }
+    }
+
+    /// Creates and seeds the database. Keys are stored as signup stores them,
+    /// so an app over it loads them on their first use.
+    pub async fn build(self) -> Fixture {
+        assert!(
+            self.usage == 0 || self.keys > 0,
+            "seeding usage needs at least one key"
+        );
+
+        let database = memory_database();
+        let key_cache = auth::KeyCache::new();
+
+        let mut api_keys = Vec::with_capacity(self.keys);
+        for owner in 0..self.keys {
+            let api_key = auth::store_api_key(
+                database.clone(),
+                &key_cache,
+                &auth::RandomKeys,
+                format!("user{owner}@example.com"),
+                db::Tier::Free,
+                None,
+                ScopeSet::ALL,
+            )
+            .await
+            .expect("unable to store a fixture key");
+            api_keys.push(api_key);
+        }
+
+        if self.usage > 0 {
+            let now = Utc::now();
+            let step = USAGE_SPAN / self.usage as i32;
+            let calls = (0..self.usage)
+                .map(|n| db::ApiUsage {
+                    api_key: api_keys[n % api_keys.len()].clone(),
+                    endpoint: ENDPOINTS[n % ENDPOINTS.len()],
+                    called_at: now - USAGE_SPAN + step * n as i32,
+                    client_request_id: None,
+                    tag: None,
+                    location: geoip::Location::default(),
+                    latency_ms: None,
+                })
+                .collect();
+
+            db::Query::RecordApiUsageBatch(calls)
+                .execute(database.clone())
+                .await
+                .expect("unable to store fixture usage");
+        }
+
+        Fixture { database, api_keys }
+    }
+}
+
+/// A private in-memory database. A single connection, because every
+/// connection to `:memory:` opens a database of its own.
+fn memory_database() -> web::Data<db::Pool> {
+    let pool = db::Pool::builder()
+        .max_size(1)
+        .build(SqliteConnectionManager::memory())
+        .expect("unable to open an in-memory database");
+    db::setup(pool.clone());
+    PluginRegistry::compiled_in().migrate(&pool);
+    web::Data::new(pool)
+}
@@ -0,0 +1,148 @@
+//! Projects a key's calls over the rest of the month from its recent daily
+//! counts, so clients can see a quota running out before it does.
+
+use actix_web::{get, web, HttpRequest, Responder};
+use actix_web_httpauth::extractors::basic::BasicAuth;
+use chrono::{DateTime, Datelike, Days, TimeDelta, Utc};
+use serde::Serialize;
+
+use crate::config::Config;
+use crate::db::{self, DailyCount};
+use crate::error::ApiError;
+use crate::{auth, casing, clock, report};
+
+/// Days of history, up to and including today, the trend is fitted on.
+const TREND_DAYS: u64 = 7;
+
+#[derive(Debug, Serialize)]
+pub struct Forecast {
+    pub period_start: DateTime<Utc>,
+    pub period_end: DateTime<Utc>,
+    /// `None` when the key's tier is unlimited.
+    pub quota: Option<u64>,
+    pub used: u64,
+    pub remaining: Option<u64>,
+    /// Calls per day at the end of the fitted line, i.e. today.
+    pub calls_per_day: f64,
+    /// How much `calls_per_day` grows (or shrinks) each day.
+    pub trend_per_day: f64,
+    /// `used` plus the calls the trend adds by `period_end`.
+    pub projected_calls: u64,
+    /// When the trend crosses the quota, if it does before `period_end` and
+    /// it hasn't been crossed already.
+    pub exhausts_at: Option<DateTime<Utc>>,
+}
+
+/// Fits a least-squares line through the calls per day of the last
+/// [`TREND_DAYS`] of the month so far, and follows it to the month's end.
+/// Today counts as a whole day, so early on the trend runs low.
+pub fn forecast(now: DateTime<Utc>, daily: &[DailyCount], quota: Option<u64>) -> Forecast {
+    let (period_start, period_end) =
+        report::month_bounds(now.year(), now.month()).expect("the current month is valid");
+    let today = now.date_naive();
+    let first_day = today
+        .checked_sub_days(Days::new(TREND_DAYS - 1))
+        .unwrap_or(today)
+        .max(period_start.date_naive());
+
+    let window: Vec<f64> = first_day
+        .iter_days()
+        .take_while(|day| *day <= today)
+        .map(|day| {
+            daily
+                .iter()
+                .find(|count| count.day == day)
+                .map_or(0.0, |count| count.calls as f64)
+        })
+        .collect();
+    let (calls_per_day, trend_per_day) = fit(&window);
+
+    let used: u64 = daily.iter().map(|count| count.calls).sum();
+    let remaining = quota.map(|quota| quota.saturating_sub(used));
+
+    // Step a day at a time from now; the last step is cut short at the end
+    // of the period.
+    let mut projected = used as f64;
+    let mut exhausts_at = None;
+    let mut start = now;
+    let mut days_ahead = 0.0;
+    while start < period_end {
+        let end = (start + TimeDelta::days(1)).min(period_end);
+        let length = (end - start).num_seconds() as f64 / 86_400.0;
+        let rate = (calls_per_day + trend_per_day * days_ahead).max(0.0);
+        let calls = rate * length;
+
+        if let (Some(quota), None) = (quota, exhausts_at) {
+            let quota = quota as f64;
+            if used as f64 <= quota && projected < quota && projected + calls >= quota {
+                let seconds = (quota - projected) / rate * 86_400.0;
+                exhausts_at = Some(start + TimeDelta::seconds(seconds.ceil() as i64));
+            }
+        }
+
+        projected += calls;
+        start = end;
+        days_ahead += 1.0;
+    }
+
+    Forecast {
+        period_start,
+        period_end,
+        quota,
+        used,
+        remaining,
+        calls_per_day,
+        trend_per_day,
+        projected_calls: projected.round() as u64,
+        exhausts_at,
+    }
+}
+
+/// The value at the last point, and the slope, of the least-squares line
+/// through `ys` at `x = 0, 1, ...`. Flat for fewer than two points.
+fn fit(ys: &[f64]) -> (f64, f64) {
+    let n = ys.len() as f64;
+    if ys.len() < 2 {
+        return (ys.first().copied().unwrap_or(0.0), 0.0);
+    }
+
+    let mean_x = (n - 1.0) / 2.0;
+    let mean_y = ys.iter().sum::<f64>() / n;
+    let (covariance, variance) = ys
+        .iter()
+        .enumerate()
+        .fold((0.0, 0.0), |(cov, var), (x, y)| {
+            let dx = x as f64 - mean_x;
+            (cov + dx * (y - mean_y), var + dx * dx)
+        });
+    let slope = covariance / variance;
+
+    (mean_y + slope * (n - 1.0 - mean_x), slope)
+}
+
+/// Forecasts the calling key's usage for the current month against its
+/// tier's quota.
+#[get("/my-usage/forecast")]
+pub async fn usage_forecast(
+    auth: BasicAuth,
+    config: web::Data<Config>,
+    database: web::Data<db::Pool>,
+    req: HttpRequest,
+) -> actix_web::Result<impl Responder> {
+    let tier = auth::key_tier(&req, auth.user_id())
+        .map_err(|_| ApiError::Internal)?
+        .ok_or(ApiError::Unauthorized)?;
+
+    let now = clock::now(&req);
+    // Up to the month's end rather than `now`: stored times are to the
+    // millisecond, so calls made in this one would fall past `now`.
+    let (from, to) =
+        report::month_bounds(now.year(), now.month()).expect("the current month is valid");
+    let daily = db::daily_usage_of_key(database, auth.user_id().to_owned(), from, to).await?;
+
+    Ok(casing::Json(forecast(
+        now,
+        &daily,
+        config.monthly_quota(tier),
+    )))
+}
@@ -0,0 +1,72 @@
+//! Conversions for clients that don't come in over HTTP, such as the
+//! [`crate::mqtt`] bridge: the same key check, conversion and usage
+//! recording as the HTTP handlers, minus the HTTP.
+
+use actix_web::web;
+use chrono::Utc;
+use tracing::warn;
+
+use crate::auth::{self, KeyCache};
+use crate::config::Config;
+use crate::error::ApiError;
+use crate::scale::Scale;
+use crate::scopes::{self, KeyScope};
+use crate::{db, geoip, Temperature};
+
+/// Converts `value` for the holder of `api_key`, recording the call as
+/// `endpoint` usage. Only the two built-in conversions are served.
+pub async fn convert(
+    database: web::Data<db::Pool>,
+    key_cache: &KeyCache,
+    config: &Config,
+    api_key: &str,
+    endpoint: db::ApiEndpoint,
+    value: f32,
+) -> Result<Temperature, ApiError> {
+    let from = match endpoint {
+        db::ApiEndpoint::ToCelsius => Scale::Fahrenheit,
+        db::ApiEndpoint::ToFahrenheit => Scale::Celsius,
+        _ => return Err(ApiError::ConversionNotFound),
+    };
+    // Outside any app, so by the system clock.
+    let now = Utc::now();
+    let output_scales = key_cache
+        .access(api_key, now)
+        .ok()
+        .flatten()
+        .map(|access| access.output_scales)
+        .unwrap_or_default();
+    let temperature = Temperature::new(value, from, output_scales, None);
+
+    match key_cache
+        .check(database.clone(), api_key, now)
+        .await
+        .map_err(|_| ApiError::Internal)?
+    {
+        auth::KeyStatus::Active => {}
+        auth::KeyStatus::Revoked => return Err(ApiError::KeyRevoked),
+        auth::KeyStatus::Expired => return Err(ApiError::KeyExpired),
+        auth::KeyStatus::Unknown => return Err(ApiError::Unauthorized),
+    }
+    scopes::require(key_cache, api_key, KeyScope::ConvertRead, now)?;
+
+    let query = db::Query::RecordApiUsage {
+        api_key: api_key.to_owned(),
+        endpoint,
+        called_at: now,
+        client_request_id: None,
+        tag: None,
+        location: geoip::Location::default(),
+        latency_ms: None,
+    };
+    if let Err(err) = query
+        .anonymized_if(config.anonymous_usage)
+        .execute(database)
+        .await
+    {
+        warn!(%err, "failed to record usage");
+        return Err(ApiError::Internal);
+    }
+
+    Ok(temperature)
+}
@@ -0,0 +1,93 @@
+//! Country and autonomous system of the caller, stored with each usage row.
+//!
+//! Resolution needs the `geoip` feature and MaxMind databases (GeoLite2 or
+//! GeoIP2) named by `geoip_country_db` and `geoip_asn_db`. Otherwise every
+//! caller resolves to an unknown [`Location`], so handlers don't need to
+//! care whether enrichment is on.
+
+use std::future::{ready, Ready};
+
+use actix_web::dev::Payload;
+use actix_web::{web, FromRequest, HttpRequest};
+
+use crate::blocklist::client_ip;
+use crate::config::Config;
+
+/// Where a request came from, as far as the databases know.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Location {
+    /// ISO 3166-1 alpha-2 code.
+    pub country: Option<String>,
+    pub asn: Option<u32>,
+}
+
+/// The opened databases, shared by every worker.
+#[derive(Default)]
+pub struct GeoIp {
+    #[cfg(feature = "geoip")]
+    country: Option<maxminddb::Reader<Vec<u8>>>,
+    #[cfg(feature = "geoip")]
+    asn: Option<maxminddb::Reader<Vec<u8>>>,
+}
+
+impl GeoIp {
+    /// Loads the databases named in `config` into memory.
+    #[cfg(feature = "geoip")]
+    pub fn open(config: &Config) -> std::io::Result<Self> {
+        let open = |path: &Option<String>| {
+            path.as_ref()
+                .map(maxminddb::Reader::open_readfile)
+                .transpose()
+                .map_err(std::io::Error::other)
+        };
+
+        Ok(GeoIp {
+            country: open(&config.geoip_country_db)?,
+            asn: open(&config.geoip_asn_db)?,
+        })
+    }
+
+    #[cfg(not(feature = "geoip"))]
+    pub fn open(config: &Config) -> std::io::Result<Self> {
+        if config.geoip_country_db.is_some() || config.geoip_asn_db.is_some() {
+            tracing::warn!("GeoIP databases are configured but the `geoip` feature is disabled");
+        }
+        Ok(GeoIp::default())
+    }
+
+    /// Addresses missing from a database resolve to `None` for its field.
+    #[cfg(feature = "geoip")]
+    pub fn locate(&self, ip: std::net::IpAddr) -> Location {
+        use maxminddb::geoip2;
+
+        let country = self.country.as_ref().and_then(|reader| {
+            let record: geoip2::Country = reader.lookup(ip).ok()?;
+            Some(record.country?.iso_code?.to_owned())
+        });
+        let asn = self.asn.as_ref().and_then(|reader| {
+            let record: geoip2::Asn = reader.lookup(ip).ok()?;
+            record.autonomous_system_number
+        });
+
+        Location { country, asn }
+    }
+
+    #[cfg(not(feature = "geoip"))]
+    pub fn locate(&self, _ip: std::net::IpAddr) -> Location {
+        Location::default()
+    }
+}
+
+impl FromRequest for Location {
+    type Error = actix_web::Error;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        let location = match (req.app_data::<web::Data<GeoIp>>(), client_ip(req)) {
+            (Some(geoip), Some(ip)) => geoip.locate(ip),
+            _ => Location::default(),
+        };
+
+        ready(Ok(location))
+    }
+}
@@ -0,0 +1,116 @@
+//! Probes for load balancers and orchestrators: liveness at `GET /health`,
+//! and readiness at `GET /ready` or `GET /readyz`, which is also unready
+//! while draining; see [`crate::drain`].
+
+use std::sync::atomic::Ordering;
+use std::time::{Duration, Instant};
+
+use actix_web::{get, web, HttpResponse};
+use serde::Serialize;
+
+use crate::auth::KeyCache;
+use crate::drain::Drain;
+use crate::{db, metrics};
+
+/// How long the database may take to answer before the instance is reported
+/// unready.
+const DB_DEADLINE: Duration = Duration::from_millis(250);
+
+#[derive(Debug, Serialize)]
+pub struct Liveness {
+    pub status: &'static str,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Probe {
+    pub ok: bool,
+    /// Time to check out a connection and run `SELECT 1`, or the deadline
+    /// when it was missed.
+    pub latency_ms: f64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Readiness {
+    pub ready: bool,
+    pub draining: bool,
+    pub database: Probe,
+    /// Whether API keys have been loaded into memory; see
+    /// [`KeyCache::is_loaded`].
+    pub api_keys_loaded: bool,
+}
+
+/// Runs `SELECT 1` against the pool within [`DB_DEADLINE`], and records the
+/// outcome in [`metrics::DB`].
+pub async fn probe_database(database: web::Data<db::Pool>) -> Probe {
+    let started = Instant::now();
+
+    let query = db::run(database, |conn| {
+        conn.query_row("SELECT 1;", (), |row| row.get::<_, i64>(0))
+    });
+
+    let ok = matches!(
+        actix_web::rt::time::timeout(DB_DEADLINE, query).await,
+        Ok(Ok(1))
+    );
+    let latency = started.elapsed().min(DB_DEADLINE);
+
+    metrics::DB.healthy.store(ok, Ordering::Relaxed);
+    metrics::DB
+        .probe_latency_micros
+        .store(latency.as_micros() as u64, Ordering::Relaxed);
+
+    Probe {
+        ok,
+        latency_ms: latency.as_secs_f64() * 1000.0,
+    }
+}
+
+/// 200 when the database answers in time, API keys are loaded and the
+/// instance isn't draining, 503 otherwise.
+#[get("/ready")]
+pub async fn ready(
+    database: web::Data<db::Pool>,
+    drain: web::Data<Drain>,
+    key_cache: web::Data<KeyCache>,
+) -> HttpResponse {
+    readiness(database, &drain, &key_cache).await
+}
+
+/// The same as [`ready`].
+#[get("/readyz")]
+pub async fn readyz(
+    database: web::Data<db::Pool>,
+    drain: web::Data<Drain>,
+    key_cache: web::Data<KeyCache>,
+) -> HttpResponse {
+    readiness(database, &drain, &key_cache).await
+}
+
+async fn readiness(
+    database: web::Data<db::Pool>,
+    drain: &Drain,
+    key_cache: &KeyCache,
+) -> HttpResponse {
+    let database = probe_database(database).await;
+    let draining = drain.is_draining();
+    let api_keys_loaded = key_cache.is_loaded();
+    let readiness = Readiness {
+        ready: database.ok && api_keys_loaded && !draining,
+        draining,
+        database,
+        api_keys_loaded,
+    };
+
+    if readiness.ready {
+        HttpResponse::Ok().json(readiness)
+    } else {
+        HttpResponse::ServiceUnavailable().json(readiness)
+    }
+}
+
+/// 200 whenever the server can answer at all. Checks nothing else, so a
+/// slow database or a drain doesn't get the instance restarted.
+#[get("/health")]
+pub async fn health() -> HttpResponse {
+    HttpResponse::Ok().json(Liveness { status: "ok" })
+}
@@ -0,0 +1,32 @@
+use actix_web::body::MessageBody;
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::header::AUTHORIZATION;
+use actix_web::middleware::Next;
+use actix_web::{web, Error};
+
+use crate::config::Config;
+use crate::error::ApiError;
+
+/// When `REQUIRE_HTTPS` is set, refuses requests that carry an
+/// `Authorization` header over plain HTTP, so a misconfigured deployment
+/// fails loudly instead of leaking keys.
+///
+/// The scheme comes from actix's connection info, which honours
+/// `Forwarded` and `X-Forwarded-Proto` from a TLS-terminating proxy.
+pub async fn require_https(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    let enforced = req
+        .app_data::<web::Data<Config>>()
+        .is_some_and(|config| config.require_https);
+
+    if enforced
+        && req.headers().contains_key(AUTHORIZATION)
+        && !req.connection_info().scheme().eq_ignore_ascii_case("https")
+    {
+        return Err(ApiError::HttpsRequired.into());
+    }
+
+    next.call(req).await
+}
@@ -0,0 +1,95 @@
+use actix_web::body::{BoxBody, MessageBody};
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::error::InternalError;
+use actix_web::http::header::ACCEPT_LANGUAGE;
+use actix_web::middleware::Next;
+use actix_web::{web, Error, HttpRequest};
+
+use crate::config::Config;
+use crate::error::ApiError;
+
+/// Languages with a translation in the error catalog.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum Lang {
+    #[default]
+    En,
+    It,
+    Es,
+}
+
+impl Lang {
+    fn from_tag(tag: &str) -> Option<Self> {
+        let primary = tag.split(['-', '_']).next()?.trim();
+
+        match primary.to_ascii_lowercase().as_str() {
+            "en" => Some(Lang::En),
+            "it" => Some(Lang::It),
+            "es" => Some(Lang::Es),
+            _ => None,
+        }
+    }
+
+    /// Picks the supported language with the highest `q` weight from an
+    /// `Accept-Language` value, e.g. `it-IT,it;q=0.9,en;q=0.8`.
+    pub fn negotiate(accept_language: &str) -> Self {
+        accept_language
+            .split(',')
+            .filter_map(|entry| {
+                let mut parts = entry.split(';');
+                let lang = Lang::from_tag(parts.next()?)?;
+                let weight = parts
+                    .find_map(|param| param.trim().strip_prefix("q="))
+                    .map_or(Some(1.0), |q| q.trim().parse::<f32>().ok())?;
+
+                (weight > 0.0).then_some((lang, weight))
+            })
+            .fold(None, |best: Option<(Lang, f32)>, candidate| match best {
+                Some(best) if best.1 >= candidate.1 => Some(best),
+                _ => Some(candidate),
+            })
+            .map(|(lang, _)| lang)
+            .unwrap_or_default()
+    }
+
+    pub fn from_request(req: &HttpRequest) -> Self {
+        req.headers()
+            .get(ACCEPT_LANGUAGE)
+            .and_then(|value| value.to_str().ok())
+            .map(Lang::negotiate)
+            .unwrap_or_default()
+    }
+}
+
+/// Re-renders [`ApiError`] responses in the language requested by the client,
+/// pointing clients turned away for their key to [`Config::key_help`].
+pub async fn localize_errors(
+    req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<BoxBody>, Error> {
+    let lang = Lang::from_request(req.request());
+    let help = req
+        .app_data::<web::Data<Config>>()
+        .map(|config| config.key_help());
+
+    match next.call(req).await {
+        Ok(res) => {
+            let localized = res
+                .response()
+                .error()
+                .and_then(|err| err.as_error::<ApiError>())
+                .map(|err| err.localized_response_with_help(lang, help.as_ref()));
+
+            match localized {
+                Some(localized) => Ok(res.into_response(localized)),
+                None => Ok(res.map_into_boxed_body()),
+            }
+        }
+        Err(err) => match err.as_error::<ApiError>() {
+            Some(api_error) => {
+                let localized = api_error.localized_response_with_help(lang, help.as_ref());
+                Err(InternalError::from_response(api_error.clone(), localized).into())
+            }
+            None => Err(err),
+        },
+    }
+}
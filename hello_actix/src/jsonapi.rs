@@ -0,0 +1,111 @@
+//! [JSON:API] documents for the admin list endpoints, for clients that send
+//! `Accept: application/vnd.api+json`. Everyone else gets a plain JSON
+//! array, as before.
+//!
+//! [JSON:API]: https://jsonapi.org/format/
+
+use actix_web::body::BoxBody;
+use actix_web::{HttpRequest, HttpResponse, Responder};
+use serde::Serialize;
+use serde_json::Value;
+
+use crate::{db, negotiate};
+
+pub const MEDIA_TYPE: &str = "application/vnd.api+json";
+
+/// Something listed as a JSON:API resource object. Its other serialized
+/// fields become the `attributes`.
+pub trait Resource: Serialize {
+    const TYPE: &'static str;
+
+    fn id(&self) -> i64;
+}
+
+impl Resource for db::ApiKeyRecord {
+    const TYPE: &'static str = "api-keys";
+
+    fn id(&self) -> i64 {
+        self.id
+    }
+}
+
+impl Resource for db::UsageRecord {
+    const TYPE: &'static str = "usage-records";
+
+    fn id(&self) -> i64 {
+        self.id
+    }
+}
+
+impl Resource for db::Block {
+    const TYPE: &'static str = "blocks";
+
+    fn id(&self) -> i64 {
+        self.id
+    }
+}
+
+impl Resource for db::ReportSubscription {
+    const TYPE: &'static str = "report-subscriptions";
+
+    fn id(&self) -> i64 {
+        self.id
+    }
+}
+
+impl Resource for db::User {
+    const TYPE: &'static str = "users";
+
+    fn id(&self) -> i64 {
+        self.id
+    }
+}
+
+#[derive(Serialize)]
+struct Document {
+    data: Vec<ResourceObject>,
+}
+
+#[derive(Serialize)]
+struct ResourceObject {
+    #[serde(rename = "type")]
+    type_: &'static str,
+    id: String,
+    attributes: Value,
+}
+
+impl ResourceObject {
+    fn of<T: Resource>(resource: &T) -> serde_json::Result<Self> {
+        let mut attributes = serde_json::to_value(resource)?;
+        if let Value::Object(fields) = &mut attributes {
+            fields.remove("id");
+        }
+
+        Ok(ResourceObject {
+            type_: T::TYPE,
+            id: resource.id().to_string(),
+            attributes,
+        })
+    }
+}
+
+/// Responds with a list of `T`, as a JSON:API document if asked for one.
+pub struct Listed<T>(pub Vec<T>);
+
+impl<T: Resource> Responder for Listed<T> {
+    type Body = BoxBody;
+
+    fn respond_to(self, req: &HttpRequest) -> HttpResponse {
+        if !negotiate::accepts(req, &[MEDIA_TYPE]) {
+            return HttpResponse::Ok().json(self.0);
+        }
+
+        let data = self.0.iter().map(ResourceObject::of).collect();
+        match data {
+            Ok(data) => HttpResponse::Ok()
+                .content_type(MEDIA_TYPE)
+                .json(Document { data }),
+            Err(err) => actix_web::error::ErrorInternalServerError(err).error_response(),
+        }
+    }
+}
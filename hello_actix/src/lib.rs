@@ -1,178 +1,966 @@
-use actix_web::dev::ServiceRequest;
-use actix_web::{delete, error, get, post, web, HttpResponse, Responder};
+use actix_web::body::MessageBody;
+use actix_web::dev::{Payload, ServiceRequest, ServiceResponse};
+use actix_web::http::header;
+use actix_web::middleware::Next;
+use actix_web::{delete, get, post, web, Error, FromRequest, HttpRequest, HttpResponse, Responder};
 use actix_web_httpauth::extractors;
 use actix_web_httpauth::extractors::basic::BasicAuth;
-use chrono::Utc;
-use serde::Serialize;
-use tracing::instrument;
+use serde::{Deserialize, Serialize};
+use tracing::{info, instrument, warn};
 
-use std::sync::Mutex;
+use std::collections::BTreeMap;
+use std::future::{ready, Ready};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, RwLock};
 
+use crate::error::ApiError;
+use crate::scale::{Scale, ScaleSet};
+
+pub mod access;
+pub mod admin;
+pub mod alerts;
 pub mod auth;
+pub mod blocklist;
+pub mod casing;
+pub mod challenge;
+pub mod chaos;
+pub mod clock;
+pub mod coap;
+pub mod concurrency;
+pub mod config;
+pub mod cron;
+pub mod csrf;
+#[cfg(feature = "dashboard")]
+pub mod dashboard;
 pub mod db;
+pub mod deprecation;
+pub mod drain;
+pub mod error;
+pub mod eval;
+pub mod fields;
+#[cfg(feature = "test-util")]
+pub mod fixtures;
+pub mod forecast;
+pub mod gateway;
+pub mod geoip;
+pub mod health;
+pub mod https;
+pub mod i18n;
+pub mod jsonapi;
+pub mod line;
+#[cfg(feature = "tools")]
+pub mod loadtest;
+#[cfg(feature = "dashboard")]
+pub mod login;
+pub mod memory;
+pub mod metrics;
+pub mod migrate;
+pub mod mqtt;
+pub mod negotiate;
+pub mod notify;
+pub mod openapi;
+pub mod plugin;
+pub mod privacy;
+#[cfg(feature = "pprof")]
+pub mod profiling;
+pub mod quota;
+pub mod ratelimit;
+pub mod rbac;
+pub mod record;
+pub mod releases;
+#[cfg(feature = "tools")]
+pub mod replay;
+pub mod report;
+pub mod routes;
+pub mod scale;
+pub mod scheduler;
+pub mod scopes;
+#[cfg(feature = "scripting")]
+pub mod scripting;
+pub mod session;
+pub mod signup;
+pub mod subscriptions;
+pub mod tasks;
+pub mod throttling;
+#[cfg(feature = "dashboard")]
+pub mod totp;
+pub mod usage;
+#[cfg(feature = "dashboard")]
+pub mod users;
+pub mod version;
 
+/// Checks the API key in `Authorization: Basic`. Credentials that aren't
+/// Basic, or have no key, are a 400; unknown keys a 401, with a challenge;
+/// revoked keys a 403. Each outcome is counted in [`metrics::AUTH`].
 pub async fn validator(
     req: ServiceRequest,
-    credentials: BasicAuth,
+    credentials: Option<BasicAuth>,
 ) -> Result<ServiceRequest, (actix_web::Error, ServiceRequest)> {
+    let Some(credentials) = credentials.filter(|credentials| !credentials.user_id().is_empty())
+    else {
+        let error = if req.headers().contains_key(header::AUTHORIZATION) {
+            metrics::AUTH.malformed.fetch_add(1, Ordering::Relaxed);
+            info!("rejected malformed credentials");
+            ApiError::MalformedCredentials
+        } else {
+            metrics::AUTH.missing.fetch_add(1, Ordering::Relaxed);
+            ApiError::Unauthorized
+        };
+        return Err((error.into(), req));
+    };
     let token = credentials.user_id();
+    let (Some(database), Some(key_cache)) = (
+        req.app_data::<web::Data<db::Pool>>().cloned(),
+        req.app_data::<web::Data<auth::KeyCache>>().cloned(),
+    ) else {
+        return Err((ApiError::Internal.into(), req));
+    };
 
-    match auth::is_key_allowed_access(token) {
-        Ok(true) => Ok(req),
-        Ok(false) => Err((
-            actix_web::error::ErrorUnauthorized("Supplied token is not authorized."),
-            req,
-        )),
-        Err(_) => Err((actix_web::error::ErrorInternalServerError(""), req)),
+    match key_cache
+        .check(database, token, clock::now(req.request()))
+        .await
+    {
+        Ok(auth::KeyStatus::Active) => match scopes::check(req.request(), token) {
+            Ok(()) => Ok(req),
+            Err(err) => {
+                info!(key = %auth::fingerprint(token), "rejected API key lacking a scope");
+                Err((err.into(), req))
+            }
+        },
+        Ok(auth::KeyStatus::Unknown) => {
+            metrics::AUTH.unknown_key.fetch_add(1, Ordering::Relaxed);
+            info!(key = %auth::fingerprint(token), "rejected unknown API key");
+            Err((ApiError::Unauthorized.into(), req))
+        }
+        Ok(auth::KeyStatus::Revoked) => {
+            metrics::AUTH.revoked_key.fetch_add(1, Ordering::Relaxed);
+            info!(key = %auth::fingerprint(token), "rejected revoked API key");
+            Err((ApiError::KeyRevoked.into(), req))
+        }
+        Ok(auth::KeyStatus::Expired) => {
+            metrics::AUTH.expired_key.fetch_add(1, Ordering::Relaxed);
+            info!(key = %auth::fingerprint(token), "rejected expired API key");
+            Err((ApiError::KeyExpired.into(), req))
+        }
+        Err(_) => Err((ApiError::Internal.into(), req)),
     }
 }
 
-#[derive(Serialize)]
+/// Turns away keys failing [`auth::is_plausible_key`] with a 401 before
+/// [`validator`] looks them up, so a flood of made-up keys costs a hash
+/// each. Counted apart from unknown keys in [`metrics::AUTH`]. Anything
+/// else, malformed credentials included, is left to [`validator`].
+pub async fn reject_implausible_keys(
+    mut req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    if let Ok(credentials) = req.extract::<BasicAuth>().await {
+        if !auth::is_plausible_key(credentials.user_id()) {
+            metrics::AUTH
+                .implausible_key
+                .fetch_add(1, Ordering::Relaxed);
+            info!("rejected implausible API key");
+            return Err(ApiError::Unauthorized.into());
+        }
+    }
+
+    next.call(req).await
+}
+
+/// Everything the routes expect as `app_data`, built once and cloned into
+/// each worker.
+#[derive(Clone)]
+pub struct AppState {
+    pub config: web::Data<config::Config>,
+    pub database: web::Data<db::Pool>,
+    pub stats: web::Data<UsageStats>,
+    /// Every conversion call goes here; see [`usage`].
+    pub usage: web::Data<dyn usage::UsageSink>,
+    pub limiter: web::Data<concurrency::ConcurrencyLimiter>,
+    pub rate_limiter: web::Data<ratelimit::RateLimiter>,
+    /// What the limiters turned away; see [`throttling`].
+    pub throttling: web::Data<throttling::Throttling>,
+    /// Also one of the [`usage`] sinks.
+    pub quota: web::Data<quota::QuotaUsage>,
+    pub blocklist: web::Data<blocklist::Blocklist>,
+    pub deprecations: web::Data<deprecation::DeprecationRegistry>,
+    pub geoip: web::Data<geoip::GeoIp>,
+    pub recorder: Option<web::Data<record::Recorder>>,
+    pub drain: web::Data<drain::Drain>,
+    pub releases: web::Data<releases::ReleaseStatus>,
+    /// Issues the keys signup hands out.
+    pub keys: web::Data<dyn auth::KeyGenerator>,
+    /// What requests' keys are checked against; see [`auth::KeyCache`].
+    pub key_cache: web::Data<auth::KeyCache>,
+    /// Sends email; see [`notify::deliver`].
+    pub mailer: web::Data<dyn notify::Mailer>,
+    /// What handlers take the time from; see [`clock`].
+    pub clock: web::Data<dyn clock::Clock>,
+    pub routes: routes::Routes,
+}
+
+impl AppState {
+    /// Fresh state over a migrated `database`, with every compiled-in
+    /// plugin, no GeoIP databases, no recording, random keys and an empty
+    /// key cache, mail as configured and the system clock.
+    /// Usage goes to memory and, queued as configured, the database. Call
+    /// on a runtime, which the usage queue's writer is spawned on.
+    pub fn new(config: config::Config, database: db::Pool) -> Self {
+        let database = web::Data::new(database);
+        let stats = web::Data::new(UsageStats::new());
+        let quota = web::Data::new(quota::QuotaUsage::new());
+        let usage = usage::FanOut::new()
+            .with(stats.clone().into_inner())
+            .with(usage::queued(
+                Arc::new(usage::DatabaseSink::new(
+                    database.clone(),
+                    config.anonymous_usage,
+                )),
+                &config,
+            ))
+            .with(quota.clone().into_inner());
+        let mailer = notify::mailer(&config);
+
+        AppState {
+            config: web::Data::new(config),
+            database,
+            stats,
+            usage: web::Data::from(Arc::new(usage) as Arc<dyn usage::UsageSink>),
+            limiter: web::Data::new(concurrency::ConcurrencyLimiter::new()),
+            rate_limiter: web::Data::new(ratelimit::RateLimiter::new()),
+            throttling: web::Data::new(throttling::Throttling::new()),
+            quota,
+            blocklist: web::Data::new(blocklist::Blocklist::new()),
+            deprecations: web::Data::new(deprecation::DeprecationRegistry::new()),
+            geoip: web::Data::new(geoip::GeoIp::default()),
+            recorder: None,
+            drain: web::Data::new(drain::Drain::new()),
+            releases: web::Data::new(releases::ReleaseStatus::new()),
+            keys: web::Data::from(Arc::new(auth::RandomKeys) as Arc<dyn auth::KeyGenerator>),
+            key_cache: web::Data::new(auth::KeyCache::new()),
+            mailer: web::Data::from(mailer),
+            clock: web::Data::from(Arc::new(clock::SystemClock) as Arc<dyn clock::Clock>),
+            routes: routes::Routes::new(plugin::PluginRegistry::compiled_in()),
+        }
+    }
+}
+
+/// Registers the state and every route into an existing app or scope, so
+/// the API can be embedded under another prefix:
+///
+/// ```no_run
+/// # fn state() -> hello_actix::AppState { unimplemented!() }
+/// use actix_web::{web, App};
+///
+/// let state = state();
+/// let app = App::new().service(
+///     web::scope("/temperature").configure(|cfg| hello_actix::mount(cfg, state.clone())),
+/// );
+/// ```
+///
+/// App-wide middleware is left to the host. `main` installs
+/// [`csrf::require_csrf`], [`session::refresh`], [`https::require_https`],
+/// [`deprecation::emit_deprecation_headers`],
+/// [`blocklist::enforce_blocklist`], [`i18n::localize_errors`],
+/// [`record::record_requests`] and [`drain::track_requests`].
+pub fn mount(cfg: &mut web::ServiceConfig, state: AppState) {
+    register_app_data(cfg, &state);
+    state.routes.configure(cfg);
+}
+
+/// Mounts the admin, metrics and health routes alone, for a listener of
+/// their own; see [`routes::Routes::configure_internal`].
+pub fn mount_internal(cfg: &mut web::ServiceConfig, state: AppState) {
+    register_app_data(cfg, &state);
+    state.routes.configure_internal(cfg);
+}
+
+/// The same `app_data` for every listener, so the routes of one can't go
+/// without what they extract.
+fn register_app_data(cfg: &mut web::ServiceConfig, state: &AppState) {
+    cfg.app_data(state.config.clone())
+        .app_data(state.database.clone())
+        .app_data(state.stats.clone())
+        .app_data(state.usage.clone())
+        .app_data(state.limiter.clone())
+        .app_data(state.rate_limiter.clone())
+        .app_data(state.throttling.clone())
+        .app_data(state.quota.clone())
+        .app_data(state.blocklist.clone())
+        .app_data(state.deprecations.clone())
+        .app_data(state.geoip.clone())
+        .app_data(state.recorder.clone())
+        .app_data(state.drain.clone())
+        .app_data(state.releases.clone())
+        .app_data(state.keys.clone())
+        .app_data(state.key_cache.clone())
+        .app_data(state.mailer.clone())
+        .app_data(state.clock.clone());
+}
+
+/// Longest `client_request_id` accepted, so clients can't stuff arbitrary
+/// payloads into the usage table.
+pub(crate) const MAX_CLIENT_REQUEST_ID_LENGTH: usize = 128;
+
+/// Most comma-separated values one conversion request may carry.
+pub(crate) const MAX_VALUES_PER_CONVERSION: usize = 100;
+
+/// Most decimal places `precision` may ask for; an `f32` holds no more.
+pub(crate) const MAX_PRECISION: u32 = 6;
+
+const USAGE_TAG_HEADER: &str = "X-Usage-Tag";
+pub(crate) const MAX_USAGE_TAG_LENGTH: usize = 64;
+
+/// Optional `X-Usage-Tag` header, letting one key split its usage between
+/// callers (e.g. "prod-frontend", "nightly-batch") for chargeback.
+#[derive(Debug, Default)]
+pub struct UsageTag(pub Option<String>);
+
+impl FromRequest for UsageTag {
+    type Error = actix_web::Error;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        let Some(value) = req.headers().get(USAGE_TAG_HEADER) else {
+            return ready(Ok(UsageTag(None)));
+        };
+
+        let tag = value.to_str().unwrap_or_default();
+        let is_valid = !tag.is_empty()
+            && tag.len() <= MAX_USAGE_TAG_LENGTH
+            && tag
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'));
+
+        if is_valid {
+            ready(Ok(UsageTag(Some(tag.to_owned()))))
+        } else {
+            ready(Err(ApiError::InvalidUsageTag {
+                max_length: MAX_USAGE_TAG_LENGTH,
+            }
+            .into()))
+        }
+    }
+}
+
+/// A converted temperature. Its JSON has the scales its key chose; see
+/// [`scale::set_output_scales`].
 pub struct Temperature {
     fahrenheit: f32,
     celsius: f32,
+    kelvin: f32,
+    scales: ScaleSet,
+    client_request_id: Option<String>,
+    /// Decimal places to round to when serialized; unrounded if `None`.
+    precision: Option<u32>,
+}
+
+impl Temperature {
+    /// `value`, read in scale `from`, in every scale.
+    pub fn new(
+        value: f32,
+        from: Scale,
+        scales: ScaleSet,
+        client_request_id: Option<String>,
+    ) -> Self {
+        Temperature {
+            fahrenheit: from.convert(value, Scale::Fahrenheit),
+            celsius: from.convert(value, Scale::Celsius),
+            kelvin: from.convert(value, Scale::Kelvin),
+            scales,
+            client_request_id,
+            precision: None,
+        }
+    }
+
+    /// Rounds every scale to `places` decimals when serialized.
+    pub fn rounded_to(self, places: Option<u32>) -> Self {
+        Temperature {
+            precision: places,
+            ..self
+        }
+    }
+
+    fn value_in(&self, scale: Scale) -> f32 {
+        let value = match scale {
+            Scale::Celsius => self.celsius,
+            Scale::Fahrenheit => self.fahrenheit,
+            Scale::Kelvin => self.kelvin,
+        };
+        match self.precision {
+            Some(places) => {
+                let factor = 10f64.powi(places as i32);
+                ((f64::from(value) * factor).round() / factor) as f32
+            }
+            None => value,
+        }
+    }
+
+    #[cfg(feature = "protobuf")]
+    fn to_proto(&self) -> conversion_core::proto::Temperature {
+        conversion_core::proto::Temperature {
+            fahrenheit: self.value_in(Scale::Fahrenheit),
+            celsius: self.value_in(Scale::Celsius),
+            client_request_id: self.client_request_id.clone(),
+            kelvin: self.value_in(Scale::Kelvin),
+        }
+    }
+}
+
+impl Serialize for Temperature {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        use serde::ser::SerializeMap as _;
+
+        let mut map = serializer.serialize_map(None)?;
+        for scale in self.scales.iter() {
+            map.serialize_entry(&scale, &self.value_in(scale))?;
+        }
+        if let Some(client_request_id) = &self.client_request_id {
+            map.serialize_entry("client_request_id", client_request_id)?;
+        }
+        map.end()
+    }
+}
+
+/// The scales `api_key` is answered in; the default for unknown keys.
+pub(crate) fn output_scales(req: &HttpRequest, api_key: &str) -> ScaleSet {
+    auth::key_access(req, api_key)
+        .ok()
+        .flatten()
+        .map(|access| access.output_scales)
+        .unwrap_or_default()
+}
+
+impl negotiate::Encode for Temperature {
+    #[cfg(feature = "protobuf")]
+    fn encode_protobuf(&self) -> Vec<u8> {
+        use prost::Message as _;
+
+        self.to_proto().encode_to_vec()
+    }
+}
+
+/// The temperatures in a conversion path: one value, or several separated by
+/// commas (`10,20,30`).
+#[derive(Debug)]
+pub struct Readings(Vec<f32>);
+
+impl<'de> Deserialize<'de> for Readings {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let values = String::deserialize(deserializer)?;
+        values
+            .split(',')
+            .map(|value| value.trim().parse())
+            .collect::<Result<_, _>>()
+            .map(Readings)
+            .map_err(serde::de::Error::custom)
+    }
+}
+
+impl Readings {
+    /// Converts every value, read in scale `from`, rounded to `precision`
+    /// decimals if given. A single value gives a single [`Temperature`], as
+    /// before lists were accepted.
+    fn convert(
+        self,
+        from: Scale,
+        scales: ScaleSet,
+        client_request_id: Option<String>,
+        precision: Option<u32>,
+    ) -> Result<Conversions, ApiError> {
+        if self.0.len() > MAX_VALUES_PER_CONVERSION {
+            return Err(ApiError::TooManyValues {
+                max: MAX_VALUES_PER_CONVERSION,
+            });
+        }
+
+        let mut temperatures: Vec<_> = self
+            .0
+            .into_iter()
+            .map(|value| {
+                Temperature::new(value, from, scales, client_request_id.clone())
+                    .rounded_to(precision)
+            })
+            .collect();
+
+        Ok(match temperatures.len() {
+            1 => Conversions::One(temperatures.remove(0)),
+            _ => Conversions::Many(temperatures),
+        })
+    }
+}
+
+/// The body of a conversion: an object for one value, an array in request
+/// order for several.
+#[derive(Serialize)]
+#[serde(untagged)]
+pub enum Conversions {
+    One(Temperature),
+    Many(Vec<Temperature>),
+}
+
+impl negotiate::Encode for Conversions {
+    #[cfg(feature = "protobuf")]
+    fn encode_protobuf(&self) -> Vec<u8> {
+        use prost::Message as _;
+
+        match self {
+            Conversions::One(temperature) => temperature.encode_protobuf(),
+            Conversions::Many(temperatures) => conversion_core::proto::Temperatures {
+                temperatures: temperatures.iter().map(Temperature::to_proto).collect(),
+            }
+            .encode_to_vec(),
+        }
+    }
+}
+
+/// Query parameters shared by the conversion endpoints.
+#[derive(Deserialize, Debug)]
+pub struct ConversionParams {
+    /// Opaque identifier chosen by the client, stored with the usage record
+    /// and echoed back so both sides can reconcile their logs.
+    client_request_id: Option<String>,
+    /// Decimal places to round results to, up to [`MAX_PRECISION`].
+    precision: Option<String>,
+}
+
+impl ConversionParams {
+    fn client_request_id(&self) -> Result<Option<String>, ApiError> {
+        match &self.client_request_id {
+            Some(id) if id.len() > MAX_CLIENT_REQUEST_ID_LENGTH => {
+                Err(ApiError::InvalidClientRequestId {
+                    max_length: MAX_CLIENT_REQUEST_ID_LENGTH,
+                })
+            }
+            id => Ok(id.clone()),
+        }
+    }
+
+    fn precision(&self) -> Result<Option<u32>, ApiError> {
+        self.precision
+            .as_deref()
+            .map(|places| {
+                places
+                    .parse()
+                    .ok()
+                    .filter(|places| *places <= MAX_PRECISION)
+                    .ok_or(ApiError::InvalidPrecision { max: MAX_PRECISION })
+            })
+            .transpose()
+    }
 }
 
 #[derive(Default, Debug)]
 pub struct UsageStats {
-    pub counters: Mutex<Counters>,
+    pub counters: Counters,
+    pub alerts: alerts::UsageWatch,
 }
 
+/// Conversions since `/usage-statistics` was last read or reset, by the
+/// worker that served them; see [`worker_id`]. Counts carried over a
+/// restart are no worker's; see [`Counters::restored`]. Counting takes a
+/// shared lock and a relaxed increment, except a worker's very first.
 #[derive(Default, Debug)]
 pub struct Counters {
-    to_celsius: u32,
-    to_fahrenheit: u32,
+    by_worker: RwLock<BTreeMap<usize, AtomicCounters>>,
+    restored: AtomicCounters,
+}
+
+#[derive(Default, Debug, Clone, Copy, Serialize)]
+pub struct WorkerCounters {
+    pub to_celsius: u64,
+    pub to_fahrenheit: u64,
+}
+
+impl std::ops::Add for WorkerCounters {
+    type Output = WorkerCounters;
+
+    fn add(self, other: WorkerCounters) -> WorkerCounters {
+        WorkerCounters {
+            to_celsius: self.to_celsius + other.to_celsius,
+            to_fahrenheit: self.to_fahrenheit + other.to_fahrenheit,
+        }
+    }
+}
+
+/// [`WorkerCounters`] to bump in place. Each count is independent of the
+/// others, so relaxed ordering is enough.
+#[derive(Default, Debug)]
+struct AtomicCounters {
+    to_celsius: AtomicU64,
+    to_fahrenheit: AtomicU64,
+}
+
+impl AtomicCounters {
+    fn count(&self, endpoint: db::ApiEndpoint) {
+        match endpoint {
+            db::ApiEndpoint::ToCelsius => self.to_celsius.fetch_add(1, Ordering::Relaxed),
+            db::ApiEndpoint::ToFahrenheit => self.to_fahrenheit.fetch_add(1, Ordering::Relaxed),
+            // Counted by `/usage-statistics` only for the two original
+            // conversions.
+            db::ApiEndpoint::Convert
+            | db::ApiEndpoint::WaitForUsage
+            | db::ApiEndpoint::ExportData
+            | db::ApiEndpoint::DeleteApiKey
+            | db::ApiEndpoint::WhoAmI
+            | db::ApiEndpoint::UsageForecast
+            | db::ApiEndpoint::Eval
+            | db::ApiEndpoint::Scales
+            | db::ApiEndpoint::OutputScales
+            | db::ApiEndpoint::ToKelvin
+            | db::ApiEndpoint::FromKelvin
+            | db::ApiEndpoint::MyUsage
+            | db::ApiEndpoint::FieldCase
+            | db::ApiEndpoint::RevokeApiKey => return,
+        };
+    }
+
+    fn add(&self, counts: WorkerCounters) {
+        self.to_celsius
+            .fetch_add(counts.to_celsius, Ordering::Relaxed);
+        self.to_fahrenheit
+            .fetch_add(counts.to_fahrenheit, Ordering::Relaxed);
+    }
+
+    fn load(&self) -> WorkerCounters {
+        WorkerCounters {
+            to_celsius: self.to_celsius.load(Ordering::Relaxed),
+            to_fahrenheit: self.to_fahrenheit.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Reads and zeroes each count in one step, so a concurrent increment
+    /// lands either in what is returned or in what is left.
+    fn take(&self) -> WorkerCounters {
+        WorkerCounters {
+            to_celsius: self.to_celsius.swap(0, Ordering::Relaxed),
+            to_fahrenheit: self.to_fahrenheit.swap(0, Ordering::Relaxed),
+        }
+    }
+}
+
+impl Counters {
+    pub(crate) fn count(&self, endpoint: db::ApiEndpoint) {
+        if !matches!(
+            endpoint,
+            db::ApiEndpoint::ToCelsius | db::ApiEndpoint::ToFahrenheit
+        ) {
+            return;
+        }
+
+        let worker = worker_id();
+        if let Some(counters) = self.by_worker.read().unwrap().get(&worker) {
+            counters.count(endpoint);
+            return;
+        }
+        self.by_worker
+            .write()
+            .unwrap()
+            .entry(worker)
+            .or_default()
+            .count(endpoint);
+    }
+
+    /// The total, zeroing every count; see [`Counters::total`].
+    fn take(&self) -> WorkerCounters {
+        self.by_worker
+            .read()
+            .unwrap()
+            .values()
+            .map(AtomicCounters::take)
+            .fold(self.restored.take(), |total, counts| total + counts)
+    }
+
+    /// Adds counts saved before a restart.
+    pub fn restore(&self, saved: WorkerCounters) {
+        self.restored.add(saved);
+    }
+
+    /// What [`Counters::restore`] added since the last reset.
+    pub fn restored(&self) -> WorkerCounters {
+        self.restored.load()
+    }
+
+    /// The workers' counts and the restored ones, added up.
+    pub fn total(&self) -> WorkerCounters {
+        self.by_worker()
+            .into_iter()
+            .fold(self.restored(), |total, (_, counts)| total + counts)
+    }
+
+    /// Workers that counted anything since the last reset, in the order
+    /// they first did.
+    pub fn by_worker(&self) -> Vec<(usize, WorkerCounters)> {
+        self.by_worker
+            .read()
+            .unwrap()
+            .iter()
+            .map(|(worker, counters)| (*worker, counters.load()))
+            .filter(|(_, counts)| counts.to_celsius + counts.to_fahrenheit > 0)
+            .collect()
+    }
+}
+
+static NEXT_WORKER: AtomicUsize = AtomicUsize::new(0);
+
+thread_local! {
+    static WORKER: usize = NEXT_WORKER.fetch_add(1, Ordering::Relaxed);
+}
+
+/// A number for the current thread, from 0 in the order threads first ask;
+/// under actix-web, one per worker.
+pub fn worker_id() -> usize {
+    WORKER.with(|id| *id)
+}
+
+impl negotiate::Encode for UsageStatsResponse {
+    #[cfg(feature = "protobuf")]
+    fn encode_protobuf(&self) -> Vec<u8> {
+        use prost::Message as _;
+
+        conversion_core::proto::UsageStatistics {
+            to_fahrenheit: self.to_fahrenheit.try_into().unwrap_or(u32::MAX),
+            to_celsius: self.to_celsius.try_into().unwrap_or(u32::MAX),
+        }
+        .encode_to_vec()
+    }
 }
 
 impl UsageStats {
     pub fn new() -> Self {
         UsageStats::default()
     }
+
+    /// Writes the counters to the `counters` table, for
+    /// [`UsageStats::restore`] to pick up after a restart.
+    pub async fn save(&self, database: web::Data<db::Pool>) -> Result<(), Error> {
+        let total = self.counters.total();
+
+        db::save_counters(
+            database,
+            vec![
+                ("to_celsius", total.to_celsius),
+                ("to_fahrenheit", total.to_fahrenheit),
+            ],
+        )
+        .await
+    }
+
+    /// Adds the counters last saved by [`UsageStats::save`]. Call once, at
+    /// startup, before anything saves them again.
+    pub async fn restore(&self, database: web::Data<db::Pool>) -> Result<(), Error> {
+        let mut saved = WorkerCounters::default();
+        for (name, value) in db::saved_counters(database).await? {
+            match name.as_str() {
+                "to_celsius" => saved.to_celsius = value,
+                "to_fahrenheit" => saved.to_fahrenheit = value,
+                _ => warn!("ignoring unknown saved counter {name}"),
+            }
+        }
+        self.counters.restore(saved);
+
+        Ok(())
+    }
 }
 
 #[derive(Serialize)]
 struct UsageStatsResponse {
-    to_fahrenheit: u32,
-    to_celsius: u32,
+    to_fahrenheit: u64,
+    to_celsius: u64,
 }
 
-#[get("/to-celsius/{fahrenheit}")]
-#[instrument(skip(stats, database, auth))]
+#[get(
+    "/to-celsius/{fahrenheit}",
+    wrap = "actix_web::middleware::from_fn(usage::record_usage)"
+)]
+#[instrument(skip(req, auth))]
 pub async fn to_celsius(
-    f: web::Path<f32>,
-    stats: web::Data<UsageStats>,
-    database: web::Data<db::Pool>,
+    f: web::Path<Readings>,
+    params: web::Query<ConversionParams>,
+    tag: UsageTag,
+    location: geoip::Location,
+    req: HttpRequest,
     auth: extractors::basic::BasicAuth,
-) -> impl Responder {
-    let now = Utc::now();
-
-    actix_web::rt::spawn(async move {
-        let mut counters = stats.counters.lock().unwrap();
-        counters.to_celsius += 1;
-    });
-
-    actix_web::rt::spawn(async move {
-        let query = db::Query::RecordApiUsage {
-            api_key: auth.user_id().to_string(),
-            endpoint: db::ApiEndpoint::ToFahrenheit,
-            called_at: now,
-        };
-        query.execute(database).await
-    });
-
-    let f = f.into_inner();
-    let c = (f - 32.0) / 1.8;
-    web::Json(Temperature {
-        celsius: c,
-        fahrenheit: f,
-    })
+) -> actix_web::Result<impl Responder> {
+    let now = clock::now(&req);
+    let client_request_id = params.client_request_id()?;
+    let conversions = f.into_inner().convert(
+        Scale::Fahrenheit,
+        output_scales(&req, auth.user_id()),
+        client_request_id.clone(),
+        params.precision()?,
+    )?;
+
+    // One record per request, however many values it converts.
+    let call = db::ApiUsage {
+        api_key: auth.user_id().to_string(),
+        endpoint: db::ApiEndpoint::ToCelsius,
+        called_at: now,
+        client_request_id,
+        tag: tag.0,
+        location,
+        latency_ms: None,
+    };
+    usage::defer(&req, call);
+
+    Ok(negotiate::Negotiated(conversions))
 }
 
-#[get("/to-fahrenheit/{celsius}")]
-#[instrument(skip(stats, database, auth))]
+#[get(
+    "/to-fahrenheit/{celsius}",
+    wrap = "actix_web::middleware::from_fn(usage::record_usage)"
+)]
+#[instrument(skip(req, auth))]
 pub async fn to_fahrenheit(
-    c: web::Path<f32>,
-    stats: web::Data<UsageStats>,
-    database: web::Data<db::Pool>,
+    c: web::Path<Readings>,
+    params: web::Query<ConversionParams>,
+    tag: UsageTag,
+    location: geoip::Location,
+    req: HttpRequest,
     auth: extractors::basic::BasicAuth,
-) -> impl Responder {
-    let now = Utc::now();
-
-    actix_web::rt::spawn(async move {
-        let mut counters = stats.counters.lock().unwrap();
-        counters.to_fahrenheit += 1;
-    });
-
-    async {
-        let query = db::Query::RecordApiUsage {
-            api_key: auth.user_id().to_string(),
-            endpoint: db::ApiEndpoint::ToFahrenheit,
-            called_at: now,
-        };
-        query.execute(database).await
-    }
-    .await
-    .map_err(error::ErrorInternalServerError)
-    .unwrap();
+) -> actix_web::Result<impl Responder> {
+    let now = clock::now(&req);
+    let client_request_id = params.client_request_id()?;
+    let conversions = c.into_inner().convert(
+        Scale::Celsius,
+        output_scales(&req, auth.user_id()),
+        client_request_id.clone(),
+        params.precision()?,
+    )?;
 
-    let c = c.into_inner();
-    let f = 32.0 + (c * 1.8);
-    web::Json(Temperature {
-        celsius: c,
-        fahrenheit: f,
-    })
+    let call = db::ApiUsage {
+        api_key: auth.user_id().to_string(),
+        endpoint: db::ApiEndpoint::ToFahrenheit,
+        called_at: now,
+        client_request_id,
+        tag: tag.0,
+        location,
+        latency_ms: None,
+    };
+    usage::defer(&req, call);
+
+    Ok(negotiate::Negotiated(conversions))
 }
 
-#[get("/usage-statistics")]
-pub async fn usage_statistics(stats: web::Data<UsageStats>) -> impl Responder {
-    let mut counters = stats.counters.lock().unwrap();
+#[get(
+    "/to-kelvin/{celsius}",
+    wrap = "actix_web::middleware::from_fn(usage::record_usage)"
+)]
+#[instrument(skip(req, auth))]
+pub async fn to_kelvin(
+    c: web::Path<Readings>,
+    params: web::Query<ConversionParams>,
+    tag: UsageTag,
+    location: geoip::Location,
+    req: HttpRequest,
+    auth: extractors::basic::BasicAuth,
+) -> actix_web::Result<impl Responder> {
+    let now = clock::now(&req);
+    let client_request_id = params.client_request_id()?;
+    // Kelvin is what was asked for, whatever scales the key chose.
+    let conversions = c.into_inner().convert(
+        Scale::Celsius,
+        output_scales(&req, auth.user_id()).with(Scale::Kelvin),
+        client_request_id.clone(),
+        params.precision()?,
+    )?;
 
-    let response = UsageStatsResponse {
-        to_fahrenheit: counters.to_fahrenheit,
-        to_celsius: counters.to_celsius,
+    let call = db::ApiUsage {
+        api_key: auth.user_id().to_string(),
+        endpoint: db::ApiEndpoint::ToKelvin,
+        called_at: now,
+        client_request_id,
+        tag: tag.0,
+        location,
+        latency_ms: None,
     };
+    usage::defer(&req, call);
 
-    counters.to_fahrenheit = 0;
-    counters.to_celsius = 0;
-
-    web::Json(response)
+    Ok(negotiate::Negotiated(conversions))
 }
 
-#[post("/reset-usage-statistics")]
-pub async fn reset_usage_statistics(stats: web::Data<UsageStats>) -> impl Responder {
-    let mut counters = stats.counters.lock().unwrap();
+#[get(
+    "/from-kelvin/{kelvin}",
+    wrap = "actix_web::middleware::from_fn(usage::record_usage)"
+)]
+#[instrument(skip(req, auth))]
+pub async fn from_kelvin(
+    k: web::Path<Readings>,
+    params: web::Query<ConversionParams>,
+    tag: UsageTag,
+    location: geoip::Location,
+    req: HttpRequest,
+    auth: extractors::basic::BasicAuth,
+) -> actix_web::Result<impl Responder> {
+    let now = clock::now(&req);
+    let client_request_id = params.client_request_id()?;
+    let conversions = k.into_inner().convert(
+        Scale::Kelvin,
+        output_scales(&req, auth.user_id()),
+        client_request_id.clone(),
+        params.precision()?,
+    )?;
 
-    counters.to_fahrenheit = 0;
-    counters.to_celsius = 0;
+    let call = db::ApiUsage {
+        api_key: auth.user_id().to_string(),
+        endpoint: db::ApiEndpoint::FromKelvin,
+        called_at: now,
+        client_request_id,
+        tag: tag.0,
+        location,
+        latency_ms: None,
+    };
+    usage::defer(&req, call);
 
-    HttpResponse::NoContent()
+    Ok(negotiate::Negotiated(conversions))
 }
 
-#[get("/api-key")]
-#[instrument(skip(database))]
-pub async fn request_api_key(database: web::Data<db::Pool>) -> actix_web::Result<impl Responder> {
-    let mut api_key = auth::create_api_key();
+/// Reads and resets each counter in one atomic step, so no conversion is
+/// lost or counted twice across reads.
+#[get("/usage-statistics")]
+pub async fn usage_statistics(stats: web::Data<UsageStats>) -> impl Responder {
+    let counts = stats.counters.take();
 
-    let api_key_ = api_key.clone();
-    web::block(move || auth::store_api_key(database.clone(), api_key_))
-        .await?
-        .await?;
+    negotiate::Negotiated(UsageStatsResponse {
+        to_fahrenheit: counts.to_fahrenheit,
+        to_celsius: counts.to_celsius,
+    })
+}
 
-    api_key.push_str("\r\n");
+#[post("/reset-usage-statistics")]
+pub async fn reset_usage_statistics(stats: web::Data<UsageStats>) -> impl Responder {
+    stats.counters.take();
 
-    Ok(api_key)
+    HttpResponse::NoContent()
 }
 
+/// Revokes the calling key. Any key may, whatever its scopes.
 #[delete("/api-key")]
 pub async fn delete_api_key(
     auth: BasicAuth,
     database: web::Data<db::Pool>,
+    key_cache: web::Data<auth::KeyCache>,
 ) -> actix_web::Result<impl Responder> {
     let token = auth.user_id().to_owned();
 
-    web::block(|| auth::revoke_api_key(database, token))
-        .await?
-        .await?;
+    if !auth::revoke_api_key(database, &key_cache, token).await? {
+        return Err(ApiError::KeyNotFound.into());
+    }
+
+    Ok(HttpResponse::NoContent().finish())
+}
+
+/// Revokes key `id`, for keys with the `admin` scope.
+#[delete("/api-key/{id}")]
+pub async fn revoke_api_key(
+    _: scopes::Scoped<scopes::Admin>,
+    id: web::Path<i64>,
+    database: web::Data<db::Pool>,
+    key_cache: web::Data<auth::KeyCache>,
+) -> actix_web::Result<impl Responder> {
+    if !auth::revoke_api_key_by_id(database, &key_cache, id.into_inner()).await? {
+        return Err(ApiError::KeyNotFound.into());
+    }
 
     Ok(HttpResponse::NoContent().finish())
 }
@@ -0,0 +1,146 @@
+//! A plain-TCP line protocol for legacy equipment that can't do HTTP,
+//! served on `line_bind` when set.
+//!
+//! Each request is one line, `<api key> CONVERT <c2f|f2c> <value>`, answered
+//! with `OK <converted value>` or `ERR <error code>`. A connection may send
+//! any number of requests. Keys cross the network in the clear, so bind the
+//! listener to a trusted network.
+
+use std::rc::Rc;
+use std::time::Duration;
+
+use actix_web::rt::net::{TcpListener, TcpStream};
+use actix_web::{rt, web};
+use chrono::Utc;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tracing::{info, warn};
+
+use crate::auth::KeyCache;
+use crate::blocklist::Blocklist;
+use crate::config::Config;
+use crate::error::ApiError;
+use crate::{db, gateway, tasks};
+
+/// Longest line accepted, so a client can't make us buffer without bound.
+const MAX_LINE: usize = 256;
+const IDLE_TIMEOUT: Duration = Duration::from_secs(300);
+
+/// Binds `line_bind` and serves connections in the background. Call once,
+/// from `main`.
+pub async fn spawn(
+    config: web::Data<Config>,
+    database: web::Data<db::Pool>,
+    key_cache: web::Data<KeyCache>,
+    blocklist: web::Data<Blocklist>,
+) -> std::io::Result<()> {
+    let Some(bind) = config.line_bind.clone() else {
+        return Ok(());
+    };
+    let listener = TcpListener::bind(&bind).await?;
+    info!(%bind, "listening for line protocol connections");
+
+    let listener = Rc::new(listener);
+    tasks::supervise("line_listener", move |_| {
+        accept(
+            listener.clone(),
+            config.clone(),
+            database.clone(),
+            key_cache.clone(),
+            blocklist.clone(),
+        )
+    });
+
+    Ok(())
+}
+
+async fn accept(
+    listener: Rc<TcpListener>,
+    config: web::Data<Config>,
+    database: web::Data<db::Pool>,
+    key_cache: web::Data<KeyCache>,
+    blocklist: web::Data<Blocklist>,
+) {
+    loop {
+        let (stream, peer) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(err) => {
+                warn!(%err, "failed to accept line protocol connection");
+                continue;
+            }
+        };
+        // Outside any app, so by the system clock.
+        if blocklist.is_blocked(peer.ip(), Utc::now()) {
+            continue;
+        }
+
+        rt::spawn(serve(
+            stream,
+            config.clone(),
+            database.clone(),
+            key_cache.clone(),
+        ));
+    }
+}
+
+async fn serve(
+    stream: TcpStream,
+    config: web::Data<Config>,
+    database: web::Data<db::Pool>,
+    key_cache: web::Data<KeyCache>,
+) {
+    let (reader, mut writer) = stream.into_split();
+    let mut reader = BufReader::new(reader).take(MAX_LINE as u64);
+    let mut line = String::new();
+
+    loop {
+        line.clear();
+        reader.set_limit(MAX_LINE as u64);
+
+        let read = rt::time::timeout(IDLE_TIMEOUT, reader.read_line(&mut line)).await;
+        let reply = match read {
+            Ok(Ok(0)) | Err(_) => return,
+            Ok(Ok(_)) if !line.ends_with('\n') => {
+                // Over-long, or cut off by the client closing; either way
+                // the stream can't be resynchronized.
+                let reply = format!("ERR {}\n", ApiError::InvalidCommand.code());
+                let _ = writer.write_all(reply.as_bytes()).await;
+                return;
+            }
+            Ok(Ok(_)) => match execute(&config, database.clone(), &key_cache, line.trim()).await {
+                Ok(value) => format!("OK {value}\n"),
+                Err(err) => format!("ERR {}\n", err.code()),
+            },
+            Ok(Err(_)) => return,
+        };
+
+        if writer.write_all(reply.as_bytes()).await.is_err() {
+            return;
+        }
+    }
+}
+
+async fn execute(
+    config: &Config,
+    database: web::Data<db::Pool>,
+    key_cache: &KeyCache,
+    line: &str,
+) -> Result<f32, ApiError> {
+    let [api_key, "CONVERT", conversion, value] = line.split_whitespace().collect::<Vec<_>>()[..]
+    else {
+        return Err(ApiError::InvalidCommand);
+    };
+    let endpoint = match conversion {
+        "c2f" => db::ApiEndpoint::ToFahrenheit,
+        "f2c" => db::ApiEndpoint::ToCelsius,
+        _ => return Err(ApiError::InvalidCommand),
+    };
+    let value: f32 = value.parse().map_err(|_| ApiError::InvalidCommand)?;
+
+    let temperature =
+        gateway::convert(database, key_cache, config, api_key, endpoint, value).await?;
+
+    Ok(match endpoint {
+        db::ApiEndpoint::ToCelsius => temperature.celsius,
+        _ => temperature.fahrenheit,
+    })
+}
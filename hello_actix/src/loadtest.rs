@@ -0,0 +1,208 @@
+//! `hello_actix loadtest`: drives a fixed request rate against a running
+//! instance and reports latency percentiles and error rates.
+
+use std::cell::RefCell;
+use std::io;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+use actix_web::rt;
+
+const USAGE: &str = "\
+usage: hello_actix loadtest [--url URL] [--rps N] [--duration SECONDS] [--path PATH]
+
+  --url       base URL of the instance under test (default http://127.0.0.1:8080)
+  --rps       requests per second to issue (default 50)
+  --duration  how long to run, in seconds (default 10)
+  --path      endpoint to call (default /api/to-celsius/100)";
+
+#[derive(Debug)]
+pub struct Options {
+    pub url: String,
+    pub rps: u32,
+    pub duration: Duration,
+    pub path: String,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Options {
+            url: "http://127.0.0.1:8080".into(),
+            rps: 50,
+            duration: Duration::from_secs(10),
+            path: "/api/to-celsius/100".into(),
+        }
+    }
+}
+
+impl Options {
+    pub fn parse(args: &[String]) -> Result<Self, String> {
+        let mut options = Options::default();
+        let mut args = args.iter();
+
+        while let Some(flag) = args.next() {
+            let mut value = || {
+                args.next()
+                    .cloned()
+                    .ok_or_else(|| format!("missing value for {flag}"))
+            };
+
+            match flag.as_str() {
+                "--url" => options.url = value()?.trim_end_matches('/').to_owned(),
+                "--rps" => {
+                    options.rps = value()?
+                        .parse()
+                        .ok()
+                        .filter(|rps| *rps > 0)
+                        .ok_or("--rps must be a positive integer")?
+                }
+                "--duration" => {
+                    let seconds: u64 = value()?
+                        .parse()
+                        .map_err(|_| "--duration must be a whole number of seconds")?;
+                    options.duration = Duration::from_secs(seconds);
+                }
+                "--path" => options.path = value()?,
+                "--help" | "-h" => return Err(USAGE.into()),
+                other => return Err(format!("unknown option {other}\n\n{USAGE}")),
+            }
+        }
+
+        Ok(options)
+    }
+}
+
+#[derive(Debug, Default)]
+struct Samples {
+    latencies: Vec<Duration>,
+    errors: usize,
+}
+
+fn percentile(sorted: &[Duration], p: f64) -> Duration {
+    if sorted.is_empty() {
+        return Duration::ZERO;
+    }
+    let rank = ((p / 100.0) * sorted.len() as f64).ceil() as usize;
+    sorted[rank.clamp(1, sorted.len()) - 1]
+}
+
+pub(crate) fn other_error(err: impl std::fmt::Display) -> io::Error {
+    io::Error::other(err.to_string())
+}
+
+/// A throwaway key, so a run is attributable and doesn't consume a real
+/// client's limits.
+pub(crate) async fn issue_temporary_key(client: &awc::Client, url: &str) -> io::Result<String> {
+    let mut response = client
+        .get(format!("{url}/api-key"))
+        .send()
+        .await
+        .map_err(other_error)?;
+    let body = response.body().await.map_err(other_error)?;
+    let api_key = String::from_utf8_lossy(&body).trim().to_owned();
+
+    if !response.status().is_success() || api_key.is_empty() {
+        return Err(other_error(format!(
+            "could not obtain an API key ({})",
+            response.status()
+        )));
+    }
+
+    Ok(api_key)
+}
+
+pub(crate) async fn revoke_temporary_key(client: &awc::Client, url: &str, api_key: &str) {
+    let _ = client
+        .delete(format!("{url}/api-key"))
+        .basic_auth(api_key, "")
+        .send()
+        .await;
+}
+
+pub async fn run(args: &[String]) -> io::Result<()> {
+    let options = match Options::parse(args) {
+        Ok(options) => options,
+        Err(message) => {
+            eprintln!("{message}");
+            return Err(io::ErrorKind::InvalidInput.into());
+        }
+    };
+
+    let client = awc::Client::default();
+    let api_key = issue_temporary_key(&client, &options.url).await?;
+
+    println!(
+        "loadtest: {} req/s for {}s against {}{}",
+        options.rps,
+        options.duration.as_secs(),
+        options.url,
+        options.path
+    );
+
+    let samples = Rc::new(RefCell::new(Samples::default()));
+    let target = format!("{}{}", options.url, options.path);
+    let mut tick = rt::time::interval(Duration::from_secs(1) / options.rps);
+    let mut in_flight = Vec::new();
+    let started = Instant::now();
+
+    while started.elapsed() < options.duration {
+        tick.tick().await;
+
+        let request = client.get(&target).basic_auth(&api_key, "");
+        let samples = samples.clone();
+
+        in_flight.push(rt::spawn(async move {
+            let sent = Instant::now();
+            let result = request.send().await;
+            let latency = sent.elapsed();
+
+            let mut samples = samples.borrow_mut();
+            match result {
+                Ok(response) if response.status().is_success() => samples.latencies.push(latency),
+                _ => samples.errors += 1,
+            }
+        }));
+    }
+
+    for request in in_flight {
+        let _ = request.await;
+    }
+    let elapsed = started.elapsed();
+
+    revoke_temporary_key(&client, &options.url, &api_key).await;
+
+    let mut samples = samples.take();
+    samples.latencies.sort();
+    let total = samples.latencies.len() + samples.errors;
+    let error_rate = if total == 0 {
+        0.0
+    } else {
+        100.0 * samples.errors as f64 / total as f64
+    };
+
+    println!("requests:   {total}");
+    println!(
+        "throughput: {:.1} req/s",
+        total as f64 / elapsed.as_secs_f64()
+    );
+    println!("errors:     {} ({error_rate:.2}%)", samples.errors);
+    for p in [50.0, 90.0, 99.0] {
+        println!(
+            "{:<12}{:.2} ms",
+            format!("p{p}:"),
+            percentile(&samples.latencies, p).as_secs_f64() * 1000.0
+        );
+    }
+    println!(
+        "max:        {:.2} ms",
+        samples
+            .latencies
+            .last()
+            .copied()
+            .unwrap_or_default()
+            .as_secs_f64()
+            * 1000.0
+    );
+
+    Ok(())
+}
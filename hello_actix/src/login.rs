@@ -0,0 +1,82 @@
+use actix_web::{get, post, web, HttpRequest, HttpResponse, Responder};
+use chrono::Duration;
+use serde::Deserialize;
+use serde_json::Value;
+use tracing::{instrument, warn};
+
+use crate::config::Config;
+use crate::error::ApiError;
+use crate::notify::{self, Target};
+use crate::signup::normalize_email;
+use crate::{auth, clock, db, session};
+
+#[derive(Deserialize, Debug)]
+pub struct MagicLinkRequest {
+    email: String,
+}
+
+/// Sends a one-time login link to a registered dashboard user.
+///
+/// Always answers 202 so the endpoint can't be used to discover which
+/// addresses have accounts.
+#[post("/auth/magic-link")]
+#[instrument(skip_all)]
+pub async fn request_magic_link(
+    body: web::Json<MagicLinkRequest>,
+    config: web::Data<Config>,
+    database: web::Data<db::Pool>,
+    mailer: web::Data<dyn notify::Mailer>,
+    req: HttpRequest,
+) -> actix_web::Result<impl Responder> {
+    let email = normalize_email(&body.email)?;
+
+    let Some(user) = db::find_user_by_email(database.clone(), email).await? else {
+        return Ok(HttpResponse::Accepted().finish());
+    };
+
+    let token = auth::generate_token().map_err(|_| ApiError::Internal)?;
+    let query = db::Query::StoreMagicLink {
+        token_hash: auth::hash_token(&token),
+        user_id: user.id,
+        expires_at: clock::now(&req) + Duration::minutes(config.magic_link_ttl_minutes),
+    };
+    query.execute(database).await?;
+
+    let link = format!(
+        "{}/auth/magic-link/{token}",
+        config.public_url.trim_end_matches('/')
+    );
+    let text = format!(
+        "Follow this link within {} minutes to log in to the dashboard:\n\n{link}",
+        config.magic_link_ttl_minutes
+    );
+    let target = Target::Email(user.email);
+    if let Err(err) =
+        notify::deliver(mailer, &target, "Your login link", &Value::String(text)).await
+    {
+        warn!(%err, "failed to send magic login link");
+    }
+
+    Ok(HttpResponse::Accepted().finish())
+}
+
+/// Redeems a login link, starting a dashboard session.
+#[get("/auth/magic-link/{token}")]
+#[instrument(skip_all)]
+pub async fn redeem_magic_link(
+    token: web::Path<String>,
+    config: web::Data<Config>,
+    database: web::Data<db::Pool>,
+    req: HttpRequest,
+) -> actix_web::Result<impl Responder> {
+    let token_hash = auth::hash_token(&token);
+    let now = clock::now(&req);
+
+    let user = db::redeem_magic_link(database.clone(), token_hash, now)
+        .await?
+        .ok_or(ApiError::InvalidMagicLink)?;
+
+    let cookie = session::start(database, &config, &user, now).await?;
+
+    Ok(HttpResponse::Ok().cookie(cookie).json(user))
+}
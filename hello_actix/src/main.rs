@@ -1,16 +1,48 @@
-use actix_web::web::scope;
+use actix_web::middleware::{from_fn, Condition};
 use actix_web::{web, App, HttpServer};
-use actix_web_httpauth::middleware::HttpAuthentication;
-use log::info;
+use log::{info, warn};
 use r2d2_sqlite::SqliteConnectionManager;
+use std::sync::Arc;
 use tracing_actix_web::TracingLogger;
 use tracing_subscriber::prelude::*;
 
+use hello_actix::blocklist::enforce_blocklist;
+use hello_actix::config::Config;
+use hello_actix::csrf::require_csrf;
+use hello_actix::deprecation::{emit_deprecation_headers, DeprecationRegistry};
+use hello_actix::geoip::GeoIp;
+use hello_actix::https::require_https;
+use hello_actix::i18n::localize_errors;
+use hello_actix::plugin::PluginRegistry;
+use hello_actix::releases;
+use hello_actix::usage::{FanOut, StatsdSink, UsageSink};
 use hello_actix::{
-    db, delete_api_key, request_api_key, reset_usage_statistics, to_celsius, to_fahrenheit,
-    usage_statistics, validator, UsageStats,
+    chaos, coap, db, drain, line, mqtt, record, scheduler, session, version, AppState,
 };
 
+/// An `App` wrapped in the middleware both listeners share, so routes that
+/// move to the internal listener keep being drained, recorded, flagged as
+/// deprecated and faulted like the public ones.
+macro_rules! app {
+    ($chaos_enabled:expr) => {
+        App::new()
+            // .wrap(middleware::Logger::default()) // Option 1: For logging with env_logger
+            .wrap(Condition::new(
+                $chaos_enabled,
+                from_fn(chaos::inject_faults),
+            ))
+            .wrap(from_fn(require_csrf))
+            .wrap(from_fn(session::refresh))
+            .wrap(from_fn(require_https))
+            .wrap(from_fn(emit_deprecation_headers))
+            .wrap(from_fn(enforce_blocklist))
+            .wrap(from_fn(localize_errors))
+            .wrap(from_fn(record::record_requests))
+            .wrap(from_fn(drain::track_requests))
+            .wrap(TracingLogger::default()) // Option 2: For logging with tracing
+    };
+}
+
 #[actix_web::main]
 pub async fn main() -> std::io::Result<()> {
     // Option 1: For logging only, uncomment this block.
@@ -28,31 +60,151 @@ pub async fn main() -> std::io::Result<()> {
         .with(tracing_subscriber::fmt::layer())
         .init();
 
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    match args.first().map(String::as_str) {
+        #[cfg(feature = "tools")]
+        Some("loadtest") => return hello_actix::loadtest::run(&args[1..]).await,
+        #[cfg(feature = "tools")]
+        Some("replay") => return hello_actix::replay::run(&args[1..]).await,
+        Some("migrate") => return hello_actix::migrate::run(&args[1..]),
+        #[cfg(not(feature = "tools"))]
+        Some(command @ ("loadtest" | "replay")) => {
+            eprintln!("`{command}` needs a build with the `tools` feature");
+            return Err(std::io::ErrorKind::Unsupported.into());
+        }
+        _ => {}
+    }
+
+    version::mark_started();
+
+    let config = Config::from_env();
+    db::start_threads(&config);
+
     let manager = SqliteConnectionManager::file(db::DB_FILE);
     let db_pool = db::Pool::new(manager).unwrap();
     db::setup(db_pool.clone());
 
-    let counts = web::Data::new(UsageStats::new());
+    let plugins = PluginRegistry::compiled_in();
+    plugins.migrate(&db_pool);
+
+    let mut state = AppState::new(config, db_pool);
+    let config = state.config.clone();
+    let database = state.database.clone();
+
+    // Before serving, so `/ready` needn't wait for a first request to load
+    // them.
+    if let Err(err) = state.key_cache.load(database.clone()) {
+        warn!("failed to load API keys, unready until they are: {err}");
+    }
 
-    HttpServer::new(move || {
+    scheduler::spawn(database.clone(), state.mailer.clone(), state.clock.clone());
+    plugins.spawn_tasks(database.clone());
+
+    info!(
+        "hello_actix {} starting with effective configuration:\n{}",
+        env!("CARGO_PKG_VERSION"),
+        serde_json::to_string_pretty(&config.effective())?
+    );
+    let chaos_enabled = chaos::install(&config);
+    mqtt::spawn(config.clone(), database.clone(), state.key_cache.clone());
+    coap::spawn(config.clone(), database.clone(), state.key_cache.clone())?;
+    if let Some(path) = &config.record_file {
+        state.recorder = Some(web::Data::new(record::Recorder::create(path)?));
+    }
+    state
+        .blocklist
+        .reload(database.clone())
+        .await
+        .map_err(|err| std::io::Error::other(err.to_string()))?;
+    line::spawn(
+        config.clone(),
+        database.clone(),
+        state.key_cache.clone(),
+        state.blocklist.clone(),
+    )
+    .await?;
+
+    // Routes being wound down, e.g.
+    //
+    // .deprecate("/api/v1/to-celsius/{fahrenheit}", Deprecation {
+    //     since: "2025-01-01T00:00:00Z".parse().unwrap(),
+    //     sunset: Some("2025-07-01T00:00:00Z".parse().unwrap()),
+    //     successor: Some("/api/to-celsius/{fahrenheit}".into()),
+    // })
+    state.deprecations = web::Data::new(DeprecationRegistry::new());
+
+    if let Err(err) = state.stats.restore(database.clone()).await {
+        warn!("failed to restore usage counters, starting from zero: {err}");
+    }
+    scheduler::spawn_counter_snapshots(state.stats.clone(), database.clone());
+    releases::spawn(config.clone(), state.releases.clone());
+    scheduler::spawn_throttling_snapshots(
+        state.throttling.clone(),
+        database.clone(),
+        state.clock.clone(),
+    );
+    if let Some(addr) = &config.statsd_addr {
+        let usage = FanOut::new()
+            .with(state.usage.clone().into_inner())
+            .with(Arc::new(StatsdSink::connect(addr)?));
+        state.usage = web::Data::from(Arc::new(usage) as Arc<dyn UsageSink>);
+    }
+    state.geoip = web::Data::new(GeoIp::open(&config)?);
+    scheduler::spawn_limiter_eviction(
+        state.limiter.clone(),
+        state.rate_limiter.clone(),
+        state.clock.clone(),
+    );
+
+    // With an internal address configured, admin, metrics and health
+    // routes move there, off the public listener.
+    if let Some(bind) = &config.internal_bind {
+        let internal = state.clone();
+
+        let server = HttpServer::new(move || {
+            app!(chaos_enabled).configure(|cfg| hello_actix::mount_internal(cfg, internal.clone()))
+        })
+        .workers(config.workers)
+        .worker_max_blocking_threads(config.blocking_threads)
+        .bind(bind)?
+        .run();
+
+        info!("serving admin, metrics and health routes on {bind}");
+        actix_web::rt::spawn(server);
+        state.routes = state.routes.without_internal();
+    }
+
+    let drain = state.drain.clone();
+    let saved_usage = state.usage.clone();
+    let saved_stats = state.stats.clone();
+    let saved_throttling = state.throttling.clone();
+    let saved_database = database;
+    let saved_clock = state.clock.clone();
+    let server = HttpServer::new(move || {
         info!("worker live");
-        App::new()
-            // .wrap(middleware::Logger::default()) // Option 1: For logging with env_logger
-            .wrap(TracingLogger::default()) // Option 2: For logging with tracing
-            .app_data(counts.clone())
-            .app_data(web::Data::new(db_pool.clone()))
-            .service(
-                scope("/api")
-                    .wrap(HttpAuthentication::basic(validator))
-                    .service(to_fahrenheit)
-                    .service(to_celsius),
-            )
-            .service(request_api_key)
-            .service(delete_api_key)
-            .service(usage_statistics)
-            .service(reset_usage_statistics)
+
+        app!(chaos_enabled).configure(|cfg| hello_actix::mount(cfg, state.clone()))
     })
+    .workers(config.workers)
+    .worker_max_blocking_threads(config.blocking_threads)
     .bind(("127.0.0.1", 8080))?
-    .run()
-    .await
+    .run();
+    drain.set_server(server.handle());
+
+    server.await?;
+
+    // Past the drain and the usage queue's flush, so no conversion is left
+    // to count.
+    saved_usage.flush().await;
+    if let Err(err) = saved_stats.save(saved_database.clone()).await {
+        warn!("failed to save usage counters: {err}");
+    }
+    if let Err(err) = saved_throttling
+        .save(saved_database, saved_clock.now())
+        .await
+    {
+        warn!("failed to save throttling counts: {err}");
+    }
+
+    Ok(())
 }
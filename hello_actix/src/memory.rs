@@ -0,0 +1,137 @@
+//! The global allocator, jemalloc or mimalloc in builds with the feature of
+//! that name, and what it reports for diagnosing memory growth: figures in
+//! `GET /admin/metrics`, and those plus the size of each in-memory cache at
+//! `GET /admin/memory`. jemalloc wins when both features are enabled.
+
+use actix_web::{get, web, Responder};
+use serde::Serialize;
+
+use crate::auth::KeyCache;
+use crate::blocklist::Blocklist;
+use crate::concurrency::ConcurrencyLimiter;
+use crate::error::ApiError;
+use crate::quota::QuotaUsage;
+use crate::ratelimit::RateLimiter;
+use crate::rbac::{Admin, Authorized};
+use crate::throttling::Throttling;
+use crate::UsageStats;
+
+#[cfg(feature = "jemalloc")]
+#[global_allocator]
+static GLOBAL: tikv_jemallocator::Jemalloc = tikv_jemallocator::Jemalloc;
+
+#[cfg(all(feature = "mimalloc", not(feature = "jemalloc")))]
+#[global_allocator]
+static GLOBAL: mimalloc::MiMalloc = mimalloc::MiMalloc;
+
+/// What the allocator says about the heap. Figures it doesn't track are
+/// `None`, as are all of them with the system allocator.
+#[derive(Debug, Clone, Serialize)]
+pub struct AllocatorStats {
+    pub allocator: &'static str,
+    /// Bytes handed out to the application.
+    pub allocated_bytes: Option<u64>,
+    /// Bytes of physical memory the allocator holds.
+    pub resident_bytes: Option<u64>,
+    /// Bytes the allocator has mapped or committed.
+    pub mapped_bytes: Option<u64>,
+}
+
+impl AllocatorStats {
+    #[cfg(feature = "jemalloc")]
+    pub fn current() -> Self {
+        use tikv_jemalloc_ctl::{epoch, stats};
+
+        // Statistics are cached until the epoch moves on.
+        let _ = epoch::advance();
+        let read = |stat: tikv_jemalloc_ctl::Result<usize>| stat.ok().map(|bytes| bytes as u64);
+        AllocatorStats {
+            allocator: "jemalloc",
+            allocated_bytes: read(stats::allocated::read()),
+            resident_bytes: read(stats::resident::read()),
+            mapped_bytes: read(stats::mapped::read()),
+        }
+    }
+
+    #[cfg(all(feature = "mimalloc", not(feature = "jemalloc")))]
+    pub fn current() -> Self {
+        let (mut elapsed, mut user, mut system) = (0, 0, 0);
+        let (mut rss, mut peak_rss, mut commit, mut peak_commit, mut faults) = (0, 0, 0, 0, 0);
+        // SAFETY: every argument points to a live `usize` for mimalloc to
+        // write.
+        unsafe {
+            libmimalloc_sys::mi_process_info(
+                &mut elapsed,
+                &mut user,
+                &mut system,
+                &mut rss,
+                &mut peak_rss,
+                &mut commit,
+                &mut peak_commit,
+                &mut faults,
+            );
+        }
+        AllocatorStats {
+            allocator: "mimalloc",
+            allocated_bytes: None,
+            resident_bytes: Some(rss as u64),
+            mapped_bytes: Some(commit as u64),
+        }
+    }
+
+    #[cfg(not(any(feature = "jemalloc", feature = "mimalloc")))]
+    pub fn current() -> Self {
+        AllocatorStats {
+            allocator: "system",
+            allocated_bytes: None,
+            resident_bytes: None,
+            mapped_bytes: None,
+        }
+    }
+}
+
+/// Entries in each in-memory cache.
+#[derive(Debug, Serialize)]
+pub struct CacheSizes {
+    pub api_keys: usize,
+    pub revoked_keys: usize,
+    pub quota_tallies: usize,
+    pub concurrency_permits: usize,
+    pub rate_limit_buckets: usize,
+    pub throttled_pending: usize,
+    pub usage_watches: usize,
+    pub auth_failures: usize,
+}
+
+#[derive(Debug, Serialize)]
+pub struct MemoryReport {
+    #[serde(flatten)]
+    pub allocator: AllocatorStats,
+    pub caches: CacheSizes,
+}
+
+#[get("/memory")]
+pub async fn memory(
+    _: Authorized<Admin>,
+    quota: web::Data<QuotaUsage>,
+    (limiter, rate_limiter): (web::Data<ConcurrencyLimiter>, web::Data<RateLimiter>),
+    throttling: web::Data<Throttling>,
+    stats: web::Data<UsageStats>,
+    blocklist: web::Data<Blocklist>,
+    key_cache: web::Data<KeyCache>,
+) -> actix_web::Result<impl Responder> {
+    let (api_keys, revoked_keys) = key_cache.counts().map_err(|_| ApiError::Internal)?;
+    Ok(web::Json(MemoryReport {
+        allocator: AllocatorStats::current(),
+        caches: CacheSizes {
+            api_keys,
+            revoked_keys,
+            quota_tallies: quota.entries(),
+            concurrency_permits: limiter.entries(),
+            rate_limit_buckets: rate_limiter.entries(),
+            throttled_pending: throttling.entries(),
+            usage_watches: stats.alerts.entries(),
+            auth_failures: blocklist.tracked_addresses(),
+        },
+    }))
+}
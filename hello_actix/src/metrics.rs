@@ -0,0 +1,160 @@
+//! Process-wide operational counters, served at `GET /admin/metrics`. They
+//! count from startup and aren't persisted.
+
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+use actix_web::{get, web, Responder};
+use serde::Serialize;
+
+use crate::memory::AllocatorStats;
+use crate::rbac::{Authorized, Viewer};
+use crate::tasks;
+
+/// Database counters, bumped by [`crate::db::Query::execute`], and gauges
+/// set by [`crate::health::probe_database`].
+#[derive(Debug, Default)]
+pub struct DbMetrics {
+    /// Queries re-run because SQLite reported the database busy or locked.
+    pub busy_retries: AtomicU64,
+    /// Queries that were still busy after the last retry.
+    pub busy_failures: AtomicU64,
+    /// Whether the last readiness probe got an answer in time. `false` until
+    /// the first probe.
+    pub healthy: AtomicBool,
+    pub probe_latency_micros: AtomicU64,
+}
+
+pub static DB: DbMetrics = DbMetrics {
+    busy_retries: AtomicU64::new(0),
+    busy_failures: AtomicU64::new(0),
+    healthy: AtomicBool::new(false),
+    probe_latency_micros: AtomicU64::new(0),
+};
+
+/// Encryption and decryption of stored API keys, timed by [`crate::auth`].
+#[derive(Debug, Default)]
+pub struct CryptoMetrics {
+    pub operations: AtomicU64,
+    pub total_micros: AtomicU64,
+    pub max_micros: AtomicU64,
+    /// Times the master key was read from its file.
+    pub master_key_loads: AtomicU64,
+}
+
+pub static CRYPTO: CryptoMetrics = CryptoMetrics {
+    operations: AtomicU64::new(0),
+    total_micros: AtomicU64::new(0),
+    max_micros: AtomicU64::new(0),
+    master_key_loads: AtomicU64::new(0),
+};
+
+impl CryptoMetrics {
+    pub fn observe(&self, micros: u64) {
+        self.operations.fetch_add(1, Ordering::Relaxed);
+        self.total_micros.fetch_add(micros, Ordering::Relaxed);
+        self.max_micros.fetch_max(micros, Ordering::Relaxed);
+    }
+}
+
+/// API-key authentication failures, counted by [`crate::validator`].
+#[derive(Debug, Default)]
+pub struct AuthMetrics {
+    /// Requests without an `Authorization` header.
+    pub missing: AtomicU64,
+    /// `Authorization` headers that aren't Basic, or carry no key.
+    pub malformed: AtomicU64,
+    pub unknown_key: AtomicU64,
+    pub revoked_key: AtomicU64,
+    pub expired_key: AtomicU64,
+    /// Keys turned away unlooked-up by [`crate::reject_implausible_keys`].
+    pub implausible_key: AtomicU64,
+}
+
+pub static AUTH: AuthMetrics = AuthMetrics {
+    missing: AtomicU64::new(0),
+    malformed: AtomicU64::new(0),
+    unknown_key: AtomicU64::new(0),
+    revoked_key: AtomicU64::new(0),
+    expired_key: AtomicU64::new(0),
+    implausible_key: AtomicU64::new(0),
+};
+
+/// The queue in front of slow usage sinks; see [`crate::usage::QueuedSink`].
+#[derive(Debug, Default)]
+pub struct UsageMetrics {
+    /// Calls waiting to be written.
+    pub queued: AtomicU64,
+    /// Calls dropped because the queue was full.
+    pub dropped: AtomicU64,
+}
+
+pub static USAGE: UsageMetrics = UsageMetrics {
+    queued: AtomicU64::new(0),
+    dropped: AtomicU64::new(0),
+};
+
+#[derive(Debug, Serialize)]
+pub struct Metrics {
+    pub db_busy_retries: u64,
+    pub db_busy_failures: u64,
+    pub db_health: bool,
+    pub db_probe_latency_ms: f64,
+    pub crypto_operations: u64,
+    /// Mean over every operation since startup.
+    pub crypto_latency_ms: f64,
+    pub crypto_max_latency_ms: f64,
+    pub master_key_loads: u64,
+    pub auth_missing: u64,
+    pub auth_malformed: u64,
+    pub auth_unknown_key: u64,
+    pub auth_revoked_key: u64,
+    pub auth_expired_key: u64,
+    pub auth_implausible_key: u64,
+    /// `system`, `jemalloc` or `mimalloc`; see [`crate::memory`].
+    pub allocator: &'static str,
+    pub allocated_bytes: Option<u64>,
+    pub resident_bytes: Option<u64>,
+    pub usage_queued: u64,
+    pub usage_dropped: u64,
+    /// Background tasks restarted after panicking, by task name.
+    pub task_restarts: BTreeMap<&'static str, u64>,
+}
+
+impl Metrics {
+    pub fn current() -> Self {
+        let operations = CRYPTO.operations.load(Ordering::Relaxed);
+        let allocator = AllocatorStats::current();
+        Metrics {
+            db_busy_retries: DB.busy_retries.load(Ordering::Relaxed),
+            db_busy_failures: DB.busy_failures.load(Ordering::Relaxed),
+            db_health: DB.healthy.load(Ordering::Relaxed),
+            db_probe_latency_ms: DB.probe_latency_micros.load(Ordering::Relaxed) as f64 / 1000.0,
+            crypto_operations: operations,
+            crypto_latency_ms: if operations == 0 {
+                0.0
+            } else {
+                CRYPTO.total_micros.load(Ordering::Relaxed) as f64 / operations as f64 / 1000.0
+            },
+            crypto_max_latency_ms: CRYPTO.max_micros.load(Ordering::Relaxed) as f64 / 1000.0,
+            master_key_loads: CRYPTO.master_key_loads.load(Ordering::Relaxed),
+            auth_missing: AUTH.missing.load(Ordering::Relaxed),
+            auth_malformed: AUTH.malformed.load(Ordering::Relaxed),
+            auth_unknown_key: AUTH.unknown_key.load(Ordering::Relaxed),
+            auth_revoked_key: AUTH.revoked_key.load(Ordering::Relaxed),
+            auth_expired_key: AUTH.expired_key.load(Ordering::Relaxed),
+            auth_implausible_key: AUTH.implausible_key.load(Ordering::Relaxed),
+            allocator: allocator.allocator,
+            allocated_bytes: allocator.allocated_bytes,
+            resident_bytes: allocator.resident_bytes,
+            usage_queued: USAGE.queued.load(Ordering::Relaxed),
+            usage_dropped: USAGE.dropped.load(Ordering::Relaxed),
+            task_restarts: tasks::restart_counts(),
+        }
+    }
+}
+
+#[get("/metrics")]
+pub async fn metrics(_: Authorized<Viewer>) -> impl Responder {
+    web::Json(Metrics::current())
+}
@@ -0,0 +1,1006 @@
+//! Versioned schema changes, tracked in SQLite's `user_version`, and the
+//! `hello_actix migrate` command that applies them ahead of a deploy.
+//!
+//! The server still migrates to the latest version at startup; see
+//! [`db::setup`]. Every step is idempotent, so databases from before
+//! versioning (at version 0) are brought in line by running them all.
+//! Plugins' tables are left to [`crate::plugin::PluginRegistry::migrate`].
+//!
+//! Changes to big tables such as `usage` are made online, over three
+//! migrations: one adds the new column ([`Step::AddColumn`]) and declares a
+//! [`Step::Backfill`], which the scheduler then fills a batch at a time with
+//! the API up; [`run_backfills`]. A later migration swaps the columns over
+//! ([`Step::RenameColumn`], [`Step::DropColumn`]), and isn't applied until
+//! the backfills before it are done.
+
+use std::fmt;
+use std::io;
+use std::path::Path;
+use std::time::Duration;
+
+use actix_web::{rt, web};
+use rusqlite::{Connection, OpenFlags};
+use tracing::{debug, info, warn};
+
+use crate::db;
+
+const USAGE: &str = "\
+usage: hello_actix migrate [--dry-run] [--to VERSION]
+
+  --dry-run  print the SQL that would run, after checking it against a copy of the database
+  --to       stop at VERSION instead of the latest";
+
+pub struct Migration {
+    pub version: u32,
+    pub name: &'static str,
+    pub steps: &'static [Step],
+}
+
+pub enum Step {
+    /// Must be safe to run again, e.g. `CREATE TABLE IF NOT EXISTS`.
+    Sql(&'static str),
+    /// `CREATE TABLE IF NOT EXISTS` leaves tables from older releases
+    /// untouched, so columns added since are patched in, unless present.
+    AddColumn {
+        table: &'static str,
+        column: &'static str,
+        decl: &'static str,
+    },
+    /// Runs nothing in the migration itself; see [`Backfill`].
+    Backfill(Backfill),
+    /// Skipped once `from` is gone, so safe to run again.
+    RenameColumn {
+        table: &'static str,
+        from: &'static str,
+        to: &'static str,
+    },
+    /// Skipped once `column` is gone, so safe to run again.
+    DropColumn {
+        table: &'static str,
+        column: &'static str,
+    },
+}
+
+/// Fills `column` of `table` from the SQL `expression` (of the row's other
+/// columns), for rows where it is `NULL` and the expression isn't.
+pub struct Backfill {
+    pub table: &'static str,
+    pub column: &'static str,
+    pub expression: &'static str,
+}
+
+/// Rows per backfill batch, taken in rowid order: each is a transaction of
+/// its own, short enough that writers queued behind it barely notice.
+const BACKFILL_BATCH: usize = 1_000;
+
+/// Pause between batches, for the API's writes to get in.
+const BACKFILL_PAUSE: Duration = Duration::from_millis(50);
+
+impl Backfill {
+    fn pending_rows(&self) -> String {
+        format!(
+            "SELECT rowid FROM {table} WHERE {column} IS NULL AND ({expression}) IS NOT NULL",
+            table = self.table,
+            column = self.column,
+            expression = self.expression,
+        )
+    }
+
+    pub fn is_done(&self, conn: &Connection) -> rusqlite::Result<bool> {
+        let sql = format!("SELECT NOT EXISTS ({});", self.pending_rows());
+        conn.query_row(&sql, (), |row| row.get(0))
+    }
+
+    /// Fills the [`BACKFILL_BATCH`] rows after rowid `after`, by the rowid
+    /// index so each batch costs the same however far along. Returns the
+    /// last rowid of the batch, to continue from; `None` past the end.
+    pub fn run_batch(&self, conn: &Connection, after: i64) -> rusqlite::Result<Option<i64>> {
+        let last: Option<i64> = conn.query_row(
+            &format!(
+                "SELECT MAX(rowid) FROM (SELECT rowid FROM {table} WHERE rowid > ?1 ORDER BY rowid LIMIT ?2);",
+                table = self.table,
+            ),
+            (after, BACKFILL_BATCH),
+            |row| row.get(0),
+        )?;
+        let Some(last) = last else {
+            return Ok(None);
+        };
+
+        conn.execute(
+            &format!(
+                "UPDATE {table} SET {column} = {expression} WHERE rowid > ?1 AND rowid <= ?2 AND {column} IS NULL;",
+                table = self.table,
+                column = self.column,
+                expression = self.expression,
+            ),
+            (after, last),
+        )?;
+        Ok(Some(last))
+    }
+}
+
+fn has_column(conn: &Connection, table: &str, column: &str) -> rusqlite::Result<bool> {
+    let mut stmt = conn.prepare(&format!("PRAGMA table_info({table});"))?;
+    let exists = stmt
+        .query_map((), |row| row.get::<_, String>(1))?
+        .filter_map(Result::ok)
+        .any(|name| name == column);
+    Ok(exists)
+}
+
+impl Step {
+    /// What the step would run against `conn`; `None` when nothing.
+    fn sql(&self, conn: &Connection) -> rusqlite::Result<Option<String>> {
+        match self {
+            Step::Sql(sql) => Ok(Some(
+                sql.trim()
+                    .lines()
+                    .map(|line| line.strip_prefix("        ").unwrap_or(line))
+                    .collect::<Vec<_>>()
+                    .join("\n"),
+            )),
+            Step::AddColumn {
+                table,
+                column,
+                decl,
+            } => Ok((!has_column(conn, table, column)?)
+                .then(|| format!("ALTER TABLE {table} ADD COLUMN {column} {decl};"))),
+            Step::Backfill(_) => Ok(None),
+            Step::RenameColumn { table, from, to } => Ok(has_column(conn, table, from)?
+                .then(|| format!("ALTER TABLE {table} RENAME COLUMN {from} TO {to};"))),
+            Step::DropColumn { table, column } => Ok(has_column(conn, table, column)?
+                .then(|| format!("ALTER TABLE {table} DROP COLUMN {column};"))),
+        }
+    }
+}
+
+const USAGE_TABLES: &[Step] = &[
+    Step::Sql(
+        "
+        CREATE TABLE IF NOT EXISTS usage (
+            id INTEGER PRIMARY KEY,
+            api_key TEXT,
+            endpoint TEXT,
+            called_at TEXT,
+            client_request_id TEXT,
+            tag TEXT,
+            country TEXT,
+            asn INTEGER,
+            latency_ms INTEGER
+        );",
+    ),
+    Step::AddColumn {
+        table: "usage",
+        column: "client_request_id",
+        decl: "TEXT",
+    },
+    Step::AddColumn {
+        table: "usage",
+        column: "tag",
+        decl: "TEXT",
+    },
+    Step::AddColumn {
+        table: "usage",
+        column: "country",
+        decl: "TEXT",
+    },
+    Step::AddColumn {
+        table: "usage",
+        column: "asn",
+        decl: "INTEGER",
+    },
+    Step::AddColumn {
+        table: "usage",
+        column: "latency_ms",
+        decl: "INTEGER",
+    },
+    Step::Sql(
+        "
+        CREATE TABLE IF NOT EXISTS usage_buckets (
+            bucket TEXT NOT NULL,
+            endpoint TEXT NOT NULL,
+            calls INTEGER NOT NULL,
+            PRIMARY KEY (bucket, endpoint)
+        );",
+    ),
+];
+
+const API_KEYS: &[Step] = &[
+    Step::Sql(
+        "
+        CREATE TABLE IF NOT EXISTS api_keys (
+            id INTEGER PRIMARY KEY,
+            salt TEXT,
+            api_key TEXT,
+            created_at TEXT NOT NULL,
+            revoked_at TEXT
+        );",
+    ),
+    Step::Sql(
+        "
+        CREATE INDEX IF NOT EXISTS api_keys_api_key_idx
+        ON api_keys (api_key);",
+    ),
+    Step::AddColumn {
+        table: "api_keys",
+        column: "email",
+        decl: "TEXT",
+    },
+    Step::AddColumn {
+        table: "api_keys",
+        column: "tier",
+        decl: "TEXT",
+    },
+];
+
+const SIGNUPS: &[Step] = &[
+    Step::Sql(
+        "
+        CREATE TABLE IF NOT EXISTS signups (
+            token_hash TEXT PRIMARY KEY,
+            email TEXT NOT NULL,
+            expires_at TEXT NOT NULL,
+            used_at TEXT
+        );",
+    ),
+    Step::Sql(
+        "
+        CREATE TABLE IF NOT EXISTS signup_challenges (
+            token_hash TEXT PRIMARY KEY,
+            expires_at TEXT NOT NULL,
+            used_at TEXT
+        );",
+    ),
+];
+
+const BLOCKLIST: &[Step] = &[Step::Sql(
+    "
+        CREATE TABLE IF NOT EXISTS blocklist (
+            id INTEGER PRIMARY KEY,
+            network TEXT NOT NULL,
+            reason TEXT,
+            created_at TEXT NOT NULL,
+            expires_at TEXT
+        );",
+)];
+
+const ERASURES: &[Step] = &[Step::Sql(
+    "
+        CREATE TABLE IF NOT EXISTS erasures (
+            id INTEGER PRIMARY KEY,
+            key_ids TEXT NOT NULL,
+            usage_rows INTEGER NOT NULL,
+            erased_by TEXT NOT NULL,
+            erased_at TEXT NOT NULL
+        );",
+)];
+
+const USERS: &[Step] = &[
+    Step::Sql(
+        "
+        CREATE TABLE IF NOT EXISTS users (
+            id INTEGER PRIMARY KEY,
+            email TEXT NOT NULL UNIQUE,
+            created_at TEXT NOT NULL
+        );",
+    ),
+    Step::Sql(
+        "
+        CREATE TABLE IF NOT EXISTS roles (
+            user_id INTEGER PRIMARY KEY REFERENCES users (id),
+            role TEXT NOT NULL
+        );",
+    ),
+    Step::AddColumn {
+        table: "users",
+        column: "totp_secret",
+        decl: "TEXT",
+    },
+    Step::AddColumn {
+        table: "users",
+        column: "totp_enabled_at",
+        decl: "TEXT",
+    },
+    Step::AddColumn {
+        table: "users",
+        column: "totp_last_step",
+        decl: "INTEGER",
+    },
+    Step::Sql(
+        "
+        CREATE TABLE IF NOT EXISTS magic_links (
+            token_hash TEXT PRIMARY KEY,
+            user_id INTEGER NOT NULL REFERENCES users (id),
+            expires_at TEXT NOT NULL,
+            used_at TEXT
+        );",
+    ),
+    Step::Sql(
+        "
+        CREATE TABLE IF NOT EXISTS sessions (
+            id_hash TEXT PRIMARY KEY,
+            user_id INTEGER NOT NULL REFERENCES users (id),
+            created_at TEXT NOT NULL,
+            expires_at TEXT NOT NULL
+        );",
+    ),
+    Step::AddColumn {
+        table: "sessions",
+        column: "last_seen_at",
+        decl: "TEXT",
+    },
+];
+
+const ENDPOINT_TOGGLES: &[Step] = &[Step::AddColumn {
+    table: "api_keys",
+    column: "disabled_endpoints",
+    decl: "INTEGER NOT NULL DEFAULT 0",
+}];
+
+const REPORT_SUBSCRIPTIONS: &[Step] = &[Step::Sql(
+    "
+        CREATE TABLE IF NOT EXISTS report_subscriptions (
+            id INTEGER PRIMARY KEY,
+            endpoint TEXT,
+            schedule TEXT NOT NULL,
+            target TEXT NOT NULL,
+            created_at TEXT NOT NULL,
+            last_run_at TEXT,
+            next_run_at TEXT
+        );",
+)];
+
+const QUOTA_WARNINGS: &[Step] = &[Step::Sql(
+    "
+        CREATE TABLE IF NOT EXISTS quota_warnings (
+            key_id INTEGER NOT NULL,
+            period TEXT NOT NULL,
+            threshold INTEGER NOT NULL,
+            sent_at TEXT NOT NULL,
+            PRIMARY KEY (key_id, period, threshold)
+        );",
+)];
+
+const OUTPUT_SCALES: &[Step] = &[Step::AddColumn {
+    table: "api_keys",
+    column: "output_scales",
+    decl: "INTEGER NOT NULL DEFAULT 0",
+}];
+
+/// Rewrites every stored time as [`db::Timestamp`] writes it. Times
+/// without an offset are taken as UTC, which is all this API ever wrote;
+/// anything SQLite can't read is left for reads to reject. Rewrites `usage`
+/// in one transaction, so on big databases run `hello_actix migrate` ahead
+/// of the deploy.
+const MILLISECOND_TIMESTAMPS: &[Step] = &[Step::Sql(
+    "
+        UPDATE usage SET called_at = COALESCE(strftime('%Y-%m-%dT%H:%M:%fZ', called_at), called_at);
+        UPDATE usage_buckets SET bucket = COALESCE(strftime('%Y-%m-%dT%H:%M:%fZ', bucket), bucket);
+        UPDATE api_keys SET
+            created_at = COALESCE(strftime('%Y-%m-%dT%H:%M:%fZ', created_at), created_at),
+            revoked_at = COALESCE(strftime('%Y-%m-%dT%H:%M:%fZ', revoked_at), revoked_at);
+        UPDATE signups SET
+            expires_at = COALESCE(strftime('%Y-%m-%dT%H:%M:%fZ', expires_at), expires_at),
+            used_at = COALESCE(strftime('%Y-%m-%dT%H:%M:%fZ', used_at), used_at);
+        UPDATE signup_challenges SET
+            expires_at = COALESCE(strftime('%Y-%m-%dT%H:%M:%fZ', expires_at), expires_at),
+            used_at = COALESCE(strftime('%Y-%m-%dT%H:%M:%fZ', used_at), used_at);
+        UPDATE blocklist SET
+            created_at = COALESCE(strftime('%Y-%m-%dT%H:%M:%fZ', created_at), created_at),
+            expires_at = COALESCE(strftime('%Y-%m-%dT%H:%M:%fZ', expires_at), expires_at);
+        UPDATE erasures SET erased_at = COALESCE(strftime('%Y-%m-%dT%H:%M:%fZ', erased_at), erased_at);
+        UPDATE users SET
+            created_at = COALESCE(strftime('%Y-%m-%dT%H:%M:%fZ', created_at), created_at),
+            totp_enabled_at = COALESCE(strftime('%Y-%m-%dT%H:%M:%fZ', totp_enabled_at), totp_enabled_at);
+        UPDATE magic_links SET
+            expires_at = COALESCE(strftime('%Y-%m-%dT%H:%M:%fZ', expires_at), expires_at),
+            used_at = COALESCE(strftime('%Y-%m-%dT%H:%M:%fZ', used_at), used_at);
+        UPDATE sessions SET
+            created_at = COALESCE(strftime('%Y-%m-%dT%H:%M:%fZ', created_at), created_at),
+            expires_at = COALESCE(strftime('%Y-%m-%dT%H:%M:%fZ', expires_at), expires_at),
+            last_seen_at = COALESCE(strftime('%Y-%m-%dT%H:%M:%fZ', last_seen_at), last_seen_at);
+        UPDATE report_subscriptions SET
+            created_at = COALESCE(strftime('%Y-%m-%dT%H:%M:%fZ', created_at), created_at),
+            last_run_at = COALESCE(strftime('%Y-%m-%dT%H:%M:%fZ', last_run_at), last_run_at),
+            next_run_at = COALESCE(strftime('%Y-%m-%dT%H:%M:%fZ', next_run_at), next_run_at);
+        UPDATE quota_warnings SET sent_at = COALESCE(strftime('%Y-%m-%dT%H:%M:%fZ', sent_at), sent_at);",
+)];
+
+/// The `/usage-statistics` counters as last saved, so a restart carries them
+/// over; see [`crate::UsageStats::save`].
+const COUNTERS: &[Step] = &[Step::Sql(
+    "
+        CREATE TABLE IF NOT EXISTS counters (
+            name TEXT PRIMARY KEY,
+            value INTEGER NOT NULL,
+            saved_at TEXT NOT NULL
+        );",
+)];
+
+/// Keys are stored encrypted under a salt of their own, so equal keys
+/// don't look equal; their [`crate::auth::fingerprint`] does. Keys stored
+/// before get theirs when next loaded.
+const KEY_FINGERPRINTS: &[Step] = &[
+    Step::AddColumn {
+        table: "api_keys",
+        column: "fingerprint",
+        decl: "TEXT",
+    },
+    Step::Sql(
+        "
+        CREATE UNIQUE INDEX IF NOT EXISTS api_keys_fingerprint_idx
+        ON api_keys (fingerprint);",
+    ),
+];
+
+/// Monthly call quotas set for single keys, over their tier's; see
+/// [`crate::quota`].
+const QUOTAS: &[Step] = &[Step::Sql(
+    "
+        CREATE TABLE IF NOT EXISTS quotas (
+            key_id INTEGER PRIMARY KEY,
+            monthly_limit INTEGER NOT NULL,
+            updated_at TEXT NOT NULL
+        );",
+)];
+
+const THROTTLING: &[Step] = &[Step::Sql(
+    "
+        CREATE TABLE IF NOT EXISTS throttled (
+            minute TEXT NOT NULL,
+            rule TEXT NOT NULL,
+            key_id INTEGER,
+            ip TEXT,
+            count INTEGER NOT NULL
+        );
+        CREATE INDEX IF NOT EXISTS throttled_minute_idx ON throttled (minute);",
+)];
+
+const KEY_EXPIRY: &[Step] = &[Step::AddColumn {
+    table: "api_keys",
+    column: "expires_at",
+    decl: "TEXT",
+}];
+
+const FIELD_CASE: &[Step] = &[Step::AddColumn {
+    table: "api_keys",
+    column: "field_case",
+    decl: "TEXT",
+}];
+
+const KEY_SCOPES: &[Step] = &[Step::AddColumn {
+    table: "api_keys",
+    column: "scopes",
+    decl: "INTEGER NOT NULL DEFAULT 0",
+}];
+
+/// `0` used to stand for every scope, `admin` included. It grants nothing
+/// now, so keys stored with it get `convert:read,usage:read` (bits 0 and
+/// 1; [`crate::scopes::ScopeSet::SELF_SERVICE`]), what a signup issues.
+/// Admin keys are issued explicitly from here on.
+const EXPLICIT_KEY_SCOPES: &[Step] = &[Step::Sql(
+    "
+        UPDATE api_keys SET scopes = 3 WHERE scopes = 0;",
+)];
+
+/// Every schema change, oldest first. Append new ones; never edit or
+/// renumber those released.
+pub const MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        name: "usage",
+        steps: USAGE_TABLES,
+    },
+    Migration {
+        version: 2,
+        name: "api_keys",
+        steps: API_KEYS,
+    },
+    Migration {
+        version: 3,
+        name: "signups",
+        steps: SIGNUPS,
+    },
+    Migration {
+        version: 4,
+        name: "blocklist",
+        steps: BLOCKLIST,
+    },
+    Migration {
+        version: 5,
+        name: "erasures",
+        steps: ERASURES,
+    },
+    Migration {
+        version: 6,
+        name: "users and sessions",
+        steps: USERS,
+    },
+    Migration {
+        version: 7,
+        name: "per-key endpoint toggles",
+        steps: ENDPOINT_TOGGLES,
+    },
+    Migration {
+        version: 8,
+        name: "report subscriptions",
+        steps: REPORT_SUBSCRIPTIONS,
+    },
+    Migration {
+        version: 9,
+        name: "quota warnings",
+        steps: QUOTA_WARNINGS,
+    },
+    Migration {
+        version: 10,
+        name: "per-key output scales",
+        steps: OUTPUT_SCALES,
+    },
+    Migration {
+        version: 11,
+        name: "millisecond UTC timestamps",
+        steps: MILLISECOND_TIMESTAMPS,
+    },
+    Migration {
+        version: 12,
+        name: "counters",
+        steps: COUNTERS,
+    },
+    Migration {
+        version: 13,
+        name: "api key fingerprints",
+        steps: KEY_FINGERPRINTS,
+    },
+    Migration {
+        version: 14,
+        name: "quotas",
+        steps: QUOTAS,
+    },
+    Migration {
+        version: 15,
+        name: "throttling",
+        steps: THROTTLING,
+    },
+    Migration {
+        version: 16,
+        name: "key expiry",
+        steps: KEY_EXPIRY,
+    },
+    Migration {
+        version: 17,
+        name: "field case",
+        steps: FIELD_CASE,
+    },
+    Migration {
+        version: 18,
+        name: "key scopes",
+        steps: KEY_SCOPES,
+    },
+    Migration {
+        version: 19,
+        name: "explicit key scopes",
+        steps: EXPLICIT_KEY_SCOPES,
+    },
+];
+
+pub fn latest() -> u32 {
+    MIGRATIONS.last().map_or(0, |migration| migration.version)
+}
+
+pub fn version(conn: &Connection) -> rusqlite::Result<u32> {
+    conn.pragma_query_value(None, "user_version", |row| row.get(0))
+}
+
+#[derive(Debug)]
+pub enum MigrateError {
+    Sqlite(rusqlite::Error),
+    /// There are no down migrations.
+    Downgrade {
+        current: u32,
+        to: u32,
+    },
+    UnknownVersion(u32),
+    /// A migration up to `to` must wait for this backfill to finish.
+    BackfillPending {
+        table: &'static str,
+        column: &'static str,
+    },
+}
+
+impl fmt::Display for MigrateError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MigrateError::Sqlite(err) => write!(f, "{err}"),
+            MigrateError::Downgrade { current, to } => {
+                write!(f, "the database is at version {current}, past {to}")
+            }
+            MigrateError::UnknownVersion(version) => write!(
+                f,
+                "no version {version}; this build knows up to {}",
+                latest()
+            ),
+            MigrateError::BackfillPending { table, column } => write!(
+                f,
+                "{table}.{column} is still being backfilled; try again once the server has finished"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for MigrateError {}
+
+impl From<rusqlite::Error> for MigrateError {
+    fn from(err: rusqlite::Error) -> Self {
+        MigrateError::Sqlite(err)
+    }
+}
+
+/// Applies the migrations after the database's version, up to `to` (the
+/// latest if `None`), each in a transaction of its own. `on_sql` sees each
+/// statement once it has run. Returns the version reached.
+///
+/// A database from a newer build is left as it is when `to` is `None`, so
+/// rolling back a release still starts. So is one with a backfill still
+/// running, at the migration waiting on it; with `to` set, that's an error.
+pub fn apply(
+    conn: &Connection,
+    to: Option<u32>,
+    mut on_sql: impl FnMut(&Migration, &str),
+) -> Result<u32, MigrateError> {
+    let current = version(conn)?;
+    let target = match to {
+        Some(to) if to > latest() => return Err(MigrateError::UnknownVersion(to)),
+        Some(to) if to < current => return Err(MigrateError::Downgrade { current, to }),
+        Some(to) => to,
+        None if current > latest() => {
+            warn!(
+                current,
+                latest = latest(),
+                "database schema is newer than this build"
+            );
+            return Ok(current);
+        }
+        None => latest(),
+    };
+
+    for migration in MIGRATIONS
+        .iter()
+        .filter(|migration| migration.version > current && migration.version <= target)
+    {
+        if let Some(backfill) = pending_backfill(conn, migration.version)? {
+            if to.is_some() {
+                return Err(MigrateError::BackfillPending {
+                    table: backfill.table,
+                    column: backfill.column,
+                });
+            }
+            info!(
+                version = migration.version,
+                table = backfill.table,
+                column = backfill.column,
+                "migration waits on a backfill"
+            );
+            return Ok(migration.version - 1);
+        }
+
+        let tx = conn.unchecked_transaction()?;
+        for step in migration.steps {
+            if let Step::Backfill(backfill) = step {
+                on_sql(
+                    migration,
+                    &format!(
+                        "-- then, in batches: UPDATE {} SET {} = {};",
+                        backfill.table, backfill.column, backfill.expression
+                    ),
+                );
+            }
+            if let Some(sql) = step.sql(&tx)? {
+                tx.execute_batch(&sql)?;
+                on_sql(migration, &sql);
+            }
+        }
+        tx.pragma_update(None, "user_version", migration.version)?;
+        tx.commit()?;
+    }
+
+    Ok(target.max(current))
+}
+
+/// The backfills declared by migrations before `version`.
+fn backfills_before(version: u32) -> impl Iterator<Item = &'static Backfill> {
+    MIGRATIONS
+        .iter()
+        .take_while(move |migration| migration.version < version)
+        .flat_map(|migration| migration.steps)
+        .filter_map(|step| match step {
+            Step::Backfill(backfill) => Some(backfill),
+            _ => None,
+        })
+}
+
+fn pending_backfill(
+    conn: &Connection,
+    version: u32,
+) -> rusqlite::Result<Option<&'static Backfill>> {
+    for backfill in backfills_before(version) {
+        if !backfill.is_done(conn)? {
+            return Ok(Some(backfill));
+        }
+    }
+    Ok(None)
+}
+
+/// Runs the applied migrations' unfinished backfills to the end, a batch at
+/// a time. Run by [`crate::scheduler`]; once they are done, the next start
+/// (or `hello_actix migrate`) applies the migrations that waited on them.
+pub async fn run_backfills(database: web::Data<db::Pool>) -> Result<(), actix_web::Error> {
+    let current = db::run(database.clone(), |conn| version(conn)).await?;
+
+    for backfill in backfills_before(current + 1) {
+        if db::run(database.clone(), |conn| backfill.is_done(conn)).await? {
+            continue;
+        }
+
+        let mut cursor = 0;
+        while let Some(last) = db::run(database.clone(), move |conn| {
+            backfill.run_batch(conn, cursor)
+        })
+        .await?
+        {
+            cursor = last;
+            debug!(
+                table = backfill.table,
+                column = backfill.column,
+                rowid = cursor,
+                "backfilling"
+            );
+            rt::time::sleep(BACKFILL_PAUSE).await;
+        }
+        info!(
+            table = backfill.table,
+            column = backfill.column,
+            "backfill done"
+        );
+    }
+
+    Ok(())
+}
+
+#[derive(Debug, Default)]
+pub struct Options {
+    pub dry_run: bool,
+    pub to: Option<u32>,
+}
+
+impl Options {
+    pub fn parse(args: &[String]) -> Result<Self, String> {
+        let mut options = Options::default();
+        let mut args = args.iter();
+
+        while let Some(flag) = args.next() {
+            match flag.as_str() {
+                "--dry-run" => options.dry_run = true,
+                "--to" => {
+                    let version = args
+                        .next()
+                        .ok_or_else(|| format!("missing value for {flag}"))?;
+                    options.to = Some(
+                        version
+                            .parse()
+                            .map_err(|_| format!("invalid version {version}"))?,
+                    );
+                }
+                "--help" | "-h" => return Err(USAGE.into()),
+                other => return Err(format!("unknown option {other}\n\n{USAGE}")),
+            }
+        }
+
+        Ok(options)
+    }
+}
+
+/// `hello_actix migrate`: migrates [`db::DB_FILE`], printing the SQL as it
+/// goes. With `--dry-run` the SQL runs against a copy instead, so it is
+/// checked without touching the database.
+pub fn run(args: &[String]) -> io::Result<()> {
+    let options = match Options::parse(args) {
+        Ok(options) => options,
+        Err(message) => {
+            eprintln!("{message}");
+            return Err(io::ErrorKind::InvalidInput.into());
+        }
+    };
+
+    let copy =
+        std::env::temp_dir().join(format!("hello_actix-migrate-{}.sqlite", std::process::id()));
+    let conn = if options.dry_run {
+        copy_database(&copy).map_err(io::Error::other)?
+    } else {
+        Connection::open(db::DB_FILE).map_err(io::Error::other)?
+    };
+
+    let from = version(&conn).map_err(io::Error::other)?;
+    let mut last_printed = None;
+    let result = apply(&conn, options.to, |migration, sql| {
+        if last_printed != Some(migration.version) {
+            println!("-- {}: {}", migration.version, migration.name);
+            last_printed = Some(migration.version);
+        }
+        println!("{sql}");
+    });
+    drop(conn);
+    if options.dry_run {
+        let _ = std::fs::remove_file(&copy);
+    }
+
+    match result {
+        Ok(to) if options.dry_run => {
+            println!(
+                "-- dry run: {} from version {from} to {to} applies cleanly; nothing was changed",
+                db::DB_FILE
+            );
+            Ok(())
+        }
+        Ok(to) => {
+            println!("-- migrated {} from version {from} to {to}", db::DB_FILE);
+            Ok(())
+        }
+        Err(err) => {
+            eprintln!("migrate: {err}");
+            Err(io::ErrorKind::Other.into())
+        }
+    }
+}
+
+/// Copies the database to `path` and opens the copy; an empty database if
+/// there is none yet.
+fn copy_database(path: &Path) -> rusqlite::Result<Connection> {
+    if !Path::new(db::DB_FILE).exists() {
+        return Connection::open_in_memory();
+    }
+
+    let source = Connection::open_with_flags(db::DB_FILE, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+    source.execute("VACUUM INTO ?1;", (path.to_string_lossy(),))?;
+    Connection::open(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scopes::{KeyScope, ScopeSet};
+
+    fn table(rows: i64) -> Connection {
+        let conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch("CREATE TABLE t (id INTEGER PRIMARY KEY, x INTEGER, y INTEGER);")
+            .unwrap();
+        for id in 1..=rows {
+            conn.execute("INSERT INTO t (id, x) VALUES (?1, ?1);", (id,))
+                .unwrap();
+        }
+        conn
+    }
+
+    fn run(conn: &Connection, step: &Step) -> bool {
+        let sql = step.sql(conn).unwrap();
+        if let Some(sql) = &sql {
+            conn.execute_batch(sql).unwrap();
+        }
+        sql.is_some()
+    }
+
+    #[test]
+    fn add_column_once() {
+        let conn = table(0);
+        let step = Step::AddColumn {
+            table: "t",
+            column: "z",
+            decl: "TEXT",
+        };
+        assert!(run(&conn, &step));
+        assert!(has_column(&conn, "t", "z").unwrap());
+        assert!(!run(&conn, &step));
+    }
+
+    #[test]
+    fn rename_column_once() {
+        let conn = table(1);
+        let step = Step::RenameColumn {
+            table: "t",
+            from: "x",
+            to: "z",
+        };
+        assert!(run(&conn, &step));
+        assert!(!has_column(&conn, "t", "x").unwrap());
+        let z: i64 = conn
+            .query_row("SELECT z FROM t;", (), |row| row.get(0))
+            .unwrap();
+        assert_eq!(z, 1);
+        assert!(!run(&conn, &step));
+    }
+
+    #[test]
+    fn drop_column_once() {
+        let conn = table(1);
+        let step = Step::DropColumn {
+            table: "t",
+            column: "y",
+        };
+        assert!(run(&conn, &step));
+        assert!(!has_column(&conn, "t", "y").unwrap());
+        assert!(!run(&conn, &step));
+    }
+
+    #[test]
+    fn backfill_in_batches() {
+        let rows = 2 * BACKFILL_BATCH as i64 + 1;
+        let conn = table(rows);
+        // Already filled, so left alone.
+        conn.execute("UPDATE t SET y = -1 WHERE id = 5;", ())
+            .unwrap();
+        let step = Step::Backfill(Backfill {
+            table: "t",
+            column: "y",
+            expression: "x * 2",
+        });
+        // Left to the scheduler.
+        assert!(!run(&conn, &step));
+        let Step::Backfill(backfill) = &step else {
+            unreachable!()
+        };
+        assert!(!backfill.is_done(&conn).unwrap());
+
+        let mut cursor = 0;
+        let mut batches = 0;
+        while let Some(last) = backfill.run_batch(&conn, cursor).unwrap() {
+            assert!(last > cursor);
+            cursor = last;
+            batches += 1;
+        }
+        assert_eq!(batches, 3);
+        assert_eq!(cursor, rows);
+        assert!(backfill.is_done(&conn).unwrap());
+
+        let wrong: i64 = conn
+            .query_row(
+                "SELECT COUNT(*) FROM t WHERE y != x * 2 AND id != 5;",
+                (),
+                |row| row.get(0),
+            )
+            .unwrap();
+        assert_eq!(wrong, 0);
+        let kept: i64 = conn
+            .query_row("SELECT y FROM t WHERE id = 5;", (), |row| row.get(0))
+            .unwrap();
+        assert_eq!(kept, -1);
+    }
+
+    #[test]
+    fn legacy_scopes_made_explicit() {
+        let conn = Connection::open_in_memory().unwrap();
+        apply(&conn, Some(18), |_, _| {}).unwrap();
+        conn.execute_batch(
+            "
+                INSERT INTO api_keys (api_key, salt, fingerprint, created_at, email, tier, expires_at, scopes)
+                VALUES ('legacy', '', 'a', '2024-01-01T00:00:00Z', 'a@example.com', NULL, NULL, 0),
+                       ('issued', '', 'b', '2024-01-01T00:00:00Z', 'b@example.com', NULL, NULL, 4);",
+        )
+        .unwrap();
+
+        apply(&conn, Some(19), |_, _| {}).unwrap();
+        let scopes: Vec<ScopeSet> = conn
+            .prepare("SELECT scopes FROM api_keys ORDER BY api_key;")
+            .unwrap()
+            .query_map((), |row| row.get(0))
+            .unwrap()
+            .collect::<rusqlite::Result<_>>()
+            .unwrap();
+        assert_eq!(
+            scopes,
+            [
+                [KeyScope::Admin].into_iter().collect(),
+                ScopeSet::SELF_SERVICE
+            ]
+        );
+    }
+}
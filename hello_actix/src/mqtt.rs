@@ -0,0 +1,168 @@
+//! Bridges conversions to an MQTT broker, for IoT devices that don't speak
+//! HTTP well. Needs the `mqtt` feature and `mqtt_broker` set.
+//!
+//! The bridge subscribes to `mqtt_topics`. A device publishes a reading as
+//! `{"api_key": "...", "value": 25.0}` to a topic ending in `/to-celsius` or
+//! `/to-fahrenheit`, authenticating with its own API key. The result is
+//! published to the same topic plus `/converted`, as the HTTP endpoints
+//! would return it, or an error body to the topic plus `/error`. Other
+//! topics matching the filters are ignored.
+
+#[cfg(feature = "mqtt")]
+pub use bridge::spawn;
+
+/// Warns when a broker is configured without the feature to use it.
+#[cfg(not(feature = "mqtt"))]
+pub fn spawn(
+    config: actix_web::web::Data<crate::config::Config>,
+    _database: actix_web::web::Data<crate::db::Pool>,
+    _key_cache: actix_web::web::Data<crate::auth::KeyCache>,
+) {
+    if config.mqtt_broker.is_some() {
+        tracing::warn!("an MQTT broker is configured but the `mqtt` feature is disabled");
+    }
+}
+
+#[cfg(feature = "mqtt")]
+mod bridge {
+    use std::time::Duration;
+
+    use actix_web::{rt, web};
+    use rumqttc::{AsyncClient, Event, MqttOptions, Packet, Publish, QoS};
+    use serde::Deserialize;
+    use tracing::{info, warn};
+
+    use crate::auth::KeyCache;
+    use crate::config::Config;
+    use crate::error::ApiError;
+    use crate::i18n::Lang;
+    use crate::{db, gateway, tasks};
+
+    const DEFAULT_PORT: u16 = 1883;
+    const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+
+    #[derive(Deserialize)]
+    struct Reading {
+        api_key: String,
+        value: f32,
+    }
+
+    /// Connects to the broker in the background, reconnecting and
+    /// resubscribing whenever the connection drops. Call once, from `main`.
+    pub fn spawn(
+        config: web::Data<Config>,
+        database: web::Data<db::Pool>,
+        key_cache: web::Data<KeyCache>,
+    ) {
+        let Some(broker) = config.mqtt_broker.clone() else {
+            return;
+        };
+        let (host, port) = match broker.rsplit_once(':') {
+            Some((host, port)) => (host.to_owned(), port.parse().unwrap_or(DEFAULT_PORT)),
+            None => (broker, DEFAULT_PORT),
+        };
+
+        let mut options = MqttOptions::new(&config.mqtt_client_id, host, port);
+        options.set_keep_alive(Duration::from_secs(30));
+        if let (Some(username), Some(password)) = (&config.mqtt_username, &config.mqtt_password) {
+            options.set_credentials(username, password);
+        }
+
+        // A fresh connection on every start, as a restart follows a panic.
+        tasks::supervise("mqtt_client", move |_| {
+            run(
+                options.clone(),
+                config.clone(),
+                database.clone(),
+                key_cache.clone(),
+            )
+        });
+    }
+
+    async fn run(
+        options: MqttOptions,
+        config: web::Data<Config>,
+        database: web::Data<db::Pool>,
+        key_cache: web::Data<KeyCache>,
+    ) {
+        let (client, mut eventloop) = AsyncClient::new(options, 64);
+        let topics = config.mqtt_topic_filters();
+
+        loop {
+            match eventloop.poll().await {
+                Ok(Event::Incoming(Packet::ConnAck(_))) => {
+                    info!(?topics, "connected to MQTT broker");
+                    for topic in &topics {
+                        if let Err(err) = client.try_subscribe(topic, QoS::AtLeastOnce) {
+                            warn!(%err, topic, "failed to subscribe");
+                        }
+                    }
+                }
+                Ok(Event::Incoming(Packet::Publish(publish))) => {
+                    rt::spawn(handle(
+                        client.clone(),
+                        config.clone(),
+                        database.clone(),
+                        key_cache.clone(),
+                        publish,
+                    ));
+                }
+                Ok(_) => {}
+                Err(err) => {
+                    warn!(%err, "MQTT connection failed; retrying");
+                    rt::time::sleep(RECONNECT_DELAY).await;
+                }
+            }
+        }
+    }
+
+    async fn handle(
+        client: AsyncClient,
+        config: web::Data<Config>,
+        database: web::Data<db::Pool>,
+        key_cache: web::Data<KeyCache>,
+        publish: Publish,
+    ) {
+        let topic = publish.topic;
+        let Some(endpoint) = topic
+            .rsplit('/')
+            .next()
+            .and_then(|last| last.parse::<db::ApiEndpoint>().ok())
+        else {
+            return;
+        };
+
+        let result = match serde_json::from_slice::<Reading>(&publish.payload) {
+            Ok(reading) => {
+                gateway::convert(
+                    database,
+                    &key_cache,
+                    &config,
+                    &reading.api_key,
+                    endpoint,
+                    reading.value,
+                )
+                .await
+            }
+            Err(_) => Err(ApiError::InvalidReading),
+        };
+
+        let (suffix, payload) = match result {
+            Ok(temperature) => ("converted", serde_json::to_vec(&temperature)),
+            Err(err) => ("error", serde_json::to_vec(&err.body(Lang::default()))),
+        };
+        let payload = payload.expect("serializing to memory cannot fail");
+
+        if let Err(err) = client
+            .publish(
+                format!("{topic}/{suffix}"),
+                QoS::AtLeastOnce,
+                false,
+                payload,
+            )
+            .await
+        {
+            warn!(%err, topic, "failed to publish conversion");
+        }
+    }
+}
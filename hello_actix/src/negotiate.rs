@@ -0,0 +1,117 @@
+//! Response bodies that follow the request's `Accept` header: Protocol
+//! Buffers for `application/protobuf` in builds with the `protobuf`
+//! feature, JSON otherwise. The messages live in
+//! `conversion_core::proto`, so clients can share them.
+//!
+//! JSON bodies come wrapped as `{ "data": ..., "meta": ... }` when the
+//! query string has `envelope=true`, name their fields in the case of
+//! [`crate::casing::field_case`], and keep only those in `?fields=` if
+//! given; see [`crate::fields`].
+
+use std::time::Instant;
+
+use actix_web::body::BoxBody;
+use actix_web::{web, HttpMessage as _, HttpRequest, HttpResponse, Responder};
+use serde::{Deserialize, Serialize};
+use tracing_actix_web::RequestId;
+
+use crate::casing;
+use crate::fields::Partial;
+
+pub const PROTOBUF: &str = "application/protobuf";
+
+/// A body with a Protocol Buffers encoding besides its JSON one.
+pub trait Encode: Serialize {
+    #[cfg(feature = "protobuf")]
+    fn encode_protobuf(&self) -> Vec<u8>;
+}
+
+/// Responds with `T` in the encoding the client asked for.
+pub struct Negotiated<T>(pub T);
+
+/// Whether `Accept` names any of `media_types`. Quality values are ignored,
+/// as clients that want these formats send nothing else.
+pub fn accepts(req: &HttpRequest, media_types: &[&str]) -> bool {
+    req.headers()
+        .get(actix_web::http::header::ACCEPT)
+        .and_then(|accept| accept.to_str().ok())
+        .is_some_and(|accept| {
+            accept.split(',').any(|media_type| {
+                let media_type = media_type.split(';').next().unwrap_or_default().trim();
+                media_types
+                    .iter()
+                    .any(|wanted| media_type.eq_ignore_ascii_case(wanted))
+            })
+        })
+}
+
+/// Whether `Accept` names Protocol Buffers.
+pub fn wants_protobuf(req: &HttpRequest) -> bool {
+    accepts(req, &[PROTOBUF, "application/x-protobuf"])
+}
+
+/// When handling of the request began, for [`Meta::duration_ms`]. Set by
+/// [`crate::usage::record_usage`].
+#[derive(Debug, Clone, Copy)]
+pub struct Started(pub Instant);
+
+#[derive(Deserialize)]
+struct EnvelopeParams {
+    #[serde(default)]
+    envelope: bool,
+}
+
+#[derive(Serialize)]
+struct Envelope<T> {
+    data: T,
+    meta: Meta,
+}
+
+#[derive(Serialize)]
+pub struct Meta {
+    /// Assigned by the tracing middleware, so it matches the logs.
+    pub request_id: Option<String>,
+    pub duration_ms: Option<u64>,
+    pub version: &'static str,
+}
+
+impl Meta {
+    fn of(req: &HttpRequest) -> Self {
+        let extensions = req.extensions();
+
+        Meta {
+            request_id: extensions.get::<RequestId>().map(ToString::to_string),
+            duration_ms: extensions
+                .get::<Started>()
+                .map(|started| started.0.elapsed().as_millis() as u64),
+            version: env!("CARGO_PKG_VERSION"),
+        }
+    }
+}
+
+/// Whether the query string asks for an [`Envelope`]. Malformed values
+/// count as no.
+pub fn wants_envelope(req: &HttpRequest) -> bool {
+    web::Query::<EnvelopeParams>::from_query(req.query_string()).is_ok_and(|params| params.envelope)
+}
+
+impl<T: Encode> Responder for Negotiated<T> {
+    type Body = BoxBody;
+
+    fn respond_to(self, req: &HttpRequest) -> HttpResponse {
+        #[cfg(feature = "protobuf")]
+        if wants_protobuf(req) {
+            return HttpResponse::Ok()
+                .content_type(PROTOBUF)
+                .body(self.0.encode_protobuf());
+        }
+
+        if wants_envelope(req) {
+            let meta = Meta::of(req);
+            let data = Partial::of(req, self.0);
+            return casing::json_response(req, Envelope { data, meta });
+        }
+
+        casing::json_response(req, Partial::of(req, self.0))
+    }
+}
@@ -0,0 +1,172 @@
+//! Delivery of notifications, such as scheduled reports and signup links,
+//! to an email address or a webhook.
+
+use std::io::Write;
+use std::process::{Command, Stdio};
+use std::sync::Arc;
+
+use actix_web::web;
+use rusqlite::types::{FromSql, FromSqlError, FromSqlResult, ToSql, ToSqlOutput, ValueRef};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::config::Config;
+use crate::error::ApiError;
+use crate::signup;
+
+/// Where to send a notification. Stored as a `mailto:` URI or the webhook's
+/// URL.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Target {
+    Email(String),
+    /// An `http` or `https` URL, which is POSTed the notification as JSON.
+    Webhook(String),
+}
+
+impl Target {
+    /// Normalizes the address, or rejects a target that can't be delivered
+    /// to.
+    pub fn validated(self) -> Result<Self, ApiError> {
+        match self {
+            Target::Email(email) => Ok(Target::Email(signup::normalize_email(&email)?)),
+            Target::Webhook(url) => {
+                let valid = ["http://", "https://"].iter().any(|scheme| {
+                    url.strip_prefix(scheme)
+                        .is_some_and(|rest| !rest.is_empty() && !rest.contains(char::is_whitespace))
+                });
+                if valid {
+                    Ok(Target::Webhook(url))
+                } else {
+                    Err(ApiError::InvalidWebhookUrl)
+                }
+            }
+        }
+    }
+}
+
+impl ToSql for Target {
+    fn to_sql(&self) -> rusqlite::Result<ToSqlOutput<'_>> {
+        Ok(match self {
+            Target::Email(email) => ToSqlOutput::from(format!("mailto:{email}")),
+            Target::Webhook(url) => ToSqlOutput::from(url.as_str()),
+        })
+    }
+}
+
+impl FromSql for Target {
+    fn column_result(value: ValueRef<'_>) -> FromSqlResult<Self> {
+        let value = value.as_str()?;
+        match value.strip_prefix("mailto:") {
+            Some(email) => Ok(Target::Email(email.to_owned())),
+            None if value.starts_with("http") => Ok(Target::Webhook(value.to_owned())),
+            None => Err(FromSqlError::InvalidType),
+        }
+    }
+}
+
+/// Sends email. Blocking, so [`deliver`] runs it off the async workers.
+pub trait Mailer: Send + Sync {
+    fn send(&self, to: &str, subject: &str, text: &str) -> Result<(), String>;
+}
+
+/// Hands each message to the local MTA through the `sendmail` interface,
+/// which Postfix, Exim and msmtp all provide.
+pub struct Sendmail {
+    /// Path of the binary, e.g. `/usr/sbin/sendmail`.
+    pub command: String,
+    pub from: String,
+}
+
+impl Mailer for Sendmail {
+    fn send(&self, to: &str, subject: &str, text: &str) -> Result<(), String> {
+        let mut child = Command::new(&self.command)
+            .args(["-i", "-f", &self.from, "--", to])
+            .stdin(Stdio::piped())
+            .spawn()
+            .map_err(|err| format!("failed to start {}: {err}", self.command))?;
+
+        let message = format!(
+            "From: {}\r\nTo: {to}\r\nSubject: {subject}\r\n\
+             Content-Type: text/plain; charset=utf-8\r\n\r\n{text}\r\n",
+            self.from
+        );
+        let written = child
+            .stdin
+            .take()
+            .map_or(Ok(()), |mut stdin| stdin.write_all(message.as_bytes()));
+        let status = child.wait().map_err(|err| err.to_string())?;
+        written.map_err(|err| err.to_string())?;
+
+        if status.success() {
+            Ok(())
+        } else {
+            Err(format!("{} exited with {status}", self.command))
+        }
+    }
+}
+
+/// Stands in where no transport is configured. Every message fails, and
+/// its text, which may be a link that signs its reader in, goes nowhere.
+pub struct NoMail;
+
+impl Mailer for NoMail {
+    fn send(&self, _to: &str, _subject: &str, _text: &str) -> Result<(), String> {
+        Err("no mail transport configured; set SENDMAIL".into())
+    }
+}
+
+/// [`Sendmail`] if [`Config::sendmail`] is set, else [`NoMail`].
+pub fn mailer(config: &Config) -> Arc<dyn Mailer> {
+    match &config.sendmail {
+        Some(command) => Arc::new(Sendmail {
+            command: command.clone(),
+            from: config.mail_from.clone(),
+        }),
+        None => Arc::new(NoMail),
+    }
+}
+
+/// Sends `body`, with `subject` for emails, which carry a string body as
+/// it is and anything else as JSON. Failures are returned for the caller
+/// to log; nothing is retried.
+pub async fn deliver(
+    mailer: web::Data<dyn Mailer>,
+    target: &Target,
+    subject: &str,
+    body: &Value,
+) -> Result<(), String> {
+    match target {
+        Target::Email(email) => {
+            let (email, subject) = (email.clone(), subject.to_owned());
+            let text = match body {
+                Value::String(text) => text.clone(),
+                body => serde_json::to_string_pretty(body).map_err(|err| err.to_string())?,
+            };
+            web::block(move || mailer.send(&email, &subject, &text))
+                .await
+                .map_err(|err| err.to_string())?
+        }
+        Target::Webhook(url) => post_webhook(url, body).await,
+    }
+}
+
+#[cfg(feature = "webhooks")]
+async fn post_webhook(url: &str, body: &Value) -> Result<(), String> {
+    let response = awc::Client::default()
+        .post(url)
+        .send_json(body)
+        .await
+        .map_err(|err| err.to_string())?;
+
+    if response.status().is_success() {
+        Ok(())
+    } else {
+        Err(format!("webhook answered {}", response.status()))
+    }
+}
+
+#[cfg(not(feature = "webhooks"))]
+async fn post_webhook(_url: &str, _body: &Value) -> Result<(), String> {
+    Err("webhooks need a build with the `webhooks` feature".into())
+}
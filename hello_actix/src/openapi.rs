@@ -0,0 +1,47 @@
+use actix_web::http::header::ContentType;
+use actix_web::{get, web, HttpResponse, Responder};
+
+use crate::config::Config;
+
+/// Hand-maintained description of the HTTP API, served for client
+/// generators. `tests/contract.rs` checks real responses against it, so
+/// update both together.
+pub const SPEC: &str = include_str!("openapi.json");
+
+/// Swagger UI pointed at [`SPEC`], loaded from a CDN by the browser.
+const SWAGGER_UI: &str = r##"<!doctype html>
+<html lang="en">
+<head>
+  <meta charset="utf-8">
+  <title>hello_actix API</title>
+  <link rel="stylesheet" href="https://unpkg.com/swagger-ui-dist@5.17.14/swagger-ui.css">
+</head>
+<body>
+  <div id="swagger-ui"></div>
+  <script src="https://unpkg.com/swagger-ui-dist@5.17.14/swagger-ui-bundle.js" crossorigin></script>
+  <script>
+    window.ui = SwaggerUIBundle({ url: "/openapi.json", dom_id: "#swagger-ui" });
+  </script>
+</body>
+</html>
+"##;
+
+#[get("/openapi.json")]
+pub async fn openapi_json() -> impl Responder {
+    HttpResponse::Ok()
+        .content_type(ContentType::json())
+        .body(SPEC)
+}
+
+/// Swagger UI, unless switched off with [`Config::swagger_ui`], in which
+/// case `/docs` is as unknown as any other path.
+#[get("/docs")]
+pub async fn swagger_ui(config: web::Data<Config>) -> impl Responder {
+    if !config.swagger_ui {
+        return HttpResponse::NotFound().finish();
+    }
+
+    HttpResponse::Ok()
+        .content_type(ContentType::html())
+        .body(SWAGGER_UI)
+}
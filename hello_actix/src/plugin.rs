@@ -0,0 +1,105 @@
+//! Optional subsystems, compiled in or out with Cargo features and hooked
+//! into the server uniformly: routes, schema and background tasks.
+
+use std::sync::Arc;
+
+use actix_web::web;
+
+use crate::db;
+
+/// One optional subsystem. Every hook defaults to doing nothing.
+pub trait Plugin: Send + Sync {
+    /// Used in logs and panic messages.
+    fn name(&self) -> &'static str;
+
+    /// Creates or patches the plugin's tables. Runs once at startup, after
+    /// [`db::setup`], so it may reference core tables.
+    fn migrate(&self, _conn: &rusqlite::Connection) -> rusqlite::Result<()> {
+        Ok(())
+    }
+
+    /// Routes under `/api`, behind API-key authentication and the
+    /// concurrency limit.
+    fn configure_api(&self, _cfg: &mut web::ServiceConfig) {}
+
+    /// Routes under `/admin`. Guard handlers with [`crate::rbac::Authorized`].
+    fn configure_admin(&self, _cfg: &mut web::ServiceConfig) {}
+
+    /// Routes at the top level.
+    fn configure(&self, _cfg: &mut web::ServiceConfig) {}
+
+    /// Starts background work, as [`crate::scheduler::spawn`] does.
+    fn spawn_tasks(&self, _database: web::Data<db::Pool>) {}
+}
+
+/// The plugins compiled into this build. Cheap to clone into each worker.
+#[derive(Clone, Default)]
+pub struct PluginRegistry {
+    plugins: Vec<Arc<dyn Plugin>>,
+}
+
+impl PluginRegistry {
+    pub fn new() -> Self {
+        PluginRegistry::default()
+    }
+
+    /// Every plugin whose feature is enabled.
+    pub fn compiled_in() -> Self {
+        #[allow(unused_mut)]
+        let mut registry = PluginRegistry::new();
+
+        #[cfg(feature = "dashboard")]
+        registry.register(crate::dashboard::Dashboard);
+
+        #[cfg(feature = "scripting")]
+        registry.register(crate::scripting::Scripting);
+
+        #[cfg(feature = "pprof")]
+        registry.register(crate::profiling::Profiling);
+
+        registry
+    }
+
+    pub fn register(&mut self, plugin: impl Plugin + 'static) {
+        self.plugins.push(Arc::new(plugin));
+    }
+
+    pub fn names(&self) -> Vec<&'static str> {
+        self.plugins.iter().map(|plugin| plugin.name()).collect()
+    }
+
+    /// Like [`db::setup`], not meant to run while the API is live.
+    pub fn migrate(&self, pool: &db::Pool) {
+        let conn = pool.get().expect("unable to connect to the database");
+
+        for plugin in &self.plugins {
+            plugin
+                .migrate(&conn)
+                .unwrap_or_else(|err| panic!("migrating plugin `{}`: {err}", plugin.name()));
+        }
+    }
+
+    pub fn configure_api(&self, cfg: &mut web::ServiceConfig) {
+        for plugin in &self.plugins {
+            plugin.configure_api(cfg);
+        }
+    }
+
+    pub fn configure_admin(&self, cfg: &mut web::ServiceConfig) {
+        for plugin in &self.plugins {
+            plugin.configure_admin(cfg);
+        }
+    }
+
+    pub fn configure(&self, cfg: &mut web::ServiceConfig) {
+        for plugin in &self.plugins {
+            plugin.configure(cfg);
+        }
+    }
+
+    pub fn spawn_tasks(&self, database: web::Data<db::Pool>) {
+        for plugin in &self.plugins {
+            plugin.spawn_tasks(database.clone());
+        }
+    }
+}
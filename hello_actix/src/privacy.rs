@@ -0,0 +1,96 @@
+//! Data subject requests against what is stored about an API key and its
+//! owner.
+
+use actix_web::http::header::{ContentDisposition, DispositionParam, DispositionType};
+use actix_web::{delete, get, web, HttpRequest, HttpResponse, Responder};
+use actix_web_httpauth::extractors::basic::BasicAuth;
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use tracing::{info, instrument};
+
+use crate::error::ApiError;
+use crate::rbac::{Admin, Authorized};
+use crate::{auth, clock, db};
+
+/// Erases everything stored about the owner of key `id`: usage of every key
+/// issued to the same address, pending signups, and the keys' secrets and
+/// address. The keys stop working immediately. A tombstone recording the
+/// erasure, but no personal data, is kept and returned.
+#[delete("/keys/{id}/data")]
+#[instrument(skip_all, fields(key_id = *id))]
+pub async fn erase_key_data(
+    admin: Authorized<Admin>,
+    id: web::Path<i64>,
+    database: web::Data<db::Pool>,
+    key_cache: web::Data<auth::KeyCache>,
+) -> actix_web::Result<impl Responder> {
+    let id = id.into_inner();
+
+    let owner = db::run(database.clone(), move |conn| {
+        auth::owner_keys(conn, id).map_err(|err| err.to_string())
+    })
+    .await?
+    .ok_or(ApiError::KeyNotFound)?;
+
+    key_cache
+        .forget(&owner.api_keys)
+        .map_err(|_| ApiError::Internal)?;
+
+    let erasure = db::erase_owner_data(
+        database,
+        owner.ids,
+        owner.api_keys,
+        owner.email,
+        admin.principal.name(),
+    )
+    .await?;
+
+    info!(
+        key_ids = ?erasure.key_ids,
+        usage_rows = erasure.usage_rows,
+        "erased key owner data"
+    );
+
+    Ok(HttpResponse::Ok().json(erasure))
+}
+
+/// Everything stored about one key, as handed to its owner.
+#[derive(Debug, Serialize)]
+pub struct DataExport {
+    pub exported_at: DateTime<Utc>,
+    pub key: db::ApiKeyRecord,
+    pub usage: Vec<db::UsageRecord>,
+}
+
+/// Answers a data access request for the calling key with a JSON document
+/// holding its metadata and every usage row recorded for it.
+#[get("/data-export")]
+#[instrument(skip_all)]
+pub async fn export_my_data(
+    auth: BasicAuth,
+    database: web::Data<db::Pool>,
+    req: HttpRequest,
+) -> actix_web::Result<impl Responder> {
+    let api_key = auth.user_id().to_owned();
+    let now = clock::now(&req);
+
+    let api_key_ = api_key.clone();
+    let key = db::run(database.clone(), move |conn| {
+        auth::find_api_key(conn, &api_key_, now).map_err(|err| err.to_string())
+    })
+    .await?
+    .ok_or(ApiError::Unauthorized)?;
+
+    let export = DataExport {
+        exported_at: now,
+        key,
+        usage: db::usage_of_key(database, api_key).await?,
+    };
+
+    Ok(HttpResponse::Ok()
+        .insert_header(ContentDisposition {
+            disposition: DispositionType::Attachment,
+            parameters: vec![DispositionParam::Filename("data-export.json".into())],
+        })
+        .json(export))
+}
@@ -0,0 +1,114 @@
+//! CPU profiles of a live instance, taken at `GET /admin/debug/pprof` for a
+//! bounded duration with [pprof-rs]. A [`Plugin`] compiled in with the
+//! `pprof` feature.
+//!
+//! The profile comes as a flamegraph to open in a browser, or with
+//! `format=protobuf` for `go tool pprof`. One is taken at a time, as the
+//! profiler samples the whole process.
+//!
+//! [pprof-rs]: https://github.com/tikv/pprof-rs
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+use actix_web::{get, rt, web, HttpResponse};
+use pprof::protos::Message as _;
+use serde::Deserialize;
+use tracing::{info, warn};
+
+use crate::error::ApiError;
+use crate::plugin::Plugin;
+use crate::rbac::{Admin, Authorized};
+
+const DEFAULT_SECONDS: u64 = 10;
+const MAX_SECONDS: u64 = 60;
+const DEFAULT_FREQUENCY: i32 = 99;
+const MAX_FREQUENCY: i32 = 1000;
+
+/// Whether a profile is being taken.
+static PROFILING: AtomicBool = AtomicBool::new(false);
+
+pub struct Profiling;
+
+impl Plugin for Profiling {
+    fn name(&self) -> &'static str {
+        "pprof"
+    }
+
+    fn configure_admin(&self, cfg: &mut web::ServiceConfig) {
+        cfg.service(profile);
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Format {
+    #[default]
+    Flamegraph,
+    Protobuf,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ProfileParams {
+    /// How long to sample for, up to [`MAX_SECONDS`].
+    seconds: Option<u64>,
+    /// Samples per second, up to [`MAX_FREQUENCY`].
+    frequency: Option<i32>,
+    #[serde(default)]
+    format: Format,
+}
+
+/// Lets the next profile start however this one ends.
+struct Taking;
+
+impl Drop for Taking {
+    fn drop(&mut self) {
+        PROFILING.store(false, Ordering::Relaxed);
+    }
+}
+
+/// Samples the process for the requested time, then answers with the
+/// profile. 409 while another is being taken.
+#[get("/debug/pprof")]
+pub async fn profile(
+    _: Authorized<Admin>,
+    params: web::Query<ProfileParams>,
+) -> actix_web::Result<HttpResponse> {
+    if PROFILING.swap(true, Ordering::Relaxed) {
+        return Err(ApiError::ProfileInProgress.into());
+    }
+    let _taking = Taking;
+
+    let seconds = params.seconds.unwrap_or(DEFAULT_SECONDS).min(MAX_SECONDS);
+    let frequency = params
+        .frequency
+        .unwrap_or(DEFAULT_FREQUENCY)
+        .clamp(1, MAX_FREQUENCY);
+    info!(seconds, frequency, "taking a CPU profile");
+
+    let failed = |err: pprof::Error| {
+        warn!(%err, "failed to take a CPU profile");
+        ApiError::Internal
+    };
+    let guard = pprof::ProfilerGuardBuilder::default()
+        .frequency(frequency)
+        .blocklist(&["libc", "libgcc", "pthread", "vdso"])
+        .build()
+        .map_err(failed)?;
+    rt::time::sleep(Duration::from_secs(seconds)).await;
+    let report = guard.report().build().map_err(failed)?;
+
+    match params.format {
+        Format::Flamegraph => {
+            let mut svg = Vec::new();
+            report.flamegraph(&mut svg).map_err(failed)?;
+            Ok(HttpResponse::Ok().content_type("image/svg+xml").body(svg))
+        }
+        Format::Protobuf => {
+            let profile = report.pprof().map_err(failed)?;
+            Ok(HttpResponse::Ok()
+                .content_type("application/octet-stream")
+                .body(profile.encode_to_vec()))
+        }
+    }
+}
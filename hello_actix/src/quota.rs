@@ -0,0 +1,405 @@
+//! Monthly call quotas, per tier (see [`Config::monthly_quota`]) or set for
+//! a key at `PUT /admin/keys/{id}/quota`. Keys get an `X-Quota-Warning`
+//! header once past 80% and 95% of theirs, and their owner (plus the ops
+//! webhook, if configured) is told once per month at each. Past 100%,
+//! [`enforce_quota`] turns their conversions away until the next month.
+
+use actix_web::body::MessageBody;
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::error::InternalError;
+use actix_web::http::header::{HeaderName, HeaderValue};
+use actix_web::middleware::Next;
+use actix_web::{put, web, Error, HttpResponse, Responder};
+use actix_web_httpauth::extractors::basic::BasicAuth;
+use chrono::{DateTime, Datelike, SecondsFormat, Utc};
+use dashmap::DashMap;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use tracing::{info_span, warn, Instrument as _};
+
+use crate::config::Config;
+use crate::db::{self, ApiEndpoint, ApiUsage};
+use crate::error::ApiError;
+use crate::i18n::Lang;
+use crate::notify::{self, Target};
+use crate::rbac::{Authorized, Operator};
+use crate::throttling::{self, Rule};
+use crate::usage::{Recorded, UsageSink};
+use crate::{auth, clock, report, tasks};
+
+const X_QUOTA_WARNING: HeaderName = HeaderName::from_static("x-quota-warning");
+const X_QUOTA_LIMIT: HeaderName = HeaderName::from_static("x-quota-limit");
+const X_QUOTA_REMAINING: HeaderName = HeaderName::from_static("x-quota-remaining");
+const X_QUOTA_RESET: HeaderName = HeaderName::from_static("x-quota-reset");
+
+/// Percentages of the quota that trigger a warning, highest first.
+const THRESHOLDS: [u64; 2] = [95, 80];
+
+/// A calendar month, as `(year, month)`.
+type Period = (i32, u32);
+
+fn period_of(at: DateTime<Utc>) -> Period {
+    (at.year(), at.month())
+}
+
+/// The monthly quota of key `access`: its own if set, else its tier's.
+/// `None` if unlimited.
+pub fn quota_of(config: &Config, access: &auth::KeyAccess) -> Option<u64> {
+    match access.monthly_quota {
+        Some(0) => None,
+        Some(quota) => Some(quota),
+        None => config.monthly_quota(access.tier),
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Tally {
+    period: Period,
+    used: u64,
+    /// Calls let through [`enforce_quota`] and not yet in `used`.
+    reserved: u64,
+    /// The highest threshold already warned about this period.
+    warned: u64,
+}
+
+/// Calls per key this month, kept in memory once counted from the `usage`
+/// table. Keyed by fingerprint, like [`crate::concurrency`]. Add it to the
+/// [`UsageSink`]s so it keeps counting.
+///
+/// With anonymous usage nothing is stored per key, so counts start from
+/// zero at every restart.
+#[derive(Debug, Default)]
+pub struct QuotaUsage {
+    tallies: DashMap<String, Tally>,
+}
+
+impl QuotaUsage {
+    pub fn new() -> Self {
+        QuotaUsage::default()
+    }
+
+    /// Keys with a tally for some month.
+    pub fn entries(&self) -> usize {
+        self.tallies.len()
+    }
+
+    /// Calls `api_key` has made in the month of `now`.
+    pub async fn used(
+        &self,
+        database: web::Data<db::Pool>,
+        api_key: &str,
+        now: DateTime<Utc>,
+    ) -> Result<u64, Error> {
+        let fingerprint = auth::fingerprint(api_key);
+        let period = period_of(now);
+        if let Some(tally) = self.tallies.get(&fingerprint) {
+            if tally.period == period {
+                return Ok(tally.used);
+            }
+        }
+
+        let (from, to) = report::month_bounds(period.0, period.1).ok_or(ApiError::Internal)?;
+        let daily = db::daily_usage_of_key(database, api_key.to_owned(), from, to).await?;
+        let used = daily.iter().map(|count| count.calls).sum();
+
+        let tally = *self
+            .tallies
+            .entry(fingerprint)
+            .and_modify(|tally| {
+                if tally.period != period {
+                    *tally = Tally {
+                        period,
+                        used,
+                        reserved: 0,
+                        warned: 0,
+                    };
+                }
+            })
+            .or_insert(Tally {
+                period,
+                used,
+                reserved: 0,
+                warned: 0,
+            });
+        Ok(tally.used)
+    }
+
+    /// Holds one of `api_key`'s `quota` calls this month for a call about to
+    /// be made, or `None` if they are all used or held. Checked and taken
+    /// under one lock, so concurrent calls can't both take the last one.
+    pub async fn reserve(
+        &self,
+        database: web::Data<db::Pool>,
+        api_key: &str,
+        now: DateTime<Utc>,
+        quota: u64,
+    ) -> Result<Option<Reservation<'_>>, Error> {
+        let used = self.used(database, api_key, now).await?;
+        let fingerprint = auth::fingerprint(api_key);
+        let period = period_of(now);
+
+        let fresh = Tally {
+            period,
+            used,
+            reserved: 0,
+            warned: 0,
+        };
+        let mut tally = self.tallies.entry(fingerprint.clone()).or_insert(fresh);
+        if tally.period != period {
+            *tally = fresh;
+        }
+        if tally.used + tally.reserved >= quota {
+            return Ok(None);
+        }
+        tally.reserved += 1;
+
+        Ok(Some(Reservation {
+            usage: self,
+            fingerprint,
+            period,
+        }))
+    }
+
+    /// Whether `threshold` is news for `api_key` this period, remembering
+    /// that it no longer is.
+    fn first_warning(&self, api_key: &str, threshold: u64) -> bool {
+        let Some(mut tally) = self.tallies.get_mut(&auth::fingerprint(api_key)) else {
+            return false;
+        };
+        if tally.warned >= threshold {
+            return false;
+        }
+        tally.warned = threshold;
+        true
+    }
+}
+
+/// A call held by [`QuotaUsage::reserve`], given back when dropped: by then
+/// a call that went through is in `used`, having been recorded, and one
+/// that failed or was cancelled doesn't count.
+pub struct Reservation<'a> {
+    usage: &'a QuotaUsage,
+    fingerprint: String,
+    period: Period,
+}
+
+impl Drop for Reservation<'_> {
+    fn drop(&mut self) {
+        if let Some(mut tally) = self.usage.tallies.get_mut(&self.fingerprint) {
+            if tally.period == self.period {
+                tally.reserved = tally.reserved.saturating_sub(1);
+            }
+        }
+    }
+}
+
+/// Counts calls for keys already tallied this period; others are counted
+/// from the database when first needed.
+impl UsageSink for QuotaUsage {
+    fn record(&self, usage: &ApiUsage) -> Recorded {
+        let period = period_of(usage.called_at);
+        if let Some(mut tally) = self.tallies.get_mut(&auth::fingerprint(&usage.api_key)) {
+            if tally.period == period {
+                tally.used += 1;
+            }
+        }
+
+        Box::pin(std::future::ready(()))
+    }
+}
+
+/// Adds `X-Quota-Warning: threshold=80; used=8012; quota=10000` to
+/// successful responses once a key is past a threshold of its monthly
+/// quota, and notifies the first time each threshold is crossed.
+///
+/// Must run inside the authentication middleware, and outside
+/// [`crate::usage::record_usage`] so the call itself is counted.
+pub async fn warn_quota(
+    mut req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    let credentials = req.extract::<BasicAuth>().await?;
+    let mut res = next.call(req).await?;
+    if !res.status().is_success() {
+        return Ok(res);
+    }
+
+    let api_key = credentials.user_id();
+    let request = res.request();
+    let (Some(config), Some(database), Some(quota_usage), Some(mailer)) = (
+        request.app_data::<web::Data<Config>>().cloned(),
+        request.app_data::<web::Data<db::Pool>>().cloned(),
+        request.app_data::<web::Data<QuotaUsage>>().cloned(),
+        request.app_data::<web::Data<dyn notify::Mailer>>().cloned(),
+    ) else {
+        return Ok(res);
+    };
+    let Ok(Some(access)) = auth::key_access(request, api_key) else {
+        return Ok(res);
+    };
+    let Some(quota) = quota_of(&config, &access) else {
+        return Ok(res);
+    };
+
+    let now = clock::now(request);
+    let used = match quota_usage.used(database.clone(), api_key, now).await {
+        Ok(used) => used,
+        Err(err) => {
+            warn!(%err, "failed to count usage against quota");
+            return Ok(res);
+        }
+    };
+    let Some(threshold) = THRESHOLDS
+        .into_iter()
+        .find(|threshold| used * 100 >= threshold * quota)
+    else {
+        return Ok(res);
+    };
+
+    let warning = format!("threshold={threshold}; used={used}; quota={quota}");
+    if let Ok(value) = HeaderValue::from_str(&warning) {
+        res.headers_mut().insert(X_QUOTA_WARNING, value);
+    }
+
+    if quota_usage.first_warning(api_key, threshold) {
+        let (year, month) = period_of(now);
+        let query = db::Query::RecordQuotaWarning {
+            key_id: access.id,
+            period: format!("{year}-{month:02}"),
+            threshold,
+        };
+        // Another instance, or this one before a restart, may have sent it.
+        match query.execute(database.clone()).await {
+            Ok(Some(true)) => {
+                let warning = json!({
+                    "key_id": access.id,
+                    "period": format!("{year}-{month:02}"),
+                    "threshold": threshold,
+                    "used": used,
+                    "quota": quota,
+                });
+                let key_id = access.id;
+                tasks::spawn("quota_warning", |task| {
+                    send_warning(task, database, config, mailer, key_id, threshold, warning)
+                        .instrument(info_span!("quota_warning", key_id))
+                });
+            }
+            Ok(_) => {}
+            Err(err) => warn!(%err, "failed to record quota warning"),
+        }
+    }
+
+    Ok(res)
+}
+
+/// Rejects a metered call (see [`ApiEndpoint::is_metered`]) with 429 once
+/// its key has used up its monthly quota, with `X-Quota-Limit`,
+/// `X-Quota-Remaining` and `X-Quota-Reset`, when the next month starts.
+/// Calls in flight count as used until they finish.
+///
+/// Must run inside the authentication middleware, and outside
+/// [`crate::usage::record_usage`] so calls are counted before their
+/// reservation is given back.
+pub async fn enforce_quota(
+    mut req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    let metered = req
+        .match_pattern()
+        .and_then(|pattern| ApiEndpoint::for_route(req.method().as_str(), &pattern))
+        .is_some_and(|endpoint| endpoint.is_metered());
+    if !metered {
+        return next.call(req).await;
+    }
+
+    let credentials = req.extract::<BasicAuth>().await?;
+    let api_key = credentials.user_id();
+    let (Some(config), Some(database), Some(quota_usage)) = (
+        req.app_data::<web::Data<Config>>().cloned(),
+        req.app_data::<web::Data<db::Pool>>().cloned(),
+        req.app_data::<web::Data<QuotaUsage>>().cloned(),
+    ) else {
+        return next.call(req).await;
+    };
+    let access = auth::key_access(req.request(), api_key).map_err(|_| ApiError::Internal)?;
+    let Some(quota) = access.and_then(|access| quota_of(&config, &access)) else {
+        return next.call(req).await;
+    };
+
+    let now = clock::now(req.request());
+    if let Some(_reservation) = quota_usage.reserve(database, api_key, now, quota).await? {
+        return next.call(req).await;
+    }
+
+    throttling::throttled(&req, Rule::Quota, api_key);
+    let (year, month) = period_of(now);
+    let (_, reset) = report::month_bounds(year, month).ok_or(ApiError::Internal)?;
+    let mut response =
+        ApiError::QuotaExceeded.localized_response(Lang::from_request(req.request()));
+    let headers = response.headers_mut();
+    headers.insert(X_QUOTA_LIMIT, HeaderValue::from(quota));
+    headers.insert(X_QUOTA_REMAINING, HeaderValue::from(0));
+    if let Ok(reset) = HeaderValue::from_str(&reset.to_rfc3339_opts(SecondsFormat::Secs, true)) {
+        headers.insert(X_QUOTA_RESET, reset);
+    }
+
+    // Already localized, so `localize_errors` leaves it, headers and all.
+    Err(InternalError::from_response(ApiError::QuotaExceeded, response).into())
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct MonthlyQuota {
+    /// 0 is unlimited; `null` leaves the key to its tier's quota.
+    monthly_limit: Option<u64>,
+}
+
+/// Sets the monthly call quota of key `id`, over its tier's.
+#[put("/keys/{id}/quota")]
+pub async fn set_quota(
+    _: Authorized<Operator>,
+    id: web::Path<i64>,
+    body: web::Json<MonthlyQuota>,
+    database: web::Data<db::Pool>,
+    key_cache: web::Data<auth::KeyCache>,
+) -> actix_web::Result<impl Responder> {
+    let found = auth::set_monthly_quota(database, &key_cache, id.into_inner(), body.monthly_limit)
+        .await
+        .map_err(|_| ApiError::Internal)?;
+    if !found {
+        return Err(ApiError::KeyNotFound.into());
+    }
+
+    Ok(HttpResponse::Ok().json(body.into_inner()))
+}
+
+async fn send_warning(
+    task: tasks::Task,
+    database: web::Data<db::Pool>,
+    config: web::Data<Config>,
+    mailer: web::Data<dyn notify::Mailer>,
+    key_id: i64,
+    threshold: u64,
+    warning: serde_json::Value,
+) {
+    let owner = db::run(database, move |conn| {
+        auth::owner_keys(conn, key_id).map_err(|err| err.to_string())
+    })
+    .await;
+
+    let mut targets = Vec::new();
+    if let Ok(Some(owner)) = &owner {
+        targets.extend(owner.email.clone().map(Target::Email));
+    }
+    task.record("look_up_owner", owner);
+    if let Some(url) = &config.quota_webhook_url {
+        targets.push(Target::Webhook(url.clone()));
+    }
+
+    let subject = format!("{threshold}% of your monthly quota used");
+    for target in targets {
+        task.record(
+            "deliver_warning",
+            notify::deliver(mailer.clone(), &target, &subject, &warning).await,
+        );
+    }
+}
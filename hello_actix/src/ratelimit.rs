@@ -0,0 +1,103 @@
+use actix_web::body::MessageBody;
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::middleware::Next;
+use actix_web::{web, Error};
+use actix_web_httpauth::extractors::basic::BasicAuth;
+use chrono::{DateTime, TimeDelta, Utc};
+use dashmap::DashMap;
+
+use crate::config::Config;
+use crate::error::ApiError;
+use crate::throttling::{self, Rule};
+use crate::{auth, clock};
+
+/// A token bucket per API key, keyed by the key's fingerprint like
+/// [`crate::concurrency::ConcurrencyLimiter`]. Each bucket holds a minute's
+/// worth of requests and refills continuously, so a key can burst to its
+/// limit and then goes at the limit's pace.
+#[derive(Debug, Default)]
+pub struct RateLimiter {
+    buckets: DashMap<String, Bucket>,
+}
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    refilled_at: DateTime<Utc>,
+}
+
+impl RateLimiter {
+    pub fn new() -> Self {
+        RateLimiter::default()
+    }
+
+    /// Keys with a bucket.
+    pub fn entries(&self) -> usize {
+        self.buckets.len()
+    }
+
+    /// Takes a token from the bucket of `fingerprint`, topped up for the
+    /// time since it was last. `false` if it is empty.
+    fn try_take(&self, fingerprint: &str, per_minute: u32, now: DateTime<Utc>) -> bool {
+        let capacity = f64::from(per_minute);
+        let mut bucket = self
+            .buckets
+            .entry(fingerprint.to_owned())
+            .or_insert(Bucket {
+                tokens: capacity,
+                refilled_at: now,
+            });
+
+        let elapsed = (now - bucket.refilled_at).num_milliseconds().max(0) as f64 / 60_000.0;
+        bucket.tokens = (bucket.tokens + elapsed * capacity).min(capacity);
+        bucket.refilled_at = now;
+
+        if bucket.tokens < 1.0 {
+            return false;
+        }
+        bucket.tokens -= 1.0;
+        true
+    }
+
+    /// Drops the buckets of keys idle for a minute, full again by then like
+    /// a new one. Run on a timer; see
+    /// [`crate::scheduler::spawn_limiter_eviction`].
+    pub fn evict_idle(&self, now: DateTime<Utc>) {
+        self.buckets
+            .retain(|_, bucket| now - bucket.refilled_at < TimeDelta::minutes(1));
+    }
+}
+
+/// Rejects a request with 429 when its API key has used up the requests
+/// per minute its tier allows.
+///
+/// Must run inside the authentication middleware.
+pub async fn limit_rate(
+    mut req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    let credentials = req.extract::<BasicAuth>().await?;
+
+    let tier = auth::key_tier(req.request(), credentials.user_id())
+        .map_err(|_| ApiError::Internal)?
+        .unwrap_or_default();
+    let per_minute = match req.app_data::<web::Data<Config>>() {
+        Some(config) => config.requests_per_minute(tier),
+        None => Config::default().requests_per_minute(tier),
+    };
+
+    if let Some(per_minute) = per_minute {
+        let limiter = req
+            .app_data::<web::Data<RateLimiter>>()
+            .cloned()
+            .ok_or(ApiError::Internal)?;
+        let now = clock::now(req.request());
+
+        if !limiter.try_take(&auth::fingerprint(credentials.user_id()), per_minute, now) {
+            throttling::throttled(&req, Rule::RateLimit, credentials.user_id());
+            return Err(ApiError::RateLimited.into());
+        }
+    }
+
+    next.call(req).await
+}
@@ -0,0 +1,121 @@
+use std::future::Future;
+use std::marker::PhantomData;
+use std::pin::Pin;
+
+use actix_web::dev::Payload;
+use actix_web::http::header::AUTHORIZATION;
+use actix_web::{web, FromRequest, HttpRequest};
+use ring::digest;
+
+use crate::config::Config;
+use crate::db::{Role, User};
+use crate::error::ApiError;
+use crate::session::DashboardUser;
+
+/// Marker types naming the minimum role an admin handler needs.
+pub trait RoleRequirement {
+    const ROLE: Role;
+}
+
+pub struct Viewer;
+pub struct Operator;
+pub struct Admin;
+
+impl RoleRequirement for Viewer {
+    const ROLE: Role = Role::Viewer;
+}
+
+impl RoleRequirement for Operator {
+    const ROLE: Role = Role::Operator;
+}
+
+impl RoleRequirement for Admin {
+    const ROLE: Role = Role::Admin;
+}
+
+/// Who is performing an admin operation.
+#[derive(Debug, Clone)]
+pub enum Principal {
+    /// The deployment-wide `ADMIN_TOKEN`, which has every permission.
+    AdminToken,
+    User(User),
+}
+
+impl Principal {
+    /// How the principal is recorded in audit trails.
+    pub fn name(&self) -> String {
+        match self {
+            Principal::AdminToken => "admin-token".into(),
+            Principal::User(user) => user.email.clone(),
+        }
+    }
+}
+
+/// Extractor admitting the request only if the caller holds at least
+/// `R::ROLE`, either through a dashboard session or the admin token.
+///
+/// ```ignore
+/// async fn handler(_: Authorized<Operator>) -> impl Responder { ... }
+/// ```
+pub struct Authorized<R> {
+    pub principal: Principal,
+    requirement: PhantomData<R>,
+}
+
+fn bearer_token(req: &HttpRequest) -> Option<&str> {
+    req.headers()
+        .get(AUTHORIZATION)?
+        .to_str()
+        .ok()?
+        .strip_prefix("Bearer ")
+}
+
+fn check_admin_token(req: &HttpRequest, supplied: &str) -> Result<(), ApiError> {
+    let expected = req
+        .app_data::<web::Data<Config>>()
+        .and_then(|config| config.admin_token.clone())
+        .ok_or(ApiError::AdminDisabled)?;
+
+    // Comparing digests keeps the comparison time independent of how much of
+    // the token matched.
+    let supplied = digest::digest(&digest::SHA256, supplied.as_bytes());
+    let expected = digest::digest(&digest::SHA256, expected.as_bytes());
+
+    if supplied.as_ref() == expected.as_ref() {
+        Ok(())
+    } else {
+        Err(ApiError::AdminUnauthorized)
+    }
+}
+
+impl<R: RoleRequirement + 'static> FromRequest for Authorized<R> {
+    type Error = actix_web::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self, Self::Error>>>>;
+
+    fn from_request(req: &HttpRequest, payload: &mut Payload) -> Self::Future {
+        if let Some(token) = bearer_token(req) {
+            let result = check_admin_token(req, token)
+                .map(|()| Authorized {
+                    principal: Principal::AdminToken,
+                    requirement: PhantomData,
+                })
+                .map_err(Into::into);
+            return Box::pin(async move { result });
+        }
+
+        let session = DashboardUser::from_request(req, payload);
+
+        Box::pin(async move {
+            let DashboardUser(user) = session.await?;
+
+            if user.role < R::ROLE {
+                return Err(ApiError::InsufficientRole.into());
+            }
+
+            Ok(Authorized {
+                principal: Principal::User(user),
+                requirement: PhantomData,
+            })
+        })
+    }
+}
@@ -0,0 +1,155 @@
+//! Opt-in request recording, writing one anonymized JSON trace per line to
+//! `RECORD_FILE`. The traces can be re-issued against another instance with
+//! `hello_actix replay`.
+
+use std::fs::{File, OpenOptions};
+use std::io::{self, LineWriter, Write};
+use std::sync::Mutex;
+use std::time::Instant;
+
+use actix_web::body::MessageBody;
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::header::{AUTHORIZATION, COOKIE};
+use actix_web::middleware::Next;
+use actix_web::{web, Error};
+use serde::{Deserialize, Serialize};
+use tracing::warn;
+
+use crate::session::SESSION_COOKIE;
+
+/// Path parameters whose values are secrets and must never reach a trace.
+const REDACTED_PARAMS: &[&str] = &["token"];
+const REDACTED: &str = "redacted";
+
+/// How the recorded request authenticated. Credentials themselves are
+/// never written.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AuthKind {
+    None,
+    Basic,
+    Bearer,
+    Session,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Trace {
+    /// Milliseconds since recording started, so replays can keep pacing.
+    pub offset_ms: u64,
+    pub method: String,
+    pub path: String,
+    pub auth: AuthKind,
+    pub status: u16,
+}
+
+#[derive(Debug)]
+pub struct Recorder {
+    started: Instant,
+    out: Mutex<LineWriter<File>>,
+}
+
+impl Recorder {
+    pub fn create(path: &str) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+
+        Ok(Recorder {
+            started: Instant::now(),
+            out: Mutex::new(LineWriter::new(file)),
+        })
+    }
+
+    fn write(&self, trace: &Trace) -> io::Result<()> {
+        let line = serde_json::to_string(trace)?;
+        let mut out = self.out.lock().map_err(|_| io::Error::other("poisoned"))?;
+        writeln!(out, "{line}")
+    }
+}
+
+fn auth_kind(req: &ServiceRequest) -> AuthKind {
+    let authorization = req
+        .headers()
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or_default();
+
+    if authorization.starts_with("Basic ") {
+        AuthKind::Basic
+    } else if authorization.starts_with("Bearer ") {
+        AuthKind::Bearer
+    } else if req.headers().contains_key(COOKIE) && req.cookie(SESSION_COOKIE).is_some() {
+        AuthKind::Session
+    } else {
+        AuthKind::None
+    }
+}
+
+/// Rebuilds the request path segment by segment against its route pattern,
+/// blanking out secret path parameters such as magic-link tokens.
+fn anonymized_path<B>(res: &ServiceResponse<B>) -> String {
+    let req = res.request();
+    let query = match req.query_string() {
+        "" => String::new(),
+        query => format!("?{query}"),
+    };
+
+    let Some(pattern) = req.match_pattern() else {
+        return format!("{}{query}", req.path());
+    };
+
+    let pattern_segments: Vec<&str> = pattern.split('/').collect();
+    let path_segments: Vec<&str> = req.path().split('/').collect();
+
+    // Only tail patterns make these differ; the pattern itself is safe.
+    if pattern_segments.len() != path_segments.len() {
+        return format!("{pattern}{query}");
+    }
+
+    let path = pattern_segments
+        .iter()
+        .zip(path_segments)
+        .map(|(pattern, actual)| {
+            let param = pattern
+                .strip_prefix('{')
+                .and_then(|p| p.strip_suffix('}'))
+                .map(|p| p.split(':').next().unwrap_or(p));
+
+            match param {
+                Some(name) if REDACTED_PARAMS.contains(&name) => REDACTED,
+                _ => actual,
+            }
+        })
+        .collect::<Vec<_>>()
+        .join("/");
+
+    format!("{path}{query}")
+}
+
+pub async fn record_requests(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    // Registered as an `Option` so the app can be built the same way whether
+    // or not recording is switched on.
+    let Some(Some(recorder)) = req.app_data::<Option<web::Data<Recorder>>>().cloned() else {
+        return next.call(req).await;
+    };
+
+    let offset_ms = recorder.started.elapsed().as_millis() as u64;
+    let method = req.method().to_string();
+    let auth = auth_kind(&req);
+
+    let res = next.call(req).await?;
+
+    let trace = Trace {
+        offset_ms,
+        method,
+        path: anonymized_path(&res),
+        auth,
+        status: res.status().as_u16(),
+    };
+    if let Err(err) = recorder.write(&trace) {
+        warn!(%err, "failed to record request");
+    }
+
+    Ok(res)
+}
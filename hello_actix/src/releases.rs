@@ -0,0 +1,152 @@
+//! An optional check against a releases document at
+//! [`Config::releases_url`], logging when a newer version is out. When this
+//! version is listed as end-of-life, every response says so with
+//! `X-API-Deprecated-Version`; see
+//! [`crate::deprecation::emit_deprecation_headers`].
+//!
+//! The document is JSON, e.g.
+//! `{ "latest": "0.3.0", "end_of_life": ["0.1.0"] }`.
+
+use std::sync::RwLock;
+use std::time::Duration;
+
+use actix_web::http::header::{HeaderName, HeaderValue};
+use actix_web::{rt, web};
+use serde::Deserialize;
+use tracing::{info, warn};
+
+use crate::config::Config;
+use crate::tasks;
+
+pub const X_API_DEPRECATED_VERSION: HeaderName =
+    HeaderName::from_static("x-api-deprecated-version");
+
+const CHECK_INTERVAL: Duration = Duration::from_secs(6 * 60 * 60);
+
+/// This build's version.
+pub const RUNNING: &str = env!("CARGO_PKG_VERSION");
+
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct Releases {
+    pub latest: Option<String>,
+    #[serde(default)]
+    pub end_of_life: Vec<String>,
+}
+
+impl Releases {
+    fn running_is_end_of_life(&self) -> bool {
+        self.end_of_life.iter().any(|version| version == RUNNING)
+    }
+}
+
+/// What the last check found. Nothing until one succeeds.
+#[derive(Debug, Default)]
+pub struct ReleaseStatus {
+    releases: RwLock<Option<Releases>>,
+}
+
+impl ReleaseStatus {
+    pub fn new() -> Self {
+        ReleaseStatus::default()
+    }
+
+    /// Records a fetched document, logging what changed for this version.
+    pub fn update(&self, releases: Releases) {
+        let mut current = self.releases.write().unwrap();
+        let previous = current.as_ref();
+
+        if let Some(latest) = &releases.latest {
+            let seen = previous.and_then(|previous| previous.latest.as_ref());
+            if is_newer(latest, RUNNING) && seen != Some(latest) {
+                info!(running = RUNNING, latest, "a newer version is available");
+            }
+        }
+        let was_end_of_life = previous.is_some_and(Releases::running_is_end_of_life);
+        if releases.running_is_end_of_life() && !was_end_of_life {
+            warn!(
+                running = RUNNING,
+                "this version is end-of-life; please upgrade"
+            );
+        }
+
+        *current = Some(releases);
+    }
+
+    /// `X-API-Deprecated-Version` for responses, while this version is
+    /// end-of-life: the running version, and the latest if known.
+    pub fn advisory(&self) -> Option<HeaderValue> {
+        let releases = self.releases.read().unwrap();
+        let releases = releases.as_ref()?;
+        if !releases.running_is_end_of_life() {
+            return None;
+        }
+
+        let value = match &releases.latest {
+            Some(latest) => format!("{RUNNING}; latest={latest}"),
+            None => RUNNING.to_owned(),
+        };
+        HeaderValue::from_str(&value).ok()
+    }
+}
+
+/// Whether dotted version `a` is after `b`, comparing numeric parts in
+/// turn. Anything after a `-` or `+` is ignored.
+pub fn is_newer(a: &str, b: &str) -> bool {
+    fn parts(version: &str) -> Vec<u64> {
+        version
+            .trim_start_matches('v')
+            .split(['-', '+'])
+            .next()
+            .unwrap_or_default()
+            .split('.')
+            .map(|part| part.parse().unwrap_or(0))
+            .collect()
+    }
+
+    let (a, b) = (parts(a), parts(b));
+    let len = a.len().max(b.len());
+    let pad = |parts: Vec<u64>| parts.into_iter().chain(std::iter::repeat(0)).take(len);
+    pad(a).cmp(pad(b)).is_gt()
+}
+
+/// Checks [`Config::releases_url`] now and every [`CHECK_INTERVAL`], if set.
+/// Call once, from `main`.
+pub fn spawn(config: web::Data<Config>, status: web::Data<ReleaseStatus>) {
+    let Some(url) = config.releases_url.clone() else {
+        return;
+    };
+
+    tasks::supervise("release_check", move |task| {
+        check_releases(task, url.clone(), status.clone())
+    });
+}
+
+async fn check_releases(task: tasks::Task, url: String, status: web::Data<ReleaseStatus>) {
+    let mut interval = rt::time::interval(CHECK_INTERVAL);
+
+    loop {
+        interval.tick().await;
+
+        let fetched = fetch(&url).await.map(|releases| status.update(releases));
+        task.record("fetch_releases", fetched);
+    }
+}
+
+#[cfg(feature = "webhooks")]
+async fn fetch(url: &str) -> Result<Releases, String> {
+    let mut response = awc::Client::default()
+        .get(url)
+        .send()
+        .await
+        .map_err(|err| err.to_string())?;
+
+    if !response.status().is_success() {
+        return Err(format!("releases URL answered {}", response.status()));
+    }
+    response.json().await.map_err(|err| err.to_string())
+}
+
+#[cfg(not(feature = "webhooks"))]
+async fn fetch(_url: &str) -> Result<Releases, String> {
+    Err("release checks need a build with the `webhooks` feature".into())
+}
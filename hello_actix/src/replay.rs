@@ -0,0 +1,153 @@
+//! `hello_actix replay`: re-issues traces captured by the recorder against
+//! another instance and reports where the responses differ.
+
+use std::fs::File;
+use std::io::{self, BufRead, BufReader};
+use std::time::{Duration, Instant};
+
+use actix_web::rt;
+
+use crate::loadtest::{issue_temporary_key, other_error, revoke_temporary_key};
+use crate::record::{AuthKind, Trace};
+
+const USAGE: &str = "\
+usage: hello_actix replay --input FILE [--url URL] [--admin-token TOKEN] [--preserve-timing] [--include-unsafe]
+
+  --input            trace file written with RECORD_FILE
+  --url              base URL of the instance to replay against (default http://127.0.0.1:8080)
+  --admin-token      token used for requests recorded with the admin token
+  --preserve-timing  wait between requests as in the recording
+  --include-unsafe   also replay non-GET requests (sent without a body)";
+
+/// Mismatches printed in full; the rest are only counted.
+const MAX_REPORTED_MISMATCHES: usize = 20;
+
+#[derive(Debug)]
+pub struct Options {
+    pub input: String,
+    pub url: String,
+    pub admin_token: Option<String>,
+    pub preserve_timing: bool,
+    pub include_unsafe: bool,
+}
+
+impl Options {
+    pub fn parse(args: &[String]) -> Result<Self, String> {
+        let mut input = None;
+        let mut options = Options {
+            input: String::new(),
+            url: "http://127.0.0.1:8080".into(),
+            admin_token: None,
+            preserve_timing: false,
+            include_unsafe: false,
+        };
+        let mut args = args.iter();
+
+        while let Some(flag) = args.next() {
+            let mut value = || {
+                args.next()
+                    .cloned()
+                    .ok_or_else(|| format!("missing value for {flag}"))
+            };
+
+            match flag.as_str() {
+                "--input" => input = Some(value()?),
+                "--url" => options.url = value()?.trim_end_matches('/').to_owned(),
+                "--admin-token" => options.admin_token = Some(value()?),
+                "--preserve-timing" => options.preserve_timing = true,
+                "--include-unsafe" => options.include_unsafe = true,
+                "--help" | "-h" => return Err(USAGE.into()),
+                other => return Err(format!("unknown option {other}\n\n{USAGE}")),
+            }
+        }
+
+        options.input = input.ok_or_else(|| format!("--input is required\n\n{USAGE}"))?;
+        Ok(options)
+    }
+}
+
+pub async fn run(args: &[String]) -> io::Result<()> {
+    let options = match Options::parse(args) {
+        Ok(options) => options,
+        Err(message) => {
+            eprintln!("{message}");
+            return Err(io::ErrorKind::InvalidInput.into());
+        }
+    };
+
+    let traces = BufReader::new(File::open(&options.input)?)
+        .lines()
+        .map(|line| serde_json::from_str::<Trace>(&line?).map_err(other_error))
+        .collect::<io::Result<Vec<_>>>()?;
+
+    let client = awc::Client::default();
+    let api_key = issue_temporary_key(&client, &options.url).await?;
+
+    let started = Instant::now();
+    let (mut replayed, mut skipped, mut mismatched) = (0, 0, 0);
+
+    for trace in &traces {
+        let is_safe = matches!(trace.method.as_str(), "GET" | "HEAD");
+        if !is_safe && !options.include_unsafe {
+            skipped += 1;
+            continue;
+        }
+
+        let Ok(method) = trace.method.parse() else {
+            skipped += 1;
+            continue;
+        };
+        let mut request = client.request(method, format!("{}{}", options.url, trace.path));
+
+        request = match (trace.auth, &options.admin_token) {
+            (AuthKind::None, _) => request,
+            // The recorded key was rejected, so reject this one too.
+            (AuthKind::Basic, _) if trace.status == 401 => request.basic_auth("invalid", ""),
+            (AuthKind::Basic, _) => request.basic_auth(&api_key, ""),
+            (AuthKind::Bearer, Some(token)) => request.bearer_auth(token),
+            (AuthKind::Bearer, None) | (AuthKind::Session, _) => {
+                skipped += 1;
+                continue;
+            }
+        };
+
+        if options.preserve_timing {
+            let due = Duration::from_millis(trace.offset_ms);
+            if let Some(wait) = due.checked_sub(started.elapsed()) {
+                rt::time::sleep(wait).await;
+            }
+        }
+
+        replayed += 1;
+        let status = match request.send().await {
+            Ok(response) => response.status().as_u16(),
+            Err(err) => {
+                eprintln!("{} {}: {err}", trace.method, trace.path);
+                0
+            }
+        };
+
+        if status != trace.status {
+            mismatched += 1;
+            if mismatched <= MAX_REPORTED_MISMATCHES {
+                println!(
+                    "{} {}: recorded {}, got {status}",
+                    trace.method, trace.path, trace.status
+                );
+            }
+        }
+    }
+
+    revoke_temporary_key(&client, &options.url, &api_key).await;
+
+    println!("traces:     {}", traces.len());
+    println!("replayed:   {replayed}");
+    println!("skipped:    {skipped}");
+    println!("mismatched: {mismatched}");
+
+    if mismatched > 0 {
+        return Err(other_error(format!("{mismatched} responses differed")));
+    }
+
+    Ok(())
+}
@@ -0,0 +1,180 @@
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+
+use chrono::{DateTime, NaiveDate, Utc};
+use serde::Serialize;
+
+use crate::auth;
+use crate::db::{CountryCount, UsageCount};
+
+/// Start (inclusive) and end (exclusive) of a calendar month in UTC.
+pub fn month_bounds(year: i32, month: u32) -> Option<(DateTime<Utc>, DateTime<Utc>)> {
+    let start = NaiveDate::from_ymd_opt(year, month, 1)?;
+    let end = if month == 12 {
+        NaiveDate::from_ymd_opt(year + 1, 1, 1)?
+    } else {
+        NaiveDate::from_ymd_opt(year, month + 1, 1)?
+    };
+
+    Some((
+        start.and_hms_opt(0, 0, 0)?.and_utc(),
+        end.and_hms_opt(0, 0, 0)?.and_utc(),
+    ))
+}
+
+/// [`month_bounds`] of a `YYYY-MM` period.
+pub fn parse_month(period: &str) -> Option<(DateTime<Utc>, DateTime<Utc>)> {
+    let (year, month) = period.split_once('-')?;
+    if month.len() != 2 {
+        return None;
+    }
+    month_bounds(year.parse().ok()?, month.parse().ok()?)
+}
+
+/// Calls to one endpoint in two periods.
+#[derive(Debug, Serialize)]
+pub struct EndpointDelta {
+    pub endpoint: String,
+    pub calls_a: u64,
+    pub calls_b: u64,
+    /// `calls_b - calls_a`.
+    pub delta: i64,
+    /// `delta` relative to `calls_a`, `None` when there were none.
+    pub change_percent: Option<f64>,
+}
+
+/// Totals the usage of each period per endpoint, keys and anonymous counts
+/// alike, and compares `b` against `a`. Endpoints called in either period
+/// are listed, by name.
+pub fn compare(a: &[UsageCount], b: &[UsageCount]) -> Vec<EndpointDelta> {
+    let mut calls: BTreeMap<&str, (u64, u64)> = BTreeMap::new();
+    for row in a {
+        calls.entry(&row.endpoint).or_default().0 += row.calls;
+    }
+    for row in b {
+        calls.entry(&row.endpoint).or_default().1 += row.calls;
+    }
+
+    calls
+        .into_iter()
+        .map(|(endpoint, (calls_a, calls_b))| {
+            let delta = calls_b as i64 - calls_a as i64;
+            EndpointDelta {
+                endpoint: endpoint.to_owned(),
+                calls_a,
+                calls_b,
+                delta,
+                change_percent: (calls_a > 0).then(|| delta as f64 * 100.0 / calls_a as f64),
+            }
+        })
+        .collect()
+}
+
+/// Calls to one endpoint within a reporting window.
+#[derive(Debug, Serialize)]
+pub struct EndpointTotal {
+    pub endpoint: String,
+    pub calls: u64,
+}
+
+/// Totals usage per endpoint, keys and anonymous counts alike, by name.
+pub fn totals(usage: &[UsageCount]) -> Vec<EndpointTotal> {
+    let mut calls: BTreeMap<&str, u64> = BTreeMap::new();
+    for row in usage {
+        *calls.entry(&row.endpoint).or_default() += row.calls;
+    }
+
+    calls
+        .into_iter()
+        .map(|(endpoint, calls)| EndpointTotal {
+            endpoint: endpoint.to_owned(),
+            calls,
+        })
+        .collect()
+}
+
+/// Renders a standalone HTML page summarising one month of usage per key,
+/// and per country when GeoIP enrichment is on.
+///
+/// Keys are shown by fingerprint so the report can be shared without
+/// leaking credentials.
+pub fn render_monthly_html(
+    year: i32,
+    month: u32,
+    usage: &[UsageCount],
+    countries: &[CountryCount],
+) -> String {
+    let total: u64 = usage.iter().map(|row| row.calls).sum();
+
+    let mut html = String::new();
+    let _ = write!(
+        html,
+        "<!DOCTYPE html>
+<html>
+<head>
+<meta charset=\"utf-8\">
+<title>Usage report {year}-{month:02}</title>
+</head>
+<body>
+<h1>Usage report {year}-{month:02}</h1>
+<p>Total calls: {total}</p>
+<table>
+<thead><tr><th>Key</th><th>Endpoint</th><th>Calls</th></tr></thead>
+<tbody>
+"
+    );
+
+    for row in usage {
+        let _ = writeln!(
+            html,
+            "<tr><td><code>{}</code></td><td>{}</td><td>{}</td></tr>",
+            row.api_key
+                .as_deref()
+                .map_or("(anonymous)".into(), |key| escape(&auth::fingerprint(key))),
+            escape(&row.endpoint),
+            row.calls,
+        );
+    }
+
+    html.push_str("</tbody>\n</table>\n");
+
+    // Without GeoIP every call lands in the unknown bucket; skip the table.
+    if countries.iter().any(|row| row.country.is_some()) {
+        html.push_str(
+            "<h2>By country</h2>
+<table>
+<thead><tr><th>Country</th><th>Calls</th></tr></thead>
+<tbody>
+",
+        );
+
+        for row in countries {
+            let _ = writeln!(
+                html,
+                "<tr><td>{}</td><td>{}</td></tr>",
+                escape(row.country.as_deref().unwrap_or("Unknown")),
+                row.calls,
+            );
+        }
+
+        html.push_str("</tbody>\n</table>\n");
+    }
+
+    html.push_str("</body>\n</html>\n");
+    html
+}
+
+fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
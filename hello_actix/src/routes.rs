@@ -0,0 +1,295 @@
+//! The HTTP routes, with the middleware applied per scope chosen by the
+//! caller, so the API can be mounted inside a larger actix app.
+//!
+//! ```no_run
+//! use actix_web::App;
+//! use hello_actix::plugin::PluginRegistry;
+//! use hello_actix::routes::{Layers, Routes};
+//!
+//! // The host app already rate-limits, so skip ours under `/api`.
+//! let routes = Routes::new(PluginRegistry::compiled_in()).api(Layers::none().with_auth());
+//!
+//! let app = App::new().configure(|cfg| routes.configure(cfg));
+//! ```
+//!
+//! App-wide middleware (sessions, CSRF, blocklist, localized errors) and
+//! `app_data` remain the caller's to install, as `main` does.
+
+use actix_web::dev::HttpServiceFactory;
+use actix_web::middleware::{from_fn, Condition};
+use actix_web::web::{self, scope};
+use actix_web::Scope;
+use actix_web_httpauth::middleware::HttpAuthentication;
+
+use crate::plugin::PluginRegistry;
+use crate::{
+    access, admin, alerts, blocklist, casing, challenge, concurrency, drain, error, eval, forecast,
+    health, memory, metrics, openapi, privacy, quota, ratelimit, scale, signup, subscriptions,
+    tasks, throttling, usage, version,
+};
+
+/// Middleware to apply to one scope. Anything not switched on is skipped.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Layers {
+    auth: bool,
+    concurrency_limit: bool,
+    rate_limit: bool,
+    endpoint_access: bool,
+    quota_enforcement: bool,
+    quota_warnings: bool,
+}
+
+impl Layers {
+    pub fn none() -> Self {
+        Layers::default()
+    }
+
+    /// API-key authentication through [`crate::validator`], after
+    /// [`crate::reject_implausible_keys`]. Handlers still read the key
+    /// themselves, so leaving this off only skips the check that it is live.
+    pub fn with_auth(self) -> Self {
+        Layers { auth: true, ..self }
+    }
+
+    /// The per-key concurrency limit of [`concurrency::limit_concurrency`].
+    pub fn with_concurrency_limit(self) -> Self {
+        Layers {
+            concurrency_limit: true,
+            ..self
+        }
+    }
+
+    /// The per-key requests per minute of [`ratelimit::limit_rate`].
+    pub fn with_rate_limit(self) -> Self {
+        Layers {
+            rate_limit: true,
+            ..self
+        }
+    }
+
+    /// The monthly quotas of [`quota::enforce_quota`].
+    pub fn with_quota_enforcement(self) -> Self {
+        Layers {
+            quota_enforcement: true,
+            ..self
+        }
+    }
+
+    /// The `X-Quota-Warning` header and notifications of
+    /// [`quota::warn_quota`].
+    pub fn with_quota_warnings(self) -> Self {
+        Layers {
+            quota_warnings: true,
+            ..self
+        }
+    }
+
+    /// The per-key endpoint toggles of [`access::enforce_endpoint_access`].
+    pub fn with_endpoint_access(self) -> Self {
+        Layers {
+            endpoint_access: true,
+            ..self
+        }
+    }
+}
+
+/// Every route of the server, by default layered as `main` serves them.
+#[derive(Clone)]
+pub struct Routes {
+    plugins: PluginRegistry,
+    api: Layers,
+    alerts: Layers,
+    my: Layers,
+    admin: Layers,
+    internal: bool,
+}
+
+impl Routes {
+    pub fn new(plugins: PluginRegistry) -> Self {
+        Routes {
+            plugins,
+            api: Layers::none()
+                .with_auth()
+                .with_endpoint_access()
+                .with_rate_limit()
+                .with_quota_enforcement()
+                .with_concurrency_limit()
+                .with_quota_warnings(),
+            alerts: Layers::none().with_auth().with_endpoint_access(),
+            my: Layers::none().with_auth().with_endpoint_access(),
+            admin: Layers::none(),
+            internal: true,
+        }
+    }
+
+    /// Middleware for `/api`.
+    pub fn api(self, layers: Layers) -> Self {
+        Routes {
+            api: layers,
+            ..self
+        }
+    }
+
+    /// Middleware for `/api/alerts`. Long polls there are kept out of the
+    /// concurrency limit by default.
+    pub fn alerts(self, layers: Layers) -> Self {
+        Routes {
+            alerts: layers,
+            ..self
+        }
+    }
+
+    /// Middleware for `/my`.
+    pub fn my(self, layers: Layers) -> Self {
+        Routes { my: layers, ..self }
+    }
+
+    /// Middleware for `/admin`, on top of the role checks in its handlers.
+    pub fn admin(self, layers: Layers) -> Self {
+        Routes {
+            admin: layers,
+            ..self
+        }
+    }
+
+    /// Leaves the admin, metrics and health routes out of
+    /// [`Routes::configure`], to be served by [`Routes::configure_internal`]
+    /// on another listener.
+    pub fn without_internal(self) -> Self {
+        Routes {
+            internal: false,
+            ..self
+        }
+    }
+
+    pub fn configure(&self, cfg: &mut web::ServiceConfig) {
+        let plugins = &self.plugins;
+
+        // Ahead of the `/api` scope, which would otherwise claim these
+        // paths: the catalog needs no key, and long polls mustn't hold a
+        // concurrency slot.
+        cfg.service(error::error_catalog)
+            .service(layered(
+                scope("/api/alerts").configure(|cfg| {
+                    cfg.service(alerts::wait_for_usage);
+                }),
+                self.alerts,
+            ))
+            .service(layered(
+                scope("/api").configure(|cfg| {
+                    cfg.service(crate::to_fahrenheit)
+                        .service(crate::to_celsius)
+                        .service(crate::to_kelvin)
+                        .service(crate::from_kelvin)
+                        .service(access::whoami)
+                        .service(forecast::usage_forecast)
+                        .service(usage::my_usage)
+                        .service(eval::eval)
+                        .service(scale::scale_info)
+                        .service(scale::set_output_scales)
+                        .service(casing::set_field_case)
+                        .configure(|cfg| plugins.configure_api(cfg));
+                }),
+                self.api,
+            ));
+
+        if self.internal {
+            self.configure_internal_scopes(cfg);
+        }
+
+        cfg.service(layered(
+            scope("/my").configure(|cfg| {
+                cfg.service(privacy::export_my_data);
+            }),
+            self.my,
+        ))
+        .service(challenge::issue_challenge)
+        .service(signup::request_signup)
+        .service(signup::confirm_signup)
+        .service(signup::verify_signup)
+        .service(crate::delete_api_key)
+        .service(crate::revoke_api_key)
+        .service(crate::usage_statistics)
+        .service(crate::reset_usage_statistics)
+        .service(openapi::openapi_json)
+        .service(openapi::swagger_ui)
+        .service(version::version)
+        .configure(|cfg| plugins.configure(cfg));
+    }
+
+    /// The admin, metrics and health routes, plus the session routes admins
+    /// sign in through.
+    pub fn configure_internal(&self, cfg: &mut web::ServiceConfig) {
+        self.configure_internal_scopes(cfg);
+        self.plugins.configure(cfg);
+    }
+
+    fn configure_internal_scopes(&self, cfg: &mut web::ServiceConfig) {
+        let plugins = &self.plugins;
+
+        cfg.service(layered(
+            scope("/admin").configure(|cfg| {
+                cfg.service(admin::monthly_report)
+                    .service(admin::compare_usage)
+                    .service(admin::bucketed_usage)
+                    .service(admin::effective_config)
+                    .service(admin::reload_master_key)
+                    .service(admin::list_keys)
+                    .service(admin::issue_key)
+                    .service(admin::key_usage)
+                    .service(access::set_endpoints)
+                    .service(quota::set_quota)
+                    .service(blocklist::list_blocks)
+                    .service(blocklist::add_block)
+                    .service(blocklist::delete_block)
+                    .service(subscriptions::list_subscriptions)
+                    .service(subscriptions::add_subscription)
+                    .service(subscriptions::delete_subscription)
+                    .service(privacy::erase_key_data)
+                    .service(metrics::metrics)
+                    .service(memory::memory)
+                    .service(admin::worker_stats)
+                    .service(throttling::throttling_report)
+                    .service(tasks::list_tasks)
+                    .service(drain::start_drain)
+                    .configure(|cfg| plugins.configure_admin(cfg));
+            }),
+            self.admin,
+        ))
+        .service(health::health)
+        .service(health::ready)
+        .service(health::readyz);
+    }
+}
+
+fn layered(scope: Scope, layers: Layers) -> impl HttpServiceFactory {
+    scope
+        .wrap(Condition::new(
+            layers.quota_warnings,
+            from_fn(quota::warn_quota),
+        ))
+        .wrap(Condition::new(
+            layers.concurrency_limit,
+            from_fn(concurrency::limit_concurrency),
+        ))
+        .wrap(Condition::new(
+            layers.quota_enforcement,
+            from_fn(quota::enforce_quota),
+        ))
+        .wrap(Condition::new(
+            layers.rate_limit,
+            from_fn(ratelimit::limit_rate),
+        ))
+        .wrap(Condition::new(
+            layers.endpoint_access,
+            from_fn(access::enforce_endpoint_access),
+        ))
+        .wrap(Condition::new(
+            layers.auth,
+            HttpAuthentication::with_fn(crate::validator),
+        ))
+        .wrap(Condition::new(
+            layers.auth,
+            from_fn(crate::reject_implausible_keys),
+        ))
+}
@@ -0,0 +1,226 @@
+//! The temperature scales the API knows. Everything converts through
+//! Celsius, with the formulas of `conversion_core`.
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::str::FromStr;
+
+use actix_web::{get, put, web, HttpRequest, Responder};
+use actix_web_httpauth::extractors::basic::BasicAuth;
+use rusqlite::types::{FromSql, FromSqlResult, ToSql, ToSqlOutput, ValueRef};
+use serde::{Deserialize, Serialize};
+
+use crate::db;
+use crate::error::ApiError;
+use crate::{auth, casing};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Scale {
+    Celsius,
+    Fahrenheit,
+    Kelvin,
+}
+
+impl Scale {
+    pub const ALL: [Scale; 3] = [Scale::Celsius, Scale::Fahrenheit, Scale::Kelvin];
+
+    pub fn symbol(&self) -> &'static str {
+        match self {
+            Scale::Celsius => "°C",
+            Scale::Fahrenheit => "°F",
+            Scale::Kelvin => "K",
+        }
+    }
+
+    fn celsius_of(self, value: f32) -> f32 {
+        match self {
+            Scale::Celsius => value,
+            Scale::Fahrenheit => conversion_core::fahrenheit_to_celsius(value),
+            Scale::Kelvin => conversion_core::kelvin_to_celsius(value),
+        }
+    }
+
+    fn in_scale(self, celsius: f32) -> f32 {
+        match self {
+            Scale::Celsius => celsius,
+            Scale::Fahrenheit => conversion_core::celsius_to_fahrenheit(celsius),
+            Scale::Kelvin => conversion_core::celsius_to_kelvin(celsius),
+        }
+    }
+
+    /// `value`, in this scale, expressed in `to`.
+    pub fn convert(self, value: f32, to: Scale) -> f32 {
+        if self == to {
+            return value;
+        }
+        to.in_scale(self.celsius_of(value))
+    }
+}
+
+/// Scales packed into a bitmask, as stored in `api_keys.output_scales`. Empty
+/// stands for [`ScaleSet::DEFAULT`], so keys that never chose follow it.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct ScaleSet(u32);
+
+impl ScaleSet {
+    /// What conversions returned before keys could choose.
+    pub const DEFAULT: ScaleSet =
+        ScaleSet(1 << Scale::Celsius as u32 | 1 << Scale::Fahrenheit as u32);
+
+    pub fn contains(&self, scale: Scale) -> bool {
+        let set = if self.0 == 0 {
+            ScaleSet::DEFAULT
+        } else {
+            *self
+        };
+        set.0 & 1 << scale as u32 != 0
+    }
+
+    /// `self`, or the default it stands for, plus `scale`.
+    pub fn with(self, scale: Scale) -> ScaleSet {
+        self.iter().chain([scale]).collect()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = Scale> + '_ {
+        Scale::ALL.into_iter().filter(|scale| self.contains(*scale))
+    }
+}
+
+impl FromIterator<Scale> for ScaleSet {
+    fn from_iter<I: IntoIterator<Item = Scale>>(scales: I) -> Self {
+        ScaleSet(
+            scales
+                .into_iter()
+                .fold(0, |bits, scale| bits | 1 << scale as u32),
+        )
+    }
+}
+
+impl ToSql for ScaleSet {
+    fn to_sql(&self) -> rusqlite::Result<ToSqlOutput<'_>> {
+        Ok(ToSqlOutput::from(self.0))
+    }
+}
+
+impl FromSql for ScaleSet {
+    fn column_result(value: ValueRef<'_>) -> FromSqlResult<Self> {
+        u32::column_result(value).map(ScaleSet)
+    }
+}
+
+impl fmt::Display for Scale {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Scale::Celsius => "celsius",
+            Scale::Fahrenheit => "fahrenheit",
+            Scale::Kelvin => "kelvin",
+        })
+    }
+}
+
+#[derive(Debug)]
+pub struct UnknownScale;
+
+impl fmt::Display for UnknownScale {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("unknown temperature scale")
+    }
+}
+
+impl std::error::Error for UnknownScale {}
+
+/// Takes names (`celsius`) or symbols (`C`, `°C`), in any case.
+impl FromStr for Scale {
+    type Err = UnknownScale;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.strip_prefix('°').unwrap_or(s).to_ascii_lowercase();
+        match s.as_str() {
+            "c" | "celsius" => Ok(Scale::Celsius),
+            "f" | "fahrenheit" => Ok(Scale::Fahrenheit),
+            "k" | "kelvin" => Ok(Scale::Kelvin),
+            _ => Err(UnknownScale),
+        }
+    }
+}
+
+/// Temperatures worth marking on a scale, in Celsius. Water's are at
+/// standard pressure.
+const REFERENCE_POINTS: [(&str, f32); 3] = [
+    ("absolute_zero", -273.15),
+    ("water_freezing", 0.0),
+    ("water_boiling", 100.0),
+];
+
+#[derive(Debug, Serialize)]
+pub struct ScaleInfo {
+    pub scale: Scale,
+    pub symbol: &'static str,
+    /// Each of [`REFERENCE_POINTS`], in every scale.
+    pub reference_points: BTreeMap<&'static str, BTreeMap<Scale, f32>>,
+}
+
+impl Scale {
+    pub fn info(self) -> ScaleInfo {
+        let reference_points = REFERENCE_POINTS
+            .into_iter()
+            .map(|(name, celsius)| {
+                let values = Scale::ALL
+                    .into_iter()
+                    .map(|scale| {
+                        // The points are exact to the hundredth; this drops
+                        // f32 noise such as -459.66998.
+                        let value = Scale::Celsius.convert(celsius, scale);
+                        (scale, (value * 100.0).round() / 100.0)
+                    })
+                    .collect();
+                (name, values)
+            })
+            .collect();
+
+        ScaleInfo {
+            scale: self,
+            symbol: self.symbol(),
+            reference_points,
+        }
+    }
+}
+
+/// Reference points for annotating `scale` in a UI. Takes the same names
+/// and symbols as `/api/eval`.
+#[get("/scales/{scale}")]
+pub async fn scale_info(scale: web::Path<String>) -> actix_web::Result<impl Responder> {
+    let scale: Scale = scale.parse().map_err(|_| ApiError::ScaleNotFound)?;
+
+    Ok(casing::Json(scale.info()))
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct OutputScales {
+    /// Empty goes back to the default, Celsius and Fahrenheit.
+    scales: Vec<Scale>,
+}
+
+/// Chooses the scales the calling key's conversions answer in.
+#[put("/output-scales")]
+pub async fn set_output_scales(
+    auth: BasicAuth,
+    body: web::Json<OutputScales>,
+    database: web::Data<db::Pool>,
+    key_cache: web::Data<auth::KeyCache>,
+    req: HttpRequest,
+) -> actix_web::Result<impl Responder> {
+    let access = auth::key_access(&req, auth.user_id())
+        .map_err(|_| ApiError::Internal)?
+        .ok_or(ApiError::Unauthorized)?;
+
+    let scales: ScaleSet = body.scales.iter().copied().collect();
+    auth::set_output_scales(database, &key_cache, access.id, scales)
+        .await
+        .map_err(|_| ApiError::Internal)?;
+
+    Ok(casing::Json(OutputScales {
+        scales: scales.iter().collect(),
+    }))
+}
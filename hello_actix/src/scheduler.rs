@@ -0,0 +1,159 @@
+use std::time::Duration;
+
+use actix_web::{rt, web};
+
+use crate::clock::Clock;
+use crate::concurrency::ConcurrencyLimiter;
+use crate::ratelimit::RateLimiter;
+use crate::throttling::Throttling;
+use crate::{db, migrate, notify, subscriptions, tasks, UsageStats};
+
+/// Also how often report subscriptions are checked, so the minute-level
+/// resolution of their schedules.
+const CLEANUP_INTERVAL: Duration = Duration::from_secs(60);
+
+/// At most this much counting is lost if the process dies without a
+/// graceful shutdown.
+const COUNTERS_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Starts the periodic housekeeping jobs. Call once, from `main`.
+pub fn spawn(
+    database: web::Data<db::Pool>,
+    mailer: web::Data<dyn notify::Mailer>,
+    clock: web::Data<dyn Clock>,
+) {
+    // Apart, since a backfill of the usage table can run for a while.
+    let backfills = database.clone();
+    tasks::supervise("backfills", move |task| {
+        run_backfills(task, backfills.clone())
+    });
+    tasks::supervise("housekeeping", move |task| {
+        housekeep(task, database.clone(), mailer.clone(), clock.clone())
+    });
+}
+
+/// Starts saving the `/usage-statistics` counters on a timer. Call once,
+/// from `main`, after [`UsageStats::restore`], or the first save overwrites
+/// what it would have restored.
+pub fn spawn_counter_snapshots(stats: web::Data<UsageStats>, database: web::Data<db::Pool>) {
+    tasks::supervise("counter_snapshots", move |task| {
+        snapshot_counters(task, stats.clone(), database.clone())
+    });
+}
+
+/// Starts saving the counts of requests turned away, once a minute, the
+/// resolution they are kept at. Call once, from `main`.
+pub fn spawn_throttling_snapshots(
+    throttling: web::Data<Throttling>,
+    database: web::Data<db::Pool>,
+    clock: web::Data<dyn Clock>,
+) {
+    tasks::supervise("throttling_snapshots", move |task| {
+        snapshot_throttling(task, throttling.clone(), database.clone(), clock.clone())
+    });
+}
+
+/// Starts dropping the entries of idle keys from the concurrency and rate
+/// limiters, which otherwise keep one for every key ever seen. Call once,
+/// from `main`.
+pub fn spawn_limiter_eviction(
+    limiter: web::Data<ConcurrencyLimiter>,
+    rate_limiter: web::Data<RateLimiter>,
+    clock: web::Data<dyn Clock>,
+) {
+    tasks::supervise("limiter_eviction", move |task| {
+        evict_limiters(task, limiter.clone(), rate_limiter.clone(), clock.clone())
+    });
+}
+
+async fn evict_limiters(
+    task: tasks::Task,
+    limiter: web::Data<ConcurrencyLimiter>,
+    rate_limiter: web::Data<RateLimiter>,
+    clock: web::Data<dyn Clock>,
+) {
+    let mut interval = rt::time::interval(CLEANUP_INTERVAL);
+
+    loop {
+        interval.tick().await;
+
+        limiter.evict_idle();
+        rate_limiter.evict_idle(clock.now());
+        task.record("evict_idle_limiters", Ok::<_, std::convert::Infallible>(()));
+    }
+}
+
+async fn snapshot_throttling(
+    task: tasks::Task,
+    throttling: web::Data<Throttling>,
+    database: web::Data<db::Pool>,
+    clock: web::Data<dyn Clock>,
+) {
+    let mut interval = rt::time::interval(CLEANUP_INTERVAL);
+
+    loop {
+        interval.tick().await;
+
+        task.record(
+            "save_throttled",
+            throttling.save(database.clone(), clock.now()).await,
+        );
+    }
+}
+
+async fn snapshot_counters(
+    task: tasks::Task,
+    stats: web::Data<UsageStats>,
+    database: web::Data<db::Pool>,
+) {
+    let mut interval = rt::time::interval(COUNTERS_INTERVAL);
+
+    loop {
+        interval.tick().await;
+
+        task.record("save_counters", stats.save(database.clone()).await);
+    }
+}
+
+async fn run_backfills(task: tasks::Task, database: web::Data<db::Pool>) {
+    let mut interval = rt::time::interval(CLEANUP_INTERVAL);
+
+    loop {
+        interval.tick().await;
+
+        task.record(
+            "run_backfills",
+            migrate::run_backfills(database.clone()).await,
+        );
+    }
+}
+
+async fn housekeep(
+    task: tasks::Task,
+    database: web::Data<db::Pool>,
+    mailer: web::Data<dyn notify::Mailer>,
+    clock: web::Data<dyn Clock>,
+) {
+    let mut interval = rt::time::interval(CLEANUP_INTERVAL);
+
+    loop {
+        interval.tick().await;
+
+        task.record(
+            "delete_expired_logins",
+            db::Query::DeleteExpiredLogins
+                .execute(database.clone())
+                .await,
+        );
+        task.record(
+            "delete_expired_blocks",
+            db::Query::DeleteExpiredBlocks
+                .execute(database.clone())
+                .await,
+        );
+        task.record(
+            "send_due_reports",
+            subscriptions::send_due_reports(database.clone(), mailer.clone(), clock.now()).await,
+        );
+    }
+}
@@ -0,0 +1,250 @@
+//! What an API key may do. Keys are issued with scopes, [`crate::validator`]
+//! refuses routes needing one the key lacks, and handlers outside the
+//! authenticated scopes ask for theirs with [`Scoped`].
+
+use std::fmt;
+use std::future::Future;
+use std::marker::PhantomData;
+use std::pin::Pin;
+use std::str::FromStr;
+
+use actix_web::dev::Payload;
+use actix_web::http::header::Header as _;
+use actix_web::{web, FromRequest, HttpRequest};
+use actix_web_httpauth::headers::authorization::{Authorization, Basic};
+use chrono::{DateTime, Utc};
+use rusqlite::types::{FromSql, FromSqlResult, ToSql, ToSqlOutput, ValueRef};
+use serde::{Deserialize, Serialize};
+
+use crate::auth::{self, KeyCache};
+use crate::clock;
+use crate::db::{self, ApiEndpoint};
+use crate::error::ApiError;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub enum KeyScope {
+    /// The conversions, and the scales and field case they answer in.
+    #[serde(rename = "convert:read")]
+    ConvertRead,
+    /// The key's own usage, as reports, forecasts, alerts and exports.
+    #[serde(rename = "usage:read")]
+    UsageRead,
+    /// Everything, including revoking other keys. Never issued at signup;
+    /// see `POST /admin/keys`.
+    #[serde(rename = "admin")]
+    Admin,
+}
+
+impl KeyScope {
+    pub const ALL: [KeyScope; 3] = [KeyScope::ConvertRead, KeyScope::UsageRead, KeyScope::Admin];
+
+    /// Position in a [`ScopeSet`]. Stored, so never renumber.
+    const fn bit(&self) -> u32 {
+        let position = match self {
+            KeyScope::ConvertRead => 0,
+            KeyScope::UsageRead => 1,
+            KeyScope::Admin => 2,
+        };
+        1 << position
+    }
+}
+
+impl fmt::Display for KeyScope {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            KeyScope::ConvertRead => "convert:read",
+            KeyScope::UsageRead => "usage:read",
+            KeyScope::Admin => "admin",
+        })
+    }
+}
+
+#[derive(Debug)]
+pub struct UnknownKeyScope;
+
+impl fmt::Display for UnknownKeyScope {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("expected convert:read, usage:read or admin")
+    }
+}
+
+impl std::error::Error for UnknownKeyScope {}
+
+impl FromStr for KeyScope {
+    type Err = UnknownKeyScope;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "convert:read" => Ok(KeyScope::ConvertRead),
+            "usage:read" => Ok(KeyScope::UsageRead),
+            "admin" => Ok(KeyScope::Admin),
+            _ => Err(UnknownKeyScope),
+        }
+    }
+}
+
+/// Scopes packed into a bitmask, as stored in `api_keys.scopes`. The empty
+/// set grants nothing; keys issued before scopes were given
+/// [`ScopeSet::SELF_SERVICE`] by migration.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct ScopeSet(u32);
+
+impl ScopeSet {
+    pub const NONE: ScopeSet = ScopeSet(0);
+
+    pub const ALL: ScopeSet =
+        ScopeSet(KeyScope::ConvertRead.bit() | KeyScope::UsageRead.bit() | KeyScope::Admin.bit());
+
+    /// What a signup issues, and the most it may ask for.
+    pub const SELF_SERVICE: ScopeSet =
+        ScopeSet(KeyScope::ConvertRead.bit() | KeyScope::UsageRead.bit());
+
+    /// Parses a comma-separated list, e.g. `convert:read,usage:read`.
+    pub fn parse(list: &str) -> Result<ScopeSet, ApiError> {
+        let scopes = list
+            .split(',')
+            .map(|scope| scope.trim().parse())
+            .collect::<Result<Vec<KeyScope>, _>>()
+            .map_err(|_| ApiError::InvalidKeyScopes)?;
+
+        Ok(scopes.into_iter().collect())
+    }
+
+    pub fn contains(&self, scope: KeyScope) -> bool {
+        self.0 & scope.bit() != 0
+    }
+
+    /// Whether the set allows what `scope` does, which `admin` always does.
+    pub fn grants(&self, scope: KeyScope) -> bool {
+        self.contains(scope) || self.contains(KeyScope::Admin)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = KeyScope> + '_ {
+        KeyScope::ALL
+            .into_iter()
+            .filter(|scope| self.contains(*scope))
+    }
+}
+
+impl FromIterator<KeyScope> for ScopeSet {
+    fn from_iter<I: IntoIterator<Item = KeyScope>>(scopes: I) -> Self {
+        ScopeSet(scopes.into_iter().fold(0, |bits, scope| bits | scope.bit()))
+    }
+}
+
+impl ToSql for ScopeSet {
+    fn to_sql(&self) -> rusqlite::Result<ToSqlOutput<'_>> {
+        Ok(ToSqlOutput::from(self.0))
+    }
+}
+
+impl FromSql for ScopeSet {
+    fn column_result(value: ValueRef<'_>) -> FromSqlResult<Self> {
+        u32::column_result(value).map(ScopeSet)
+    }
+}
+
+/// Whether `api_key` may call the route `req` was matched to. Routes that
+/// aren't an [`ApiEndpoint`] need no scope.
+pub fn check(req: &HttpRequest, api_key: &str) -> Result<(), ApiError> {
+    let scope = req
+        .match_pattern()
+        .and_then(|pattern| ApiEndpoint::for_route(req.method().as_str(), &pattern))
+        .and_then(|endpoint| endpoint.scope());
+    let Some(scope) = scope else {
+        return Ok(());
+    };
+    let key_cache = req
+        .app_data::<web::Data<KeyCache>>()
+        .ok_or(ApiError::Internal)?;
+
+    require(key_cache, api_key, scope, clock::now(req))
+}
+
+/// Refuses `api_key` with 403 unless it is granted `scope`, and with 401
+/// unless it is an active key in `key_cache` at `now`.
+pub fn require(
+    key_cache: &KeyCache,
+    api_key: &str,
+    scope: KeyScope,
+    now: DateTime<Utc>,
+) -> Result<(), ApiError> {
+    let access = key_cache
+        .access(api_key, now)
+        .map_err(|_| ApiError::Internal)?
+        .ok_or(ApiError::Unauthorized)?;
+
+    if !access.scopes.grants(scope) {
+        return Err(ApiError::MissingScope);
+    }
+    Ok(())
+}
+
+/// Marker types naming the scope a handler needs.
+pub trait ScopeRequirement {
+    const SCOPE: KeyScope;
+}
+
+pub struct ConvertRead;
+pub struct UsageRead;
+pub struct Admin;
+
+impl ScopeRequirement for ConvertRead {
+    const SCOPE: KeyScope = KeyScope::ConvertRead;
+}
+
+impl ScopeRequirement for UsageRead {
+    const SCOPE: KeyScope = KeyScope::UsageRead;
+}
+
+impl ScopeRequirement for Admin {
+    const SCOPE: KeyScope = KeyScope::Admin;
+}
+
+/// Extractor admitting the request only if its API key is live and granted
+/// `S::SCOPE`, for handlers not behind [`crate::validator`]. Turns keys
+/// away as the validator does.
+///
+/// ```ignore
+/// async fn handler(_: Scoped<Admin>) -> impl Responder { ... }
+/// ```
+pub struct Scoped<S>(PhantomData<S>);
+
+impl<S: ScopeRequirement + 'static> FromRequest for Scoped<S> {
+    type Error = actix_web::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self, Self::Error>>>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        let api_key = Authorization::<Basic>::parse(req)
+            .map(|auth| auth.as_ref().user_id().to_owned())
+            .map_err(|_| ApiError::Unauthorized);
+        let database = req
+            .app_data::<web::Data<db::Pool>>()
+            .cloned()
+            .ok_or(ApiError::Internal);
+        let key_cache = req
+            .app_data::<web::Data<KeyCache>>()
+            .cloned()
+            .ok_or(ApiError::Internal);
+        let now = clock::now(req);
+
+        Box::pin(async move {
+            let (api_key, database, key_cache) = (api_key?, database?, key_cache?);
+
+            // A cache miss is looked up, as by the validator.
+            match key_cache
+                .check(database, &api_key, now)
+                .await
+                .map_err(|_| ApiError::Internal)?
+            {
+                auth::KeyStatus::Active => {}
+                auth::KeyStatus::Revoked => return Err(ApiError::KeyRevoked.into()),
+                auth::KeyStatus::Expired => return Err(ApiError::KeyExpired.into()),
+                auth::KeyStatus::Unknown => return Err(ApiError::Unauthorized.into()),
+            }
+            require(&key_cache, &api_key, S::SCOPE, now)?;
+
+            Ok(Scoped(PhantomData))
+        })
+    }
+}
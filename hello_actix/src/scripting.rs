@@ -0,0 +1,248 @@
+//! Conversions defined at runtime by admins as small [Rhai] scripts, served
+//! at `/api/convert/{name}/{value}`. A [`Plugin`] compiled in with the
+//! `scripting` feature.
+//!
+//! A script sees its input as the constant `x` and evaluates to the result,
+//! e.g. `x * 1.8 + 32.0`. Scripts are sandboxed: no modules or I/O, and
+//! bounded operations, call depth, collection sizes and wall-clock time.
+//!
+//! [Rhai]: https://rhai.rs
+
+use std::time::{Duration, Instant};
+
+use actix_web::{delete, get, put, web, HttpRequest, HttpResponse, Responder};
+use actix_web_httpauth::extractors::basic::BasicAuth;
+use rhai::{Dynamic, Engine, Scope};
+use rusqlite::OptionalExtension;
+use serde::{Deserialize, Serialize};
+
+use crate::config::Config;
+use crate::error::ApiError;
+use crate::plugin::Plugin;
+use crate::rbac::{Admin, Authorized};
+use crate::{clock, db, geoip, usage};
+
+pub(crate) const MAX_NAME_LENGTH: usize = 32;
+const MAX_SCRIPT_LENGTH: usize = 4096;
+const MAX_OPERATIONS: u64 = 100_000;
+
+pub struct Scripting;
+
+impl Plugin for Scripting {
+    fn name(&self) -> &'static str {
+        "scripting"
+    }
+
+    fn migrate(&self, conn: &rusqlite::Connection) -> rusqlite::Result<()> {
+        conn.execute(
+            "
+        CREATE TABLE IF NOT EXISTS custom_conversions (
+            name TEXT PRIMARY KEY,
+            script TEXT NOT NULL,
+            updated_at TEXT NOT NULL
+        );",
+            (),
+        )?;
+        Ok(())
+    }
+
+    fn configure_api(&self, cfg: &mut web::ServiceConfig) {
+        cfg.service(convert);
+    }
+
+    fn configure_admin(&self, cfg: &mut web::ServiceConfig) {
+        cfg.service(register).service(unregister);
+    }
+}
+
+/// Registers a conversion, replacing any with the same name.
+async fn store_conversion(
+    database: web::Data<db::Pool>,
+    name: String,
+    script: String,
+) -> actix_web::Result<()> {
+    let sql = "
+    INSERT INTO custom_conversions (name, script, updated_at)
+    VALUES (?1, ?2, ?3)
+    ON CONFLICT (name) DO UPDATE
+    SET script = excluded.script, updated_at = excluded.updated_at;
+    ";
+
+    db::run(database, move |conn| -> rusqlite::Result<_> {
+        conn.execute(sql, (name, script, db::Timestamp::now()))?;
+
+        Ok(())
+    })
+    .await
+}
+
+/// Returns `false` when no conversion has that name.
+async fn delete_conversion(database: web::Data<db::Pool>, name: String) -> actix_web::Result<bool> {
+    db::run(database, move |conn| -> rusqlite::Result<_> {
+        let n_rows = conn.execute("DELETE FROM custom_conversions WHERE name = ?1;", (name,))?;
+
+        Ok(n_rows > 0)
+    })
+    .await
+}
+
+async fn find_conversion(
+    database: web::Data<db::Pool>,
+    name: String,
+) -> actix_web::Result<Option<String>> {
+    db::run(database, move |conn| {
+        conn.query_row(
+            "SELECT script FROM custom_conversions WHERE name = ?1;",
+            (name,),
+            |row| row.get(0),
+        )
+        .optional()
+    })
+    .await
+}
+
+/// An engine with every resource limit applied. Built per run, because the
+/// wall-clock limit is measured from construction.
+fn sandbox(timeout: Duration) -> Engine {
+    let mut engine = Engine::new();
+
+    engine.set_max_operations(MAX_OPERATIONS);
+    engine.set_max_call_levels(16);
+    engine.set_max_expr_depths(32, 16);
+    engine.set_max_string_size(1024);
+    engine.set_max_array_size(1024);
+    engine.set_max_map_size(256);
+
+    engine.on_print(|_| {});
+    engine.on_debug(|_, _, _| {});
+
+    let started = Instant::now();
+    engine.on_progress(move |_| (started.elapsed() > timeout).then_some(Dynamic::UNIT));
+
+    engine
+}
+
+fn validate_name(name: &str) -> Result<(), ApiError> {
+    let is_valid = !name.is_empty()
+        && name.len() <= MAX_NAME_LENGTH
+        && name
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-');
+
+    if is_valid {
+        Ok(())
+    } else {
+        Err(ApiError::InvalidConversionName {
+            max_length: MAX_NAME_LENGTH,
+        })
+    }
+}
+
+fn evaluate(script: &str, x: f64, timeout: Duration) -> Result<f64, ApiError> {
+    let engine = sandbox(timeout);
+    let mut scope = Scope::new();
+    scope.push_constant("x", x);
+
+    let result: Dynamic = engine
+        .eval_with_scope(&mut scope, script)
+        .map_err(|_| ApiError::ScriptFailed)?;
+
+    result
+        .as_float()
+        .or_else(|_| result.as_int().map(|n| n as f64))
+        .ok()
+        .filter(|output| output.is_finite())
+        .ok_or(ApiError::ScriptFailed)
+}
+
+#[derive(Deserialize, Debug)]
+pub struct ConversionScript {
+    script: String,
+}
+
+/// Registers a conversion, or replaces the script of an existing one.
+#[put("/conversions/{name}")]
+async fn register(
+    _: Authorized<Admin>,
+    name: web::Path<String>,
+    body: web::Json<ConversionScript>,
+    config: web::Data<Config>,
+    database: web::Data<db::Pool>,
+) -> actix_web::Result<impl Responder> {
+    let name = name.into_inner();
+    validate_name(&name)?;
+
+    let script = body.into_inner().script;
+    if script.len() > MAX_SCRIPT_LENGTH {
+        return Err(ApiError::InvalidScript.into());
+    }
+
+    let timeout = Duration::from_millis(config.script_timeout_ms);
+    sandbox(timeout)
+        .compile(&script)
+        .map_err(|_| ApiError::InvalidScript)?;
+
+    store_conversion(database, name, script).await?;
+
+    Ok(HttpResponse::NoContent().finish())
+}
+
+#[delete("/conversions/{name}")]
+async fn unregister(
+    _: Authorized<Admin>,
+    name: web::Path<String>,
+    database: web::Data<db::Pool>,
+) -> actix_web::Result<impl Responder> {
+    if delete_conversion(database, name.into_inner()).await? {
+        Ok(HttpResponse::NoContent().finish())
+    } else {
+        Err(ApiError::ConversionNotFound.into())
+    }
+}
+
+#[derive(Serialize)]
+struct Conversion {
+    name: String,
+    input: f64,
+    output: f64,
+}
+
+#[get(
+    "/convert/{name}/{value}",
+    wrap = "actix_web::middleware::from_fn(usage::record_usage)"
+)]
+async fn convert(
+    path: web::Path<(String, f64)>,
+    config: web::Data<Config>,
+    database: web::Data<db::Pool>,
+    req: HttpRequest,
+    auth: BasicAuth,
+) -> actix_web::Result<impl Responder> {
+    let (name, input) = path.into_inner();
+    validate_name(&name)?;
+
+    let script = find_conversion(database, name.clone())
+        .await?
+        .ok_or(ApiError::ConversionNotFound)?;
+
+    let call = db::ApiUsage {
+        api_key: auth.user_id().to_string(),
+        endpoint: db::ApiEndpoint::Convert,
+        called_at: clock::now(&req),
+        client_request_id: None,
+        tag: None,
+        location: geoip::Location::default(),
+        latency_ms: None,
+    };
+    usage::defer(&req, call);
+
+    // Scripts are CPU-bound, so keep them off the async workers.
+    let timeout = Duration::from_millis(config.script_timeout_ms);
+    let output = web::block(move || evaluate(&script, input, timeout)).await??;
+
+    Ok(web::Json(Conversion {
+        name,
+        input,
+        output,
+    }))
+}
@@ -0,0 +1,112 @@
+use std::future::Future;
+use std::pin::Pin;
+
+use actix_web::body::MessageBody;
+use actix_web::cookie::{time, Cookie, SameSite};
+use actix_web::dev::{Payload, ServiceRequest, ServiceResponse};
+use actix_web::middleware::Next;
+use actix_web::{web, Error, FromRequest, HttpRequest};
+use chrono::{DateTime, Duration, Utc};
+use tracing::warn;
+
+use crate::config::Config;
+use crate::db::{self, User};
+use crate::error::ApiError;
+use crate::{auth, clock};
+
+pub const SESSION_COOKIE: &str = "session";
+
+/// Persists a new session for `user`, started at `now`, and returns the
+/// cookie carrying it.
+pub async fn start(
+    database: web::Data<db::Pool>,
+    config: &Config,
+    user: &User,
+    now: DateTime<Utc>,
+) -> Result<Cookie<'static>, actix_web::Error> {
+    let session_id = auth::generate_token().map_err(|_| ApiError::Internal)?;
+    let expires_at = now + Duration::hours(config.session_ttl_hours);
+
+    let query = db::Query::CreateSession {
+        id_hash: auth::hash_token(&session_id),
+        user_id: user.id,
+        expires_at,
+    };
+    query.execute(database).await?;
+
+    Ok(session_cookie(session_id, config))
+}
+
+fn session_cookie(session_id: String, config: &Config) -> Cookie<'static> {
+    Cookie::build(SESSION_COOKIE, session_id)
+        .path("/")
+        .http_only(true)
+        .secure(config.require_https)
+        .same_site(SameSite::Lax)
+        .max_age(time::Duration::hours(config.session_ttl_hours))
+        .finish()
+}
+
+/// Sliding expiry: each request made with a live session extends it by the
+/// full TTL, in the database and in the browser.
+pub async fn refresh(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    let session_id = req.cookie(SESSION_COOKIE).map(|c| c.value().to_owned());
+    let database = req.app_data::<web::Data<db::Pool>>().cloned();
+    let config = req.app_data::<web::Data<Config>>().cloned();
+    let now = clock::now(req.request());
+
+    let mut res = next.call(req).await?;
+
+    let (Some(session_id), Some(database), Some(config)) = (session_id, database, config) else {
+        return Ok(res);
+    };
+
+    let query = db::Query::RefreshSession {
+        id_hash: auth::hash_token(&session_id),
+        expires_at: now + Duration::hours(config.session_ttl_hours),
+    };
+
+    match query.execute(database).await {
+        Ok(Some(true)) => {
+            let cookie = session_cookie(session_id, &config);
+            if let Err(err) = res.response_mut().add_cookie(&cookie) {
+                warn!(%err, "failed to refresh session cookie");
+            }
+        }
+        Ok(_) => {}
+        Err(err) => warn!(%err, "failed to refresh session"),
+    }
+
+    Ok(res)
+}
+
+/// The dashboard user behind the request's session cookie.
+///
+/// Extraction fails with 401 when there is no cookie or the session has
+/// expired.
+#[derive(Debug, Clone)]
+pub struct DashboardUser(pub User);
+
+impl FromRequest for DashboardUser {
+    type Error = actix_web::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Self, Self::Error>>>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        let session_id = req.cookie(SESSION_COOKIE).map(|c| c.value().to_owned());
+        let database = req.app_data::<web::Data<db::Pool>>().cloned();
+        let now = clock::now(req);
+
+        Box::pin(async move {
+            let session_id = session_id.ok_or(ApiError::NotLoggedIn)?;
+            let database = database.ok_or(ApiError::Internal)?;
+
+            db::find_session_user(database, auth::hash_token(&session_id), now)
+                .await?
+                .map(DashboardUser)
+                .ok_or_else(|| ApiError::NotLoggedIn.into())
+        })
+    }
+}
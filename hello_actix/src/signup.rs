@@ -0,0 +1,212 @@
+//! Self-service API keys. A caller proves they own an email address by
+//! following a verification link, and is then issued a free-tier key tied
+//! to that address.
+
+use actix_web::{get, post, web, HttpRequest, HttpResponse, Responder};
+use chrono::{Duration, TimeDelta};
+use serde::Deserialize;
+use serde_json::Value;
+use tracing::{instrument, warn};
+
+use crate::config::Config;
+use crate::error::ApiError;
+use crate::notify::{self, Target};
+use crate::scopes::{KeyScope, ScopeSet};
+use crate::{auth, challenge, clock, db};
+
+/// Rough sanity check; deliverability is what really validates an address.
+/// Whitespace and control characters are refused, as the address ends up in
+/// mail headers.
+pub fn normalize_email(email: &str) -> Result<String, ApiError> {
+    let email = email.trim().to_lowercase();
+    if email.contains(|c: char| c.is_whitespace() || c.is_control()) {
+        return Err(ApiError::InvalidEmail);
+    }
+
+    match email.split_once('@') {
+        Some((local, domain))
+            if !local.is_empty() && domain.contains('.') && email.len() <= 254 =>
+        {
+            Ok(email)
+        }
+        _ => Err(ApiError::InvalidEmail),
+    }
+}
+
+/// Parses a key lifetime as a whole number of days or hours, e.g. `30d` or
+/// `12h`.
+pub fn parse_ttl(ttl: &str) -> Result<TimeDelta, ApiError> {
+    let count = |count: &str| count.parse::<i64>().ok().filter(|count| *count > 0);
+
+    if let Some(days) = ttl.strip_suffix('d') {
+        count(days).and_then(TimeDelta::try_days)
+    } else if let Some(hours) = ttl.strip_suffix('h') {
+        count(hours).and_then(TimeDelta::try_hours)
+    } else {
+        None
+    }
+    .ok_or(ApiError::InvalidKeyTtl)
+}
+
+#[derive(Deserialize, Debug)]
+pub struct SignupRequest {
+    email: String,
+    /// Required unless the deployment disabled the challenge.
+    #[serde(flatten)]
+    solution: Option<challenge::Solution>,
+}
+
+/// Sends a verification link to `email`. Answers 202 whether or not the
+/// address already holds a key.
+#[post("/signup")]
+#[instrument(skip_all)]
+pub async fn request_signup(
+    body: web::Json<SignupRequest>,
+    config: web::Data<Config>,
+    database: web::Data<db::Pool>,
+    mailer: web::Data<dyn notify::Mailer>,
+    req: HttpRequest,
+) -> actix_web::Result<impl Responder> {
+    let email = normalize_email(&body.email)?;
+    let now = clock::now(&req);
+    challenge::verify(database.clone(), &config, body.solution.as_ref(), now).await?;
+
+    let token = auth::generate_token().map_err(|_| ApiError::Internal)?;
+    let query = db::Query::StoreSignup {
+        token_hash: auth::hash_token(&token),
+        email: email.clone(),
+        expires_at: now + Duration::hours(config.signup_link_ttl_hours),
+    };
+    query.execute(database).await?;
+
+    let link = format!("{}/signup/{token}", config.public_url.trim_end_matches('/'));
+    let text = format!(
+        "Follow this link within {} hours to get your API key:\n\n{link}",
+        config.signup_link_ttl_hours
+    );
+    let target = Target::Email(email);
+    if let Err(err) = notify::deliver(mailer, &target, "Your API key", &Value::String(text)).await {
+        warn!(%err, "failed to send signup verification link");
+    }
+
+    Ok(HttpResponse::Accepted().finish())
+}
+
+#[derive(Deserialize, Debug)]
+pub struct KeyOptions {
+    /// See [`parse_ttl`]. Keys without one don't expire.
+    ttl: Option<String>,
+    /// See [`ScopeSet::parse`]. Keys without them get
+    /// [`ScopeSet::SELF_SERVICE`].
+    scopes: Option<String>,
+}
+
+/// Posts back to the link it was opened from, query included, so the
+/// token never appears in the page.
+const CONFIRM_SIGNUP_PAGE: &str = r#"<!doctype html>
+<html lang="en">
+<meta charset="utf-8">
+<title>Get your API key</title>
+<form method="post">
+  <p>Issue an API key for the address this link was sent to? Any key issued to it before is revoked.</p>
+  <button>Get my API key</button>
+</form>
+</html>
+"#;
+
+/// Where a verification link lands: a page to confirm from, as mail
+/// scanners and link previews open links too. Redeems nothing.
+#[get("/signup/{token}")]
+pub async fn confirm_signup() -> impl Responder {
+    HttpResponse::Ok()
+        .content_type("text/html; charset=utf-8")
+        .insert_header(("Referrer-Policy", "no-referrer"))
+        .body(CONFIRM_SIGNUP_PAGE)
+}
+
+/// Redeems a verification link, issuing a free-tier key, limited to `?ttl=`
+/// and `?scopes=` if given, e.g. for a CI pipeline that only converts. Any
+/// key issued to the same address before is revoked.
+#[post("/signup/{token}")]
+#[instrument(skip_all)]
+pub async fn verify_signup(
+    token: web::Path<String>,
+    options: web::Query<KeyOptions>,
+    database: web::Data<db::Pool>,
+    keys: web::Data<dyn auth::KeyGenerator>,
+    key_cache: web::Data<auth::KeyCache>,
+    req: HttpRequest,
+) -> actix_web::Result<impl Responder> {
+    // Before redeeming, so a mistyped option doesn't use up the link. By the
+    // app's clock, as expiry is checked by it.
+    let now = clock::now(&req);
+    let expires_at = match options.ttl.as_deref() {
+        Some(ttl) => Some(
+            now.checked_add_signed(parse_ttl(ttl)?)
+                .ok_or(ApiError::InvalidKeyTtl)?,
+        ),
+        None => None,
+    };
+    let scopes = match options.scopes.as_deref() {
+        Some(scopes) => ScopeSet::parse(scopes)?,
+        None => ScopeSet::SELF_SERVICE,
+    };
+    // A verified address is no grounds for revoking keys and the like.
+    if scopes.contains(KeyScope::Admin) {
+        return Err(ApiError::InvalidKeyScopes.into());
+    }
+    let token_hash = auth::hash_token(&token);
+
+    let email = db::redeem_signup(database.clone(), token_hash, now)
+        .await?
+        .ok_or(ApiError::InvalidSignupLink)?;
+
+    let mut api_key = auth::store_api_key(
+        database,
+        &key_cache,
+        &**keys,
+        email,
+        db::Tier::Free,
+        expires_at,
+        scopes,
+    )
+    .await?;
+
+    api_key.push_str("\r\n");
+
+    Ok(api_key)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn emails() {
+        assert_eq!(
+            normalize_email(" Ada@Example.COM ").unwrap(),
+            "ada@example.com"
+        );
+        for email in [
+            "",
+            "ada",
+            "@example.com",
+            "ada@localhost",
+            "a da@example.com",
+            "ada@exa\nmple.com",
+        ] {
+            assert!(normalize_email(email).is_err(), "{email:?}");
+        }
+        let long = format!("{}@example.com", "a".repeat(250));
+        assert!(normalize_email(&long).is_err());
+    }
+
+    #[test]
+    fn ttls() {
+        assert_eq!(parse_ttl("30d").unwrap(), TimeDelta::days(30));
+        assert_eq!(parse_ttl("12h").unwrap(), TimeDelta::hours(12));
+        for ttl in ["", "d", "0d", "-1d", "30", "30m", "1.5h", "99999999999999d"] {
+            assert!(parse_ttl(ttl).is_err(), "{ttl}");
+        }
+    }
+}
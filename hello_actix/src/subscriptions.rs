@@ -0,0 +1,144 @@
+//! Recurring usage reports: admins subscribe an email address or webhook to
+//! a cron schedule, and [`crate::scheduler`] sends each report once due.
+
+use actix_web::{delete, get, post, web, HttpRequest, HttpResponse, Responder};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use tracing::{info, warn};
+
+use crate::cron::Schedule;
+use crate::db::{self, ApiEndpoint, ReportSubscription};
+use crate::error::ApiError;
+use crate::jsonapi::Listed;
+use crate::notify::{self, Target};
+use crate::rbac::{Authorized, Operator, Viewer};
+use crate::{clock, report};
+
+#[get("/report-subscriptions")]
+pub async fn list_subscriptions(
+    _: Authorized<Viewer>,
+    database: web::Data<db::Pool>,
+) -> actix_web::Result<impl Responder> {
+    Ok(Listed(db::list_report_subscriptions(database).await?))
+}
+
+#[derive(Deserialize, Debug)]
+pub struct NewSubscription {
+    /// Omit to report every endpoint.
+    endpoint: Option<ApiEndpoint>,
+    schedule: String,
+    target: Target,
+}
+
+#[post("/report-subscriptions")]
+pub async fn add_subscription(
+    _: Authorized<Operator>,
+    body: web::Json<NewSubscription>,
+    database: web::Data<db::Pool>,
+    req: HttpRequest,
+) -> actix_web::Result<impl Responder> {
+    let body = body.into_inner();
+    let schedule: Schedule = body
+        .schedule
+        .parse()
+        .map_err(|_| ApiError::InvalidSchedule)?;
+    let next_run_at = schedule
+        .next_after(clock::now(&req))
+        .ok_or(ApiError::InvalidSchedule)?;
+
+    let query = db::Query::AddReportSubscription {
+        endpoint: body.endpoint,
+        schedule: schedule.to_string(),
+        target: body.target.validated()?,
+        next_run_at,
+    };
+    query.execute(database).await?;
+
+    Ok(HttpResponse::NoContent().finish())
+}
+
+#[delete("/report-subscriptions/{id}")]
+pub async fn delete_subscription(
+    _: Authorized<Operator>,
+    id: web::Path<i64>,
+    database: web::Data<db::Pool>,
+) -> actix_web::Result<impl Responder> {
+    let query = db::Query::DeleteReportSubscription {
+        id: id.into_inner(),
+    };
+
+    if query.execute(database).await? != Some(true) {
+        return Err(ApiError::SubscriptionNotFound.into());
+    }
+
+    Ok(HttpResponse::NoContent().finish())
+}
+
+/// What a subscriber is sent: calls per endpoint since the previous report,
+/// or since subscribing.
+#[derive(Serialize)]
+struct UsageReport {
+    subscription_id: i64,
+    from: DateTime<Utc>,
+    to: DateTime<Utc>,
+    endpoint: Option<ApiEndpoint>,
+    usage: Vec<report::EndpointTotal>,
+}
+
+/// Sends every report due by `now` and schedules the next. A report that
+/// can't be delivered is logged and skipped rather than retried, so a dead
+/// webhook isn't hammered every minute.
+pub async fn send_due_reports(
+    database: web::Data<db::Pool>,
+    mailer: web::Data<dyn notify::Mailer>,
+    now: DateTime<Utc>,
+) -> Result<(), actix_web::Error> {
+    for subscription in db::due_report_subscriptions(database.clone(), now).await? {
+        send_report(database.clone(), mailer.clone(), &subscription, now).await?;
+
+        // Checked when subscribing, so only a corrupted row fails to parse.
+        let next_run_at = subscription
+            .schedule
+            .parse::<Schedule>()
+            .ok()
+            .and_then(|schedule| schedule.next_after(now));
+        let query = db::Query::MarkReportSent {
+            id: subscription.id,
+            ran_at: now,
+            next_run_at,
+        };
+        query.execute(database.clone()).await?;
+    }
+
+    Ok(())
+}
+
+async fn send_report(
+    database: web::Data<db::Pool>,
+    mailer: web::Data<dyn notify::Mailer>,
+    subscription: &ReportSubscription,
+    now: DateTime<Utc>,
+) -> Result<(), actix_web::Error> {
+    let from = subscription.last_run_at.unwrap_or(subscription.created_at);
+    let mut usage = db::usage_counts(database, from, now).await?;
+    if let Some(endpoint) = subscription.endpoint {
+        usage.retain(|row| row.endpoint == endpoint.as_str());
+    }
+
+    let report = UsageReport {
+        subscription_id: subscription.id,
+        from,
+        to: now,
+        endpoint: subscription.endpoint,
+        usage: report::totals(&usage),
+    };
+    let subject = format!("Usage report {from} to {now}");
+
+    match notify::deliver(mailer, &subscription.target, &subject, &json!(report)).await {
+        Ok(()) => info!(subscription_id = subscription.id, "usage report sent"),
+        Err(err) => warn!(subscription_id = subscription.id, %err, "failed to send usage report"),
+    }
+
+    Ok(())
+}
@@ -0,0 +1,223 @@
+//! Background tasks: the scheduler's jobs, the release check, listeners for
+//! other protocols, and notification deliveries. Each runs in a `task` span
+//! naming it and its id. Each is listed at `GET /admin/tasks` while it runs.
+//!
+//! Periodic tasks report every job they run through [`Task::record`], which
+//! logs the outcome and keeps the task's health. Long-running tasks are
+//! started with [`supervise`], which restarts them when they panic.
+
+use std::any::Any;
+use std::collections::BTreeMap;
+use std::fmt::Display;
+use std::future::Future;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{LazyLock, Mutex};
+use std::time::{Duration, Instant};
+
+use actix_web::{get, rt, web, Responder};
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use tracing::{debug, info_span, warn, Instrument as _};
+
+use crate::rbac::{Authorized, Viewer};
+
+/// Wait before the first restart of a panicked task. Doubles with every
+/// further panic, up to [`MAX_BACKOFF`].
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+
+/// Longest wait between restarts. A task that ran this long before
+/// panicking is restarted after [`INITIAL_BACKOFF`] again.
+const MAX_BACKOFF: Duration = Duration::from_secs(5 * 60);
+
+static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+
+static RUNNING: LazyLock<Mutex<BTreeMap<u64, TaskStatus>>> =
+    LazyLock::new(|| Mutex::new(BTreeMap::new()));
+
+/// Restarts since startup by task name, kept after the tasks end.
+static RESTARTS: LazyLock<Mutex<BTreeMap<&'static str, u64>>> =
+    LazyLock::new(|| Mutex::new(BTreeMap::new()));
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Health {
+    /// Hasn't reported a job yet, or never does.
+    Running,
+    /// Its last job succeeded.
+    Ok,
+    /// Its last job failed, or it panicked since.
+    Failing,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct TaskStatus {
+    pub id: u64,
+    pub name: &'static str,
+    pub started_at: DateTime<Utc>,
+    pub health: Health,
+    pub runs: u64,
+    pub failures: u64,
+    /// Times [`supervise`] restarted the task after a panic.
+    pub restarts: u64,
+    pub last_run_at: Option<DateTime<Utc>>,
+    pub last_error: Option<String>,
+}
+
+/// A running task's handle on its own entry.
+#[derive(Debug, Clone, Copy)]
+pub struct Task {
+    id: u64,
+}
+
+impl Task {
+    /// Records one run of `job`, logging its outcome.
+    pub fn record<T, E: Display>(&self, job: &'static str, outcome: Result<T, E>) {
+        let error = outcome.err().map(|err| err.to_string());
+        match &error {
+            Some(err) => warn!(job, %err, "job failed"),
+            None => debug!(job, "job succeeded"),
+        }
+
+        if let Some(status) = RUNNING.lock().unwrap().get_mut(&self.id) {
+            status.runs += 1;
+            status.last_run_at = Some(Utc::now());
+            status.health = match error {
+                Some(_) => {
+                    status.failures += 1;
+                    Health::Failing
+                }
+                None => Health::Ok,
+            };
+            status.last_error = error;
+        }
+    }
+
+    /// Counts a restart after `panic`, returning the task's restarts so far.
+    fn restarted(&self, name: &'static str, panic: &str) -> u64 {
+        *RESTARTS.lock().unwrap().entry(name).or_default() += 1;
+
+        let mut running = RUNNING.lock().unwrap();
+        let Some(status) = running.get_mut(&self.id) else {
+            return 0;
+        };
+        status.restarts += 1;
+        status.health = Health::Failing;
+        status.last_error = Some(panic.to_owned());
+        status.restarts
+    }
+}
+
+/// Removes a task's entry however it ends, panics included.
+struct Registration(u64);
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        RUNNING.lock().unwrap().remove(&self.0);
+    }
+}
+
+fn register(name: &'static str) -> (Task, Registration) {
+    let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+    RUNNING.lock().unwrap().insert(
+        id,
+        TaskStatus {
+            id,
+            name,
+            started_at: Utc::now(),
+            health: Health::Running,
+            runs: 0,
+            failures: 0,
+            restarts: 0,
+            last_run_at: None,
+            last_error: None,
+        },
+    );
+    (Task { id }, Registration(id))
+}
+
+/// Spawns the future `task` makes on the current runtime, listed as `name`
+/// until it ends.
+pub fn spawn<F, Fut>(name: &'static str, task: F)
+where
+    F: FnOnce(Task) -> Fut,
+    Fut: Future<Output = ()> + 'static,
+{
+    let (handle, registration) = register(name);
+    let future = task(handle);
+    rt::spawn(
+        async move {
+            let _registration = registration;
+            debug!("task started");
+            future.await;
+            debug!("task finished");
+        }
+        .instrument(info_span!("task", task = name, task_id = handle.id)),
+    );
+}
+
+/// Like [`spawn`], but should the future panic, `task` is called for a new
+/// one after a backoff. The task keeps its entry, and its restarts are
+/// counted in [`crate::metrics`].
+pub fn supervise<F, Fut>(name: &'static str, mut task: F)
+where
+    F: FnMut(Task) -> Fut + 'static,
+    Fut: Future<Output = ()> + 'static,
+{
+    let (handle, registration) = register(name);
+    rt::spawn(
+        async move {
+            let _registration = registration;
+            let mut backoff = INITIAL_BACKOFF;
+
+            loop {
+                debug!("task started");
+                let started = Instant::now();
+                let panic = match rt::spawn(task(handle).in_current_span()).await {
+                    Ok(()) => {
+                        debug!("task finished");
+                        break;
+                    }
+                    Err(err) if err.is_panic() => panic_message(err.into_panic()),
+                    // Cancelled, as the runtime shuts down.
+                    Err(_) => break,
+                };
+
+                if started.elapsed() >= MAX_BACKOFF {
+                    backoff = INITIAL_BACKOFF;
+                }
+                let restarts = handle.restarted(name, &panic);
+                warn!(%panic, restarts, ?backoff, "task panicked; restarting");
+                rt::time::sleep(backoff).await;
+                backoff = (backoff * 2).min(MAX_BACKOFF);
+            }
+        }
+        .instrument(info_span!("task", task = name, task_id = handle.id)),
+    );
+}
+
+fn panic_message(payload: Box<dyn Any + Send>) -> String {
+    let message = match payload.downcast::<String>() {
+        Ok(message) => *message,
+        Err(payload) => match payload.downcast::<&str>() {
+            Ok(message) => (*message).to_owned(),
+            Err(_) => "(a non-string payload)".to_owned(),
+        },
+    };
+    format!("panicked: {message}")
+}
+
+/// The tasks running now, oldest first.
+pub fn running() -> Vec<TaskStatus> {
+    RUNNING.lock().unwrap().values().cloned().collect()
+}
+
+/// Restarts after panics since startup, by task name.
+pub fn restart_counts() -> BTreeMap<&'static str, u64> {
+    RESTARTS.lock().unwrap().clone()
+}
+
+/// Background tasks running now, and how their jobs are going.
+#[get("/tasks")]
+pub async fn list_tasks(_: Authorized<Viewer>) -> impl Responder {
+    web::Json(running())
+}
@@ -0,0 +1,182 @@
+//! Requests turned away with 429, by the rule that did it, to tune the
+//! limits with data. Counted in memory per minute, key and address, saved
+//! by [`crate::scheduler::spawn_throttling_snapshots`], and reported at
+//! `GET /admin/throttling`.
+
+use std::net::IpAddr;
+
+use actix_web::dev::ServiceRequest;
+use actix_web::{get, web, Error, HttpRequest, Responder};
+use chrono::{DateTime, DurationRound, TimeDelta, Utc};
+use dashmap::DashMap;
+use serde::Serialize;
+
+use crate::db::{self, Throttled, ThrottledSummary};
+use crate::rbac::{Authorized, Viewer};
+use crate::{auth, blocklist, clock};
+
+/// What turned a request away.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Rule {
+    /// [`crate::concurrency::limit_concurrency`].
+    Concurrency,
+    /// [`crate::ratelimit::limit_rate`].
+    RateLimit,
+    /// [`crate::quota::enforce_quota`].
+    Quota,
+}
+
+impl Rule {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Rule::Concurrency => "concurrency",
+            Rule::RateLimit => "rate_limit",
+            Rule::Quota => "quota",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct Throttle {
+    minute: DateTime<Utc>,
+    rule: Rule,
+    key_id: Option<i64>,
+    ip: Option<IpAddr>,
+}
+
+/// The windows `GET /admin/throttling` reports on, longest last. Rows older
+/// than the longest are dropped as the counts are saved.
+const WINDOWS: [(&str, TimeDelta); 3] = [
+    ("15m", TimeDelta::minutes(15)),
+    ("1h", TimeDelta::hours(1)),
+    ("24h", TimeDelta::hours(24)),
+];
+
+/// Keys and addresses listed per window.
+const TOP: usize = 10;
+
+/// Counts not yet saved.
+#[derive(Debug, Default)]
+pub struct Throttling {
+    pending: DashMap<Throttle, u64>,
+}
+
+impl Throttling {
+    pub fn new() -> Self {
+        Throttling::default()
+    }
+
+    /// Counts not yet saved, by minute, rule, key and address.
+    pub fn entries(&self) -> usize {
+        self.pending.len()
+    }
+
+    fn count(&self, throttle: Throttle, n: u64) {
+        *self.pending.entry(throttle).or_insert(0) += n;
+    }
+
+    /// Adds the counts so far to the `throttled` table. Counts that fail to
+    /// save are kept for the next try.
+    pub async fn save(
+        &self,
+        database: web::Data<db::Pool>,
+        now: DateTime<Utc>,
+    ) -> Result<(), Error> {
+        let throttles: Vec<Throttle> = self
+            .pending
+            .iter()
+            .map(|entry| entry.key().clone())
+            .collect();
+        let taken: Vec<(Throttle, u64)> = throttles
+            .into_iter()
+            .filter_map(|throttle| self.pending.remove(&throttle))
+            .collect();
+        if taken.is_empty() {
+            return Ok(());
+        }
+
+        let rows = taken
+            .iter()
+            .map(|(throttle, count)| Throttled {
+                minute: throttle.minute,
+                rule: throttle.rule.as_str(),
+                key_id: throttle.key_id,
+                ip: throttle.ip.map(|ip| ip.to_string()),
+                count: *count,
+            })
+            .collect();
+        let (_, longest) = WINDOWS[WINDOWS.len() - 1];
+
+        let saved = db::save_throttled(database, rows, now - longest).await;
+        if saved.is_err() {
+            for (throttle, count) in taken {
+                self.count(throttle, count);
+            }
+        }
+        saved
+    }
+}
+
+/// Counts `req`, from `api_key`, as turned away by `rule`. A no-op in apps
+/// without [`Throttling`].
+pub fn throttled(req: &ServiceRequest, rule: Rule, api_key: &str) {
+    let Some(throttling) = req.app_data::<web::Data<Throttling>>() else {
+        return;
+    };
+    let now = clock::now(req.request());
+
+    throttling.count(
+        Throttle {
+            minute: now.duration_trunc(TimeDelta::minutes(1)).unwrap_or(now),
+            rule,
+            key_id: auth::key_access(req.request(), api_key)
+                .ok()
+                .flatten()
+                .map(|access| access.id),
+            ip: blocklist::client_ip(req.request()),
+        },
+        1,
+    );
+}
+
+#[derive(Serialize)]
+struct ThrottlingWindow {
+    window: &'static str,
+    since: DateTime<Utc>,
+    #[serde(flatten)]
+    summary: ThrottledSummary,
+}
+
+#[derive(Serialize)]
+struct ThrottlingReport {
+    windows: Vec<ThrottlingWindow>,
+}
+
+/// Requests turned away with 429 over the last 15 minutes, hour and day,
+/// per rule, and the keys and addresses turned away most. As last saved, so
+/// up to a minute behind.
+#[get("/throttling")]
+pub async fn throttling_report(
+    _: Authorized<Viewer>,
+    req: HttpRequest,
+    database: web::Data<db::Pool>,
+) -> actix_web::Result<impl Responder> {
+    let now = clock::now(&req);
+
+    let windows = db::read_consistent(database, move |conn| {
+        WINDOWS
+            .iter()
+            .map(|&(window, length)| {
+                let since = now - length;
+                Ok(ThrottlingWindow {
+                    window,
+                    since,
+                    summary: db::summarize_throttled(conn, since, TOP)?,
+                })
+            })
+            .collect()
+    })
+    .await?;
+
+    Ok(web::Json(ThrottlingReport { windows }))
+}
@@ -0,0 +1,225 @@
+//! Time-based one-time passwords (RFC 6238) as a second factor for
+//! destructive dashboard operations.
+
+use actix_web::body::MessageBody;
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::middleware::Next;
+use actix_web::{post, web, Error, HttpRequest, HttpResponse, Responder};
+use chrono::{DateTime, Utc};
+use ring::hmac;
+use ring::rand::{self, SecureRandom};
+use serde::{Deserialize, Serialize};
+
+use crate::error::ApiError;
+use crate::session::DashboardUser;
+use crate::{clock, db};
+
+pub const TOTP_HEADER: &str = "X-TOTP-Code";
+
+const ISSUER: &str = "hello_actix";
+const SECRET_LENGTH: usize = 20;
+const STEP_SECONDS: i64 = 30;
+const DIGITS: u32 = 6;
+/// Steps either side of "now" still accepted, to tolerate clock drift.
+const ALLOWED_DRIFT: i64 = 1;
+const BASE32_ALPHABET: &[u8; 32] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ234567";
+
+fn base32_encode(bytes: &[u8]) -> String {
+    let mut encoded = String::new();
+    for chunk in bytes.chunks(5) {
+        let mut buffer = [0u8; 5];
+        buffer[..chunk.len()].copy_from_slice(chunk);
+        let bits = buffer
+            .iter()
+            .fold(0u64, |acc, &b| (acc << 8) | u64::from(b));
+
+        let n_chars = (chunk.len() * 8).div_ceil(5);
+        for i in 0..n_chars {
+            let index = (bits >> (35 - i * 5)) & 0x1f;
+            encoded.push(BASE32_ALPHABET[index as usize] as char);
+        }
+    }
+    encoded
+}
+
+fn base32_decode(text: &str) -> Option<Vec<u8>> {
+    let mut bytes = Vec::new();
+    let mut bits = 0u32;
+    let mut n_bits = 0;
+
+    for c in text.bytes().filter(|c| *c != b'=') {
+        let value = BASE32_ALPHABET
+            .iter()
+            .position(|&a| a == c.to_ascii_uppercase())?;
+        bits = (bits << 5) | value as u32;
+        n_bits += 5;
+
+        if n_bits >= 8 {
+            n_bits -= 8;
+            bytes.push((bits >> n_bits) as u8);
+            bits &= (1 << n_bits) - 1;
+        }
+    }
+
+    Some(bytes)
+}
+
+fn generate_secret() -> Result<String, ApiError> {
+    let mut secret = [0u8; SECRET_LENGTH];
+    rand::SystemRandom::new()
+        .fill(&mut secret)
+        .map_err(|_| ApiError::Internal)?;
+    Ok(base32_encode(&secret))
+}
+
+/// The HOTP value (RFC 4226) for one time step.
+fn code_at(secret: &[u8], step: i64) -> u32 {
+    let key = hmac::Key::new(hmac::HMAC_SHA1_FOR_LEGACY_USE_ONLY, secret);
+    let tag = hmac::sign(&key, &step.to_be_bytes());
+    let hash = tag.as_ref();
+
+    let offset = (hash[hash.len() - 1] & 0x0f) as usize;
+    let truncated = u32::from_be_bytes([
+        hash[offset] & 0x7f,
+        hash[offset + 1],
+        hash[offset + 2],
+        hash[offset + 3],
+    ]);
+
+    truncated % 10u32.pow(DIGITS)
+}
+
+/// Returns the time step `code` is valid for at `now`, if any.
+fn verify(secret: &str, code: &str, now: DateTime<Utc>) -> Option<i64> {
+    let code = code.trim();
+    if code.len() != DIGITS as usize {
+        return None;
+    }
+    let code: u32 = code.parse().ok()?;
+    let secret = base32_decode(secret)?;
+
+    let now = now.timestamp() / STEP_SECONDS;
+    (now - ALLOWED_DRIFT..=now + ALLOWED_DRIFT).find(|&step| code_at(&secret, step) == code)
+}
+
+/// Checks `code` against the user's secret and burns its time step.
+async fn accept_code(
+    database: web::Data<db::Pool>,
+    user_id: i64,
+    secret: &str,
+    code: &str,
+    now: DateTime<Utc>,
+) -> Result<(), Error> {
+    let step = verify(secret, code, now).ok_or(ApiError::InvalidTotpCode)?;
+
+    let query = db::Query::AcceptTotpStep { user_id, step };
+    if query.execute(database).await? == Some(true) {
+        Ok(())
+    } else {
+        Err(ApiError::InvalidTotpCode.into())
+    }
+}
+
+#[derive(Serialize)]
+struct Enrollment {
+    secret: String,
+    otpauth_uri: String,
+}
+
+/// Starts (or restarts) TOTP enrollment for the logged-in user. TOTP only
+/// becomes active once a code is confirmed.
+#[post("/dashboard/totp/enroll")]
+pub async fn enroll(
+    user: DashboardUser,
+    database: web::Data<db::Pool>,
+) -> actix_web::Result<impl Responder> {
+    let secret = generate_secret()?;
+
+    let query = db::Query::SetTotpSecret {
+        user_id: user.0.id,
+        secret: secret.clone(),
+    };
+    if query.execute(database).await? != Some(true) {
+        return Err(ApiError::TotpAlreadyEnabled.into());
+    }
+
+    let otpauth_uri = format!(
+        "otpauth://totp/{ISSUER}:{}?secret={secret}&issuer={ISSUER}&digits={DIGITS}&period={STEP_SECONDS}",
+        user.0.email.replace('@', "%40"),
+    );
+
+    Ok(web::Json(Enrollment {
+        secret,
+        otpauth_uri,
+    }))
+}
+
+#[derive(Deserialize, Debug)]
+pub struct Confirmation {
+    code: String,
+}
+
+/// Activates TOTP once the user proves their authenticator produces codes.
+#[post("/dashboard/totp/confirm")]
+pub async fn confirm(
+    user: DashboardUser,
+    body: web::Json<Confirmation>,
+    database: web::Data<db::Pool>,
+    req: HttpRequest,
+) -> actix_web::Result<impl Responder> {
+    let state = db::find_totp(database.clone(), user.0.id)
+        .await?
+        .ok_or(ApiError::TotpNotEnrolled)?;
+
+    if state.enabled {
+        return Err(ApiError::TotpAlreadyEnabled.into());
+    }
+
+    accept_code(
+        database,
+        user.0.id,
+        &state.secret,
+        &body.code,
+        clock::now(&req),
+    )
+    .await?;
+
+    Ok(HttpResponse::NoContent().finish())
+}
+
+/// Requires a logged-in dashboard user with TOTP enabled to send a fresh
+/// code in `X-TOTP-Code`. Wrap scopes holding destructive operations with
+/// this.
+pub async fn require_totp(
+    mut req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    let user = req.extract::<DashboardUser>().await?;
+    let database = req
+        .app_data::<web::Data<db::Pool>>()
+        .cloned()
+        .ok_or(ApiError::Internal)?;
+
+    let code = req
+        .headers()
+        .get(TOTP_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .map(str::to_owned)
+        .ok_or(ApiError::TotpRequired)?;
+
+    let state = db::find_totp(database.clone(), user.0.id)
+        .await?
+        .filter(|state| state.enabled)
+        .ok_or(ApiError::TotpNotEnrolled)?;
+
+    accept_code(
+        database,
+        user.0.id,
+        &state.secret,
+        &code,
+        clock::now(req.request()),
+    )
+    .await?;
+
+    next.call(req).await
+}
@@ -0,0 +1,482 @@
+//! Where conversion calls get recorded. Handlers describe each call with
+//! [`defer`], and [`record_usage`] hands it to one [`UsageSink`] once the
+//! response is ready; embedders pick which sinks that fans out to.
+//!
+//! ```no_run
+//! # fn database() -> actix_web::web::Data<hello_actix::db::Pool> { unimplemented!() }
+//! use std::sync::Arc;
+//!
+//! use hello_actix::usage::{DatabaseSink, FanOut, StatsdSink};
+//! use hello_actix::UsageStats;
+//!
+//! let sink = FanOut::new()
+//!     .with(Arc::new(UsageStats::new()))
+//!     .with(Arc::new(DatabaseSink::new(database(), false)))
+//!     .with(Arc::new(StatsdSink::connect("127.0.0.1:8125")?));
+//! # Ok::<(), std::io::Error>(())
+//! ```
+//!
+//! A slow sink can be put behind a [`QueuedSink`], so calls wait in a
+//! bounded queue instead of holding up responses, and reach it in batches.
+//!
+//! Keys read what the database recorded for them back at `GET /api/usage/me`.
+
+use std::collections::VecDeque;
+use std::fmt;
+use std::future::{ready, Future};
+use std::net::UdpSocket;
+use std::pin::Pin;
+use std::str::FromStr;
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use actix_web::body::MessageBody;
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::middleware::Next;
+use actix_web::{get, rt, web, Error, HttpMessage as _, HttpRequest, Responder};
+use actix_web_httpauth::extractors::basic::BasicAuth;
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use tokio::sync::{Notify, Semaphore};
+use tracing::warn;
+
+use crate::config::Config;
+use crate::db::{self, ApiUsage, EndpointUsage};
+use crate::{casing, metrics, negotiate, tasks, UsageStats};
+
+/// Queues `call` for [`record_usage`], which fills in its latency. Handlers
+/// using this need that middleware wrapped around them.
+pub fn defer(req: &HttpRequest, call: ApiUsage) {
+    req.extensions_mut().insert(call);
+}
+
+/// Times the handler and records the call it [`defer`]red, if any, with the
+/// [`UsageSink`] in `app_data`. Also marks the start for envelopes; see
+/// [`negotiate::Started`].
+pub async fn record_usage(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    let started = Instant::now();
+    req.extensions_mut().insert(negotiate::Started(started));
+
+    let res = next.call(req).await?;
+    let latency = started.elapsed();
+
+    let call = res.request().extensions_mut().remove::<ApiUsage>();
+    let sink = res
+        .request()
+        .app_data::<web::Data<dyn UsageSink>>()
+        .cloned();
+    if let (Some(mut call), Some(sink)) = (call, sink) {
+        call.latency_ms = Some(latency.as_millis().try_into().unwrap_or(u32::MAX));
+        sink.record(&call).await;
+    }
+
+    Ok(res)
+}
+
+/// Resolves once a sink is done with a call.
+pub type Recorded = Pin<Box<dyn Future<Output = ()> + Send>>;
+
+/// Somewhere to send conversion calls. Sinks report their own failures;
+/// a call is never refused because it couldn't be recorded.
+pub trait UsageSink: Send + Sync {
+    fn record(&self, usage: &ApiUsage) -> Recorded;
+
+    /// Several calls at once, as a [`QueuedSink`] drains them. One by one,
+    /// unless the sink can do better.
+    fn record_batch(&self, calls: Vec<ApiUsage>) -> Recorded {
+        let recorded: Vec<_> = calls.iter().map(|usage| self.record(usage)).collect();
+
+        Box::pin(async move {
+            for recorded in recorded {
+                recorded.await;
+            }
+        })
+    }
+
+    /// Waits until every call recorded so far has been handed on, e.g.
+    /// before shutting down. Only sinks that hold calls back have anything
+    /// to wait for.
+    fn flush(&self) -> Recorded {
+        Box::pin(ready(()))
+    }
+}
+
+/// In memory, for `/usage-statistics` and usage alerts.
+impl UsageSink for UsageStats {
+    fn record(&self, usage: &ApiUsage) -> Recorded {
+        self.counters.count(usage.endpoint);
+        self.alerts.record(&usage.api_key);
+
+        Box::pin(ready(()))
+    }
+}
+
+/// The `usage` table, or only hourly counts when `anonymous`; see
+/// [`db::Query::anonymized_if`].
+pub struct DatabaseSink {
+    database: web::Data<db::Pool>,
+    anonymous: bool,
+}
+
+impl DatabaseSink {
+    pub fn new(database: web::Data<db::Pool>, anonymous: bool) -> Self {
+        DatabaseSink {
+            database,
+            anonymous,
+        }
+    }
+}
+
+impl UsageSink for DatabaseSink {
+    fn record(&self, usage: &ApiUsage) -> Recorded {
+        self.record_batch(vec![usage.clone()])
+    }
+
+    /// In one transaction.
+    fn record_batch(&self, calls: Vec<ApiUsage>) -> Recorded {
+        let query = db::Query::RecordApiUsageBatch(calls).anonymized_if(self.anonymous);
+        let database = self.database.clone();
+
+        Box::pin(async move {
+            if let Err(err) = query.execute(database).await {
+                warn!(%err, "failed to record usage");
+            }
+        })
+    }
+}
+
+/// A statsd counter per endpoint, `hello_actix.usage.<endpoint>`. Packets
+/// that can't be sent right away are dropped.
+pub struct StatsdSink {
+    socket: UdpSocket,
+}
+
+impl StatsdSink {
+    pub fn connect(addr: &str) -> std::io::Result<Self> {
+        let socket = UdpSocket::bind(("0.0.0.0", 0))?;
+        socket.connect(addr)?;
+        socket.set_nonblocking(true)?;
+
+        Ok(StatsdSink { socket })
+    }
+}
+
+impl UsageSink for StatsdSink {
+    fn record(&self, usage: &ApiUsage) -> Recorded {
+        let metric = format!("hello_actix.usage.{}:1|c", usage.endpoint.as_str());
+        let _ = self.socket.send(metric.as_bytes());
+
+        Box::pin(ready(()))
+    }
+}
+
+/// Sends every call to each of its sinks, in the order they were added.
+#[derive(Clone, Default)]
+pub struct FanOut {
+    sinks: Vec<Arc<dyn UsageSink>>,
+}
+
+impl FanOut {
+    pub fn new() -> Self {
+        FanOut::default()
+    }
+
+    pub fn with(mut self, sink: Arc<dyn UsageSink>) -> Self {
+        self.sinks.push(sink);
+        self
+    }
+}
+
+impl UsageSink for FanOut {
+    fn record(&self, usage: &ApiUsage) -> Recorded {
+        let recorded: Vec<_> = self.sinks.iter().map(|sink| sink.record(usage)).collect();
+
+        Box::pin(async move {
+            for recorded in recorded {
+                recorded.await;
+            }
+        })
+    }
+
+    fn record_batch(&self, calls: Vec<ApiUsage>) -> Recorded {
+        let recorded: Vec<_> = self
+            .sinks
+            .iter()
+            .map(|sink| sink.record_batch(calls.clone()))
+            .collect();
+
+        Box::pin(async move {
+            for recorded in recorded {
+                recorded.await;
+            }
+        })
+    }
+
+    fn flush(&self) -> Recorded {
+        let flushed: Vec<_> = self.sinks.iter().map(|sink| sink.flush()).collect();
+
+        Box::pin(async move {
+            for flushed in flushed {
+                flushed.await;
+            }
+        })
+    }
+}
+
+/// What a [`QueuedSink`] does with a call that finds its queue full.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Overflow {
+    /// Make room by dropping the call queued longest.
+    DropOldest,
+    /// Drop the call itself.
+    DropNew,
+    /// Hold up the response until there is room.
+    Block,
+}
+
+impl fmt::Display for Overflow {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Overflow::DropOldest => "drop-oldest",
+            Overflow::DropNew => "drop-new",
+            Overflow::Block => "block",
+        })
+    }
+}
+
+#[derive(Debug)]
+pub struct UnknownOverflow;
+
+impl fmt::Display for UnknownOverflow {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("expected drop-oldest, drop-new or block")
+    }
+}
+
+impl std::error::Error for UnknownOverflow {}
+
+impl FromStr for Overflow {
+    type Err = UnknownOverflow;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "drop-oldest" => Ok(Overflow::DropOldest),
+            "drop-new" => Ok(Overflow::DropNew),
+            "block" => Ok(Overflow::Block),
+            _ => Err(UnknownOverflow),
+        }
+    }
+}
+
+/// Hands calls to another sink from a bounded queue, in batches, so
+/// recording returns without waiting for it. Calls dropped on overflow are
+/// counted in [`metrics::USAGE`].
+pub struct QueuedSink {
+    queue: Arc<Queue>,
+    sink: Arc<dyn UsageSink>,
+    batching: Batching,
+}
+
+struct Queue {
+    calls: Mutex<VecDeque<ApiUsage>>,
+    /// One permit per free slot. Taken and returned under `calls`' lock,
+    /// except while a blocked call waits for one.
+    free: Semaphore,
+    queued: Notify,
+    overflow: Overflow,
+    /// Held while a batch is handed on, by the writer or a flush, so a
+    /// flush also waits out the batch in flight.
+    writing: tokio::sync::Mutex<()>,
+}
+
+/// How a [`QueuedSink`]'s writer groups calls.
+#[derive(Debug, Clone, Copy)]
+pub struct Batching {
+    /// Most calls handed on at once.
+    pub max_calls: usize,
+    /// Pause after a batch short of `max_calls`, for more to queue up. A
+    /// call arriving at an idle queue is handed on right away.
+    pub flush_interval: Duration,
+}
+
+impl Batching {
+    pub fn from_config(config: &Config) -> Self {
+        Batching {
+            max_calls: config.usage_batch_size,
+            flush_interval: Duration::from_millis(config.usage_flush_interval_ms),
+        }
+    }
+}
+
+impl QueuedSink {
+    /// Queues up to `capacity` calls for `sink`, written by a supervised
+    /// `usage_writer` task on the current runtime.
+    pub fn spawn(
+        sink: Arc<dyn UsageSink>,
+        capacity: usize,
+        overflow: Overflow,
+        batching: Batching,
+    ) -> Self {
+        let queue = Arc::new(Queue {
+            calls: Mutex::new(VecDeque::with_capacity(capacity)),
+            free: Semaphore::new(capacity),
+            queued: Notify::new(),
+            overflow,
+            writing: tokio::sync::Mutex::new(()),
+        });
+
+        let (writer, writer_sink) = (queue.clone(), sink.clone());
+        tasks::supervise("usage_writer", move |_| {
+            write_queued(writer.clone(), writer_sink.clone(), batching)
+        });
+
+        QueuedSink {
+            queue,
+            sink,
+            batching,
+        }
+    }
+}
+
+/// `sink` behind a [`QueuedSink`] as configured, or as is when
+/// [`Config::usage_queue_capacity`] is 0.
+pub fn queued(sink: Arc<dyn UsageSink>, config: &Config) -> Arc<dyn UsageSink> {
+    match config.usage_queue_capacity {
+        0 => sink,
+        capacity => Arc::new(QueuedSink::spawn(
+            sink,
+            capacity,
+            config.usage_queue_overflow,
+            Batching::from_config(config),
+        )),
+    }
+}
+
+impl Queue {
+    fn push(&self, usage: ApiUsage) {
+        self.calls.lock().unwrap().push_back(usage);
+        metrics::USAGE.queued.fetch_add(1, Ordering::Relaxed);
+        self.queued.notify_one();
+    }
+
+    /// Up to `max` calls, oldest first.
+    fn pop(&self, max: usize) -> Vec<ApiUsage> {
+        let mut calls = self.calls.lock().unwrap();
+        let n = calls.len().min(max);
+        let batch: Vec<_> = calls.drain(..n).collect();
+        self.free.add_permits(n);
+        metrics::USAGE.queued.fetch_sub(n as u64, Ordering::Relaxed);
+        batch
+    }
+}
+
+impl UsageSink for QueuedSink {
+    fn record(&self, usage: &ApiUsage) -> Recorded {
+        let queue = &self.queue;
+        let mut calls = queue.calls.lock().unwrap();
+
+        if let Ok(permit) = queue.free.try_acquire() {
+            permit.forget();
+            drop(calls);
+            queue.push(usage.clone());
+            return Box::pin(ready(()));
+        }
+
+        // Full: no permit is returned while the lock is held.
+
+        match queue.overflow {
+            Overflow::DropOldest => {
+                calls.pop_front();
+                calls.push_back(usage.clone());
+                metrics::USAGE.dropped.fetch_add(1, Ordering::Relaxed);
+                queue.queued.notify_one();
+            }
+            Overflow::DropNew => {
+                metrics::USAGE.dropped.fetch_add(1, Ordering::Relaxed);
+            }
+            Overflow::Block => {
+                drop(calls);
+                let (queue, usage) = (self.queue.clone(), usage.clone());
+                return Box::pin(async move {
+                    if let Ok(permit) = queue.free.acquire().await {
+                        permit.forget();
+                        queue.push(usage);
+                    }
+                });
+            }
+        }
+
+        Box::pin(ready(()))
+    }
+
+    /// Hands on what is queued without waiting out the flush interval.
+    fn flush(&self) -> Recorded {
+        let (queue, sink) = (self.queue.clone(), self.sink.clone());
+        let max_calls = self.batching.max_calls;
+
+        Box::pin(async move {
+            let _writing = queue.writing.lock().await;
+            loop {
+                let batch = queue.pop(max_calls);
+                if batch.is_empty() {
+                    break;
+                }
+                sink.record_batch(batch).await;
+            }
+            sink.flush().await;
+        })
+    }
+}
+
+async fn write_queued(queue: Arc<Queue>, sink: Arc<dyn UsageSink>, batching: Batching) {
+    loop {
+        let writing = queue.writing.lock().await;
+        let batch = queue.pop(batching.max_calls);
+        if batch.is_empty() {
+            drop(writing);
+            queue.queued.notified().await;
+            continue;
+        }
+
+        let full = batch.len() == batching.max_calls;
+        sink.record_batch(batch).await;
+        drop(writing);
+        if !full {
+            rt::time::sleep(batching.flush_interval).await;
+        }
+    }
+}
+
+/// Everything recorded for a key, per endpoint.
+#[derive(Debug, Serialize)]
+pub struct MyUsage {
+    pub calls: u64,
+    pub first_call_at: Option<DateTime<Utc>>,
+    pub last_call_at: Option<DateTime<Utc>>,
+    pub endpoints: Vec<EndpointUsage>,
+}
+
+/// The calling key's recorded calls. Empty with `ANONYMOUS_USAGE`, which
+/// stores no keys with them.
+#[get("/usage/me")]
+pub async fn my_usage(
+    auth: BasicAuth,
+    database: web::Data<db::Pool>,
+) -> actix_web::Result<impl Responder> {
+    let endpoints = db::usage_summary_of_key(database, auth.user_id().to_owned()).await?;
+
+    Ok(casing::Json(MyUsage {
+        calls: endpoints.iter().map(|endpoint| endpoint.calls).sum(),
+        first_call_at: endpoints
+            .iter()
+            .map(|endpoint| endpoint.first_call_at)
+            .min(),
+        last_call_at: endpoints.iter().map(|endpoint| endpoint.last_call_at).max(),
+        endpoints,
+    }))
+}
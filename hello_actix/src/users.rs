@@ -0,0 +1,91 @@
+//! Dashboard account management under `/admin/users`.
+
+use actix_web::{delete, get, post, put, web, HttpResponse, Responder};
+use serde::Deserialize;
+
+use crate::db;
+use crate::db::Role;
+use crate::error::ApiError;
+use crate::jsonapi::Listed;
+use crate::rbac::{Admin, Authorized};
+use crate::signup::normalize_email;
+
+#[get("/users")]
+pub async fn list_users(
+    _: Authorized<Admin>,
+    database: web::Data<db::Pool>,
+) -> actix_web::Result<impl Responder> {
+    Ok(Listed(db::list_users(database).await?))
+}
+
+#[derive(Deserialize, Debug)]
+pub struct NewUser {
+    email: String,
+    #[serde(default)]
+    role: Role,
+}
+
+/// Registers a dashboard user, who can then log in via magic link.
+#[post("/users")]
+pub async fn create_user(
+    _: Authorized<Admin>,
+    body: web::Json<NewUser>,
+    database: web::Data<db::Pool>,
+) -> actix_web::Result<impl Responder> {
+    let email = normalize_email(&body.email)?;
+
+    let query = db::Query::CreateUser {
+        email: email.clone(),
+        role: body.role,
+    };
+    let created = query.execute(database.clone()).await? == Some(true);
+
+    let user = db::find_user_by_email(database, email)
+        .await?
+        .ok_or(ApiError::Internal)?;
+
+    if created {
+        Ok(HttpResponse::Created().json(user))
+    } else {
+        Ok(HttpResponse::Ok().json(user))
+    }
+}
+
+#[derive(Deserialize, Debug)]
+pub struct RoleChange {
+    role: Role,
+}
+
+#[put("/users/{id}/role")]
+pub async fn set_user_role(
+    _: Authorized<Admin>,
+    user_id: web::Path<i64>,
+    body: web::Json<RoleChange>,
+    database: web::Data<db::Pool>,
+) -> actix_web::Result<impl Responder> {
+    let query = db::Query::SetRole {
+        user_id: user_id.into_inner(),
+        role: body.role,
+    };
+
+    if query.execute(database).await? == Some(true) {
+        Ok(HttpResponse::NoContent().finish())
+    } else {
+        Err(ApiError::UserNotFound.into())
+    }
+}
+
+/// Revokes every session a user holds, forcing them to log in again.
+#[delete("/users/{id}/sessions")]
+pub async fn revoke_user_sessions(
+    _: Authorized<Admin>,
+    user_id: web::Path<i64>,
+    database: web::Data<db::Pool>,
+) -> actix_web::Result<impl Responder> {
+    let query = db::Query::DeleteUserSessions {
+        user_id: user_id.into_inner(),
+    };
+    query.execute(database).await?;
+
+    Ok(HttpResponse::NoContent().finish())
+}
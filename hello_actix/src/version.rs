@@ -0,0 +1,46 @@
+//! Build and process information, served at `GET /version`. The commit and
+//! build timestamp are embedded by `build.rs`.
+
+use std::sync::LazyLock;
+use std::time::Instant;
+
+use actix_web::{get, web, Responder};
+use serde::Serialize;
+
+use crate::config;
+
+static STARTED: LazyLock<Instant> = LazyLock::new(Instant::now);
+
+/// Starts the uptime clock. Call once at startup; otherwise uptime counts
+/// from the first request to `/version`.
+pub fn mark_started() {
+    LazyLock::force(&STARTED);
+}
+
+#[derive(Debug, Serialize)]
+pub struct Version {
+    pub version: &'static str,
+    pub git_commit: &'static str,
+    pub git_dirty: bool,
+    pub build_timestamp: &'static str,
+    pub features: Vec<&'static str>,
+    pub uptime_seconds: u64,
+}
+
+impl Version {
+    pub fn current() -> Self {
+        Version {
+            version: env!("CARGO_PKG_VERSION"),
+            git_commit: env!("VERGEN_GIT_SHA"),
+            git_dirty: env!("VERGEN_GIT_DIRTY") == "true",
+            build_timestamp: env!("VERGEN_BUILD_TIMESTAMP"),
+            features: config::enabled_features(),
+            uptime_seconds: STARTED.elapsed().as_secs(),
+        }
+    }
+}
+
+#[get("/version")]
+pub async fn version() -> impl Responder {
+    web::Json(Version::current())
+}
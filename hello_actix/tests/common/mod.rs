@@ -0,0 +1,237 @@
+//! Shared fixtures for the integration tests.
+#![allow(dead_code)]
+
+use actix_web::body::{to_bytes, MessageBody};
+use actix_web::cookie::Cookie;
+use actix_web::dev::{Service, ServiceResponse};
+use actix_web::http::header::{HeaderName, AUTHORIZATION, CONTENT_TYPE};
+use actix_web::http::StatusCode;
+use actix_web::test;
+use actix_web::web::{self, Bytes};
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine as _;
+use r2d2_sqlite::SqliteConnectionManager;
+use std::sync::Mutex;
+
+use hello_actix::config::Config;
+use hello_actix::notify::Mailer;
+use hello_actix::plugin::PluginRegistry;
+use hello_actix::scopes::KeyScope;
+use hello_actix::{auth, challenge, db, session};
+
+pub const ADMIN_TOKEN: &str = "test-admin-token";
+
+/// The application as `main` assembles it, minus chaos, recording and
+/// tracing. Takes a config and database, or a whole `AppState`.
+macro_rules! app {
+    ($config:expr, $database:expr) => {
+        app!(hello_actix::AppState::new($config, (**$database).clone()))
+    };
+    ($state:expr) => {{
+        use actix_web::middleware::from_fn;
+        use hello_actix::*;
+
+        let state = $state;
+
+        actix_web::test::init_service(
+            actix_web::App::new()
+                .wrap(from_fn(csrf::require_csrf))
+                .wrap(from_fn(session::refresh))
+                .wrap(from_fn(https::require_https))
+                .wrap(from_fn(deprecation::emit_deprecation_headers))
+                .wrap(from_fn(blocklist::enforce_blocklist))
+                .wrap(from_fn(i18n::localize_errors))
+                .wrap(from_fn(drain::track_requests))
+                .configure(|cfg| mount(cfg, state.clone())),
+        )
+        .await
+    }};
+}
+
+/// A private in-memory database. A single connection, because every
+/// connection to `:memory:` opens a database of its own.
+pub fn database() -> web::Data<db::Pool> {
+    let pool = db::Pool::builder()
+        .max_size(1)
+        .build(SqliteConnectionManager::memory())
+        .unwrap();
+    db::setup(pool.clone());
+    PluginRegistry::compiled_in().migrate(&pool);
+    web::Data::new(pool)
+}
+
+/// Defaults, with the admin routes enabled and a signup challenge cheap
+/// enough to solve in every test.
+pub fn config() -> Config {
+    Config {
+        admin_token: Some(ADMIN_TOKEN.into()),
+        signup_pow_difficulty: 8,
+        // Written before responses, so tests can read usage back at once.
+        usage_queue_capacity: 0,
+        ..Config::default()
+    }
+}
+
+/// Keeps the email the app sends, for tests to follow the links in.
+#[derive(Default)]
+pub struct Outbox(Mutex<Vec<Mail>>);
+
+#[derive(Debug, Clone)]
+pub struct Mail {
+    pub to: String,
+    pub subject: String,
+    pub text: String,
+}
+
+impl Mailer for Outbox {
+    fn send(&self, to: &str, subject: &str, text: &str) -> Result<(), String> {
+        self.0.lock().unwrap().push(Mail {
+            to: to.into(),
+            subject: subject.into(),
+            text: text.into(),
+        });
+        Ok(())
+    }
+}
+
+impl Outbox {
+    /// Everything sent so far, emptying the outbox.
+    pub fn take(&self) -> Vec<Mail> {
+        std::mem::take(&mut self.0.lock().unwrap())
+    }
+}
+
+impl Mail {
+    /// The path of the link in the text, which [`config`] puts on the
+    /// default public URL.
+    pub fn link(&self) -> &str {
+        let (_, link) = self.text.split_once(&Config::default().public_url).unwrap();
+        link.split_whitespace().next().unwrap()
+    }
+}
+
+pub fn admin_bearer() -> (HeaderName, String) {
+    (AUTHORIZATION, format!("Bearer {ADMIN_TOKEN}"))
+}
+
+/// A key with the `admin` scope, stored as an operator would: signup never
+/// issues one. Apps over `database` look it up on its first use.
+pub async fn admin_key(database: &web::Data<db::Pool>, email: &str) -> String {
+    auth::store_api_key(
+        database.clone(),
+        &auth::KeyCache::new(),
+        &auth::RandomKeys,
+        email.into(),
+        db::Tier::Free,
+        None,
+        [KeyScope::Admin].into_iter().collect(),
+    )
+    .await
+    .unwrap()
+}
+
+pub fn basic(api_key: &str) -> (HeaderName, String) {
+    let credentials = BASE64.encode(format!("{api_key}:"));
+    (AUTHORIZATION, format!("Basic {credentials}"))
+}
+
+/// Registers a dashboard user with `role` and logs them in.
+pub async fn log_in(
+    database: &web::Data<db::Pool>,
+    email: &str,
+    role: db::Role,
+) -> Cookie<'static> {
+    let query = db::Query::CreateUser {
+        email: email.into(),
+        role,
+    };
+    query.execute(database.clone()).await.unwrap();
+
+    let user = db::find_user_by_email(database.clone(), email.into())
+        .await
+        .unwrap()
+        .unwrap();
+
+    session::start(database.clone(), &config(), &user, chrono::Utc::now())
+        .await
+        .unwrap()
+}
+
+/// A pending signup for `email`, as the path of its verification link.
+pub async fn signup_link(database: &web::Data<db::Pool>, email: &str) -> String {
+    signup_link_at(database, email, chrono::Utc::now()).await
+}
+
+/// [`signup_link`], issued at `now` by an app's own clock.
+pub async fn signup_link_at(
+    database: &web::Data<db::Pool>,
+    email: &str,
+    now: chrono::DateTime<chrono::Utc>,
+) -> String {
+    let token = auth::generate_token().unwrap();
+    let query = db::Query::StoreSignup {
+        token_hash: auth::hash_token(&token),
+        email: email.into(),
+        expires_at: now + chrono::Duration::hours(1),
+    };
+    query.execute(database.clone()).await.unwrap();
+
+    format!("/signup/{token}")
+}
+
+/// Fetches a signup challenge and solves it, as the `challenge` and `nonce`
+/// fields of a signup request.
+pub async fn solved_challenge<S, B>(app: &S) -> serde_json::Value
+where
+    S: Service<actix_http::Request, Response = ServiceResponse<B>, Error = actix_web::Error>,
+    B: MessageBody,
+{
+    let req = test::TestRequest::get()
+        .uri("/signup/challenge")
+        .to_request();
+    let reply = send(app, req).await;
+    let body: serde_json::Value = serde_json::from_slice(&reply.body).unwrap();
+
+    let challenge = body["challenge"].as_str().unwrap();
+    let difficulty = body["difficulty"].as_u64().unwrap() as u32;
+    let nonce = challenge::solve(challenge, difficulty);
+
+    serde_json::json!({ "challenge": challenge, "nonce": nonce })
+}
+
+pub struct Reply {
+    pub status: StatusCode,
+    pub content_type: Option<String>,
+    pub body: Bytes,
+}
+
+/// Calls the app, rendering errors raised by middleware the way the server
+/// would.
+pub async fn send<S, R, B>(app: &S, req: R) -> Reply
+where
+    S: Service<R, Response = ServiceResponse<B>, Error = actix_web::Error>,
+    B: MessageBody,
+{
+    match app.call(req).await {
+        Ok(res) => Reply {
+            status: res.status(),
+            content_type: content_type(res.headers()),
+            body: test::read_body(res).await,
+        },
+        Err(err) => {
+            let res = err.error_response();
+            Reply {
+                status: res.status(),
+                content_type: content_type(res.headers()),
+                body: to_bytes(res.into_body()).await.ok().unwrap_or_default(),
+            }
+        }
+    }
+}
+
+fn content_type(headers: &actix_web::http::header::HeaderMap) -> Option<String> {
+    headers
+        .get(CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .map(str::to_owned)
+}
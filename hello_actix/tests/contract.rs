@@ -0,0 +1,990 @@
+//! Checks real responses against `/openapi.json`: every status must be
+//! declared for its route, and every JSON body must match the declared
+//! schema. Also fails when a documented operation is never exercised, so
+//! new routes get covered here along with the spec.
+
+use std::collections::BTreeSet;
+
+use actix_web::body::MessageBody;
+use actix_web::dev::{Service, ServiceResponse};
+use actix_web::http::header::{ACCEPT, AUTHORIZATION};
+use actix_web::test;
+use serde_json::{json, Value};
+
+use hello_actix::config::Config;
+use hello_actix::db;
+
+#[macro_use]
+mod common;
+
+#[cfg(feature = "dashboard")]
+use common::log_in;
+use common::{
+    admin_bearer, admin_key, basic, config, database, send, signup_link, solved_challenge, Reply,
+};
+
+/// Documented whatever the build, but only served with the `dashboard`
+/// feature.
+const DASHBOARD_PATHS: [&str; 3] = ["/admin/users", "/auth/", "/dashboard/"];
+
+struct Contract {
+    spec: Value,
+    exercised: BTreeSet<(String, String)>,
+    violations: Vec<String>,
+}
+
+/// Whether a concrete path matches a path template like `/users/{id}/role`.
+fn matches_template(template: &str, path: &str) -> bool {
+    let template: Vec<_> = template.split('/').collect();
+    let path: Vec<_> = path.split('/').collect();
+
+    template.len() == path.len()
+        && template
+            .iter()
+            .zip(&path)
+            .all(|(t, p)| t == p || (t.starts_with('{') && t.ends_with('}') && !p.is_empty()))
+}
+
+impl Contract {
+    fn new(spec: Value) -> Self {
+        Contract {
+            spec,
+            exercised: BTreeSet::new(),
+            violations: Vec::new(),
+        }
+    }
+
+    /// Follows a local `$ref`, if `value` is one.
+    fn resolve<'a>(&'a self, value: &'a Value) -> &'a Value {
+        match value["$ref"].as_str() {
+            Some(reference) => self
+                .spec
+                .pointer(reference.trim_start_matches('#'))
+                .unwrap_or_else(|| panic!("dangling reference {reference}")),
+            None => value,
+        }
+    }
+
+    fn validate(&self, schema: &Value, instance: &Value) -> Result<(), String> {
+        // Schemas point into `#/components`, so carry those along with them.
+        let mut schema = schema.clone();
+        schema["components"] = self.spec["components"].clone();
+
+        let validator = jsonschema::draft202012::new(&schema).map_err(|err| err.to_string())?;
+        let errors: Vec<_> = validator
+            .iter_errors(instance)
+            .map(|err| format!("{} at {}", err, err.instance_path))
+            .collect();
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors.join("; "))
+        }
+    }
+
+    fn check(&mut self, method: &str, path: &str, reply: &Reply) -> Result<(), String> {
+        let method = method.to_ascii_lowercase();
+        let paths = self.spec["paths"].as_object().ok_or("spec has no paths")?;
+
+        let (template, operation) = paths
+            .iter()
+            .filter(|(template, _)| matches_template(template, path))
+            .find_map(|(template, item)| Some((template.clone(), item.get(&method)?.clone())))
+            .ok_or("operation is not documented")?;
+        self.exercised.insert((method, template));
+
+        let status = reply.status.as_str();
+        let response = operation["responses"]
+            .get(status)
+            .map(|response| self.resolve(response))
+            .ok_or_else(|| format!("status {status} is not documented"))?;
+
+        let Some(content) = response.get("content").and_then(Value::as_object) else {
+            return if reply.body.is_empty() {
+                Ok(())
+            } else {
+                Err(format!("status {status} is documented without a body"))
+            };
+        };
+
+        let content_type = reply.content_type.as_deref().unwrap_or_default();
+        let (media_type, media) = content
+            .iter()
+            .find(|(media_type, _)| content_type.starts_with(media_type.as_str()))
+            .ok_or_else(|| format!("content type {content_type:?} is not documented"))?;
+
+        if media_type != "application/json" && media_type != "application/vnd.api+json" {
+            return Ok(());
+        }
+
+        let body: Value = serde_json::from_slice(&reply.body)
+            .map_err(|err| format!("body is not valid JSON: {err}"))?;
+        self.validate(&media["schema"], &body)
+    }
+
+    async fn exercise<S, B>(&mut self, app: &S, req: test::TestRequest) -> Reply
+    where
+        S: Service<actix_http::Request, Response = ServiceResponse<B>, Error = actix_web::Error>,
+        B: MessageBody,
+    {
+        let req = req.to_request();
+        let method = req.method().to_string();
+        let path = req.path().to_owned();
+
+        let reply = send(app, req).await;
+        if let Err(violation) = self.check(&method, &path, &reply) {
+            self.violations
+                .push(format!("{method} {path} -> {}: {violation}", reply.status));
+        }
+        reply
+    }
+
+    fn unexercised(&self) -> Vec<String> {
+        let mut missing = Vec::new();
+        for (template, item) in self.spec["paths"].as_object().into_iter().flatten() {
+            if !cfg!(feature = "dashboard")
+                && DASHBOARD_PATHS
+                    .iter()
+                    .any(|prefix| template.starts_with(prefix))
+            {
+                continue;
+            }
+            for method in item.as_object().into_iter().flatten().map(|(m, _)| m) {
+                if !self.exercised.contains(&(method.clone(), template.clone())) {
+                    missing.push(format!("{} {template}", method.to_ascii_uppercase()));
+                }
+            }
+        }
+        missing
+    }
+}
+
+#[actix_web::test]
+async fn responses_match_openapi_spec() {
+    let database = database();
+    let app = app!(config(), database);
+
+    let req = test::TestRequest::get().uri("/openapi.json").to_request();
+    let reply = send(&app, req).await;
+    assert!(reply.status.is_success(), "GET /openapi.json failed");
+    let mut contract = Contract::new(serde_json::from_slice(&reply.body).unwrap());
+    let c = &mut contract;
+
+    c.exercise(&app, test::TestRequest::get().uri("/openapi.json"))
+        .await;
+    c.exercise(&app, test::TestRequest::get().uri("/docs"))
+        .await;
+    let without_docs = app!(
+        Config {
+            swagger_ui: false,
+            ..config()
+        },
+        database
+    );
+    c.exercise(&without_docs, test::TestRequest::get().uri("/docs"))
+        .await;
+    c.exercise(&app, test::TestRequest::get().uri("/api/errors"))
+        .await;
+    c.exercise(&app, test::TestRequest::get().uri("/health"))
+        .await;
+    c.exercise(&app, test::TestRequest::get().uri("/ready"))
+        .await;
+    c.exercise(&app, test::TestRequest::get().uri("/readyz"))
+        .await;
+    c.exercise(&app, test::TestRequest::get().uri("/version"))
+        .await;
+
+    // API keys and conversions.
+    c.exercise(&app, test::TestRequest::get().uri("/signup/challenge"))
+        .await;
+    let mut signup = solved_challenge(&app).await;
+    signup["email"] = json!("ada@example.com");
+    c.exercise(
+        &app,
+        test::TestRequest::post().uri("/signup").set_json(&signup),
+    )
+    .await;
+    c.exercise(
+        &app,
+        test::TestRequest::post()
+            .uri("/signup")
+            .set_json(json!({ "email": "ada@example.com" })),
+    )
+    .await;
+    c.exercise(
+        &app,
+        test::TestRequest::post()
+            .uri("/signup")
+            .set_json(json!({ "email": "nobody" })),
+    )
+    .await;
+    c.exercise(&app, test::TestRequest::post().uri("/signup/not-a-token"))
+        .await;
+
+    let link = signup_link(&database, "ada@example.com").await;
+    c.exercise(&app, test::TestRequest::get().uri(&link)).await;
+    let reply = c.exercise(&app, test::TestRequest::post().uri(&link)).await;
+    let api_key = String::from_utf8(reply.body.to_vec()).unwrap();
+    let api_key = api_key.trim();
+
+    c.exercise(
+        &app,
+        test::TestRequest::get()
+            .uri("/api/to-celsius/100")
+            .insert_header(basic(api_key)),
+    )
+    .await;
+    c.exercise(&app, test::TestRequest::get().uri("/api/to-celsius/100"))
+        .await;
+    c.exercise(
+        &app,
+        test::TestRequest::get()
+            .uri("/api/to-celsius/100")
+            .insert_header((AUTHORIZATION, "Bearer not-basic")),
+    )
+    .await;
+    c.exercise(
+        &app,
+        test::TestRequest::get()
+            .uri("/api/alerts/wait?threshold=1&timeout_seconds=1")
+            .insert_header(basic(api_key)),
+    )
+    .await;
+    c.exercise(
+        &app,
+        test::TestRequest::get()
+            .uri("/api/to-fahrenheit/37.5?client_request_id=abc-123")
+            .insert_header(basic(api_key))
+            .insert_header(("X-Usage-Tag", "nightly-batch")),
+    )
+    .await;
+    c.exercise(
+        &app,
+        test::TestRequest::get()
+            .uri("/api/to-kelvin/100?precision=1")
+            .insert_header(basic(api_key)),
+    )
+    .await;
+    c.exercise(
+        &app,
+        test::TestRequest::get()
+            .uri("/api/from-kelvin/0,273.15")
+            .insert_header(basic(api_key)),
+    )
+    .await;
+    c.exercise(
+        &app,
+        test::TestRequest::get()
+            .uri("/api/to-celsius/99?precision=two")
+            .insert_header(basic(api_key)),
+    )
+    .await;
+    c.exercise(
+        &app,
+        test::TestRequest::get()
+            .uri(&format!(
+                "/api/to-celsius/1?client_request_id={}",
+                "x".repeat(129)
+            ))
+            .insert_header(basic(api_key)),
+    )
+    .await;
+    c.exercise(
+        &app,
+        test::TestRequest::get()
+            .uri("/api/to-fahrenheit/1")
+            .insert_header(basic("not-a-key")),
+    )
+    .await;
+    c.exercise(
+        &app,
+        test::TestRequest::get()
+            .uri("/api/scales/kelvin")
+            .insert_header(basic(api_key)),
+    )
+    .await;
+    c.exercise(
+        &app,
+        test::TestRequest::get()
+            .uri("/api/scales/rankine")
+            .insert_header(basic(api_key)),
+    )
+    .await;
+    c.exercise(
+        &app,
+        test::TestRequest::get()
+            .uri("/api/eval?expr=300K+to+C")
+            .insert_header(basic(api_key)),
+    )
+    .await;
+    c.exercise(
+        &app,
+        test::TestRequest::get()
+            .uri("/api/eval?expr=300+to+C")
+            .insert_header(basic(api_key)),
+    )
+    .await;
+    c.exercise(
+        &app,
+        test::TestRequest::get()
+            .uri("/api/to-fahrenheit/10,20,30")
+            .insert_header(basic(api_key)),
+    )
+    .await;
+    c.exercise(
+        &app,
+        test::TestRequest::get()
+            .uri(&format!("/api/to-celsius/{}", vec!["1"; 101].join(",")))
+            .insert_header(basic(api_key)),
+    )
+    .await;
+    c.exercise(
+        &app,
+        test::TestRequest::get()
+            .uri("/my/data-export")
+            .insert_header(basic(api_key)),
+    )
+    .await;
+    c.exercise(
+        &app,
+        test::TestRequest::get()
+            .uri("/my/data-export")
+            .insert_header(basic("not-a-key")),
+    )
+    .await;
+    c.exercise(
+        &app,
+        test::TestRequest::get()
+            .uri("/api/to-celsius/100?envelope=true")
+            .insert_header(basic(api_key)),
+    )
+    .await;
+    c.exercise(&app, test::TestRequest::get().uri("/usage-statistics"))
+        .await;
+    c.exercise(
+        &app,
+        test::TestRequest::get().uri("/usage-statistics?envelope=true"),
+    )
+    .await;
+    c.exercise(
+        &app,
+        test::TestRequest::post().uri("/reset-usage-statistics"),
+    )
+    .await;
+
+    // Admin.
+    c.exercise(
+        &app,
+        test::TestRequest::get()
+            .uri("/admin/reports/monthly/2025/1")
+            .insert_header(admin_bearer()),
+    )
+    .await;
+    c.exercise(
+        &app,
+        test::TestRequest::get()
+            .uri("/admin/reports/monthly/2025/13")
+            .insert_header(admin_bearer()),
+    )
+    .await;
+    c.exercise(
+        &app,
+        test::TestRequest::get()
+            .uri("/admin/usage/compare?period_a=2025-01&period_b=2025-02")
+            .insert_header(admin_bearer()),
+    )
+    .await;
+    c.exercise(
+        &app,
+        test::TestRequest::get()
+            .uri("/admin/usage/compare?period_a=2025-13&period_b=2025-02")
+            .insert_header(admin_bearer()),
+    )
+    .await;
+    c.exercise(
+        &app,
+        test::TestRequest::get()
+            .uri("/admin/usage?from=2025-01-01T00:00:00Z&to=2025-01-02T00:00:00Z&bucket=day")
+            .insert_header(admin_bearer()),
+    )
+    .await;
+    c.exercise(
+        &app,
+        test::TestRequest::get()
+            .uri("/admin/usage?from=2025-01-02T00:00:00Z&to=2025-01-01T00:00:00Z")
+            .insert_header(admin_bearer()),
+    )
+    .await;
+
+    c.exercise(
+        &app,
+        test::TestRequest::get()
+            .uri("/admin/config")
+            .insert_header(admin_bearer()),
+    )
+    .await;
+
+    c.exercise(
+        &app,
+        test::TestRequest::get()
+            .uri("/admin/metrics")
+            .insert_header(admin_bearer()),
+    )
+    .await;
+    c.exercise(
+        &app,
+        test::TestRequest::get()
+            .uri("/admin/memory")
+            .insert_header(admin_bearer()),
+    )
+    .await;
+    c.exercise(
+        &app,
+        test::TestRequest::get()
+            .uri("/admin/stats/workers")
+            .insert_header(admin_bearer()),
+    )
+    .await;
+    c.exercise(
+        &app,
+        test::TestRequest::get()
+            .uri("/admin/throttling")
+            .insert_header(admin_bearer()),
+    )
+    .await;
+    c.exercise(
+        &app,
+        test::TestRequest::get()
+            .uri("/admin/tasks")
+            .insert_header(admin_bearer()),
+    )
+    .await;
+
+    c.exercise(
+        &app,
+        test::TestRequest::post()
+            .uri("/admin/blocklist")
+            .insert_header(admin_bearer())
+            .set_json(json!({
+                "network": "2001:db8::/32",
+                "reason": "scraping",
+                "expires_at": "2099-01-01T00:00:00Z",
+            })),
+    )
+    .await;
+    c.exercise(
+        &app,
+        test::TestRequest::post()
+            .uri("/admin/blocklist")
+            .insert_header(admin_bearer())
+            .set_json(json!({ "network": "2001:db8::/129" })),
+    )
+    .await;
+    c.exercise(
+        &app,
+        test::TestRequest::get()
+            .uri("/admin/blocklist")
+            .insert_header(admin_bearer()),
+    )
+    .await;
+    c.exercise(
+        &app,
+        test::TestRequest::get()
+            .uri("/admin/blocklist")
+            .insert_header(admin_bearer())
+            .insert_header((ACCEPT, "application/vnd.api+json")),
+    )
+    .await;
+
+    c.exercise(
+        &app,
+        test::TestRequest::get()
+            .uri("/admin/keys")
+            .insert_header(admin_bearer()),
+    )
+    .await;
+    c.exercise(
+        &app,
+        test::TestRequest::get()
+            .uri("/admin/keys")
+            .insert_header(admin_bearer())
+            .insert_header((ACCEPT, "application/vnd.api+json")),
+    )
+    .await;
+    c.exercise(&app, test::TestRequest::get().uri("/admin/keys"))
+        .await;
+    c.exercise(
+        &app,
+        test::TestRequest::get()
+            .uri("/admin/keys/1/usage")
+            .insert_header(admin_bearer()),
+    )
+    .await;
+    c.exercise(
+        &app,
+        test::TestRequest::get()
+            .uri("/admin/keys/1/usage")
+            .insert_header(admin_bearer())
+            .insert_header((ACCEPT, "application/vnd.api+json")),
+    )
+    .await;
+    c.exercise(
+        &app,
+        test::TestRequest::put()
+            .uri("/admin/keys/1/endpoints")
+            .insert_header(admin_bearer())
+            .set_json(json!({ "disabled": ["to-celsius"] })),
+    )
+    .await;
+    c.exercise(
+        &app,
+        test::TestRequest::get()
+            .uri("/api/to-celsius/1")
+            .insert_header(basic(api_key)),
+    )
+    .await;
+    c.exercise(
+        &app,
+        test::TestRequest::get()
+            .uri("/api/whoami")
+            .insert_header(basic(api_key)),
+    )
+    .await;
+    c.exercise(
+        &app,
+        test::TestRequest::put()
+            .uri("/api/output-scales")
+            .insert_header(basic(api_key))
+            .set_json(json!({ "scales": ["celsius", "kelvin"] })),
+    )
+    .await;
+    c.exercise(
+        &app,
+        test::TestRequest::put()
+            .uri("/api/field-case")
+            .insert_header(basic(api_key))
+            .set_json(json!({ "field_case": "snake" })),
+    )
+    .await;
+    c.exercise(
+        &app,
+        test::TestRequest::get()
+            .uri("/api/to-celsius/1")
+            .insert_header(basic(api_key)),
+    )
+    .await;
+    c.exercise(
+        &app,
+        test::TestRequest::get()
+            .uri("/api/my-usage/forecast")
+            .insert_header(basic(api_key)),
+    )
+    .await;
+    c.exercise(
+        &app,
+        test::TestRequest::get()
+            .uri("/api/usage/me")
+            .insert_header(basic(api_key)),
+    )
+    .await;
+    c.exercise(
+        &app,
+        test::TestRequest::put()
+            .uri("/admin/keys/1/endpoints")
+            .insert_header(admin_bearer())
+            .set_json(json!({ "disabled": [] })),
+    )
+    .await;
+    c.exercise(
+        &app,
+        test::TestRequest::put()
+            .uri("/admin/keys/999/endpoints")
+            .insert_header(admin_bearer())
+            .set_json(json!({ "disabled": [] })),
+    )
+    .await;
+    c.exercise(
+        &app,
+        test::TestRequest::put()
+            .uri("/admin/keys/1/quota")
+            .insert_header(admin_bearer())
+            .set_json(json!({ "monthly_limit": 0 })),
+    )
+    .await;
+    c.exercise(
+        &app,
+        test::TestRequest::put()
+            .uri("/admin/keys/999/quota")
+            .insert_header(admin_bearer())
+            .set_json(json!({ "monthly_limit": null })),
+    )
+    .await;
+    c.exercise(
+        &app,
+        test::TestRequest::delete()
+            .uri("/admin/blocklist/1")
+            .insert_header(admin_bearer()),
+    )
+    .await;
+    c.exercise(
+        &app,
+        test::TestRequest::delete()
+            .uri("/admin/blocklist/1")
+            .insert_header(admin_bearer()),
+    )
+    .await;
+    c.exercise(
+        &app,
+        test::TestRequest::post()
+            .uri("/admin/report-subscriptions")
+            .insert_header(admin_bearer())
+            .set_json(json!({
+                "endpoint": "to-celsius",
+                "schedule": "0 8 * * 1",
+                "target": { "email": "ops@example.com" },
+            })),
+    )
+    .await;
+    c.exercise(
+        &app,
+        test::TestRequest::post()
+            .uri("/admin/report-subscriptions")
+            .insert_header(admin_bearer())
+            .set_json(json!({
+                "schedule": "0 8 * *",
+                "target": { "webhook": "https://example.com/hook" },
+            })),
+    )
+    .await;
+    c.exercise(
+        &app,
+        test::TestRequest::get()
+            .uri("/admin/report-subscriptions")
+            .insert_header(admin_bearer()),
+    )
+    .await;
+    c.exercise(
+        &app,
+        test::TestRequest::get()
+            .uri("/admin/report-subscriptions")
+            .insert_header(admin_bearer())
+            .insert_header((ACCEPT, "application/vnd.api+json")),
+    )
+    .await;
+    c.exercise(
+        &app,
+        test::TestRequest::delete()
+            .uri("/admin/report-subscriptions/1")
+            .insert_header(admin_bearer()),
+    )
+    .await;
+    c.exercise(
+        &app,
+        test::TestRequest::delete()
+            .uri("/admin/report-subscriptions/1")
+            .insert_header(admin_bearer()),
+    )
+    .await;
+
+    #[cfg(feature = "dashboard")]
+    exercise_dashboard(c, &app, &database).await;
+
+    c.exercise(
+        &app,
+        test::TestRequest::delete()
+            .uri("/api-key")
+            .insert_header(basic(api_key)),
+    )
+    .await;
+    let ops_key = admin_key(&database, "ops@example.com").await;
+    c.exercise(
+        &app,
+        test::TestRequest::post()
+            .uri("/admin/keys")
+            .insert_header(admin_bearer())
+            .set_json(json!({ "email": "ci@example.com", "scopes": ["convert:read"] })),
+    )
+    .await;
+    c.exercise(
+        &app,
+        test::TestRequest::post()
+            .uri("/admin/keys")
+            .insert_header(admin_bearer())
+            .set_json(json!({ "email": "ci@example.com", "scopes": [] })),
+    )
+    .await;
+    c.exercise(
+        &app,
+        test::TestRequest::delete()
+            .uri("/api-key/1")
+            .insert_header(basic(&ops_key)),
+    )
+    .await;
+    c.exercise(
+        &app,
+        test::TestRequest::delete()
+            .uri("/api-key/999")
+            .insert_header(basic(&ops_key)),
+    )
+    .await;
+    c.exercise(
+        &app,
+        test::TestRequest::delete()
+            .uri("/api-key")
+            .insert_header(basic(&ops_key)),
+    )
+    .await;
+    c.exercise(
+        &app,
+        test::TestRequest::delete()
+            .uri("/admin/keys/1/data")
+            .insert_header(admin_bearer()),
+    )
+    .await;
+    c.exercise(
+        &app,
+        test::TestRequest::delete()
+            .uri("/admin/keys/999/data")
+            .insert_header(admin_bearer()),
+    )
+    .await;
+
+    c.exercise(
+        &app,
+        test::TestRequest::post()
+            .uri("/admin/master-key/reload")
+            .insert_header(admin_bearer()),
+    )
+    .await;
+
+    // Last, as it takes the instance out of rotation.
+    c.exercise(
+        &app,
+        test::TestRequest::post()
+            .uri("/admin/drain?wait_seconds=1")
+            .insert_header(admin_bearer()),
+    )
+    .await;
+    c.exercise(&app, test::TestRequest::get().uri("/readyz"))
+        .await;
+
+    assert!(
+        contract.violations.is_empty(),
+        "responses drifted from the spec:\n{}",
+        contract.violations.join("\n")
+    );
+
+    let unexercised = contract.unexercised();
+    assert!(
+        unexercised.is_empty(),
+        "operations without contract coverage:\n{}",
+        unexercised.join("\n")
+    );
+}
+
+/// Users, login and the dashboard, which only builds with the `dashboard`
+/// feature serve.
+#[cfg(feature = "dashboard")]
+async fn exercise_dashboard<S, B>(
+    c: &mut Contract,
+    app: &S,
+    database: &actix_web::web::Data<db::Pool>,
+) where
+    S: Service<actix_http::Request, Response = ServiceResponse<B>, Error = actix_web::Error>,
+    B: MessageBody,
+{
+    c.exercise(app, test::TestRequest::get().uri("/admin/users"))
+        .await;
+    c.exercise(
+        app,
+        test::TestRequest::post()
+            .uri("/admin/users")
+            .insert_header(admin_bearer())
+            .set_json(json!({ "email": "ada@example.com", "role": "operator" })),
+    )
+    .await;
+    c.exercise(
+        app,
+        test::TestRequest::post()
+            .uri("/admin/users")
+            .insert_header(admin_bearer())
+            .set_json(json!({ "email": "ada@example.com" })),
+    )
+    .await;
+    c.exercise(
+        app,
+        test::TestRequest::post()
+            .uri("/admin/users")
+            .insert_header(admin_bearer())
+            .set_json(json!({ "email": "nobody" })),
+    )
+    .await;
+    c.exercise(
+        app,
+        test::TestRequest::get()
+            .uri("/admin/users")
+            .insert_header(admin_bearer()),
+    )
+    .await;
+    c.exercise(
+        app,
+        test::TestRequest::put()
+            .uri("/admin/users/1/role")
+            .insert_header(admin_bearer())
+            .set_json(json!({ "role": "admin" })),
+    )
+    .await;
+    c.exercise(
+        app,
+        test::TestRequest::put()
+            .uri("/admin/users/999/role")
+            .insert_header(admin_bearer())
+            .set_json(json!({ "role": "admin" })),
+    )
+    .await;
+    c.exercise(
+        app,
+        test::TestRequest::delete()
+            .uri("/admin/users/1/sessions")
+            .insert_header(admin_bearer()),
+    )
+    .await;
+    let viewer = log_in(database, "viewer@example.com", db::Role::Viewer).await;
+    c.exercise(
+        app,
+        test::TestRequest::get().uri("/admin/users").cookie(viewer),
+    )
+    .await;
+
+    // Login.
+    c.exercise(
+        app,
+        test::TestRequest::post()
+            .uri("/auth/magic-link")
+            .set_json(json!({ "email": "ada@example.com" })),
+    )
+    .await;
+    c.exercise(
+        app,
+        test::TestRequest::post()
+            .uri("/auth/magic-link")
+            .set_json(json!({ "email": "nobody" })),
+    )
+    .await;
+    c.exercise(
+        app,
+        test::TestRequest::get().uri("/auth/magic-link/not-a-token"),
+    )
+    .await;
+    c.exercise(app, test::TestRequest::get().uri("/dashboard/me"))
+        .await;
+
+    let session = log_in(database, "grace@example.com", db::Role::Admin).await;
+    c.exercise(
+        app,
+        test::TestRequest::get()
+            .uri("/dashboard/me")
+            .cookie(session.clone()),
+    )
+    .await;
+    let reply = c
+        .exercise(
+            app,
+            test::TestRequest::get()
+                .uri("/auth/csrf-token")
+                .cookie(session.clone()),
+        )
+        .await;
+    let csrf: Value = serde_json::from_slice(&reply.body).unwrap();
+    let csrf = csrf["token"].as_str().unwrap().to_owned();
+
+    c.exercise(
+        app,
+        test::TestRequest::post()
+            .uri("/dashboard/totp/enroll")
+            .cookie(session.clone()),
+    )
+    .await;
+    c.exercise(
+        app,
+        test::TestRequest::post()
+            .uri("/dashboard/totp/enroll")
+            .cookie(session.clone())
+            .insert_header(("X-CSRF-Token", csrf.as_str())),
+    )
+    .await;
+    c.exercise(
+        app,
+        test::TestRequest::post()
+            .uri("/dashboard/totp/confirm")
+            .cookie(session.clone())
+            .insert_header(("X-CSRF-Token", csrf.as_str()))
+            .set_json(json!({ "code": "12" })),
+    )
+    .await;
+    c.exercise(
+        app,
+        test::TestRequest::post()
+            .uri("/auth/logout")
+            .cookie(session)
+            .insert_header(("X-CSRF-Token", csrf.as_str())),
+    )
+    .await;
+}
+
+/// Usage rows name their route by [`db::ApiEndpoint`], so every route API
+/// keys can call needs a variant.
+#[actix_web::test]
+async fn api_key_routes_have_endpoints() {
+    let spec: Value = serde_json::from_str(hello_actix::openapi::SPEC).unwrap();
+
+    let mut unlabelled = Vec::new();
+    for (path, operations) in spec["paths"].as_object().unwrap() {
+        for (method, operation) in operations.as_object().unwrap() {
+            let keyed = operation["security"]
+                .as_array()
+                .is_some_and(|schemes| schemes.iter().any(|scheme| scheme.get("apiKey").is_some()));
+            let method = method.to_uppercase();
+
+            let labelled = db::ApiEndpoint::ALL
+                .iter()
+                .any(|endpoint| endpoint.route() == (method.as_str(), path.as_str()));
+            if keyed && !labelled {
+                unlabelled.push(format!("{method} {path}"));
+            }
+        }
+    }
+
+    assert!(
+        unlabelled.is_empty(),
+        "routes without an ApiEndpoint:\n{}",
+        unlabelled.join("\n")
+    );
+}
+
+/// Client generators name methods after `operationId` and version packages
+/// after `info.version`, so every operation needs a unique id and the spec
+/// must follow the crate's version.
+#[actix_web::test]
+async fn spec_is_ready_for_client_generation() {
+    let spec: Value = serde_json::from_str(hello_actix::openapi::SPEC).unwrap();
+    assert_eq!(spec["info"]["version"], env!("CARGO_PKG_VERSION"));
+
+    let mut ids = BTreeSet::new();
+    let mut problems = Vec::new();
+    for (path, operations) in spec["paths"].as_object().unwrap() {
+        for (method, operation) in operations.as_object().unwrap() {
+            let method = method.to_uppercase();
+            match operation["operationId"].as_str() {
+                None => problems.push(format!("{method} {path} has no operationId")),
+                Some(id) if !ids.insert(id) => {
+                    problems.push(format!("{method} {path} reuses operationId {id}"))
+                }
+                Some(_) => {}
+            }
+        }
+    }
+
+    assert!(problems.is_empty(), "{}", problems.join("\n"));
+}
@@ -0,0 +1,2446 @@
+//! Snapshots of every endpoint's JSON bodies, success and error alike, so an
+//! accidental serialization change (a renamed field, different float
+//! formatting, a reworded error) shows up as a diff.
+//!
+//! After an intended change, review and accept the new snapshots with
+//! `cargo insta review`, or re-run with `INSTA_UPDATE=always`.
+
+use std::cell::Cell;
+use std::rc::Rc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use actix_web::http::header::{ACCEPT, ACCEPT_LANGUAGE, AUTHORIZATION};
+use actix_web::{test, web};
+use insta::assert_json_snapshot;
+use serde_json::{json, Value};
+
+use hello_actix::auth::{self, KeyGenerator};
+use hello_actix::usage::{Batching, FanOut, Overflow, QueuedSink, Recorded, UsageSink};
+use hello_actix::{db, migrate, notify, subscriptions, tasks, AppState};
+use r2d2_sqlite::SqliteConnectionManager;
+
+#[macro_use]
+mod common;
+
+#[cfg(feature = "dashboard")]
+use common::log_in;
+use common::{
+    admin_bearer, admin_key, basic, config, database, send, signup_link, solved_challenge, Outbox,
+};
+
+/// Status plus body, parsed as JSON where possible.
+async fn call<S, R, B>(app: &S, req: R) -> Value
+where
+    S: actix_web::dev::Service<
+        R,
+        Response = actix_web::dev::ServiceResponse<B>,
+        Error = actix_web::Error,
+    >,
+    B: actix_web::body::MessageBody,
+{
+    let reply = send(app, req).await;
+
+    let body = if reply.body.is_empty() {
+        Value::Null
+    } else {
+        serde_json::from_slice(&reply.body)
+            .unwrap_or_else(|_| Value::String(String::from_utf8_lossy(&reply.body).into_owned()))
+    };
+
+    json!({ "status": reply.status.as_u16(), "body": body })
+}
+
+#[actix_web::test]
+async fn conversions() {
+    let database = database();
+    let app = app!(config(), database);
+
+    let req = test::TestRequest::post()
+        .uri("/signup")
+        .set_json(json!({ "email": "ada@example.com" }))
+        .to_request();
+    assert_json_snapshot!("invalid_challenge", call(&app, req).await);
+
+    let mut signup = solved_challenge(&app).await;
+    signup["email"] = json!("ada@example.com");
+    let req = test::TestRequest::post()
+        .uri("/signup")
+        .set_json(&signup)
+        .to_request();
+    assert_json_snapshot!("request_signup", call(&app, req).await);
+
+    // Each challenge is good for one signup only.
+    let req = test::TestRequest::post()
+        .uri("/signup")
+        .set_json(&signup)
+        .to_request();
+    assert_json_snapshot!("reused_challenge", call(&app, req).await);
+
+    let req = test::TestRequest::post()
+        .uri("/signup/not-a-token")
+        .to_request();
+    assert_json_snapshot!("invalid_signup_link", call(&app, req).await);
+
+    let link = signup_link(&database, "ada@example.com").await;
+    let req = test::TestRequest::post().uri(&link).to_request();
+    let res = test::call_service(&app, req).await;
+    assert!(res.status().is_success());
+    let api_key = String::from_utf8(test::read_body(res).await.to_vec()).unwrap();
+    let api_key = api_key.trim();
+    assert!(api_key.starts_with(auth::KEY_PREFIX));
+
+    let req = test::TestRequest::get()
+        .uri("/api/to-celsius/100")
+        .insert_header(basic(api_key))
+        .to_request();
+    assert_json_snapshot!("to_celsius", call(&app, req).await);
+
+    let req = test::TestRequest::get()
+        .uri("/api/to-fahrenheit/37.5?client_request_id=abc-123")
+        .insert_header(basic(api_key))
+        .insert_header(("X-Usage-Tag", "nightly-batch"))
+        .to_request();
+    assert_json_snapshot!(
+        "to_fahrenheit_with_client_request_id",
+        call(&app, req).await
+    );
+
+    let req = test::TestRequest::get()
+        .uri("/api/to-kelvin/100")
+        .insert_header(basic(api_key))
+        .to_request();
+    assert_json_snapshot!("to_kelvin", call(&app, req).await);
+
+    let req = test::TestRequest::get()
+        .uri("/api/from-kelvin/0,273.15")
+        .insert_header(basic(api_key))
+        .to_request();
+    assert_json_snapshot!("from_kelvin", call(&app, req).await);
+
+    let req = test::TestRequest::get()
+        .uri("/api/to-celsius/99,100?precision=2")
+        .insert_header(basic(api_key))
+        .to_request();
+    assert_json_snapshot!("to_celsius_with_precision", call(&app, req).await);
+
+    let req = test::TestRequest::get()
+        .uri("/api/to-celsius/99?precision=7")
+        .insert_header(basic(api_key))
+        .to_request();
+    assert_json_snapshot!("invalid_precision", call(&app, req).await);
+
+    let req = test::TestRequest::get()
+        .uri("/api/to-celsius/100?envelope=true")
+        .insert_header(basic(api_key))
+        .to_request();
+    assert_json_snapshot!("to_celsius_enveloped", call(&app, req).await, {
+        ".body.meta.duration_ms" => "[duration]",
+        ".body.meta.version" => "[version]",
+    });
+
+    let req = test::TestRequest::get()
+        .uri("/api/scales/F")
+        .insert_header(basic(api_key))
+        .to_request();
+    assert_json_snapshot!("scale_info", call(&app, req).await);
+
+    let req = test::TestRequest::get()
+        .uri("/api/scales/rankine")
+        .insert_header(basic(api_key))
+        .to_request();
+    assert_json_snapshot!("scale_not_found", call(&app, req).await);
+
+    let req = test::TestRequest::get()
+        .uri("/api/eval?expr=72%C2%B0F+in+kelvin")
+        .insert_header(basic(api_key))
+        .to_request();
+    assert_json_snapshot!("eval", call(&app, req).await);
+
+    let req = test::TestRequest::get()
+        .uri("/api/eval?expr=25+degrees+to+F")
+        .insert_header(basic(api_key))
+        .to_request();
+    assert_json_snapshot!("invalid_expression", call(&app, req).await);
+
+    let req = test::TestRequest::get()
+        .uri("/api/to-celsius/10,20,30")
+        .insert_header(basic(api_key))
+        .to_request();
+    assert_json_snapshot!("to_celsius_many", call(&app, req).await);
+
+    let req = test::TestRequest::get()
+        .uri(&format!("/api/to-celsius/{}", vec!["1"; 101].join(",")))
+        .insert_header(basic(api_key))
+        .to_request();
+    assert_json_snapshot!("too_many_values", call(&app, req).await);
+
+    let req = test::TestRequest::get()
+        .uri(&format!(
+            "/api/to-celsius/1?client_request_id={}",
+            "x".repeat(129)
+        ))
+        .insert_header(basic(api_key))
+        .to_request();
+    assert_json_snapshot!("invalid_client_request_id", call(&app, req).await);
+
+    let req = test::TestRequest::get()
+        .uri("/api/to-celsius/1")
+        .insert_header(basic(api_key))
+        .insert_header(("X-Usage-Tag", "not a tag"))
+        .to_request();
+    assert_json_snapshot!("invalid_usage_tag", call(&app, req).await);
+
+    let req = test::TestRequest::get()
+        .uri("/api/to-celsius/1")
+        .insert_header(basic("not-a-key"))
+        .to_request();
+    assert_json_snapshot!("unauthorized", call(&app, req).await);
+
+    let req = test::TestRequest::get()
+        .uri("/api/to-celsius/1")
+        .insert_header(basic("not-a-key"))
+        .insert_header((ACCEPT_LANGUAGE, "it-IT,it;q=0.9,en;q=0.8"))
+        .to_request();
+    assert_json_snapshot!("unauthorized_it", call(&app, req).await);
+
+    let api_key = admin_key(&database, "ops@example.com").await;
+    let req = test::TestRequest::delete()
+        .uri("/api-key")
+        .insert_header(basic(&api_key))
+        .to_request();
+    assert_json_snapshot!("delete_api_key", call(&app, req).await);
+
+    let req = test::TestRequest::get()
+        .uri("/api/to-celsius/100")
+        .insert_header(basic(&api_key))
+        .to_request();
+    assert_eq!(send(&app, req).await.status, 403);
+}
+
+#[actix_web::test]
+async fn usage_statistics_counters() {
+    let database = database();
+    let app = app!(config(), database);
+
+    let req = test::TestRequest::get()
+        .uri("/usage-statistics")
+        .to_request();
+    assert_json_snapshot!("usage_statistics", call(&app, req).await);
+
+    let req = test::TestRequest::post()
+        .uri("/reset-usage-statistics")
+        .to_request();
+    assert_json_snapshot!("reset_usage_statistics", call(&app, req).await);
+}
+
+#[actix_web::test]
+async fn error_catalog() {
+    let database = database();
+    let app = app!(config(), database);
+
+    // The catalog grows with features such as `scripting`; snapshot the
+    // default build's.
+    let req = test::TestRequest::get().uri("/api/errors").to_request();
+    #[cfg(not(feature = "scripting"))]
+    assert_json_snapshot!("error_catalog", call(&app, req).await);
+    #[cfg(feature = "scripting")]
+    assert_eq!(send(&app, req).await.status, 200);
+
+    let req = test::TestRequest::get()
+        .uri("/api/errors")
+        .insert_header((ACCEPT_LANGUAGE, "it"))
+        .to_request();
+    let body = call(&app, req).await;
+    assert_eq!(
+        body["body"][0]["description"],
+        "Il token fornito non è autorizzato."
+    );
+}
+
+#[actix_web::test]
+async fn liveness() {
+    let app = app!(config(), database());
+
+    let req = test::TestRequest::get().uri("/health").to_request();
+    assert_json_snapshot!("health", call(&app, req).await);
+}
+
+#[actix_web::test]
+async fn readiness() {
+    let database = database();
+    let state = AppState::new(config(), (**database).clone());
+    let app = app!(state.clone());
+
+    // Unready until the key cache is loaded, as `main` does before serving.
+    let req = test::TestRequest::get().uri("/ready").to_request();
+    assert_eq!(send(&app, req).await.status, 503);
+    state.key_cache.load(database.clone()).unwrap();
+
+    for (uri, snapshot) in [("/ready", "ready"), ("/readyz", "readyz")] {
+        let req = test::TestRequest::get().uri(uri).to_request();
+        assert_json_snapshot!(snapshot, call(&app, req).await, {
+            ".body.database.latency_ms" => "[latency]",
+        });
+    }
+}
+
+#[actix_web::test]
+async fn drain() {
+    let database = database();
+    let state = AppState::new(config(), (**database).clone());
+    state.key_cache.load(database.clone()).unwrap();
+    let app = app!(state.clone());
+
+    let req = test::TestRequest::post()
+        .uri("/admin/drain?wait_seconds=1")
+        .insert_header(admin_bearer())
+        .to_request();
+    let res = test::call_service(&app, req).await;
+    assert_eq!(res.headers().get("Connection").unwrap(), "close");
+    let body: Value = test::read_body_json(res).await;
+    assert_json_snapshot!("drain", body);
+
+    let req = test::TestRequest::get().uri("/readyz").to_request();
+    assert_json_snapshot!("readyz_draining", call(&app, req).await, {
+        ".body.database.latency_ms" => "[latency]",
+    });
+
+    // Still alive, so not restarted mid-drain.
+    let req = test::TestRequest::get().uri("/health").to_request();
+    assert_eq!(send(&app, req).await.status, 200);
+}
+
+#[actix_web::test]
+async fn embedded() {
+    let database = database();
+    let state = hello_actix::AppState::new(config(), (**database).clone());
+    let app = test::init_service(
+        actix_web::App::new().service(
+            actix_web::web::scope("/temperature")
+                .configure(|cfg| hello_actix::mount(cfg, state.clone())),
+        ),
+    )
+    .await;
+
+    let link = signup_link(&database, "ada@example.com").await;
+    let req = test::TestRequest::post()
+        .uri(&format!("/temperature{link}"))
+        .to_request();
+    let api_key = String::from_utf8(send(&app, req).await.body.to_vec()).unwrap();
+
+    let req = test::TestRequest::get()
+        .uri("/temperature/api/to-fahrenheit/100")
+        .insert_header(basic(api_key.trim()))
+        .to_request();
+    assert_json_snapshot!("embedded_conversion", call(&app, req).await);
+}
+
+#[actix_web::test]
+async fn internal_listener() {
+    let database = database();
+    let state = AppState::new(config(), (**database).clone());
+    let app = test::init_service(
+        actix_web::App::new().configure(|cfg| hello_actix::mount_internal(cfg, state.clone())),
+    )
+    .await;
+
+    auth::store_api_key(
+        database.clone(),
+        &state.key_cache,
+        &auth::RandomKeys,
+        "ada@example.com".into(),
+        db::Tier::Free,
+        None,
+        hello_actix::scopes::ScopeSet::ALL,
+    )
+    .await
+    .unwrap();
+
+    // Every admin route, as `main` serves them with `INTERNAL_BIND` set.
+    // None may go without the app data the public listener has.
+    let requests = [
+        test::TestRequest::get().uri("/admin/reports/monthly/2025/1"),
+        test::TestRequest::get().uri("/admin/usage/compare?period_a=2025-01&period_b=2025-02"),
+        test::TestRequest::get()
+            .uri("/admin/usage?from=2025-01-01T00:00:00Z&to=2025-01-02T00:00:00Z&bucket=day"),
+        test::TestRequest::get().uri("/admin/config"),
+        test::TestRequest::post().uri("/admin/master-key/reload"),
+        test::TestRequest::get().uri("/admin/keys"),
+        test::TestRequest::get().uri("/admin/keys/1/usage"),
+        test::TestRequest::put()
+            .uri("/admin/keys/1/endpoints")
+            .set_json(json!({ "disabled": [] })),
+        test::TestRequest::put()
+            .uri("/admin/keys/1/quota")
+            .set_json(json!({ "monthly_limit": null })),
+        test::TestRequest::post()
+            .uri("/admin/blocklist")
+            .set_json(json!({ "network": "2001:db8::/32" })),
+        test::TestRequest::get().uri("/admin/blocklist"),
+        test::TestRequest::delete().uri("/admin/blocklist/1"),
+        test::TestRequest::post()
+            .uri("/admin/report-subscriptions")
+            .set_json(json!({
+                "schedule": "0 8 * * 1",
+                "target": { "email": "ops@example.com" },
+            })),
+        test::TestRequest::get().uri("/admin/report-subscriptions"),
+        test::TestRequest::delete().uri("/admin/report-subscriptions/1"),
+        test::TestRequest::get().uri("/admin/metrics"),
+        test::TestRequest::get().uri("/admin/memory"),
+        test::TestRequest::get().uri("/admin/stats/workers"),
+        test::TestRequest::get().uri("/admin/throttling"),
+        test::TestRequest::get().uri("/admin/tasks"),
+        test::TestRequest::delete().uri("/admin/keys/1/data"),
+    ];
+    for req in requests {
+        let req = req.insert_header(admin_bearer()).to_request();
+        let (method, path) = (req.method().clone(), req.path().to_owned());
+        let status = send(&app, req).await.status;
+        assert!(status.is_success(), "{method} {path}: {status}");
+    }
+
+    for uri in ["/health", "/ready", "/readyz"] {
+        let req = test::TestRequest::get().uri(uri).to_request();
+        assert!(send(&app, req).await.status.is_success(), "{uri}");
+    }
+
+    let req = test::TestRequest::post()
+        .uri("/admin/drain?wait_seconds=1")
+        .insert_header(admin_bearer())
+        .to_request();
+    assert!(send(&app, req).await.status.is_success());
+}
+
+#[actix_web::test]
+async fn end_of_life_advisory() {
+    use hello_actix::releases::{Releases, RUNNING};
+
+    let database = database();
+    let state = hello_actix::AppState::new(config(), (**database).clone());
+    let app = test::init_service(
+        actix_web::App::new()
+            .wrap(actix_web::middleware::from_fn(
+                hello_actix::deprecation::emit_deprecation_headers,
+            ))
+            .configure(|cfg| hello_actix::mount(cfg, state.clone())),
+    )
+    .await;
+
+    let req = test::TestRequest::get().uri("/version").to_request();
+    let res = test::call_service(&app, req).await;
+    assert!(res.headers().get("X-API-Deprecated-Version").is_none());
+
+    state.releases.update(Releases {
+        latest: Some("99.0.0".into()),
+        end_of_life: vec![RUNNING.into()],
+    });
+    let req = test::TestRequest::get().uri("/version").to_request();
+    let res = test::call_service(&app, req).await;
+    assert_eq!(
+        res.headers().get("X-API-Deprecated-Version").unwrap(),
+        &format!("{RUNNING}; latest=99.0.0")
+    );
+}
+
+#[cfg(feature = "protobuf")]
+#[actix_web::test]
+async fn protobuf() {
+    use conversion_core::proto;
+    use prost::Message as _;
+
+    let database = database();
+    let app = app!(config(), database);
+
+    let link = signup_link(&database, "ada@example.com").await;
+    let req = test::TestRequest::post().uri(&link).to_request();
+    let api_key = String::from_utf8(send(&app, req).await.body.to_vec()).unwrap();
+    let api_key = api_key.trim();
+
+    let req = test::TestRequest::get()
+        .uri("/api/to-fahrenheit/100?client_request_id=abc")
+        .insert_header(basic(api_key))
+        .insert_header(("Accept", "application/protobuf"))
+        .to_request();
+    let reply = send(&app, req).await;
+    assert_eq!(reply.content_type.as_deref(), Some("application/protobuf"));
+    assert_eq!(
+        proto::Temperature::decode(reply.body).unwrap(),
+        proto::Temperature {
+            celsius: 100.0,
+            fahrenheit: 212.0,
+            client_request_id: Some("abc".into()),
+            kelvin: 373.15,
+        }
+    );
+
+    let req = test::TestRequest::get()
+        .uri("/usage-statistics")
+        .insert_header(("Accept", "application/x-protobuf"))
+        .to_request();
+    let reply = send(&app, req).await;
+    let statistics = proto::UsageStatistics::decode(reply.body).unwrap();
+    assert_eq!(statistics.to_celsius, 0);
+}
+
+#[actix_web::test]
+async fn usage_alerts() {
+    let database = database();
+    let app = app!(config(), database);
+
+    let link = signup_link(&database, "ada@example.com").await;
+    let req = test::TestRequest::post().uri(&link).to_request();
+    let api_key = String::from_utf8(send(&app, req).await.body.to_vec()).unwrap();
+    let api_key = api_key.trim();
+
+    let req = test::TestRequest::get()
+        .uri("/api/alerts/wait?threshold=1&timeout_seconds=0")
+        .insert_header(basic(api_key))
+        .to_request();
+    assert_json_snapshot!("usage_alert_timed_out", call(&app, req).await);
+
+    let req = test::TestRequest::get()
+        .uri("/api/to-celsius/212")
+        .insert_header(basic(api_key))
+        .to_request();
+    assert!(send(&app, req).await.status.is_success());
+
+    let req = test::TestRequest::get()
+        .uri("/api/alerts/wait?threshold=1&timeout_seconds=5")
+        .insert_header(basic(api_key))
+        .to_request();
+    assert_json_snapshot!("usage_alert_crossed", call(&app, req).await);
+
+    let req = test::TestRequest::get()
+        .uri("/api/alerts/wait?threshold=1")
+        .to_request();
+    assert_eq!(send(&app, req).await.status, 401);
+}
+
+#[actix_web::test]
+async fn admin() {
+    let app = app!(config(), database());
+
+    let req = test::TestRequest::get().uri("/admin/keys").to_request();
+    assert_json_snapshot!("admin_unauthorized", call(&app, req).await);
+
+    let req = test::TestRequest::get()
+        .uri("/admin/reports/monthly/2025/13")
+        .insert_header(admin_bearer())
+        .to_request();
+    assert_json_snapshot!("invalid_report_period", call(&app, req).await);
+}
+
+#[cfg(feature = "dashboard")]
+#[actix_web::test]
+async fn users() {
+    let database = database();
+    let app = app!(config(), database);
+
+    let req = test::TestRequest::post()
+        .uri("/admin/users")
+        .insert_header(admin_bearer())
+        .set_json(json!({ "email": "  Ada@Example.com ", "role": "operator" }))
+        .to_request();
+    assert_json_snapshot!("create_user", call(&app, req).await);
+
+    let req = test::TestRequest::post()
+        .uri("/admin/users")
+        .insert_header(admin_bearer())
+        .set_json(json!({ "email": "ada@example.com" }))
+        .to_request();
+    assert_json_snapshot!("create_existing_user", call(&app, req).await);
+
+    let req = test::TestRequest::post()
+        .uri("/admin/users")
+        .insert_header(admin_bearer())
+        .set_json(json!({ "email": "nobody" }))
+        .to_request();
+    assert_json_snapshot!("invalid_email", call(&app, req).await);
+
+    let req = test::TestRequest::get()
+        .uri("/admin/users")
+        .insert_header(admin_bearer())
+        .to_request();
+    assert_json_snapshot!("list_users", call(&app, req).await);
+
+    let req = test::TestRequest::put()
+        .uri("/admin/users/999/role")
+        .insert_header(admin_bearer())
+        .set_json(json!({ "role": "admin" }))
+        .to_request();
+    assert_json_snapshot!("user_not_found", call(&app, req).await);
+
+    let cookie = log_in(&database, "viewer@example.com", db::Role::Viewer).await;
+    let req = test::TestRequest::get()
+        .uri("/admin/users")
+        .cookie(cookie)
+        .to_request();
+    assert_json_snapshot!("insufficient_role", call(&app, req).await);
+}
+
+#[actix_web::test]
+async fn admin_disabled() {
+    let database = database();
+    let app = app!(hello_actix::config::Config::default(), database);
+
+    let req = test::TestRequest::get()
+        .uri("/admin/keys")
+        .insert_header(admin_bearer())
+        .to_request();
+    assert_json_snapshot!("admin_disabled", call(&app, req).await);
+}
+
+#[actix_web::test]
+async fn admin_keys() {
+    let database = database();
+    let app = app!(config(), database);
+
+    let link = signup_link(&database, "ada@example.com").await;
+    let req = test::TestRequest::post().uri(&link).to_request();
+    let api_key = String::from_utf8(send(&app, req).await.body.to_vec()).unwrap();
+
+    let req = test::TestRequest::get()
+        .uri("/api/to-fahrenheit/20")
+        .insert_header(basic(api_key.trim()))
+        .to_request();
+    assert!(send(&app, req).await.status.is_success());
+
+    let req = test::TestRequest::get()
+        .uri("/admin/keys")
+        .insert_header(admin_bearer())
+        .to_request();
+    assert_json_snapshot!("admin_keys", call(&app, req).await, {
+        ".body[].created_at" => "[timestamp]",
+    });
+
+    let req = test::TestRequest::get()
+        .uri("/admin/keys/1/usage")
+        .insert_header(admin_bearer())
+        .insert_header((ACCEPT, "application/vnd.api+json"))
+        .to_request();
+    assert_json_snapshot!("admin_key_usage_jsonapi", call(&app, req).await, {
+        ".body.data[].attributes.called_at" => "[timestamp]",
+        ".body.data[].attributes.latency_ms" => "[latency]",
+    });
+}
+
+#[actix_web::test]
+async fn legacy_timestamps() {
+    let pool = db::Pool::builder()
+        .max_size(1)
+        .build(SqliteConnectionManager::memory())
+        .unwrap();
+    {
+        let conn = pool.get().unwrap();
+        migrate::apply(&conn, Some(10), |_, _| {}).unwrap();
+        conn.execute_batch(
+            "
+            INSERT INTO api_keys (created_at, email, tier)
+            VALUES ('2024-05-01 12:00:00.123456789+02:00', 'ada@example.com', 'free'),
+                   ('2024-05-01 12:00:00', 'grace@example.com', 'free');
+            ",
+        )
+        .unwrap();
+    }
+    db::setup(pool.clone());
+    let database = web::Data::new(pool);
+    let app = app!(config(), database);
+
+    let req = test::TestRequest::get()
+        .uri("/admin/keys")
+        .insert_header(admin_bearer())
+        .to_request();
+    let body = call(&app, req).await["body"].clone();
+    assert_eq!(body[0]["created_at"], "2024-05-01T10:00:00.123Z");
+    assert_eq!(body[1]["created_at"], "2024-05-01T12:00:00Z");
+}
+
+#[actix_web::test]
+async fn deterministic_keys() {
+    let database = database();
+    let mut state = AppState::new(config(), (**database).clone());
+    let issued = AtomicUsize::new(0);
+    let keys = move || format!("{:0>40}", issued.fetch_add(1, Ordering::Relaxed));
+    state.keys = web::Data::from(Arc::new(keys) as Arc<dyn KeyGenerator>);
+    let app = app!(state);
+
+    for (email, expected) in [("ada@example.com", "0"), ("grace@example.com", "1")] {
+        let link = signup_link(&database, email).await;
+        let req = test::TestRequest::post().uri(&link).to_request();
+        let api_key = String::from_utf8(send(&app, req).await.body.to_vec()).unwrap();
+        assert_eq!(api_key.trim(), format!("{expected:0>40}"));
+    }
+
+    let req = test::TestRequest::get()
+        .uri("/api/whoami")
+        .insert_header(basic(&format!("{:0>40}", 1)))
+        .to_request();
+    assert_eq!(send(&app, req).await.status, 200);
+}
+
+#[actix_web::test]
+async fn colliding_keys_are_drawn_again() {
+    let database = database();
+    let mut state = AppState::new(config(), (**database).clone());
+    let issued = AtomicUsize::new(0);
+    // Repeats the first key once.
+    let keys = move || {
+        let n = issued.fetch_add(1, Ordering::Relaxed);
+        format!("{:0>40}", n.saturating_sub(1))
+    };
+    state.keys = web::Data::from(Arc::new(keys) as Arc<dyn KeyGenerator>);
+    let app = app!(state);
+
+    for (email, expected) in [("ada@example.com", "0"), ("grace@example.com", "1")] {
+        let link = signup_link(&database, email).await;
+        let req = test::TestRequest::post().uri(&link).to_request();
+        let api_key = String::from_utf8(send(&app, req).await.body.to_vec()).unwrap();
+        assert_eq!(api_key.trim(), format!("{expected:0>40}"));
+    }
+
+    let req = test::TestRequest::get()
+        .uri("/api/whoami")
+        .insert_header(basic(&format!("{:0>40}", 0)))
+        .to_request();
+    assert_eq!(send(&app, req).await.status, 200);
+}
+
+#[actix_web::test]
+async fn issued_key_format() {
+    let database = database();
+    let app = app!(config(), database);
+
+    let link = signup_link(&database, "ada@example.com").await;
+    let req = test::TestRequest::post().uri(&link).to_request();
+    let api_key = String::from_utf8(send(&app, req).await.body.to_vec()).unwrap();
+    let api_key = api_key.trim();
+    assert!(api_key.starts_with(auth::KEY_PREFIX), "{api_key}");
+    assert_eq!(api_key.len(), auth::KEY_PREFIX.len() + 43 + 6);
+    assert!(auth::is_plausible_key(api_key));
+
+    let req = test::TestRequest::get()
+        .uri("/api/whoami")
+        .insert_header(basic(api_key))
+        .to_request();
+    assert_eq!(send(&app, req).await.status, 200);
+
+    // One payload character off no longer matches the checksum.
+    let mut tampered = api_key.to_owned();
+    let at = auth::KEY_PREFIX.len();
+    let swapped = if &tampered[at..=at] == "0" { "1" } else { "0" };
+    tampered.replace_range(at..=at, swapped);
+    assert!(!auth::is_plausible_key(&tampered));
+
+    let rejected = || {
+        hello_actix::metrics::AUTH
+            .implausible_key
+            .load(Ordering::Relaxed)
+    };
+    let before = rejected();
+    let req = test::TestRequest::get()
+        .uri("/api/whoami")
+        .insert_header(basic(&tampered))
+        .to_request();
+    assert_json_snapshot!("implausible_key", call(&app, req).await);
+    assert!(rejected() > before);
+}
+
+#[actix_web::test]
+async fn master_key_reload() {
+    let database = database();
+    let app = app!(config(), database);
+
+    let link = signup_link(&database, "ada@example.com").await;
+    let req = test::TestRequest::post().uri(&link).to_request();
+    let api_key = String::from_utf8(send(&app, req).await.body.to_vec()).unwrap();
+
+    let req = test::TestRequest::post()
+        .uri("/admin/master-key/reload")
+        .to_request();
+    assert_eq!(send(&app, req).await.status, 401);
+
+    let loads = || {
+        hello_actix::metrics::CRYPTO
+            .master_key_loads
+            .load(Ordering::Relaxed)
+    };
+    let before = loads();
+    let req = test::TestRequest::post()
+        .uri("/admin/master-key/reload")
+        .insert_header(admin_bearer())
+        .to_request();
+    assert_eq!(send(&app, req).await.status, 204);
+    assert!(loads() > before);
+
+    // The key file is unchanged, so a cold cache still decrypts the key.
+    let app = app!(config(), database);
+    let req = test::TestRequest::get()
+        .uri("/api/to-celsius/212")
+        .insert_header(basic(api_key.trim()))
+        .to_request();
+    assert!(send(&app, req).await.status.is_success());
+}
+
+/// Holds calls until let through, then keeps their tags.
+struct GatedSink {
+    gate: Arc<tokio::sync::Semaphore>,
+    written: Arc<std::sync::Mutex<Vec<String>>>,
+}
+
+impl UsageSink for GatedSink {
+    fn record(&self, usage: &db::ApiUsage) -> Recorded {
+        let (gate, written) = (self.gate.clone(), self.written.clone());
+        let tag = usage.tag.clone().unwrap();
+        Box::pin(async move {
+            gate.acquire().await.unwrap().forget();
+            written.lock().unwrap().push(tag);
+        })
+    }
+}
+
+#[actix_web::test]
+async fn usage_queue_overflow() {
+    for (overflow, kept) in [
+        (Overflow::DropOldest, ["3", "4"]),
+        (Overflow::DropNew, ["1", "2"]),
+    ] {
+        let gate = Arc::new(tokio::sync::Semaphore::new(0));
+        let written = Arc::default();
+        let sink = GatedSink {
+            gate: gate.clone(),
+            written: Arc::clone(&written),
+        };
+        let batching = Batching {
+            max_calls: 1,
+            flush_interval: Duration::ZERO,
+        };
+        let queue = QueuedSink::spawn(Arc::new(sink), 2, overflow, batching);
+
+        // Queued before the writer gets to run, so two don't fit.
+        for tag in ["1", "2", "3", "4"] {
+            queue
+                .record(&db::ApiUsage {
+                    api_key: "key".into(),
+                    endpoint: db::ApiEndpoint::ToCelsius,
+                    called_at: chrono::Utc::now(),
+                    client_request_id: None,
+                    tag: Some(tag.into()),
+                    location: Default::default(),
+                    latency_ms: None,
+                })
+                .await;
+        }
+
+        gate.add_permits(4);
+        actix_web::rt::time::timeout(Duration::from_secs(5), async {
+            while written.lock().unwrap().len() < 2 {
+                actix_web::rt::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("the queue wasn't written");
+        assert_eq!(*written.lock().unwrap(), kept, "{overflow}");
+    }
+}
+
+/// Keeps the size of each batch it is handed.
+struct BatchSizes(Arc<std::sync::Mutex<Vec<usize>>>);
+
+impl UsageSink for BatchSizes {
+    fn record(&self, _: &db::ApiUsage) -> Recorded {
+        unreachable!("a queue hands on batches")
+    }
+
+    fn record_batch(&self, calls: Vec<db::ApiUsage>) -> Recorded {
+        self.0.lock().unwrap().push(calls.len());
+        Box::pin(std::future::ready(()))
+    }
+}
+
+#[actix_web::test]
+async fn usage_queue_batches() {
+    let sizes = Arc::default();
+    let batching = Batching {
+        max_calls: 3,
+        flush_interval: Duration::ZERO,
+    };
+    let queue = QueuedSink::spawn(
+        Arc::new(BatchSizes(Arc::clone(&sizes))),
+        10,
+        Overflow::DropNew,
+        batching,
+    );
+
+    // Queued before the writer gets to run.
+    for _ in 0..7 {
+        queue
+            .record(&db::ApiUsage {
+                api_key: "key".into(),
+                endpoint: db::ApiEndpoint::ToCelsius,
+                called_at: chrono::Utc::now(),
+                client_request_id: None,
+                tag: None,
+                location: Default::default(),
+                latency_ms: None,
+            })
+            .await;
+    }
+
+    actix_web::rt::time::timeout(Duration::from_secs(5), async {
+        while sizes.lock().unwrap().iter().sum::<usize>() < 7 {
+            actix_web::rt::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .expect("the queue wasn't written");
+    assert_eq!(*sizes.lock().unwrap(), [3, 3, 1]);
+}
+
+#[actix_web::test]
+async fn usage_queue_flush() {
+    let sizes = Arc::default();
+    let batching = Batching {
+        max_calls: 3,
+        flush_interval: Duration::from_secs(3600),
+    };
+    let queue = QueuedSink::spawn(
+        Arc::new(BatchSizes(Arc::clone(&sizes))),
+        10,
+        Overflow::DropNew,
+        batching,
+    );
+    let usage = db::ApiUsage {
+        api_key: "key".into(),
+        endpoint: db::ApiEndpoint::ToCelsius,
+        called_at: chrono::Utc::now(),
+        client_request_id: None,
+        tag: None,
+        location: Default::default(),
+        latency_ms: None,
+    };
+
+    // A short batch puts the writer to sleep for the flush interval.
+    queue.record(&usage).await;
+    actix_web::rt::time::timeout(Duration::from_secs(5), async {
+        while sizes.lock().unwrap().is_empty() {
+            actix_web::rt::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .expect("the queue wasn't written");
+
+    for _ in 0..4 {
+        queue.record(&usage).await;
+    }
+    let sink: Arc<dyn UsageSink> = Arc::new(queue);
+    FanOut::new().with(sink).flush().await;
+    assert_eq!(*sizes.lock().unwrap(), [1, 3, 1]);
+}
+
+#[cfg(feature = "test-util")]
+#[actix_web::test]
+async fn seeded_fixtures() {
+    use hello_actix::fixtures::FixtureDb;
+
+    let fixture = FixtureDb::with_keys(3).with_usage(100).build().await;
+    let app = app!(config(), fixture.database);
+
+    let req = test::TestRequest::get()
+        .uri("/admin/keys")
+        .insert_header(admin_bearer())
+        .to_request();
+    assert_json_snapshot!("seeded_keys", call(&app, req).await, {
+        ".body[].created_at" => "[timestamp]",
+    });
+
+    let req = test::TestRequest::get()
+        .uri("/api/whoami")
+        .insert_header(basic(&fixture.api_keys[2]))
+        .to_request();
+    assert_eq!(send(&app, req).await.status, 200);
+
+    let usage = db::usage_counts(
+        fixture.database.clone(),
+        chrono::Utc::now() - chrono::Duration::days(31),
+        chrono::Utc::now(),
+    )
+    .await
+    .unwrap();
+    assert_eq!(usage.iter().map(|count| count.calls).sum::<u64>(), 100);
+}
+
+#[actix_web::test]
+async fn endpoint_toggles() {
+    let database = database();
+    let app = app!(config(), database);
+
+    let link = signup_link(&database, "ada@example.com").await;
+    let req = test::TestRequest::post().uri(&link).to_request();
+    let api_key = String::from_utf8(send(&app, req).await.body.to_vec()).unwrap();
+    let api_key = api_key.trim();
+
+    let req = test::TestRequest::put()
+        .uri("/admin/keys/1/endpoints")
+        .insert_header(admin_bearer())
+        .set_json(json!({ "disabled": ["to-celsius", "export-data"] }))
+        .to_request();
+    assert!(send(&app, req).await.status.is_success());
+
+    let req = test::TestRequest::get()
+        .uri("/api/to-celsius/100")
+        .insert_header(basic(api_key))
+        .to_request();
+    assert_json_snapshot!("endpoint_disabled", call(&app, req).await);
+
+    let req = test::TestRequest::get()
+        .uri("/api/whoami")
+        .insert_header(basic(api_key))
+        .to_request();
+    assert_json_snapshot!("whoami", call(&app, req).await);
+}
+
+#[actix_web::test]
+async fn output_scales() {
+    let database = database();
+    let app = app!(config(), database);
+
+    let link = signup_link(&database, "ada@example.com").await;
+    let req = test::TestRequest::post().uri(&link).to_request();
+    let api_key = String::from_utf8(send(&app, req).await.body.to_vec()).unwrap();
+    let api_key = api_key.trim();
+
+    let req = test::TestRequest::put()
+        .uri("/api/output-scales")
+        .insert_header(basic(api_key))
+        .set_json(json!({ "scales": ["kelvin", "celsius"] }))
+        .to_request();
+    assert_json_snapshot!("set_output_scales", call(&app, req).await);
+
+    let req = test::TestRequest::get()
+        .uri("/api/to-fahrenheit/37.5,-40")
+        .insert_header(basic(api_key))
+        .to_request();
+    assert_json_snapshot!("to_fahrenheit_in_output_scales", call(&app, req).await);
+}
+
+#[actix_web::test]
+async fn field_case() {
+    let database = database();
+    let app = app!(
+        hello_actix::config::Config {
+            field_case: hello_actix::casing::FieldCase::Camel,
+            ..config()
+        },
+        database
+    );
+
+    let link = signup_link(&database, "ada@example.com").await;
+    let req = test::TestRequest::post().uri(&link).to_request();
+    let api_key = String::from_utf8(send(&app, req).await.body.to_vec()).unwrap();
+    let api_key = api_key.trim();
+
+    let req = test::TestRequest::get()
+        .uri("/api/whoami")
+        .insert_header(basic(api_key))
+        .to_request();
+    assert_json_snapshot!("whoami_in_camel_case", call(&app, req).await);
+
+    let req = test::TestRequest::get()
+        .uri("/api/to-celsius/212?client_request_id=abc&envelope=true")
+        .insert_header(basic(api_key))
+        .to_request();
+    assert_json_snapshot!("conversion_in_camel_case", call(&app, req).await, {
+        ".body.meta.duration_ms" => "[duration]",
+        ".body.meta.durationMs" => "[duration]",
+        ".body.meta.request_id" => "[request_id]",
+        ".body.meta.requestId" => "[request_id]",
+    });
+
+    // The key's own choice wins over the instance's.
+    let req = test::TestRequest::put()
+        .uri("/api/field-case")
+        .insert_header(basic(api_key))
+        .set_json(json!({ "field_case": "snake" }))
+        .to_request();
+    assert_json_snapshot!("set_field_case", call(&app, req).await);
+
+    let req = test::TestRequest::get()
+        .uri("/api/whoami")
+        .insert_header(basic(api_key))
+        .to_request();
+    let whoami = call(&app, req).await;
+    assert_eq!(whoami["body"]["field_case"], "snake");
+    assert!(whoami["body"]["output_scales"].is_array());
+}
+
+#[actix_web::test]
+async fn usage_forecast() {
+    let database = database();
+    let app = app!(config(), database);
+
+    let link = signup_link(&database, "ada@example.com").await;
+    let req = test::TestRequest::post().uri(&link).to_request();
+    let api_key = String::from_utf8(send(&app, req).await.body.to_vec()).unwrap();
+    let api_key = api_key.trim();
+
+    for _ in 0..3 {
+        let req = test::TestRequest::get()
+            .uri("/api/to-celsius/100")
+            .insert_header(basic(api_key))
+            .to_request();
+        assert!(send(&app, req).await.status.is_success());
+    }
+
+    // The fitted rate depends on how far into the month today is.
+    let req = test::TestRequest::get()
+        .uri("/api/my-usage/forecast")
+        .insert_header(basic(api_key))
+        .to_request();
+    assert_json_snapshot!("usage_forecast", call(&app, req).await, {
+        ".body.period_start" => "[timestamp]",
+        ".body.period_end" => "[timestamp]",
+        ".body.calls_per_day" => "[rate]",
+        ".body.trend_per_day" => "[rate]",
+        ".body.projected_calls" => "[calls]",
+        ".body.exhausts_at" => "[timestamp]",
+    });
+}
+
+#[actix_web::test]
+async fn my_usage() {
+    let database = database();
+    let app = app!(config(), database);
+
+    let link = signup_link(&database, "ada@example.com").await;
+    let req = test::TestRequest::post().uri(&link).to_request();
+    let api_key = String::from_utf8(send(&app, req).await.body.to_vec()).unwrap();
+    let api_key = api_key.trim();
+
+    let req = test::TestRequest::get()
+        .uri("/api/usage/me")
+        .insert_header(basic(api_key))
+        .to_request();
+    assert_json_snapshot!("my_usage_before_any_call", call(&app, req).await);
+
+    for uri in [
+        "/api/to-celsius/100",
+        "/api/to-celsius/0",
+        "/api/to-kelvin/0",
+    ] {
+        let req = test::TestRequest::get()
+            .uri(uri)
+            .insert_header(basic(api_key))
+            .to_request();
+        assert!(send(&app, req).await.status.is_success());
+    }
+
+    let req = test::TestRequest::get()
+        .uri("/api/usage/me")
+        .insert_header(basic(api_key))
+        .to_request();
+    assert_json_snapshot!("my_usage", call(&app, req).await, {
+        ".body.**.first_call_at" => "[timestamp]",
+        ".body.**.last_call_at" => "[timestamp]",
+    });
+}
+
+#[actix_web::test]
+async fn partial_responses() {
+    let database = database();
+    let app = app!(config(), database);
+
+    let link = signup_link(&database, "ada@example.com").await;
+    let req = test::TestRequest::post().uri(&link).to_request();
+    let api_key = String::from_utf8(send(&app, req).await.body.to_vec()).unwrap();
+    let api_key = api_key.trim();
+
+    let req = test::TestRequest::get()
+        .uri("/api/to-celsius/212?fields=celsius")
+        .insert_header(basic(api_key))
+        .to_request();
+    assert_json_snapshot!("partial_conversion", call(&app, req).await);
+
+    // The envelope's meta is kept whole.
+    let req = test::TestRequest::get()
+        .uri("/api/to-celsius/212?envelope=true&fields=fahrenheit,client_request_id&client_request_id=abc")
+        .insert_header(basic(api_key))
+        .to_request();
+    assert_json_snapshot!("partial_conversion_in_envelope", call(&app, req).await, {
+        ".body.meta.duration_ms" => "[duration]",
+        ".body.meta.request_id" => "[request_id]",
+    });
+
+    let req = test::TestRequest::get()
+        .uri("/api/usage/me?fields=calls,last_call_at")
+        .insert_header(basic(api_key))
+        .to_request();
+    assert_json_snapshot!("partial_usage", call(&app, req).await, {
+        ".body.last_call_at" => "[timestamp]",
+    });
+
+    // Camel-case names pick the same fields.
+    let req = test::TestRequest::get()
+        .uri("/api/usage/me?fields=firstCallAt")
+        .insert_header(basic(api_key))
+        .to_request();
+    let body = call(&app, req).await["body"].take();
+    assert_eq!(
+        body.as_object().unwrap().keys().collect::<Vec<_>>(),
+        ["first_call_at"]
+    );
+}
+
+#[cfg(feature = "test-util")]
+#[actix_web::test]
+async fn usage_forecast_on_a_mock_clock() {
+    use common::signup_link_at;
+    use hello_actix::clock::{Clock, MockClock};
+
+    let database = database();
+    let clock = Arc::new(MockClock::new("2030-01-10T12:00:00Z".parse().unwrap()));
+    let mut state = AppState::new(config(), (**database).clone());
+    state.clock = web::Data::from(clock.clone() as Arc<dyn Clock>);
+    let app = app!(state);
+
+    let link = signup_link_at(&database, "ada@example.com", clock.now()).await;
+    let req = test::TestRequest::post().uri(&link).to_request();
+    let api_key = String::from_utf8(send(&app, req).await.body.to_vec()).unwrap();
+    let api_key = api_key.trim();
+
+    for _ in 0..3 {
+        let req = test::TestRequest::get()
+            .uri("/api/to-celsius/100")
+            .insert_header(basic(api_key))
+            .to_request();
+        assert!(send(&app, req).await.status.is_success());
+        clock.advance(chrono::Duration::days(1));
+    }
+
+    let req = test::TestRequest::get()
+        .uri("/api/my-usage/forecast")
+        .insert_header(basic(api_key))
+        .to_request();
+    assert_json_snapshot!("usage_forecast_on_a_mock_clock", call(&app, req).await);
+
+    clock.set("2030-02-01T00:00:00Z".parse().unwrap());
+    let req = test::TestRequest::get()
+        .uri("/api/my-usage/forecast")
+        .insert_header(basic(api_key))
+        .to_request();
+    assert_eq!(call(&app, req).await["body"]["used"], 0);
+}
+
+#[actix_web::test]
+async fn quota_warnings() {
+    let database = database();
+    let config = hello_actix::config::Config {
+        free_tier_monthly_call_quota: 5,
+        ..config()
+    };
+    let app = app!(config, database);
+
+    let link = signup_link(&database, "ada@example.com").await;
+    let req = test::TestRequest::post().uri(&link).to_request();
+    let api_key = String::from_utf8(send(&app, req).await.body.to_vec()).unwrap();
+    let api_key = api_key.trim();
+
+    let mut warnings = Vec::new();
+    for _ in 0..5 {
+        let req = test::TestRequest::get()
+            .uri("/api/to-celsius/100")
+            .insert_header(basic(api_key))
+            .to_request();
+        let res = test::call_service(&app, req).await;
+        assert!(res.status().is_success());
+        warnings.push(
+            res.headers()
+                .get("X-Quota-Warning")
+                .map(|value| value.to_str().unwrap().to_owned()),
+        );
+    }
+    assert_eq!(
+        warnings,
+        [
+            None,
+            None,
+            None,
+            Some("threshold=80; used=4; quota=5".to_owned()),
+            Some("threshold=95; used=5; quota=5".to_owned()),
+        ]
+    );
+
+    // One notification per threshold, however many calls pass it.
+    let sent: i64 = database
+        .get()
+        .unwrap()
+        .query_row("SELECT COUNT(*) FROM quota_warnings;", (), |row| row.get(0))
+        .unwrap();
+    assert_eq!(sent, 2);
+}
+
+#[actix_web::test]
+async fn quota_enforcement() {
+    let database = database();
+    let app = app!(config(), database);
+
+    let link = signup_link(&database, "ada@example.com").await;
+    let req = test::TestRequest::post().uri(&link).to_request();
+    let api_key = String::from_utf8(send(&app, req).await.body.to_vec()).unwrap();
+    let api_key = api_key.trim();
+
+    let req = test::TestRequest::put()
+        .uri("/admin/keys/1/quota")
+        .insert_header(admin_bearer())
+        .set_json(json!({ "monthly_limit": 2 }))
+        .to_request();
+    assert!(send(&app, req).await.status.is_success());
+
+    for _ in 0..2 {
+        let req = test::TestRequest::get()
+            .uri("/api/to-celsius/212")
+            .insert_header(basic(api_key))
+            .to_request();
+        assert!(send(&app, req).await.status.is_success());
+    }
+
+    // Only conversions count against the quota, and only they are refused.
+    let req = test::TestRequest::get()
+        .uri("/api/whoami")
+        .insert_header(basic(api_key))
+        .to_request();
+    assert!(send(&app, req).await.status.is_success());
+
+    let req = test::TestRequest::get()
+        .uri("/api/to-celsius/212")
+        .insert_header(basic(api_key))
+        .to_request();
+    let Err(err) = test::try_call_service(&app, req).await else {
+        panic!("the call past the quota went through");
+    };
+    let res = err.error_response();
+    assert_eq!(res.status(), 429);
+    assert_eq!(res.headers().get("X-Quota-Limit").unwrap(), "2");
+    assert_eq!(res.headers().get("X-Quota-Remaining").unwrap(), "0");
+    assert!(res.headers().contains_key("X-Quota-Reset"));
+
+    let req = test::TestRequest::get()
+        .uri("/api/to-celsius/212")
+        .insert_header(basic(api_key))
+        .to_request();
+    assert_json_snapshot!("quota_exceeded", call(&app, req).await);
+
+    // Back on the tier's quota.
+    let req = test::TestRequest::put()
+        .uri("/admin/keys/1/quota")
+        .insert_header(admin_bearer())
+        .set_json(json!({ "monthly_limit": null }))
+        .to_request();
+    assert!(send(&app, req).await.status.is_success());
+    let req = test::TestRequest::get()
+        .uri("/api/to-celsius/212")
+        .insert_header(basic(api_key))
+        .to_request();
+    assert!(send(&app, req).await.status.is_success());
+}
+
+/// Hands calls on to `0` a turn late, as a sink another worker is still
+/// writing to would.
+struct LateSink(Arc<dyn UsageSink>);
+
+impl UsageSink for LateSink {
+    fn record(&self, usage: &db::ApiUsage) -> Recorded {
+        let (sink, usage) = (self.0.clone(), usage.clone());
+        Box::pin(async move {
+            tokio::task::yield_now().await;
+            sink.record(&usage).await;
+        })
+    }
+}
+
+#[actix_web::test]
+async fn quota_under_concurrent_calls() {
+    let database = database();
+    let mut state = AppState::new(config(), (**database).clone());
+    state.usage =
+        web::Data::from(Arc::new(LateSink(state.quota.clone().into_inner())) as Arc<dyn UsageSink>);
+    let app = app!(state);
+
+    let link = signup_link(&database, "ada@example.com").await;
+    let req = test::TestRequest::post().uri(&link).to_request();
+    let api_key = String::from_utf8(send(&app, req).await.body.to_vec()).unwrap();
+    let api_key = api_key.trim();
+
+    let req = test::TestRequest::put()
+        .uri("/admin/keys/1/quota")
+        .insert_header(admin_bearer())
+        .set_json(json!({ "monthly_limit": 1 }))
+        .to_request();
+    assert!(send(&app, req).await.status.is_success());
+
+    // A call that fails gives back what it held.
+    let req = test::TestRequest::get()
+        .uri("/api/to-celsius/hot")
+        .insert_header(basic(api_key))
+        .to_request();
+    assert_eq!(send(&app, req).await.status, 404);
+
+    // The second is checked while the first is still being counted.
+    let convert = || {
+        test::try_call_service(
+            &app,
+            test::TestRequest::get()
+                .uri("/api/to-celsius/212")
+                .insert_header(basic(api_key))
+                .to_request(),
+        )
+    };
+    let (first, second) = tokio::join!(convert(), convert());
+    let statuses = [first, second].map(|res| match res {
+        Ok(res) => res.status(),
+        Err(err) => err.error_response().status(),
+    });
+    assert_eq!(statuses, [200, 429]);
+}
+
+#[actix_web::test]
+async fn keys_from_other_replicas() {
+    let database = database();
+    let state = AppState::new(config(), (**database).clone());
+    state.key_cache.load(database.clone()).unwrap();
+    let app = app!(state.clone());
+
+    // Stored by another replica, with a cache of its own.
+    let api_key = auth::store_api_key(
+        database.clone(),
+        &auth::KeyCache::new(),
+        &auth::RandomKeys,
+        "ada@example.com".into(),
+        db::Tier::Free,
+        None,
+        hello_actix::scopes::ScopeSet::SELF_SERVICE,
+    )
+    .await
+    .unwrap();
+
+    // A miss on a key nobody issued holds up no other key's lookup.
+    let req = test::TestRequest::get()
+        .uri("/api/to-celsius/212")
+        .insert_header(basic(&auth::create_api_key().unwrap()))
+        .to_request();
+    assert_eq!(send(&app, req).await.status, 401);
+
+    let req = test::TestRequest::get()
+        .uri("/api/to-celsius/212")
+        .insert_header(basic(&api_key))
+        .to_request();
+    assert!(send(&app, req).await.status.is_success());
+}
+
+#[actix_web::test]
+async fn key_ttl() {
+    let database = database();
+    let app = app!(config(), database);
+
+    // A mistyped lifetime leaves the link to be used again.
+    let link = signup_link(&database, "ci@example.com").await;
+    let req = test::TestRequest::post()
+        .uri(&format!("{link}?ttl=30"))
+        .to_request();
+    assert_json_snapshot!("invalid_key_ttl", call(&app, req).await);
+
+    let req = test::TestRequest::post()
+        .uri(&format!("{link}?ttl=1h"))
+        .to_request();
+    assert!(send(&app, req).await.status.is_success());
+}
+
+#[cfg(feature = "test-util")]
+#[actix_web::test]
+async fn key_expiry() {
+    use common::signup_link_at;
+    use hello_actix::clock::{Clock, MockClock};
+
+    let database = database();
+    let clock = Arc::new(MockClock::new("2030-01-10T12:00:00Z".parse().unwrap()));
+    let mut state = AppState::new(config(), (**database).clone());
+    state.clock = web::Data::from(clock.clone() as Arc<dyn Clock>);
+    let app = app!(state);
+
+    let link = signup_link_at(&database, "ci@example.com", clock.now()).await;
+    let req = test::TestRequest::post()
+        .uri(&format!("{link}?ttl=1h"))
+        .to_request();
+    let api_key = String::from_utf8(send(&app, req).await.body.to_vec()).unwrap();
+    let api_key = api_key.trim();
+
+    clock.advance(chrono::Duration::minutes(59));
+    let req = test::TestRequest::get()
+        .uri("/api/to-celsius/212")
+        .insert_header(basic(api_key))
+        .to_request();
+    assert!(send(&app, req).await.status.is_success());
+
+    clock.advance(chrono::Duration::minutes(1));
+    let req = test::TestRequest::get()
+        .uri("/api/to-celsius/212")
+        .insert_header(basic(api_key))
+        .to_request();
+    assert_json_snapshot!("key_expired", call(&app, req).await);
+}
+
+#[cfg(feature = "test-util")]
+#[actix_web::test]
+async fn signup_links_expire_by_the_app_clock() {
+    use hello_actix::clock::{Clock, MockClock};
+
+    let database = database();
+    let clock = Arc::new(MockClock::new("2020-01-10T12:00:00Z".parse().unwrap()));
+    let outbox = Arc::new(Outbox::default());
+    let mut state = AppState::new(config(), (**database).clone());
+    state.clock = web::Data::from(clock.clone() as Arc<dyn Clock>);
+    state.mailer = web::Data::from(outbox.clone() as Arc<dyn notify::Mailer>);
+    let app = app!(state);
+
+    for (wait, status) in [(25, 400), (23, 200)] {
+        let mut signup = solved_challenge(&app).await;
+        signup["email"] = json!("ada@example.com");
+        let req = test::TestRequest::post()
+            .uri("/signup")
+            .set_json(&signup)
+            .to_request();
+        assert_eq!(send(&app, req).await.status, 202);
+        let mail = outbox.take().pop().unwrap();
+
+        clock.advance(chrono::Duration::hours(wait));
+        let req = test::TestRequest::post().uri(mail.link()).to_request();
+        assert_eq!(send(&app, req).await.status, status, "after {wait}h");
+    }
+}
+
+#[actix_web::test]
+async fn key_scopes() {
+    let database = database();
+    let app = app!(config(), database);
+
+    let link = signup_link(&database, "ci@example.com").await;
+    let req = test::TestRequest::post()
+        .uri(&format!("{link}?scopes=convert:read,delete"))
+        .to_request();
+    assert_json_snapshot!("invalid_key_scopes", call(&app, req).await);
+
+    // Nor may a signup ask for `admin`.
+    let req = test::TestRequest::post()
+        .uri(&format!("{link}?scopes=convert:read,admin"))
+        .to_request();
+    assert_eq!(send(&app, req).await.status, 400);
+
+    let req = test::TestRequest::post()
+        .uri(&format!("{link}?scopes=convert:read"))
+        .to_request();
+    let api_key = String::from_utf8(send(&app, req).await.body.to_vec()).unwrap();
+    let api_key = api_key.trim();
+
+    let req = test::TestRequest::get()
+        .uri("/api/to-celsius/212")
+        .insert_header(basic(api_key))
+        .to_request();
+    assert!(send(&app, req).await.status.is_success());
+
+    let req = test::TestRequest::get()
+        .uri("/api/whoami")
+        .insert_header(basic(api_key))
+        .to_request();
+    assert_eq!(
+        call(&app, req).await["body"]["scopes"],
+        json!(["convert:read"])
+    );
+
+    let req = test::TestRequest::get()
+        .uri("/api/usage/me")
+        .insert_header(basic(api_key))
+        .to_request();
+    assert_json_snapshot!("missing_scope", call(&app, req).await);
+
+    // Revoking other keys takes `admin`.
+    let req = test::TestRequest::delete()
+        .uri("/api-key/1")
+        .insert_header(basic(api_key))
+        .to_request();
+    assert_eq!(send(&app, req).await.status, 403);
+
+    // A key nobody issued is not let through for want of scopes to check.
+    let req = test::TestRequest::delete()
+        .uri("/api-key/1")
+        .insert_header(basic("not-an-issued-key"))
+        .to_request();
+    assert_eq!(send(&app, req).await.status, 401);
+
+    let req = test::TestRequest::get()
+        .uri("/api/to-celsius/212")
+        .insert_header(basic(api_key))
+        .to_request();
+    assert!(send(&app, req).await.status.is_success());
+}
+
+#[actix_web::test]
+async fn revoking_keys() {
+    let database = database();
+    let app = app!(config(), database);
+
+    let mut keys = Vec::new();
+    for email in ["ci@example.com", "build@example.com"] {
+        let req = test::TestRequest::post()
+            .uri(&signup_link(&database, email).await)
+            .to_request();
+        let api_key = String::from_utf8(send(&app, req).await.body.to_vec()).unwrap();
+        keys.push(api_key.trim().to_owned());
+    }
+    let (own_key, other_key) = (&keys[0], &keys[1]);
+
+    // A key from signup holds no `admin`, and may still revoke itself.
+    let req = test::TestRequest::delete()
+        .uri("/api-key")
+        .insert_header(basic(own_key))
+        .to_request();
+    assert_eq!(send(&app, req).await.status, 204);
+
+    let req = test::TestRequest::get()
+        .uri("/api/to-celsius/212")
+        .insert_header(basic(own_key))
+        .to_request();
+    assert_eq!(send(&app, req).await.status, 403);
+
+    // Operators issue the keys that may revoke others.
+    let req = test::TestRequest::post()
+        .uri("/admin/keys")
+        .insert_header(admin_bearer())
+        .set_json(json!({ "email": "ops@example.com", "scopes": ["admin"] }))
+        .to_request();
+    let issued = call(&app, req).await;
+    assert_eq!(issued["status"], 201);
+    assert_eq!(issued["body"]["scopes"], json!(["admin"]));
+    let ops_key = issued["body"]["api_key"].as_str().unwrap().to_owned();
+
+    let req = test::TestRequest::get()
+        .uri("/api/whoami")
+        .insert_header(basic(other_key))
+        .to_request();
+    let other_id = call(&app, req).await["body"]["id"].as_i64().unwrap();
+
+    let req = test::TestRequest::delete()
+        .uri(&format!("/api-key/{other_id}"))
+        .insert_header(basic(&ops_key))
+        .to_request();
+    assert_eq!(send(&app, req).await.status, 204);
+
+    let req = test::TestRequest::get()
+        .uri("/api/to-celsius/212")
+        .insert_header(basic(other_key))
+        .to_request();
+    assert_eq!(send(&app, req).await.status, 403);
+
+    let req = test::TestRequest::delete()
+        .uri(&format!("/api-key/{other_id}"))
+        .insert_header(basic(&ops_key))
+        .to_request();
+    assert_eq!(send(&app, req).await.status, 404);
+}
+
+#[actix_web::test]
+async fn authentication_failures() {
+    let database = database();
+    let app = app!(config(), database);
+
+    let req = test::TestRequest::get()
+        .uri("/api/to-celsius/100")
+        .to_request();
+    let res = test::call_service(&app, req).await;
+    assert_eq!(res.status(), 401);
+    assert_eq!(
+        res.headers().get("WWW-Authenticate").unwrap(),
+        r#"Basic realm="api", charset="UTF-8""#
+    );
+
+    let req = test::TestRequest::get()
+        .uri("/api/to-celsius/100")
+        .insert_header((AUTHORIZATION, "Bearer not-basic"))
+        .to_request();
+    assert_json_snapshot!("malformed_credentials", call(&app, req).await);
+
+    let req = test::TestRequest::get()
+        .uri("/api/to-celsius/100")
+        .insert_header(basic(""))
+        .to_request();
+    assert_eq!(send(&app, req).await.status, 400);
+
+    // Signing up again revokes the address's previous key.
+    let link = signup_link(&database, "ada@example.com").await;
+    let req = test::TestRequest::post().uri(&link).to_request();
+    let revoked = String::from_utf8(send(&app, req).await.body.to_vec()).unwrap();
+    let link = signup_link(&database, "ada@example.com").await;
+    let req = test::TestRequest::post().uri(&link).to_request();
+    assert!(send(&app, req).await.status.is_success());
+
+    let req = test::TestRequest::get()
+        .uri("/api/to-celsius/100")
+        .insert_header(basic(revoked.trim()))
+        .to_request();
+    assert_json_snapshot!("key_revoked", call(&app, req).await);
+}
+
+#[actix_web::test]
+async fn rate_limit() {
+    let database = database();
+    let app = app!(
+        hello_actix::config::Config {
+            free_tier_requests_per_minute: 2,
+            ..config()
+        },
+        database
+    );
+
+    let link = signup_link(&database, "ada@example.com").await;
+    let req = test::TestRequest::post().uri(&link).to_request();
+    let api_key = String::from_utf8(send(&app, req).await.body.to_vec()).unwrap();
+
+    for _ in 0..2 {
+        let req = test::TestRequest::get()
+            .uri("/api/to-celsius/212")
+            .insert_header(basic(api_key.trim()))
+            .to_request();
+        assert!(send(&app, req).await.status.is_success());
+    }
+
+    let req = test::TestRequest::get()
+        .uri("/api/to-celsius/212")
+        .insert_header(basic(api_key.trim()))
+        .to_request();
+    assert_json_snapshot!("rate_limited", call(&app, req).await);
+}
+
+/// Never done recording, so calls hang until given up on.
+struct StuckSink;
+
+impl UsageSink for StuckSink {
+    fn record(&self, _: &db::ApiUsage) -> Recorded {
+        Box::pin(std::future::pending())
+    }
+}
+
+#[actix_web::test]
+async fn limiter_eviction() {
+    let database = database();
+    let mut state = AppState::new(
+        hello_actix::config::Config {
+            free_tier_requests_per_minute: 2,
+            ..config()
+        },
+        (**database).clone(),
+    );
+    state.usage = web::Data::from(Arc::new(StuckSink) as Arc<dyn UsageSink>);
+    let app = app!(state.clone());
+
+    let link = signup_link(&database, "ada@example.com").await;
+    let req = test::TestRequest::post().uri(&link).to_request();
+    let api_key = String::from_utf8(send(&app, req).await.body.to_vec()).unwrap();
+
+    // A client hanging up mid-call leaves nothing in flight behind.
+    let req = test::TestRequest::get()
+        .uri("/api/to-celsius/212")
+        .insert_header(basic(api_key.trim()))
+        .to_request();
+    let call = test::call_service(&app, req);
+    assert!(
+        actix_web::rt::time::timeout(Duration::from_millis(50), call)
+            .await
+            .is_err()
+    );
+    assert_eq!(state.limiter.entries(), 0);
+
+    // A bucket is kept while it refills, and dropped once it is full.
+    let now = chrono::Utc::now();
+    assert_eq!(state.rate_limiter.entries(), 1);
+    state.rate_limiter.evict_idle(now);
+    assert_eq!(state.rate_limiter.entries(), 1);
+    state
+        .rate_limiter
+        .evict_idle(now + chrono::Duration::minutes(1));
+    assert_eq!(state.rate_limiter.entries(), 0);
+}
+
+#[actix_web::test]
+async fn throttling_report() {
+    let database = database();
+    let state = AppState::new(
+        hello_actix::config::Config {
+            free_tier_requests_per_minute: 2,
+            ..config()
+        },
+        (**database).clone(),
+    );
+    let app = app!(state.clone());
+
+    let link = signup_link(&database, "ada@example.com").await;
+    let req = test::TestRequest::post().uri(&link).to_request();
+    let api_key = String::from_utf8(send(&app, req).await.body.to_vec()).unwrap();
+
+    for _ in 0..4 {
+        let req = test::TestRequest::get()
+            .uri("/api/to-celsius/212")
+            .insert_header(basic(api_key.trim()))
+            .peer_addr("203.0.113.7:4000".parse().unwrap())
+            .to_request();
+        send(&app, req).await;
+    }
+
+    // Reported as saved, not as counted.
+    let req = test::TestRequest::get()
+        .uri("/admin/throttling")
+        .insert_header(admin_bearer())
+        .to_request();
+    let before = call(&app, req).await;
+    assert_eq!(before["body"]["windows"][0]["total"], 0);
+
+    state
+        .throttling
+        .save(state.database.clone(), state.clock.now())
+        .await
+        .unwrap();
+    let req = test::TestRequest::get()
+        .uri("/admin/throttling")
+        .insert_header(admin_bearer())
+        .to_request();
+    assert_json_snapshot!("throttling_report", call(&app, req).await, {
+        ".body.windows[].since" => "[since]",
+    });
+}
+
+#[actix_web::test]
+async fn worker_stats() {
+    let database = database();
+    let app = app!(config(), database);
+
+    let link = signup_link(&database, "ada@example.com").await;
+    let req = test::TestRequest::post().uri(&link).to_request();
+    let api_key = String::from_utf8(send(&app, req).await.body.to_vec()).unwrap();
+
+    for uri in [
+        "/api/to-celsius/212",
+        "/api/to-celsius/32",
+        "/api/to-fahrenheit/0",
+    ] {
+        let req = test::TestRequest::get()
+            .uri(uri)
+            .insert_header(basic(api_key.trim()))
+            .to_request();
+        assert!(send(&app, req).await.status.is_success());
+    }
+
+    let req = test::TestRequest::get()
+        .uri("/admin/stats/workers")
+        .insert_header(admin_bearer())
+        .to_request();
+    assert_json_snapshot!("worker_stats", call(&app, req).await, {
+        ".body.workers[].worker" => "[worker]",
+    });
+}
+
+#[actix_web::test]
+async fn worker_stats_after_restart() {
+    let database = database();
+    let before = hello_actix::AppState::new(config(), (**database).clone());
+    let app = app!(before.clone());
+
+    let link = signup_link(&database, "ada@example.com").await;
+    let req = test::TestRequest::post().uri(&link).to_request();
+    let api_key = String::from_utf8(send(&app, req).await.body.to_vec()).unwrap();
+
+    for uri in ["/api/to-celsius/212", "/api/to-fahrenheit/0"] {
+        let req = test::TestRequest::get()
+            .uri(uri)
+            .insert_header(basic(api_key.trim()))
+            .to_request();
+        assert!(send(&app, req).await.status.is_success());
+    }
+    before.stats.save(database.clone()).await.unwrap();
+
+    let after = hello_actix::AppState::new(config(), (**database).clone());
+    after.stats.restore(database.clone()).await.unwrap();
+    let app = app!(after);
+
+    let req = test::TestRequest::get()
+        .uri("/api/to-celsius/32")
+        .insert_header(basic(api_key.trim()))
+        .to_request();
+    assert!(send(&app, req).await.status.is_success());
+
+    let req = test::TestRequest::get()
+        .uri("/admin/stats/workers")
+        .insert_header(admin_bearer())
+        .to_request();
+    assert_json_snapshot!("worker_stats_after_restart", call(&app, req).await, {
+        ".body.workers[].worker" => "[worker]",
+    });
+}
+
+#[actix_web::test]
+async fn memory_report() {
+    let database = database();
+    let app = app!(config(), database);
+
+    let link = signup_link(&database, "ada@example.com").await;
+    let req = test::TestRequest::post().uri(&link).to_request();
+    let api_key = String::from_utf8(send(&app, req).await.body.to_vec()).unwrap();
+    let req = test::TestRequest::get()
+        .uri("/api/to-celsius/212")
+        .insert_header(basic(api_key.trim()))
+        .to_request();
+    assert!(send(&app, req).await.status.is_success());
+
+    let req = test::TestRequest::get()
+        .uri("/admin/memory")
+        .insert_header(admin_bearer())
+        .to_request();
+    // The allocator depends on features.
+    assert_json_snapshot!("memory_report", call(&app, req).await, {
+        ".body.allocator" => "[allocator]",
+        ".body.allocated_bytes" => "[bytes]",
+        ".body.resident_bytes" => "[bytes]",
+        ".body.mapped_bytes" => "[bytes]",
+    });
+}
+
+#[actix_web::test]
+async fn background_tasks() {
+    let app = app!(config(), database());
+
+    tasks::spawn("snapshot_idle", |_| std::future::pending());
+    tasks::spawn("snapshot_jobs", |task| async move {
+        task.record("succeeds", Ok::<_, String>(()));
+        task.record("fails", Err::<(), _>("no route to host"));
+        std::future::pending().await
+    });
+    actix_web::rt::task::yield_now().await;
+
+    let req = test::TestRequest::get()
+        .uri("/admin/tasks")
+        .insert_header(admin_bearer())
+        .to_request();
+    let mut reply = call(&app, req).await;
+    // Other tests' tasks run alongside these.
+    reply["body"]
+        .as_array_mut()
+        .unwrap()
+        .retain(|task| task["name"].as_str().unwrap().starts_with("snapshot_"));
+    assert_json_snapshot!("background_tasks", reply, {
+        ".body[].id" => "[id]",
+        ".body[].started_at" => "[time]",
+        ".body[].last_run_at" => insta::dynamic_redaction(|value, _| {
+            if value.as_str().is_some() { "[time]".into() } else { value }
+        }),
+    });
+}
+
+#[actix_web::test]
+async fn supervised_task_restart() {
+    let app = app!(config(), database());
+
+    let starts = Rc::new(Cell::new(0));
+    let counted = starts.clone();
+    tasks::supervise("panics_once", move |_| {
+        let starts = counted.clone();
+        async move {
+            starts.set(starts.get() + 1);
+            if starts.get() == 1 {
+                panic!("the first start");
+            }
+            std::future::pending().await
+        }
+    });
+    actix_web::rt::time::timeout(Duration::from_secs(5), async {
+        while starts.get() < 2 {
+            actix_web::rt::time::sleep(Duration::from_millis(20)).await;
+        }
+    })
+    .await
+    .expect("the task wasn't restarted");
+
+    let req = test::TestRequest::get()
+        .uri("/admin/tasks")
+        .insert_header(admin_bearer())
+        .to_request();
+    let mut reply = call(&app, req).await;
+    reply["body"]
+        .as_array_mut()
+        .unwrap()
+        .retain(|task| task["name"] == "panics_once");
+    assert_json_snapshot!("supervised_task_restart", reply, {
+        ".body[].id" => "[id]",
+        ".body[].started_at" => "[time]",
+    });
+
+    let req = test::TestRequest::get()
+        .uri("/admin/metrics")
+        .insert_header(admin_bearer())
+        .to_request();
+    assert_eq!(
+        call(&app, req).await["body"]["task_restarts"]["panics_once"],
+        1
+    );
+}
+
+#[actix_web::test]
+async fn erasure() {
+    let database = database();
+    let app = app!(config(), database);
+
+    let link = signup_link(&database, "ada@example.com").await;
+    let req = test::TestRequest::post().uri(&link).to_request();
+    let api_key = String::from_utf8(send(&app, req).await.body.to_vec()).unwrap();
+    let api_key = api_key.trim();
+
+    let req = test::TestRequest::get()
+        .uri("/api/to-fahrenheit/20")
+        .insert_header(basic(api_key))
+        .to_request();
+    assert!(send(&app, req).await.status.is_success());
+
+    let req = test::TestRequest::get()
+        .uri("/my/data-export")
+        .insert_header(basic(api_key))
+        .to_request();
+    assert_json_snapshot!("data_export", call(&app, req).await, {
+        ".body.exported_at" => "[timestamp]",
+        ".body.key.created_at" => "[timestamp]",
+        ".body.usage[].called_at" => "[timestamp]",
+        ".body.usage[].latency_ms" => "[latency]",
+    });
+
+    let req = test::TestRequest::delete()
+        .uri("/admin/keys/1/data")
+        .insert_header(admin_bearer())
+        .to_request();
+    assert_json_snapshot!("erase_key_data", call(&app, req).await, {
+        ".body.erased_at" => "[timestamp]",
+    });
+
+    let req = test::TestRequest::get()
+        .uri("/api/to-fahrenheit/20")
+        .insert_header(basic(api_key))
+        .to_request();
+    assert_eq!(send(&app, req).await.status, 401);
+
+    let req = test::TestRequest::delete()
+        .uri("/admin/keys/999/data")
+        .insert_header(admin_bearer())
+        .to_request();
+    assert_json_snapshot!("key_not_found", call(&app, req).await);
+}
+
+#[actix_web::test]
+async fn anonymous_usage() {
+    let database = database();
+    let config = hello_actix::config::Config {
+        anonymous_usage: true,
+        ..config()
+    };
+    let app = app!(config, database);
+
+    let link = signup_link(&database, "ada@example.com").await;
+    let req = test::TestRequest::post().uri(&link).to_request();
+    let api_key = String::from_utf8(send(&app, req).await.body.to_vec()).unwrap();
+    let api_key = api_key.trim();
+
+    let req = test::TestRequest::get()
+        .uri("/api/to-fahrenheit/20")
+        .insert_header(basic(api_key))
+        .to_request();
+    assert!(send(&app, req).await.status.is_success());
+
+    // The call is counted, but nothing ties it to the key.
+    let req = test::TestRequest::get()
+        .uri("/my/data-export")
+        .insert_header(basic(api_key))
+        .to_request();
+    let export = call(&app, req).await;
+    assert_eq!(export["body"]["usage"], json!([]));
+
+    let now = chrono::Utc::now();
+    let req = test::TestRequest::get()
+        .uri(&format!(
+            "/admin/reports/monthly/{}/{}",
+            now.format("%Y"),
+            now.format("%-m")
+        ))
+        .insert_header(admin_bearer())
+        .to_request();
+    let report = String::from_utf8(send(&app, req).await.body.to_vec()).unwrap();
+    assert!(report.contains("(anonymous)"), "{report}");
+}
+
+#[actix_web::test]
+async fn usage_comparison() {
+    let database = database();
+    let app = app!(config(), database);
+
+    let calls = [
+        ("2025-01-10T12:00:00Z", db::ApiEndpoint::ToCelsius),
+        ("2025-01-11T12:00:00Z", db::ApiEndpoint::ToCelsius),
+        ("2025-01-12T12:00:00Z", db::ApiEndpoint::ToFahrenheit),
+        ("2025-02-10T12:00:00Z", db::ApiEndpoint::ToCelsius),
+        ("2025-02-11T12:00:00Z", db::ApiEndpoint::ToCelsius),
+        ("2025-02-12T12:00:00Z", db::ApiEndpoint::ToCelsius),
+        ("2025-02-13T12:00:00Z", db::ApiEndpoint::Convert),
+    ];
+    let usage = calls
+        .into_iter()
+        .map(|(called_at, endpoint)| db::ApiUsage {
+            api_key: "seeded".into(),
+            endpoint,
+            called_at: called_at.parse().unwrap(),
+            client_request_id: None,
+            tag: None,
+            location: Default::default(),
+            latency_ms: None,
+        })
+        .collect();
+    db::Query::RecordApiUsageBatch(usage)
+        .execute(database.clone())
+        .await
+        .unwrap();
+
+    let req = test::TestRequest::get()
+        .uri("/admin/usage/compare?period_a=2025-01&period_b=2025-02")
+        .insert_header(admin_bearer())
+        .to_request();
+    assert_json_snapshot!("usage_comparison", call(&app, req).await);
+
+    let req = test::TestRequest::get()
+        .uri("/admin/usage/compare?period_a=2025-1&period_b=2025-02")
+        .insert_header(admin_bearer())
+        .to_request();
+    assert_eq!(call(&app, req).await["status"], 400);
+}
+
+#[actix_web::test]
+async fn bucketed_usage() {
+    let database = database();
+    let app = app!(config(), database);
+
+    let calls = [
+        ("2025-01-10T12:05:00Z", db::ApiEndpoint::ToCelsius),
+        ("2025-01-10T12:55:00Z", db::ApiEndpoint::ToCelsius),
+        ("2025-01-10T13:10:00Z", db::ApiEndpoint::ToCelsius),
+        ("2025-01-10T13:20:00Z", db::ApiEndpoint::ToFahrenheit),
+        ("2025-01-11T09:00:00Z", db::ApiEndpoint::ToCelsius),
+    ];
+    let usage = calls
+        .into_iter()
+        .map(|(called_at, endpoint)| db::ApiUsage {
+            api_key: "seeded".into(),
+            endpoint,
+            called_at: called_at.parse().unwrap(),
+            client_request_id: None,
+            tag: None,
+            location: Default::default(),
+            latency_ms: None,
+        })
+        .collect();
+    db::Query::RecordApiUsageBatch(usage)
+        .execute(database.clone())
+        .await
+        .unwrap();
+
+    let req = test::TestRequest::get()
+        .uri("/admin/usage?from=2025-01-10T00:00:00Z&to=2025-01-11T00:00:00Z")
+        .insert_header(admin_bearer())
+        .to_request();
+    assert_json_snapshot!("bucketed_usage_by_hour", call(&app, req).await);
+
+    let req = test::TestRequest::get()
+        .uri("/admin/usage?from=2025-01-01T00:00:00Z&to=2025-02-01T00:00:00Z&bucket=day")
+        .insert_header(admin_bearer())
+        .to_request();
+    assert_json_snapshot!("bucketed_usage_by_day", call(&app, req).await);
+}
+
+#[actix_web::test]
+async fn report_subscriptions() {
+    let database = database();
+    let outbox = Arc::new(Outbox::default());
+    let mut state = AppState::new(config(), (**database).clone());
+    state.mailer = web::Data::from(outbox.clone() as Arc<dyn notify::Mailer>);
+    let mailer = state.mailer.clone();
+    let app = app!(state);
+
+    let req = test::TestRequest::post()
+        .uri("/admin/report-subscriptions")
+        .insert_header(admin_bearer())
+        .set_json(json!({
+            "schedule": "0 8 * * 1",
+            "target": { "webhook": "ftp://example.com/hook" },
+        }))
+        .to_request();
+    assert_json_snapshot!("invalid_webhook_url", call(&app, req).await);
+
+    let req = test::TestRequest::post()
+        .uri("/admin/report-subscriptions")
+        .insert_header(admin_bearer())
+        .set_json(json!({
+            "endpoint": "to-celsius",
+            "schedule": "0  8 * * 1",
+            "target": { "email": " Ops@Example.com" },
+        }))
+        .to_request();
+    assert_eq!(send(&app, req).await.status, 204);
+
+    // Bring the report forward rather than wait for Monday.
+    database
+        .get()
+        .unwrap()
+        .execute(
+            "UPDATE report_subscriptions SET next_run_at = ?1;",
+            (chrono::Utc::now(),),
+        )
+        .unwrap();
+    subscriptions::send_due_reports(database.clone(), mailer, chrono::Utc::now())
+        .await
+        .unwrap();
+    let mail = outbox.take().pop().unwrap();
+    assert_eq!(mail.to, "ops@example.com");
+    assert!(mail.subject.starts_with("Usage report"), "{mail:?}");
+
+    let req = test::TestRequest::get()
+        .uri("/admin/report-subscriptions")
+        .insert_header(admin_bearer())
+        .to_request();
+    let listed = call(&app, req).await;
+    assert!(listed["body"][0]["last_run_at"].is_string(), "{listed}");
+    assert_json_snapshot!("report_subscriptions", listed, {
+        ".body[].created_at" => "[timestamp]",
+        ".body[].last_run_at" => "[timestamp]",
+        ".body[].next_run_at" => "[timestamp]",
+    });
+}
+
+#[actix_web::test]
+async fn blocklist() {
+    let database = database();
+    let config = hello_actix::config::Config {
+        auth_failure_limit: 3,
+        ..config()
+    };
+    let app = app!(config, database);
+
+    let req = test::TestRequest::post()
+        .uri("/admin/blocklist")
+        .insert_header(admin_bearer())
+        .set_json(json!({ "network": "not-a-network" }))
+        .to_request();
+    assert_json_snapshot!("invalid_network", call(&app, req).await);
+
+    let req = test::TestRequest::post()
+        .uri("/admin/blocklist")
+        .insert_header(admin_bearer())
+        .set_json(json!({ "network": "198.51.100.77/24", "reason": "scraping" }))
+        .to_request();
+    assert_json_snapshot!("add_block", call(&app, req).await);
+
+    let req = test::TestRequest::get()
+        .uri("/openapi.json")
+        .peer_addr("198.51.100.9:4000".parse().unwrap())
+        .to_request();
+    assert_json_snapshot!("blocked", call(&app, req).await);
+
+    // Repeated bad keys block the address itself, and nothing wider.
+    for _ in 0..3 {
+        let req = test::TestRequest::get()
+            .uri("/api/to-celsius/1")
+            .insert_header(basic("not-a-key"))
+            .peer_addr("203.0.113.5:4000".parse().unwrap())
+            .to_request();
+        call(&app, req).await;
+    }
+    for (peer, status) in [("203.0.113.5:4000", 403), ("203.0.113.6:4000", 200)] {
+        let req = test::TestRequest::get()
+            .uri("/openapi.json")
+            .peer_addr(peer.parse().unwrap())
+            .to_request();
+        assert_eq!(send(&app, req).await.status, status, "{peer}");
+    }
+
+    let req = test::TestRequest::get()
+        .uri("/admin/blocklist")
+        .insert_header(admin_bearer())
+        .to_request();
+    assert_json_snapshot!(
+        "list_blocks",
+        call(&app, req).await,
+        {
+            ".body[].created_at" => "[timestamp]",
+            ".body[].expires_at" => insta::dynamic_redaction(|value, _| {
+                if value.as_str().is_some() { "[timestamp]".into() } else { value }
+            }),
+        }
+    );
+
+    let req = test::TestRequest::delete()
+        .uri("/admin/blocklist/1")
+        .insert_header(admin_bearer())
+        .to_request();
+    assert_json_snapshot!("delete_block", call(&app, req).await);
+
+    let req = test::TestRequest::delete()
+        .uri("/admin/blocklist/1")
+        .insert_header(admin_bearer())
+        .to_request();
+    assert_json_snapshot!("block_not_found", call(&app, req).await);
+
+    let req = test::TestRequest::get()
+        .uri("/openapi.json")
+        .peer_addr("198.51.100.9:4000".parse().unwrap())
+        .to_request();
+    assert!(send(&app, req).await.status.is_success());
+}
+
+#[actix_web::test]
+async fn mailed_links() {
+    let database = database();
+    let outbox = Arc::new(Outbox::default());
+    let mut state = AppState::new(config(), (**database).clone());
+    state.mailer = web::Data::from(outbox.clone() as Arc<dyn notify::Mailer>);
+    let app = app!(state);
+
+    let mut signup = solved_challenge(&app).await;
+    signup["email"] = json!("Ada@Example.com");
+    let req = test::TestRequest::post()
+        .uri("/signup")
+        .set_json(&signup)
+        .to_request();
+    assert_eq!(send(&app, req).await.status, 202);
+
+    let mail = outbox.take().pop().unwrap();
+    assert_eq!(mail.to, "ada@example.com");
+    // Opening the link only asks to confirm, so a scanner following it
+    // leaves it to be redeemed.
+    let req = test::TestRequest::get().uri(mail.link()).to_request();
+    let page = send(&app, req).await;
+    assert_eq!(page.status, 200);
+    assert!(String::from_utf8_lossy(&page.body).contains(r#"<form method="post">"#));
+
+    let req = test::TestRequest::post().uri(mail.link()).to_request();
+    let api_key = String::from_utf8(send(&app, req).await.body.to_vec()).unwrap();
+    assert!(api_key.starts_with(auth::KEY_PREFIX));
+
+    // Login links, with the dashboard.
+    #[cfg(feature = "dashboard")]
+    {
+        // Unknown addresses get nothing, though the answer is the same.
+        let req = test::TestRequest::post()
+            .uri("/auth/magic-link")
+            .set_json(json!({ "email": "grace@example.com" }))
+            .to_request();
+        assert_eq!(send(&app, req).await.status, 202);
+        assert!(outbox.take().is_empty());
+
+        log_in(&database, "grace@example.com", db::Role::Viewer).await;
+        let req = test::TestRequest::post()
+            .uri("/auth/magic-link")
+            .set_json(json!({ "email": "grace@example.com" }))
+            .to_request();
+        assert_eq!(send(&app, req).await.status, 202);
+
+        let mail = outbox.take().pop().unwrap();
+        assert_eq!(mail.to, "grace@example.com");
+        let req = test::TestRequest::get().uri(mail.link()).to_request();
+        assert_eq!(send(&app, req).await.status, 200);
+    }
+}
+
+#[cfg(feature = "dashboard")]
+#[actix_web::test]
+async fn dashboard() {
+    let database = database();
+    let app = app!(config(), database);
+
+    let req = test::TestRequest::get().uri("/dashboard/me").to_request();
+    assert_json_snapshot!("not_logged_in", call(&app, req).await);
+
+    let req = test::TestRequest::post()
+        .uri("/auth/magic-link")
+        .set_json(json!({ "email": "unknown@example.com" }))
+        .to_request();
+    assert_json_snapshot!("request_magic_link", call(&app, req).await);
+
+    let req = test::TestRequest::get()
+        .uri("/auth/magic-link/not-a-token")
+        .to_request();
+    assert_json_snapshot!("invalid_magic_link", call(&app, req).await);
+
+    let cookie = log_in(&database, "grace@example.com", db::Role::Admin).await;
+
+    let req = test::TestRequest::get()
+        .uri("/dashboard/me")
+        .cookie(cookie.clone())
+        .to_request();
+    assert_json_snapshot!("dashboard_me", call(&app, req).await);
+
+    let req = test::TestRequest::get()
+        .uri("/auth/csrf-token")
+        .cookie(cookie.clone())
+        .to_request();
+    let res = call(&app, req).await;
+    assert_json_snapshot!("csrf_token", res, { ".body.token" => "[token]" });
+    let csrf = res["body"]["token"].as_str().unwrap().to_owned();
+
+    let req = test::TestRequest::post()
+        .uri("/dashboard/totp/enroll")
+        .cookie(cookie.clone())
+        .to_request();
+    assert_json_snapshot!("invalid_csrf_token", call(&app, req).await);
+
+    let req = test::TestRequest::post()
+        .uri("/dashboard/totp/confirm")
+        .cookie(cookie.clone())
+        .insert_header(("X-CSRF-Token", csrf.as_str()))
+        .set_json(json!({ "code": "000000" }))
+        .to_request();
+    assert_json_snapshot!("totp_not_enrolled", call(&app, req).await);
+
+    let req = test::TestRequest::post()
+        .uri("/dashboard/totp/enroll")
+        .cookie(cookie.clone())
+        .insert_header(("X-CSRF-Token", csrf.as_str()))
+        .to_request();
+    assert_json_snapshot!(
+        "totp_enroll",
+        call(&app, req).await,
+        {
+            ".body.secret" => "[secret]",
+            ".body.otpauth_uri" => insta::dynamic_redaction(|value, _| {
+                let uri = value.as_str().unwrap();
+                let (prefix, rest) = uri.split_once("secret=").unwrap();
+                let (_, suffix) = rest.split_once('&').unwrap();
+                format!("{prefix}secret=[secret]&{suffix}")
+            }),
+        }
+    );
+
+    let req = test::TestRequest::post()
+        .uri("/dashboard/totp/confirm")
+        .cookie(cookie.clone())
+        .insert_header(("X-CSRF-Token", csrf.as_str()))
+        .set_json(json!({ "code": "12" }))
+        .to_request();
+    assert_json_snapshot!("invalid_totp_code", call(&app, req).await);
+
+    let req = test::TestRequest::post()
+        .uri("/auth/logout")
+        .cookie(cookie)
+        .insert_header(("X-CSRF-Token", csrf.as_str()))
+        .to_request();
+    assert_json_snapshot!("logout", call(&app, req).await);
+}